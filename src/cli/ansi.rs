@@ -0,0 +1,200 @@
+use std::io;
+use crossterm::{
+    cursor, execute,
+    style::{Attribute, Color, SetAttribute, SetBackgroundColor, SetForegroundColor},
+};
+use crate::error::Result;
+
+/// Colors and attributes set by SGR (`ESC [ ... m`) sequences
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AnsiStyle {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+}
+
+impl AnsiStyle {
+    /// Apply the parameters of one SGR sequence
+    fn apply(&mut self, params: &str) {
+        let mut codes = params.split(';').map(|code| code.parse::<u16>().unwrap_or(0));
+        while let Some(code) = codes.next() {
+            match code {
+                0 => *self = Self::default(),
+                1 => self.bold = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                7 => self.reverse = true,
+                22 => self.bold = false,
+                23 => self.italic = false,
+                24 => self.underline = false,
+                27 => self.reverse = false,
+                30..=37 => self.fg = Some(basic_color(code - 30)),
+                38 => self.fg = extended_color(&mut codes),
+                39 => self.fg = None,
+                40..=47 => self.bg = Some(basic_color(code - 40)),
+                48 => self.bg = extended_color(&mut codes),
+                49 => self.bg = None,
+                90..=97 => self.fg = Some(bright_color(code - 90)),
+                100..=107 => self.bg = Some(bright_color(code - 100)),
+                _ => {},
+            }
+        }
+    }
+}
+
+fn basic_color(n: u16) -> Color {
+    match n {
+        0 => Color::Black,
+        1 => Color::DarkRed,
+        2 => Color::DarkGreen,
+        3 => Color::DarkYellow,
+        4 => Color::DarkBlue,
+        5 => Color::DarkMagenta,
+        6 => Color::DarkCyan,
+        _ => Color::Grey,
+    }
+}
+
+fn bright_color(n: u16) -> Color {
+    match n {
+        0 => Color::DarkGrey,
+        1 => Color::Red,
+        2 => Color::Green,
+        3 => Color::Yellow,
+        4 => Color::Blue,
+        5 => Color::Magenta,
+        6 => Color::Cyan,
+        _ => Color::White,
+    }
+}
+
+/// The color after a 38 or 48 code: `5;n` for the 256-color palette or `2;r;g;b`
+fn extended_color(codes: &mut impl Iterator<Item = u16>) -> Option<Color> {
+    match codes.next() {
+        Some(5) => codes.next().map(|n| Color::AnsiValue(n as u8)),
+        Some(2) => {
+            let (r, g, b) = (codes.next()?, codes.next()?, codes.next()?);
+            Some(Color::Rgb { r: r as u8, g: g as u8, b: b as u8 })
+        },
+        _ => None,
+    }
+}
+
+/// Split a line of terminal output into styled runs of text. SGR sequences
+/// set the style; other escape sequences and control characters are dropped
+pub fn parse_line(line: &str) -> Vec<(String, AnsiStyle)> {
+    let mut spans = Vec::new();
+    let mut style = AnsiStyle::default();
+    let mut text = String::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            if !c.is_control() || c == '\t' {
+                text.push(c);
+            }
+            continue;
+        }
+        match chars.next() {
+            // CSI: parameters, then a final byte in @..~
+            Some('[') => {
+                let mut params = String::new();
+                let mut final_byte = None;
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        final_byte = Some(c);
+                        break;
+                    }
+                    params.push(c);
+                }
+                if final_byte == Some('m') {
+                    if !text.is_empty() {
+                        spans.push((std::mem::take(&mut text), style));
+                    }
+                    style.apply(&params);
+                }
+            },
+            // OSC (window titles, hyperlinks): ends with BEL or ESC \
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+    if !text.is_empty() {
+        spans.push((text, style));
+    }
+    spans
+}
+
+/// Draw a line of terminal output at (`x`, `y`) in its colors, cut off after
+/// `width` characters
+pub fn render_styled_line(x: usize, y: usize, line: &str, width: usize) -> Result<()> {
+    execute!(io::stdout(), cursor::MoveTo(x as u16, y as u16))?;
+    let mut remaining = width;
+    for (text, style) in parse_line(line) {
+        if remaining == 0 {
+            break;
+        }
+        let text: String = text.chars().take(remaining).collect();
+        remaining -= text.chars().count();
+
+        let (fg, bg) = if style.reverse {
+            (style.bg.or(Some(Color::Black)), style.fg.or(Some(Color::White)))
+        } else {
+            (style.fg, style.bg)
+        };
+        if let Some(fg) = fg {
+            execute!(io::stdout(), SetForegroundColor(fg))?;
+        }
+        if let Some(bg) = bg {
+            execute!(io::stdout(), SetBackgroundColor(bg))?;
+        }
+        if style.bold {
+            execute!(io::stdout(), SetAttribute(Attribute::Bold))?;
+        }
+        if style.italic {
+            execute!(io::stdout(), SetAttribute(Attribute::Italic))?;
+        }
+        if style.underline {
+            execute!(io::stdout(), SetAttribute(Attribute::Underlined))?;
+        }
+        print!("{}", text);
+        execute!(io::stdout(), SetAttribute(Attribute::Reset), SetForegroundColor(Color::Reset),
+            SetBackgroundColor(Color::Reset))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_text_at_color_changes() {
+        let spans = parse_line("a \x1b[1;31merror\x1b[0m: b");
+        let texts: Vec<&str> = spans.iter().map(|(text, _)| text.as_str()).collect();
+        assert_eq!(texts, ["a ", "error", ": b"]);
+        assert_eq!(spans[1].1, AnsiStyle { fg: Some(Color::DarkRed), bold: true, ..Default::default() });
+        assert_eq!(spans[2].1, AnsiStyle::default());
+    }
+
+    #[test]
+    fn reads_extended_colors() {
+        let spans = parse_line("\x1b[38;5;208mx\x1b[48;2;1;2;3my");
+        assert_eq!(spans[0].1.fg, Some(Color::AnsiValue(208)));
+        assert_eq!(spans[1].1.bg, Some(Color::Rgb { r: 1, g: 2, b: 3 }));
+    }
+
+    #[test]
+    fn drops_other_escape_sequences() {
+        let spans = parse_line("\x1b]0;title\x07one\x1b[Ktwo\r");
+        assert_eq!(spans, [("onetwo".to_string(), AnsiStyle::default())]);
+    }
+}
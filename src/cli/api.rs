@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use crate::cli::options::{FileTypeConfig, Options};
+
+/// A mark position as (row, col), both zero-based
+pub type MarkPosition = (usize, usize);
+
+/// What a leader mapping from `rvim.map` runs
+pub enum MapAction {
+    /// An Ex command, from an action like `:Format<CR>`
+    Command(String),
+    /// Keys handled as if typed in Normal mode
+    Keys(String),
+    /// A Lua function
+    Lua(mlua::RegistryKey),
+}
+
+/// A `<Space>{key}` mapping registered with `rvim.map('n', '<space>r', action, { desc = ... })`
+pub struct LeaderMapping {
+    pub key: char,
+    pub action: MapAction,
+    pub desc: String,
+}
+
+/// The key after the leader in a mapping's keys, e.g. `r` for `<space>r` or `<leader>r`
+pub fn parse_leader_key(keys: &str) -> Option<char> {
+    let lower = keys.to_ascii_lowercase();
+    let rest = ["<space>", "<leader>", " "].iter()
+        .find(|leader| lower.starts_with(*leader))
+        .map(|leader| &keys[leader.len()..])?;
+    let mut chars = rest.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => None,
+    }
+}
+
+/// Action for a mapping's string: `:cmd<CR>` runs an Ex command, anything else is typed
+pub fn map_action(action: &str) -> MapAction {
+    match action.strip_prefix(':') {
+        Some(command) => {
+            let command = command.strip_suffix("<CR>").or_else(|| command.strip_suffix("<cr>")).unwrap_or(command);
+            MapAction::Command(command.to_string())
+        },
+        None => MapAction::Keys(action.to_string()),
+    }
+}
+
+/// Editor state shared with the Lua API functions
+///
+/// Lua closures cannot borrow the `Editor`, so anything they need to read or
+/// change lives here and the editor keeps it in sync.
+#[derive(Default)]
+pub struct ApiState {
+    /// Id of the active buffer, what `0` refers to in `rvim.buf` functions
+    pub current_buffer: usize,
+    /// Ids of all open buffers
+    pub buffers: Vec<usize>,
+    /// Ids of tab buffers whose files have not been read yet
+    pub unloaded_buffers: Vec<usize>,
+    /// Marks set in each buffer, keyed by buffer id
+    pub marks: HashMap<usize, HashMap<char, MarkPosition>>,
+    /// Editor options, changed by `rvim.set` and `:set`
+    pub options: Options,
+    /// Settings by filetype, from `rvim.filetype.set`
+    pub filetypes: HashMap<String, FileTypeConfig>,
+    /// Directory of the loaded `.rvim.lua`, returned by `rvim.api.project_root()`
+    pub project_root: Option<PathBuf>,
+    /// Branch of the active file's repository, returned by `rvim.api.get_git_branch()`
+    pub git_branch: Option<String>,
+}
+
+pub type SharedApiState = Arc<Mutex<ApiState>>;
+
+impl ApiState {
+    pub fn new_shared() -> SharedApiState {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    /// Turn a Lua buffer id into a buffer id, treating 0 as the current buffer
+    pub fn resolve_buffer(&self, buf_id: usize) -> mlua::Result<usize> {
+        let id = if buf_id == 0 { self.current_buffer } else { buf_id };
+        if self.buffers.contains(&id) {
+            Ok(id)
+        } else {
+            Err(mlua::Error::RuntimeError(format!("Invalid buffer id: {}", buf_id)))
+        }
+    }
+
+    pub fn get_mark(&self, buf_id: usize, name: char) -> Option<MarkPosition> {
+        self.marks.get(&buf_id).and_then(|marks| marks.get(&name)).copied()
+    }
+
+    pub fn set_mark(&mut self, buf_id: usize, name: char, position: MarkPosition) {
+        self.marks.entry(buf_id).or_default().insert(name, position);
+    }
+
+    pub fn del_mark(&mut self, buf_id: usize, name: char) -> bool {
+        self.marks.get_mut(&buf_id)
+            .is_some_and(|marks| marks.remove(&name).is_some())
+    }
+
+    /// Move a buffer's marks after lines `at..at + removed` were replaced by
+    /// `inserted` lines. Replaced lines pair up with the new ones in order, and
+    /// marks on lines that are gone are deleted
+    pub fn shift_marks(&mut self, buf_id: usize, at: usize, removed: usize, inserted: usize) {
+        let Some(marks) = self.marks.get_mut(&buf_id) else {
+            return;
+        };
+        let (kept, after) = (at + inserted.min(removed), at + removed);
+        marks.retain(|_, (row, _)| match *row {
+            r if r < kept => true,
+            r if r >= after => {
+                *row = r + inserted - removed;
+                true
+            },
+            _ => false,
+        });
+    }
+
+    /// Whether a buffer's contents are in memory; false for unknown ids
+    pub fn is_loaded(&self, buf_id: usize) -> bool {
+        let id = if buf_id == 0 { self.current_buffer } else { buf_id };
+        self.buffers.contains(&id)
+    }
+
+    /// Forget state belonging to buffers that are no longer open
+    pub fn retain_buffers(&mut self, ids: Vec<usize>) {
+        self.marks.retain(|id, _| ids.contains(id) || self.unloaded_buffers.contains(id));
+        self.buffers = ids;
+    }
+}
+
+/// Parse a mark name given from Lua; marks are single letters
+pub fn parse_mark_name(name: &str) -> mlua::Result<char> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_alphabetic() => Ok(c),
+        _ => Err(mlua::Error::RuntimeError(format!("Invalid mark name: {:?}", name))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_follow_added_and_removed_lines() {
+        let mut state = ApiState::default();
+        for (name, row) in [('a', 1), ('b', 3), ('c', 5)] {
+            state.set_mark(1, name, (row, 2));
+        }
+        // Two lines opened above line 3
+        state.shift_marks(1, 3, 0, 2);
+        assert_eq!([state.get_mark(1, 'a'), state.get_mark(1, 'b'), state.get_mark(1, 'c')],
+            [Some((1, 2)), Some((5, 2)), Some((7, 2))]);
+        // Lines 4..=5 deleted: the mark on line 5 goes with them
+        state.shift_marks(1, 4, 2, 0);
+        assert_eq!([state.get_mark(1, 'a'), state.get_mark(1, 'b'), state.get_mark(1, 'c')],
+            [Some((1, 2)), None, Some((5, 2))]);
+    }
+}
@@ -0,0 +1,79 @@
+use std::path::Path;
+use mlua::RegistryKey;
+
+/// Events `rvim.autocmd` and `rvim.on` accept
+pub const EVENTS: [&str; 7] = ["BufEnter", "BufLeave", "BufRead", "BufWrite", "InsertEnter", "InsertLeave", "ModeChanged"];
+
+/// A Lua callback registered with `rvim.autocmd(event, pattern, callback)`, or
+/// with `rvim.on(event, callback)` for every buffer
+pub struct Autocommand {
+    pub event: String,
+    /// Glob matched against the buffer's file name, e.g. `*.rs`; `*` matches
+    /// every buffer, including ones without a name
+    pub pattern: String,
+    pub callback: RegistryKey,
+    /// Registered with `rvim.on` while a plugin was loading. Plugins are not
+    /// loaded again when config.lua is, so their hooks are kept across a reload
+    pub plugin: bool,
+}
+
+impl Autocommand {
+    pub fn matches(&self, event: &str, filename: Option<&str>) -> bool {
+        if self.event != event {
+            return false;
+        }
+        if self.pattern == "*" {
+            return true;
+        }
+        // Patterns without a slash only need to match the last component
+        filename.is_some_and(|filename| {
+            let name = Path::new(filename).file_name().map_or(filename.into(), |name| name.to_string_lossy());
+            glob_match(&self.pattern, filename) || (!self.pattern.contains('/') && glob_match(&self.pattern, &name))
+        })
+    }
+}
+
+/// Match `text` against a pattern where `*` stands for any run of characters
+/// and `?` for one character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was and how much of the text it has taken
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("*.rs", "main.rs"));
+        assert!(glob_match("src/*.rs", "src/main.rs"));
+        assert!(glob_match("?ake*", "Makefile"));
+        assert!(!glob_match("*.rs", "main.rs.bak"));
+        assert!(!glob_match("*.py", "main.rs"));
+    }
+}
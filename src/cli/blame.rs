@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::error::{Error, Result};
+
+/// Width of the blame column: author, a space and the relative date
+pub const BLAME_WIDTH: usize = 30;
+
+/// Author and date of the commit that last changed each line of `path`, keyed
+/// by 0-based line number, from `git blame --porcelain`
+pub fn blame(path: &Path) -> Result<HashMap<usize, String>> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path.file_name().ok_or_else(|| Error::Message(format!("Cannot blame {}", path.display())))?;
+    let output = Command::new("git")
+        .args(["blame", "--porcelain", "--"])
+        .arg(name)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| Error::Message(format!("Cannot run git: {}", e)))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr.lines().next().unwrap_or("git blame failed").trim_start_matches("fatal: ");
+        return Err(Error::Message(reason.to_string()));
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    Ok(parse_porcelain(&String::from_utf8_lossy(&output.stdout), now))
+}
+
+/// Blame summaries from porcelain output. A commit's author and time are only
+/// given the first time it appears, so they are remembered by commit id
+pub fn parse_porcelain(output: &str, now: u64) -> HashMap<usize, String> {
+    let mut commits: HashMap<&str, (&str, u64)> = HashMap::new();
+    let mut blame = HashMap::new();
+    let mut current: Option<(&str, usize)> = None;
+    for line in output.lines() {
+        // The line's content, after a tab, ends its entry
+        if line.starts_with('\t') {
+            if let Some((commit, row)) = current.take() {
+                let (author, time) = commits.get(commit).copied().unwrap_or(("", 0));
+                blame.insert(row, format!("{:<16.16} {:>13}", author, relative_time(now.saturating_sub(time))));
+            }
+        } else if let Some(author) = line.strip_prefix("author ") {
+            if let Some((commit, _)) = current {
+                commits.entry(commit).or_insert((author, 0)).0 = author;
+            }
+        } else if let Some(time) = line.strip_prefix("author-time ") {
+            if let Some((commit, _)) = current {
+                commits.entry(commit).or_insert(("", 0)).1 = time.parse().unwrap_or(0);
+            }
+        } else {
+            // `<commit> <original line> <final line> [<lines in group>]`
+            let mut fields = line.split(' ');
+            if let (Some(commit), Some(_), Some(final_line)) = (fields.next(), fields.next(), fields.next())
+                && commit.len() == 40
+                && let Ok(final_line) = final_line.parse::<usize>()
+            {
+                current = Some((commit, final_line.saturating_sub(1)));
+            }
+        }
+    }
+    blame
+}
+
+/// `seconds` ago as text, e.g. `3 days ago`
+fn relative_time(seconds: u64) -> String {
+    const UNITS: [(u64, &str); 5] = [
+        (365 * 24 * 3600, "year"),
+        (30 * 24 * 3600, "month"),
+        (24 * 3600, "day"),
+        (3600, "hour"),
+        (60, "minute"),
+    ];
+    match UNITS.iter().find(|(unit, _)| seconds >= *unit) {
+        Some((unit, name)) => {
+            let count = seconds / unit;
+            format!("{} {}{} ago", count, name, if count == 1 { "" } else { "s" })
+        },
+        None => "just now".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_commit_details_across_entries() {
+        let sha = "a".repeat(40);
+        let output = format!(
+            "{sha} 1 1 2\nauthor Ada Lovelace\nauthor-time 1000\nsummary first\nfilename f.rs\n\tfn a() {{}}\n\
+             {sha} 2 2\n\tfn b() {{}}\n"
+        );
+        let blame = parse_porcelain(&output, 1000 + 2 * 24 * 3600);
+        assert_eq!(blame.len(), 2);
+        assert_eq!(blame[&1], format!("{:<16} {:>13}", "Ada Lovelace", "2 days ago"));
+    }
+}
@@ -1,10 +1,14 @@
 use crate::cli::shell::Shell;
+use crate::cli::githunk::HunkKind;
 use crate::error::{Error, Result};
 use crate::lsp::{self, get_language_id_from_extension, get_language};  // Add explicit imports
+use regex::Regex;
 use ropey::Rope;
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tree_sitter::{Language, Parser as TsParser, Tree};
 
 // Add error conversion for tree-sitter language errors
@@ -14,8 +18,27 @@ impl From<tree_sitter::LanguageError> for Error {
     }
 }
 
+/// Replacement of the text from a start to an end (row, byte col) position
+pub type RangeEdit = ((usize, usize), (usize, usize), String);
+
+/// Lines `start..=end` folded with `zf`; a closed fold shows as one summary line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fold {
+    pub start: usize,
+    pub end: usize,
+    pub closed: bool,
+}
+
+// Buffer ids start at 1 so that 0 can mean "the current buffer" in the Lua API
+static NEXT_BUFFER_ID: AtomicUsize = AtomicUsize::new(1);
+
+pub fn next_buffer_id() -> usize {
+    NEXT_BUFFER_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 #[derive(Clone)]
 pub struct Buffer {
+    pub id: usize,
     pub document: Document,
     pub cursor_x: usize,
     pub cursor_y: usize,
@@ -26,7 +49,22 @@ pub struct Buffer {
     pub filename: Option<String>,
     parser: Option<Arc<TsParser>>, // Wrap Parser in Arc for Clone
     tree: Option<Tree>,
+    // Document version the syntax tree was parsed from
+    tree_version: Option<u64>,
     language: Option<Language>,
+    /// Detected from the file name, e.g. `rust` or `makefile`; picks the filetype settings
+    pub filetype: Option<String>,
+    /// Continue long lines on the next screen row; follows the `wrap` option
+    pub wrap: bool,
+    /// Prefix of continuation rows; follows the `showbreak` option
+    pub showbreak: String,
+    /// Mark tabs with a `→`; follows the `show_tabs` option
+    pub show_tabs: bool,
+    /// Show line numbers; follows the `number` and `relativenumber` options
+    pub number: bool,
+    /// Unstaged git changes as first row, end row (exclusive) and kind, from
+    /// the file as it was last opened or written
+    pub git_hunks: Vec<(usize, usize, HunkKind)>,
 }
 
 #[derive(Clone)]
@@ -35,14 +73,68 @@ pub struct Document {
     pub lines: Vec<String>, // Cache for line display
     pub filename: Option<String>,
     pub modified: bool,
+    pub line_ending: LineEnding,
+    /// End the last line with a line break when writing; kept from the file read
+    pub final_newline: bool,
     pub undo_tree: UndoTree,
+    /// Bumped on every edit so derived data such as the syntax tree can tell it is stale
+    pub version: u64,
+    /// Spaces per indentation level; follows the `shiftwidth` option
+    pub shiftwidth: usize,
+    /// Columns between tab stops; follows the `tabstop` option
+    pub tabstop: usize,
+    /// Follows the `expandtab` option; unset, Tab inserts a tab and indentation
+    /// uses spaces
+    pub expandtab: Option<bool>,
+    /// Folds made with `zf`, outer ones before the folds nested in them. Edits
+    /// that add or remove lines move them along with their text
+    pub folds: Vec<Fold>,
+    /// Line changes `(at, removed, inserted)` the editor has not yet applied
+    /// to the buffer's marks, oldest first
+    pub line_shifts: Vec<(usize, usize, usize)>,
 }
 
-#[derive(Clone)]
-struct UndoTree {
-    // Add fields for undo/redo functionality
+/// Line terminator style detected when a file is read
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineEnding {
+    Unix,
+    Dos,
+}
+
+impl LineEnding {
+    fn detect(content: &str) -> Self {
+        if content.contains("\r\n") {
+            LineEnding::Dos
+        } else {
+            LineEnding::Unix
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LineEnding::Unix => "unix",
+            LineEnding::Dos => "dos",
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Unix => "\n",
+            LineEnding::Dos => "\r\n",
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UndoTree {
+    // States saved before each change, oldest first. After an undo the last
+    // entry is the newest text, so it can be redone
     history: Vec<(usize, String)>, // (position, content)
+    // Number of changes applied; history[current] is the state a redo returns to
     current: usize,
+    // Maximum number of states kept, from the undolevels option
+    #[serde(skip)]
+    limit: Option<usize>,
 }
 
 impl UndoTree {
@@ -50,14 +142,153 @@ impl UndoTree {
         Self {
             history: Vec::new(),
             current: 0,
+            limit: None,
+        }
+    }
+
+    /// Record a state, dropping any redo states and the oldest ones over the limit
+    fn push(&mut self, position: usize, content: String) {
+        self.history.truncate(self.current);
+        if self.limit == Some(0) {
+            // undolevels=0 disables undo
+            self.history.clear();
+            self.current = 0;
+            return;
+        }
+        if let Some(limit) = self.limit
+            && self.history.len() >= limit
+        {
+            let excess = self.history.len() + 1 - limit;
+            self.history.drain(..excess);
+        }
+        self.history.push((position, content));
+        self.current = self.history.len();
+    }
+
+    fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+        if let Some(limit) = limit
+            && self.history.len() > limit
+        {
+            let excess = self.history.len() - limit;
+            self.history.drain(..excess);
+            self.current = self.current.saturating_sub(excess);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Step back one change. `latest` is the current text, kept so it can be redone
+    fn undo(&mut self, position: usize, latest: String) -> Option<&(usize, String)> {
+        if self.current == 0 {
+            return None;
+        }
+        if self.current == self.history.len() {
+            self.history.push((position, latest));
+        }
+        self.current -= 1;
+        self.history.get(self.current)
+    }
+
+    /// Step forward one change, returning where that change was made and the text after it
+    fn redo(&mut self) -> Option<(usize, &str)> {
+        if self.current + 1 >= self.history.len() {
+            return None;
+        }
+        let position = self.history[self.current].0;
+        self.current += 1;
+        Some((position, &self.history[self.current].1))
+    }
+}
+
+/// Filetype of a file: from its name for files such as `Makefile`, otherwise
+/// the language id of its extension
+pub fn detect_filetype(filename: &str) -> Option<String> {
+    let path = Path::new(filename);
+    let name = path.file_name()?.to_str()?;
+    let filetype = match name {
+        "Makefile" | "makefile" | "GNUmakefile" => "makefile",
+        "Dockerfile" => "dockerfile",
+        "CMakeLists.txt" => "cmake",
+        _ => match path.extension().and_then(|ext| ext.to_str())? {
+            "mk" => "makefile",
+            "cmake" => "cmake",
+            ext => get_language_id_from_extension(ext)?,
+        },
+    };
+    Some(filetype.to_string())
+}
+
+/// Spaces per indentation level until the `shiftwidth` option says otherwise
+pub const DEFAULT_SHIFTWIDTH: usize = 4;
+
+/// Columns between tab stops until the `tabstop` option says otherwise
+pub const DEFAULT_TABSTOP: usize = 4;
+
+/// Line endings after which the next line is indented one level deeper
+const INDENT_OPENERS: [&str; 5] = ["{", "(", "[", ":", "->"];
+
+/// Line starts that close a block and so sit one level shallower
+const INDENT_CLOSERS: [char; 3] = ['}', ')', ']'];
+
+/// `indent` with one level removed from its end: a tab or up to `shiftwidth` spaces
+fn dedent(indent: &str, shiftwidth: usize) -> &str {
+    if let Some(rest) = indent.strip_suffix('\t') {
+        return rest;
+    }
+    let spaces = indent.bytes().rev().take(shiftwidth).take_while(|&b| b == b' ').count();
+    &indent[..indent.len() - spaces]
+}
+
+/// Byte range of characters `start_char..end_char` in `line`, clamped to its end
+pub fn char_range_to_bytes(line: &str, start_char: usize, end_char: usize) -> std::ops::Range<usize> {
+    let byte_at = |n: usize| line.char_indices().nth(n).map_or(line.len(), |(idx, _)| idx);
+    byte_at(start_char)..byte_at(end_char)
+}
+
+/// One entry per screen column of `line`: the byte the column shows and the
+/// char drawn there. A tab fills the columns up to the next tab stop with spaces
+pub fn expand_tabs(line: &str, tabstop: usize) -> Vec<(usize, char)> {
+    let tabstop = tabstop.max(1);
+    let mut cells = Vec::with_capacity(line.len());
+    for (idx, c) in line.char_indices() {
+        if c == '\t' {
+            let spaces = tabstop - cells.len() % tabstop;
+            cells.extend(std::iter::repeat_n((idx, ' '), spaces));
+        } else {
+            cells.push((idx, c));
         }
     }
+    cells
+}
+
+/// Contents of an undo file; the hash ties the history to the text it was saved with
+#[derive(Serialize, Deserialize)]
+struct UndoFile {
+    text_hash: u64,
+    tree: UndoTree,
+}
+
+/// FNV-1a, stable across builds unlike the std hasher
+fn text_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Undo file for `filename` in `dir`, named after the full path with `/` replaced by `%` as in Vim
+fn undo_file_path(dir: &Path, filename: &str) -> PathBuf {
+    let path = fs::canonicalize(filename).unwrap_or_else(|_| PathBuf::from(filename));
+    dir.join(path.to_string_lossy().replace(['/', '\\'], "%"))
 }
 
 impl Buffer {
     pub fn new() -> Self {
         let parser = TsParser::new();
         Self {
+            id: next_buffer_id(),
             document: Document::new(),
             cursor_x: 0,
             cursor_y: 0,
@@ -68,7 +299,14 @@ impl Buffer {
             filename: None,
             parser: Some(Arc::new(parser)),
             tree: None,
+            tree_version: None,
             language: None,
+            filetype: None,
+            wrap: false,
+            showbreak: String::new(),
+            show_tabs: false,
+            number: true,
+            git_hunks: Vec::new(),
         }
     }
 
@@ -78,6 +316,7 @@ impl Buffer {
 
         let mut parser = TsParser::new();
         let mut buffer = Self {
+            id: next_buffer_id(),
             document: Document::from_file(filename)?,
             cursor_x: 0,
             cursor_y: 0,
@@ -88,10 +327,23 @@ impl Buffer {
             filename: Some(filename.to_string()),
             parser: Some(Arc::new(parser)),
             tree: None,
+            tree_version: None,
             language: None,
+            filetype: None,
+            wrap: false,
+            showbreak: String::new(),
+            show_tabs: false,
+            number: true,
+            git_hunks: Vec::new(),
         };
 
-        // Initialize language if available
+        buffer.detect_language(filename)?;
+        Ok(buffer)
+    }
+
+    // Initialize language from the file extension if available
+    fn detect_language(&mut self, filename: &str) -> Result<()> {
+        self.filetype = detect_filetype(filename);
         let ext = Path::new(filename)
             .extension()
             .and_then(|ext| ext.to_str())
@@ -101,16 +353,16 @@ impl Buffer {
             if let Some(lang) = get_language(lang_id) {
                 let mut new_parser = TsParser::new();
                 new_parser.set_language(lang)?;
-                buffer.parser = Some(Arc::new(new_parser));
-                buffer.language = Some(lang);
+                self.parser = Some(Arc::new(new_parser));
+                self.language = Some(lang);
             }
         }
-
-        Ok(buffer)
+        Ok(())
     }
 
     pub fn from_shell(is_horizontal: bool) -> Self {
         Self {
+            id: next_buffer_id(),
             document: Document::new(),
             cursor_x: 0,
             cursor_y: 0,
@@ -121,7 +373,26 @@ impl Buffer {
             filename: None,
             parser: None,
             tree: None,
+            tree_version: None,
             language: None,
+            filetype: None,
+            wrap: false,
+            showbreak: String::new(),
+            show_tabs: false,
+            number: true,
+            git_hunks: Vec::new(),
+        }
+    }
+
+    /// Resolve a relative file name against `base`, so the buffer keeps its
+    /// file when the working directory changes
+    pub fn make_path_absolute(&mut self, base: &Path) {
+        if let Some(filename) = &self.filename
+            && Path::new(filename).is_relative()
+        {
+            let path = base.join(filename).to_string_lossy().to_string();
+            self.document.filename = Some(path.clone());
+            self.filename = Some(path);
         }
     }
 
@@ -132,6 +403,20 @@ impl Buffer {
         self.document.save()
     }
     
+    /// Write the buffer to `filename` and make that its file from now on
+    pub fn save_as(&mut self, filename: &str) -> Result<()> {
+        if self.is_shell {
+            return Err(Error::Message("Cannot save shell buffer".into()));
+        }
+        self.document.save_as(filename)?;
+        self.filename = Some(filename.to_string());
+        if self.language.is_none() {
+            self.detect_language(filename)?;
+            self.tree_version = None;
+        }
+        Ok(())
+    }
+
     pub fn set_language(&mut self, lang: Language) -> Result<()> {
         // Create a new parser since we can't modify through Arc
         let mut new_parser = TsParser::new();
@@ -144,18 +429,354 @@ impl Buffer {
     
     fn update_syntax_tree(&mut self) -> Result<()> {
         if let (Some(_), Some(_)) = (&self.parser, &self.language) {
-            let text = self.document.rope.to_string();
+            // Parse the lines as drawn so tree positions match screen columns
+            let text = self.document.lines.join("\n");
             // Create a new parser instance since we can't mutably borrow from Arc
             let mut parser_instance = TsParser::new();
             if let Some(lang) = &self.language {
                 parser_instance.set_language(*lang)?;
                 if let Some(tree) = parser_instance.parse(&text, None) {
                     self.tree = Some(tree);
+                    self.tree_version = Some(self.document.version);
                 }
             }
         }
         Ok(())
     }
+
+    /// Screen column of the cursor with tabs expanded, the unit `offset_x` scrolls by
+    pub fn cursor_column(&self) -> usize {
+        self.document.lines.get(self.cursor_y)
+            .and_then(|line| line.get(..self.cursor_x))
+            .map_or(0, |before| expand_tabs(before, self.document.tabstop).len())
+    }
+
+    /// Scroll so the cursor is inside a viewport of `height` lines and `width`
+    /// columns, with `scrolloff` lines kept between it and the top and bottom
+    /// edges where the file allows
+    pub fn scroll_to_cursor(&mut self, height: usize, width: usize, scrolloff: usize) {
+        if height == 0 || width == 0 {
+            return;
+        }
+        // The cursor rests on the first line of a closed fold
+        self.cursor_y = self.fold_start(self.cursor_y);
+        // A margin of more than half the viewport would never let the cursor settle
+        let margin = scrolloff.min((height - 1) / 2);
+        let below = margin.min(self.document.lines.len().saturating_sub(self.cursor_y + 1));
+        if self.wrap || self.document.folds.iter().any(|fold| fold.closed) {
+            if self.wrap {
+                self.offset_x = 0;
+            }
+            if self.cursor_y < self.offset_y + margin {
+                self.offset_y = self.cursor_y.saturating_sub(margin);
+            } else {
+                // Every line takes at least one row, so start no further up than that allows
+                self.offset_y = self.offset_y.max((self.cursor_y + below + 1).saturating_sub(height));
+                let below_rows: usize = (1..=below).map(|n| self.screen_rows(self.cursor_y + n, width)).sum();
+                let column = self.cursor_column();
+                while self.offset_y < self.cursor_y
+                    && self.logical_to_screen_pos(self.cursor_y, column, width).0 + below_rows >= height
+                {
+                    self.offset_y += 1;
+                }
+            }
+            // Start the window on a line that is shown
+            while self.offset_y < self.cursor_y && self.screen_rows(self.offset_y, width) == 0 {
+                self.offset_y += 1;
+            }
+            if self.wrap {
+                return;
+            }
+        } else if self.cursor_y < self.offset_y + margin {
+            self.offset_y = self.cursor_y.saturating_sub(margin);
+        } else if self.cursor_y + below >= self.offset_y + height {
+            self.offset_y = self.cursor_y + below + 1 - height;
+        }
+
+        let column = self.cursor_column();
+        if column < self.offset_x {
+            self.offset_x = column;
+        } else if column >= self.offset_x + width {
+            self.offset_x = column + 1 - width;
+        }
+    }
+
+    /// Columns of text on each continuation row of a line wrapped at `width` columns
+    fn continuation_width(&self, width: usize) -> usize {
+        width.saturating_sub(self.showbreak.chars().count()).max(1)
+    }
+
+    /// Screen rows line `row` takes in a text area `width` columns wide
+    pub fn screen_rows(&self, row: usize, width: usize) -> usize {
+        // Lines inside a closed fold are hidden behind its summary line
+        if let Some((start, _)) = self.closed_fold_at(row) {
+            return usize::from(row == start);
+        }
+        let columns = self.document.lines.get(row).map_or(0, |line| expand_tabs(line, self.document.tabstop).len());
+        if !self.wrap || width == 0 || columns <= width {
+            return 1;
+        }
+        1 + (columns - width).div_ceil(self.continuation_width(width))
+    }
+
+    /// Screen row within its line, and column after the showbreak, of screen column `col`
+    pub fn wrap_position(&self, col: usize, width: usize) -> (usize, usize) {
+        if !self.wrap || width == 0 || col < width {
+            return (0, col);
+        }
+        let continuation = self.continuation_width(width);
+        (1 + (col - width) / continuation, (col - width) % continuation)
+    }
+
+    /// Screen column of the line where row `segment` of a wrapped line starts
+    pub fn segment_start(&self, segment: usize, width: usize) -> usize {
+        match segment {
+            0 => 0,
+            _ => width + (segment - 1) * self.continuation_width(width),
+        }
+    }
+
+    /// Columns the showbreak takes on screen row `segment` of a line
+    pub fn segment_indent(&self, segment: usize) -> usize {
+        if segment == 0 { 0 } else { self.showbreak.chars().count() }
+    }
+
+    /// Screen row and column, from the top left of a text area `width` columns
+    /// wide, of screen column `col` on line `row`
+    pub fn logical_to_screen_pos(&self, row: usize, col: usize, width: usize) -> (usize, usize) {
+        if !self.wrap && self.document.folds.is_empty() {
+            return (row.saturating_sub(self.offset_y), col.saturating_sub(self.offset_x));
+        }
+        let row = self.fold_start(row);
+        let above: usize = (self.offset_y..row).map(|row| self.screen_rows(row, width)).sum();
+        if self.closed_fold_at(row).is_some() {
+            return (above, 0);
+        }
+        if !self.wrap {
+            return (above, col.saturating_sub(self.offset_x));
+        }
+        let (segment, column) = self.wrap_position(col, width);
+        (above + segment, self.segment_indent(segment) + column)
+    }
+
+    /// Line and wrapped segment shown on screen row `screen_row` of the text area
+    pub fn line_at_screen_row(&self, screen_row: usize, width: usize) -> (usize, usize) {
+        if !self.wrap && self.document.folds.is_empty() {
+            return (self.offset_y + screen_row, 0);
+        }
+        let mut remaining = screen_row;
+        let mut row = self.offset_y;
+        while row < self.document.lines.len() {
+            let rows = self.screen_rows(row, width);
+            if remaining < rows {
+                return (row, remaining);
+            }
+            remaining -= rows;
+            row += 1;
+        }
+        (row + remaining, 0)
+    }
+
+    /// Line shown on screen row `screen_row` of the text area, and the screen
+    /// column of that line where the row starts
+    pub fn screen_row_to_logical(&self, screen_row: usize, viewport_width: usize) -> (usize, usize) {
+        let (row, segment) = self.line_at_screen_row(screen_row, viewport_width);
+        let offset = if self.wrap { self.segment_start(segment, viewport_width) } else { self.offset_x };
+        (row, offset)
+    }
+
+    /// Line and byte column shown at screen row `screen_row` and column `screen_col`
+    /// of the text area, as for a mouse click. Clicks past the end of a line or of
+    /// the file land on the last character
+    pub fn screen_to_logical_pos(&self, screen_row: usize, screen_col: usize, width: usize) -> (usize, usize) {
+        let last = self.document.lines.len().saturating_sub(1);
+        let (row, segment) = match self.line_at_screen_row(screen_row, width) {
+            (row, segment) if row <= last => (row, segment),
+            _ => (last, self.screen_rows(last, width).saturating_sub(1)),
+        };
+        if self.closed_fold_at(row).is_some() {
+            return (self.fold_start(row), 0);
+        }
+        let column = if self.wrap {
+            let segment_width = if segment == 0 { width } else { self.continuation_width(width) };
+            let column = screen_col.saturating_sub(self.segment_indent(segment)).min(segment_width.saturating_sub(1));
+            self.segment_start(segment, width) + column
+        } else {
+            self.offset_x + screen_col
+        };
+        let cells = expand_tabs(&self.document.lines[row], self.document.tabstop);
+        let col = cells.get(column).or_else(|| cells.last()).map_or(0, |&(idx, _)| idx);
+        (row, col)
+    }
+
+    /// j / k with wrap on: move one screen row, keeping the screen column
+    pub fn move_screen_row(&mut self, down: bool, width: usize) {
+        // Screen rows count from the top of the window, so bring the cursor line
+        // into it, and the line above too when going up from the top row
+        self.offset_y = self.offset_y.min(self.fold_start(self.cursor_y));
+        let (mut screen_row, screen_column) = self.logical_to_screen_pos(self.cursor_y, self.cursor_column(), width);
+        if !down && screen_row == 0 {
+            if self.offset_y == 0 {
+                return;
+            }
+            self.offset_y = self.fold_start(self.offset_y - 1);
+            screen_row = self.logical_to_screen_pos(self.cursor_y, self.cursor_column(), width).0;
+        }
+        let target = if down { screen_row + 1 } else { screen_row - 1 };
+        let (row, start) = self.screen_row_to_logical(target, width);
+        if row >= self.document.lines.len() {
+            return;
+        }
+        // Rows after the first of a wrapped line start past the showbreak
+        let segment = usize::from(start > 0);
+        let segment_width = if segment == 0 { width } else { self.continuation_width(width) };
+        let column = screen_column.saturating_sub(self.segment_indent(segment)).min(segment_width.saturating_sub(1));
+        let cells = expand_tabs(&self.document.lines[row], self.document.tabstop);
+        self.cursor_y = row;
+        self.cursor_x = cells.get(start + column)
+            .or_else(|| cells.last())
+            .map_or(0, |&(idx, _)| idx);
+    }
+
+    /// Outermost closed fold around `row`, as its first and last line
+    pub fn closed_fold_at(&self, row: usize) -> Option<(usize, usize)> {
+        let last = self.document.lines.len().checked_sub(1)?;
+        self.document.folds.iter()
+            .filter(|fold| fold.closed && fold.start <= row && row <= fold.end.min(last))
+            .map(|fold| (fold.start, fold.end.min(last)))
+            .min_by_key(|&(start, end)| (start, std::cmp::Reverse(end)))
+    }
+
+    /// First line shown for `row`: the start of the closed fold hiding it, or `row`
+    pub fn fold_start(&self, row: usize) -> usize {
+        self.closed_fold_at(row).map_or(row, |(start, _)| start)
+    }
+
+    /// Last line behind `row` on screen: the end of the closed fold hiding it, or `row`
+    pub fn fold_end(&self, row: usize) -> usize {
+        self.closed_fold_at(row).map_or(row, |(_, end)| end)
+    }
+
+    /// First and last line of `count` lines from `row` for line operators such
+    /// as `dd`, where a closed fold counts as one line
+    pub fn fold_span(&self, row: usize, count: usize) -> (usize, usize) {
+        let last_line = self.document.lines.len().saturating_sub(1);
+        let mut last = self.fold_end(row);
+        for _ in 1..count {
+            if last >= last_line {
+                break;
+            }
+            last = self.fold_end(last + 1);
+        }
+        (self.fold_start(row), last)
+    }
+
+    /// `zf`: fold lines `start..=end` and close the fold. Folds may nest but not
+    /// overlap; returns false for a fold that would cut through another
+    pub fn add_fold(&mut self, start: usize, end: usize) -> bool {
+        let (start, end) = (start.min(end), start.max(end));
+        let crosses = |fold: &Fold| (fold.start < start && start <= fold.end && fold.end < end)
+            || (start < fold.start && fold.start <= end && end < fold.end);
+        if self.document.folds.iter().any(crosses) {
+            return false;
+        }
+        self.document.folds.retain(|fold| (fold.start, fold.end) != (start, end));
+        self.document.folds.push(Fold { start, end, closed: true });
+        self.document.folds.sort_by_key(|fold| (fold.start, std::cmp::Reverse(fold.end)));
+        true
+    }
+
+    /// `zo`: open the closed fold that hides `row`
+    pub fn open_fold(&mut self, row: usize) -> bool {
+        let Some(found) = self.closed_fold_at(row) else {
+            return false;
+        };
+        let last = self.document.lines.len().saturating_sub(1);
+        for fold in &mut self.document.folds {
+            if fold.closed && (fold.start, fold.end.min(last)) == found {
+                fold.closed = false;
+            }
+        }
+        true
+    }
+
+    /// `zc`: close the innermost open fold around `row`
+    pub fn close_fold(&mut self, row: usize) -> bool {
+        let fold = self.document.folds.iter_mut()
+            .filter(|fold| !fold.closed && fold.start <= row && row <= fold.end)
+            .min_by_key(|fold| fold.end - fold.start);
+        match fold {
+            Some(fold) => {
+                fold.closed = true;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Reparse the syntax tree if the document changed since it was built
+    pub fn refresh_syntax_tree(&mut self) -> Result<()> {
+        if self.language.is_some() && self.tree_version != Some(self.document.version) {
+            self.update_syntax_tree()?;
+        }
+        Ok(())
+    }
+
+    /// Highlighted ranges of rows `start_row..end_row` as `(row, col_start, col_end, name)`
+    /// with byte columns; names are `keyword`, `string`, `comment`, `number` and `type`
+    pub fn highlight_spans(&self, start_row: usize, end_row: usize) -> Vec<(usize, usize, usize, &'static str)> {
+        let mut spans = Vec::new();
+        let Some(tree) = &self.tree else {
+            return spans;
+        };
+        let mut cursor = tree.walk();
+        loop {
+            let node = cursor.node();
+            let (start, end) = (node.start_position(), node.end_position());
+            let overlaps = start.row < end_row && end.row >= start_row;
+            let name = if overlaps { highlight_name(&node) } else { None };
+            if let Some(name) = name {
+                for row in start.row.max(start_row)..=end.row.min(end_row.saturating_sub(1)) {
+                    let line_len = self.document.lines.get(row).map_or(0, String::len);
+                    let col_start = if row == start.row { start.column } else { 0 };
+                    let col_end = if row == end.row { end.column } else { line_len };
+                    if col_start < col_end {
+                        spans.push((row, col_start, col_end.min(line_len), name));
+                    }
+                }
+            }
+            // Highlighted nodes are not split further, and subtrees outside the rows are skipped
+            if name.is_none() && overlaps && cursor.goto_first_child() {
+                continue;
+            }
+            while !cursor.goto_next_sibling() {
+                if !cursor.goto_parent() {
+                    return spans;
+                }
+            }
+        }
+    }
+}
+
+// Highlight name for a syntax node, judged by its kind
+fn highlight_name(node: &tree_sitter::Node) -> Option<&'static str> {
+    let kind = node.kind();
+    if !node.is_named() {
+        // Anonymous word tokens such as `fn` or `return` are keywords
+        return (!kind.is_empty() && kind.chars().all(|c| c.is_ascii_alphabetic() || c == '_'))
+            .then_some("keyword");
+    }
+    if kind.contains("comment") {
+        Some("comment")
+    } else if kind.contains("string") || kind == "char_literal" {
+        Some("string")
+    } else if matches!(kind, "integer_literal" | "float_literal" | "number" | "integer" | "float") {
+        Some("number")
+    } else if matches!(kind, "primitive_type" | "type_identifier") {
+        Some("type")
+    } else {
+        None
+    }
 }
 
 impl Document {
@@ -165,27 +786,47 @@ impl Document {
             lines: vec![String::new()],
             filename: None,
             modified: false,
+            line_ending: LineEnding::Unix,
+            final_newline: true,
             undo_tree: UndoTree::new(),
+            version: 0,
+            shiftwidth: DEFAULT_SHIFTWIDTH,
+            tabstop: DEFAULT_TABSTOP,
+            expandtab: None,
+            folds: Vec::new(),
+            line_shifts: Vec::new(),
         }
     }
 
     pub fn from_file(filename: &str) -> Result<Self> {
         let content = fs::read_to_string(filename)
             .map_err(|e| Error::Io(e))?;
-            
+        let mut lines: Vec<String> = content.lines().map(String::from).collect();
+        // An empty file still has one line to put the cursor on
+        if lines.is_empty() {
+            lines.push(String::new());
+        }
+
         Ok(Self {
             rope: Rope::from_str(&content),
-            lines: content.lines().map(String::from).collect(),
+            lines,
             filename: Some(filename.to_string()),
             modified: false,
+            line_ending: LineEnding::detect(&content),
+            final_newline: content.is_empty() || content.ends_with('\n'),
             undo_tree: UndoTree::new(),
+            version: 0,
+            shiftwidth: DEFAULT_SHIFTWIDTH,
+            tabstop: DEFAULT_TABSTOP,
+            expandtab: None,
+            folds: Vec::new(),
+            line_shifts: Vec::new(),
         })
     }
 
     pub fn save(&mut self) -> Result<()> {
         if let Some(filename) = &self.filename {
-            let content = self.lines.join("\n");
-            fs::write(filename, content)
+            fs::write(filename, self.file_content())
                 .map_err(|e| Error::Io(e))?;
             self.modified = false;
             Ok(())
@@ -194,10 +835,29 @@ impl Document {
         }
     }
 
+    /// Write the content to `filename`, which becomes the document's file
+    pub fn save_as(&mut self, filename: &str) -> Result<()> {
+        fs::write(filename, self.file_content())?;
+        self.filename = Some(filename.to_string());
+        self.modified = false;
+        Ok(())
+    }
+
+    /// The text as written to disk, with the file's line endings. A buffer that
+    /// is one empty line writes nothing, so empty files stay empty
+    pub fn file_content(&self) -> String {
+        let mut content = self.lines.join(self.line_ending.as_str());
+        if self.final_newline && !content.is_empty() {
+            content.push_str(self.line_ending.as_str());
+        }
+        content
+    }
+
     pub fn insert_char(&mut self, row: usize, col: usize, c: char) {
         if row >= self.lines.len() {
             return;
         }
+        self.record_undo_state(self.get_char_position(row, col));
         
         let line = &mut self.lines[row];
         if col > line.len() {
@@ -208,23 +868,164 @@ impl Document {
         
         // Update rope
         // calculate and clamp to valid range
-        let mut pos = self.get_char_position(row, col);
-        pos = pos.min(self.rope.len_chars());
+        let pos = self.rope_char_index(row, col);
         self.rope.insert_char(pos, c);
         self.modified = true;
     }
 
+    /// Break line `row` at byte `col`, moving the text after it to a new line below
+    pub fn split_line(&mut self, row: usize, col: usize) {
+        if row >= self.lines.len() {
+            return;
+        }
+        let mut col = col.min(self.lines[row].len());
+        while !self.lines[row].is_char_boundary(col) {
+            col -= 1;
+        }
+        self.record_undo_state(self.get_char_position(row, col));
+        let pos = self.rope_char_index(row, col);
+        let tail = self.lines[row].split_off(col);
+        self.lines.insert(row + 1, tail);
+        self.rope.insert_char(pos, '\n');
+        self.modified = true;
+        self.shift_folds(row, 1, 2);
+    }
+
+    /// Split line `row` at byte `col` like `split_line`, indenting the new line
+    /// as deep as `row`: one level deeper after an opening bracket, `:` or `->`,
+    /// one level shallower when it starts with a closing bracket. Between a pair
+    /// such as `{}` the closer gets a line of its own. Returns the cursor position
+    pub fn split_line_indented(&mut self, row: usize, col: usize) -> (usize, usize) {
+        if row >= self.lines.len() {
+            return (row, col);
+        }
+        let mut col = col.min(self.lines[row].len());
+        while !self.lines[row].is_char_boundary(col) {
+            col -= 1;
+        }
+        self.record_undo_state(self.get_char_position(row, col));
+
+        let line = &self.lines[row];
+        let indent: String = line[..col].chars().take_while(|c| *c == ' ' || *c == '\t').collect();
+        let unit = self.indent_unit(&indent);
+        let head = line[..col].trim_end();
+        let tail = line[col..].trim_start();
+        let opens = INDENT_OPENERS.iter().any(|opener| head.ends_with(opener));
+        let closes = tail.starts_with(INDENT_CLOSERS);
+
+        let inner = if opens { format!("{}{}", indent, unit) } else { indent.clone() };
+        let (new_lines, cursor_col) = match (opens, closes) {
+            (true, true) => (vec![inner.clone(), format!("{}{}", indent, tail)], inner.len()),
+            (false, true) => {
+                let outer = dedent(&inner, self.shiftwidth);
+                (vec![format!("{}{}", outer, tail)], outer.len())
+            },
+            _ => (vec![format!("{}{}", inner, tail)], inner.len()),
+        };
+
+        // A line left holding only the indentation is cleared, as in Vim
+        self.lines[row] = head.to_string();
+        self.shift_folds(row, 1, 1 + new_lines.len());
+        for (i, new_line) in new_lines.into_iter().enumerate() {
+            self.lines.insert(row + 1 + i, new_line);
+        }
+        self.sync_rope();
+        (row + 1, cursor_col)
+    }
+
+    /// One level of indentation: a tab after tab indentation or with `noexpandtab`,
+    /// `shiftwidth` spaces otherwise
+    fn indent_unit(&self, indent: &str) -> String {
+        if indent.contains('\t') || self.expandtab == Some(false) { "\t".to_string() } else { " ".repeat(self.shiftwidth) }
+    }
+
+    /// Indentation for a line opened with `o` (`below`) or `O` next to line `row`:
+    /// the same as that line's, one level deeper below a line ending in `{` or
+    /// another opener
+    pub fn open_line_indent(&self, row: usize, below: bool) -> String {
+        let line = self.lines.get(row).map_or("", String::as_str);
+        let indent: String = line.chars().take_while(|c| *c == ' ' || *c == '\t').collect();
+        if below && INDENT_OPENERS.iter().any(|opener| line.trim_end().ends_with(opener)) {
+            let unit = self.indent_unit(&indent);
+            indent + &unit
+        } else {
+            indent
+        }
+    }
+
+    /// Insert `content` as a new line before line `row`, or after the last line
+    /// when `row` is past the end, as a single undo step
+    pub fn insert_line_at(&mut self, row: usize, content: String) {
+        let row = row.min(self.lines.len());
+        self.record_undo_state(self.get_char_position(row.min(self.lines.len().saturating_sub(1)), 0));
+        // The rope has no newline after the last line
+        if row < self.lines.len() {
+            let pos = self.rope_char_index(row, 0);
+            self.rope.insert(pos, &format!("{}\n", content));
+        } else {
+            let end = self.rope.len_chars();
+            self.rope.insert(end, &format!("\n{}", content));
+        }
+        self.lines.insert(row, content);
+        self.modified = true;
+        self.shift_folds(row, 0, 1);
+    }
+
+    /// Sort lines `start..=end` by byte value, descending when `reverse`, as one
+    /// undo step. Only the rope text of those lines is rebuilt
+    pub fn sort_lines(&mut self, start: usize, end: usize, reverse: bool) {
+        let end = end.min(self.lines.len().saturating_sub(1));
+        if start >= end {
+            return;
+        }
+        let mut sorted = self.lines[start..=end].to_vec();
+        sorted.sort();
+        if reverse {
+            sorted.reverse();
+        }
+        if sorted == self.lines[start..=end] {
+            return;
+        }
+        self.record_undo_state(self.get_char_position(start, 0));
+        let from = self.rope_char_index(start, 0);
+        let to = self.rope_char_index(end, self.lines[end].len());
+        self.rope.remove(from..to);
+        self.rope.insert(from, &sorted.join("\n"));
+        self.lines.splice(start..=end, sorted);
+        self.modified = true;
+    }
+
+    /// Append line `row` to the line above it. Returns the byte column of the
+    /// join point, or None for the first line
+    pub fn join_lines(&mut self, row: usize) -> Option<usize> {
+        if row == 0 || row >= self.lines.len() {
+            return None;
+        }
+        let col = self.lines[row - 1].len();
+        self.record_undo_state(self.get_char_position(row - 1, col));
+        let pos = self.rope_char_index(row - 1, col);
+        let line = self.lines.remove(row);
+        self.lines[row - 1].push_str(&line);
+        if pos < self.rope.len_chars() {
+            self.rope.remove(pos..pos + 1);
+        }
+        self.modified = true;
+        self.shift_folds(row - 1, 2, 1);
+        Some(col)
+    }
+
     pub fn delete_char(&mut self, row: usize, col: usize) -> bool {
         if row >= self.lines.len() {
             return false;
         }
         
-        let line = &mut self.lines[row];
-        if col < line.len() {
+        if col < self.lines[row].len() {
+            self.record_undo_state(self.get_char_position(row, col));
+            let pos = self.rope_char_index(row, col);
+            let line = &mut self.lines[row];
             line.remove(col);
             // Update rope
             // only remove if within bounds
-            let pos = self.get_char_position(row, col);
             if pos < self.rope.len_chars() {
                 self.rope.remove(pos..pos+1);
                 self.modified = true;
@@ -237,12 +1038,761 @@ impl Document {
         }
     }
 
+    /// Remove lines `first..=last` as a single undo step, returning them.
+    /// Deleting every line leaves one empty line
+    pub fn delete_lines(&mut self, first: usize, last: usize) -> Vec<String> {
+        if first > last || first >= self.lines.len() {
+            return Vec::new();
+        }
+        let last = last.min(self.lines.len() - 1);
+        self.record_undo_state(self.get_char_position(first, 0));
+        let deleted = self.lines.drain(first..=last).collect();
+        if self.lines.is_empty() {
+            self.lines.push(String::new());
+        }
+        self.shift_folds(first, last + 1 - first, 0);
+        self.sync_rope();
+        deleted
+    }
+
+    /// Text from `start` up to, but not including, `end`; both are (row, byte col)
+    pub fn text_in_range(&self, start: (usize, usize), end: (usize, usize)) -> String {
+        let Some((start, end)) = self.clamp_range(start, end) else {
+            return String::new();
+        };
+        if start.0 == end.0 {
+            return self.lines[start.0][start.1..end.1].to_string();
+        }
+        let mut text = self.lines[start.0][start.1..].to_string();
+        for line in &self.lines[start.0 + 1..end.0] {
+            text.push('\n');
+            text.push_str(line);
+        }
+        text.push('\n');
+        text.push_str(&self.lines[end.0][..end.1]);
+        text
+    }
+
+    /// Delete from `start` up to, but not including, `end` as a single undo step,
+    /// joining the first and last line. Returns the deleted text
+    pub fn delete_range(&mut self, start: (usize, usize), end: (usize, usize)) -> String {
+        let Some((start, end)) = self.clamp_range(start, end) else {
+            return String::new();
+        };
+        let deleted = self.text_in_range(start, end);
+        self.record_undo_state(self.get_char_position(start.0, start.1));
+        let tail = self.lines[end.0][end.1..].to_string();
+        self.lines[start.0].truncate(start.1);
+        self.lines[start.0].push_str(&tail);
+        self.lines.drain(start.0 + 1..=end.0);
+        self.shift_folds(start.0, end.0 + 1 - start.0, 1);
+        self.sync_rope();
+        deleted
+    }
+
+    /// Delete the characters `start_char..end_char` from each of lines `first..=last`
+    /// as a single undo step, returning the removed pieces
+    pub fn delete_block(&mut self, first: usize, last: usize, start_char: usize, end_char: usize) -> Vec<String> {
+        if first > last || first >= self.lines.len() || start_char >= end_char {
+            return Vec::new();
+        }
+        let last = last.min(self.lines.len() - 1);
+        self.record_undo_state(self.get_char_position(first, 0));
+        let mut deleted = Vec::new();
+        for line in &mut self.lines[first..=last] {
+            let range = char_range_to_bytes(line, start_char, end_char);
+            deleted.push(line.drain(range).collect());
+        }
+        self.sync_rope();
+        deleted
+    }
+
+    /// Insert `text`, which may span several lines, at (row, byte col) as a single
+    /// undo step. Returns the position just after the inserted text
+    pub fn insert_text(&mut self, row: usize, col: usize, text: &str) -> (usize, usize) {
+        if row >= self.lines.len() || text.is_empty() {
+            return (row, col);
+        }
+        let col = col.min(self.lines[row].len());
+        self.record_undo_state(self.get_char_position(row, col));
+        let tail = self.lines[row].split_off(col);
+        let mut pieces = text.split('\n');
+        self.lines[row].push_str(pieces.next().unwrap_or(""));
+        let mut end_row = row;
+        for piece in pieces {
+            end_row += 1;
+            self.lines.insert(end_row, piece.to_string());
+        }
+        let end_col = self.lines[end_row].len();
+        self.lines[end_row].push_str(&tail);
+        self.shift_folds(row, 1, end_row + 1 - row);
+        self.sync_rope();
+        (end_row, end_col)
+    }
+
+    /// Replace ranges that do not overlap, with positions in the current text, as
+    /// a single undo step
+    pub fn replace_ranges(&mut self, edits: &[RangeEdit]) {
+        let Some(first) = edits.iter().map(|(start, end, _)| *start.min(end)).min() else {
+            return;
+        };
+        let last = self.lines.len().saturating_sub(1);
+        let first_row = first.0.min(last);
+        self.record_undo_state(self.get_char_position(first_row, first.1.min(self.lines[first_row].len())));
+        // From the end backwards so earlier positions stay valid. Edits at the same
+        // position go last one first, so they come out in the order given
+        let mut edits: Vec<_> = edits.iter().enumerate().collect();
+        edits.sort_by_key(|(idx, (start, end, _))| std::cmp::Reverse((*start.min(end), *idx)));
+        for (_, (start, end, text)) in edits {
+            // An empty range is an insertion, which clamp_range does not return
+            let (start, end) = self.clamp_range(*start, *end).unwrap_or_else(|| {
+                let row = start.0.min(last);
+                let at = (row, start.1.min(self.lines[row].len()));
+                (at, at)
+            });
+            let text = format!("{}{}{}", &self.lines[start.0][..start.1], text.replace("\r\n", "\n"), &self.lines[end.0][end.1..]);
+            let replaced: Vec<String> = text.split('\n').map(String::from).collect();
+            self.shift_folds(start.0, end.0 + 1 - start.0, replaced.len());
+            self.lines.splice(start.0..=end.0, replaced);
+        }
+        self.sync_rope();
+    }
+
+    /// Replace lines `rows` with `lines` as a single undo step; an empty `rows`
+    /// inserts before its start
+    pub fn replace_lines(&mut self, rows: std::ops::Range<usize>, lines: &[String]) {
+        let count = self.lines.len();
+        let text = lines.join("\n");
+        let end_of = |row: usize| (row, self.lines[row].len());
+        let edit = if rows.is_empty() {
+            match rows.start {
+                row if row < count => ((row, 0), (row, 0), text + "\n"),
+                _ => (end_of(count - 1), end_of(count - 1), "\n".to_string() + &text),
+            }
+        } else if !lines.is_empty() || rows.len() == count {
+            ((rows.start, 0), end_of(rows.end.min(count) - 1), text)
+        } else if rows.end < count {
+            // Take the line break after the last line with it
+            ((rows.start, 0), (rows.end, 0), String::new())
+        } else {
+            (end_of(rows.start - 1), end_of(count - 1), String::new())
+        };
+        self.replace_ranges(&[edit]);
+    }
+
+    /// Replace the whole text with `text` as a single undo step. Returns false
+    /// when nothing changed
+    pub fn replace_all(&mut self, text: &str) -> bool {
+        let mut lines: Vec<String> = text.lines().map(String::from).collect();
+        if lines.is_empty() {
+            lines.push(String::new());
+        }
+        if lines == self.lines {
+            return false;
+        }
+        // Undo goes back to the first line that changed
+        let first_change = self.lines.iter().zip(&lines)
+            .position(|(old, new)| old != new)
+            .unwrap_or(self.lines.len().min(lines.len()))
+            .min(self.lines.len() - 1);
+        self.record_undo_state(self.get_char_position(first_change, 0));
+        let old = std::mem::replace(&mut self.lines, lines);
+        self.shift_folds_after(&old);
+        self.sync_rope();
+        true
+    }
+
+    /// Insert whole lines before line `at` (or append them when `at` is past the
+    /// end) as a single undo step
+    pub fn insert_lines(&mut self, at: usize, lines: &[&str]) {
+        if lines.is_empty() {
+            return;
+        }
+        let at = at.min(self.lines.len());
+        self.record_undo_state(self.get_char_position(at.min(self.lines.len().saturating_sub(1)), 0));
+        self.lines.splice(at..at, lines.iter().map(|line| line.to_string()));
+        self.shift_folds(at, 0, lines.len());
+        self.sync_rope();
+    }
+
+    /// Indent lines `first..=last` by one level, leaving empty lines alone
+    pub fn indent_lines(&mut self, first: usize, last: usize) {
+        if first > last || first >= self.lines.len() {
+            return;
+        }
+        let last = last.min(self.lines.len() - 1);
+        self.record_undo_state(self.get_char_position(first, 0));
+        let unit = if self.expandtab == Some(false) { "\t".to_string() } else { " ".repeat(self.shiftwidth) };
+        for line in &mut self.lines[first..=last] {
+            if !line.is_empty() {
+                line.insert_str(0, &unit);
+            }
+        }
+        self.sync_rope();
+    }
+
+    /// Remove one level of indentation, a tab or up to `shiftwidth` spaces, from lines `first..=last`
+    pub fn unindent_lines(&mut self, first: usize, last: usize) {
+        if first > last || first >= self.lines.len() {
+            return;
+        }
+        let last = last.min(self.lines.len() - 1);
+        self.record_undo_state(self.get_char_position(first, 0));
+        for line in &mut self.lines[first..=last] {
+            let width = if line.starts_with('\t') {
+                1
+            } else {
+                line.bytes().take(self.shiftwidth).take_while(|&b| b == b' ').count()
+            };
+            line.drain(..width);
+        }
+        self.sync_rope();
+    }
+
+    /// Replace matches of `regex` in lines `first..=last` as a single undo step;
+    /// only the first match in each line unless `global`. Returns the number of
+    /// substitutions and of lines changed
+    pub fn substitute(&mut self, first: usize, last: usize, regex: &Regex, replacement: &str, global: bool) -> (usize, usize) {
+        if first > last || first >= self.lines.len() {
+            return (0, 0);
+        }
+        let last = last.min(self.lines.len() - 1);
+        let limit = if global { 0 } else { 1 };
+        let mut changed = Vec::new();
+        let mut count = 0;
+        for row in first..=last {
+            let matches = regex.find_iter(&self.lines[row]).count();
+            if matches > 0 {
+                count += if global { matches } else { 1 };
+                changed.push((row, regex.replacen(&self.lines[row], limit, replacement).into_owned()));
+            }
+        }
+        if let Some(&(row, _)) = changed.first() {
+            self.record_undo_state(self.get_char_position(row, 0));
+        }
+        let lines = changed.len();
+        for (row, line) in changed {
+            self.lines[row] = line;
+        }
+        if lines > 0 {
+            self.sync_rope();
+        }
+        (count, lines)
+    }
+
+    /// Replace a byte range within one line. Does not record an undo state, so
+    /// callers making several replacements record one first
+    pub fn replace_in_line(&mut self, row: usize, range: std::ops::Range<usize>, text: &str) {
+        if let Some(line) = self.lines.get_mut(row) {
+            line.replace_range(range, text);
+            self.sync_rope();
+        }
+    }
+
+    // Order a range and clamp it to the text; None when it is empty
+    fn clamp_range(&self, start: (usize, usize), end: (usize, usize)) -> Option<((usize, usize), (usize, usize))> {
+        let last = self.lines.len().checked_sub(1)?;
+        // A column inside a character takes in the whole character
+        let (start, end) = (start.min(end), start.max(end));
+        let start = (start.0.min(last), self.lines[start.0.min(last)].floor_char_boundary(start.1));
+        let end = (end.0.min(last), self.lines[end.0.min(last)].ceil_char_boundary(end.1));
+        (start < end).then_some((start, end))
+    }
+
+    /// Move folds after lines `at..at + removed` were replaced by `inserted`
+    /// lines, and note the change for the marks. Replaced lines pair up with
+    /// the new ones in order; a fold whose lines are all gone is dropped
+    fn shift_folds(&mut self, at: usize, removed: usize, inserted: usize) {
+        if removed == inserted {
+            return;
+        }
+        self.line_shifts.push((at, removed, inserted));
+        if self.folds.is_empty() {
+            return;
+        }
+        let (kept, after) = (at + inserted.min(removed), at + removed);
+        let moved = |row: usize| row + inserted - removed;
+        self.folds.retain_mut(|fold| {
+            let start = match fold.start {
+                row if row < kept => row,
+                row if row >= after => moved(row),
+                _ => at + inserted,
+            };
+            let end = match fold.end {
+                row if row < kept => Some(row),
+                row if row >= after => Some(moved(row)),
+                _ => (at + inserted).checked_sub(1),
+            };
+            match end {
+                Some(end) if end >= start => {
+                    (fold.start, fold.end) = (start, end);
+                    true
+                },
+                _ => false,
+            }
+        });
+        self.folds.dedup_by_key(|fold| (fold.start, fold.end));
+    }
+
+    /// Move folds for a change that replaced `old` with the current lines,
+    /// taking the lines both share at the start and end as unchanged
+    fn shift_folds_after(&mut self, old: &[String]) {
+        let prefix = old.iter().zip(&self.lines).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..].iter().rev().zip(self.lines[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        self.shift_folds(prefix, old.len() - prefix - suffix, self.lines.len() - prefix - suffix);
+    }
+
+    // Rebuild the rope after editing `lines` directly
+    fn sync_rope(&mut self) {
+        self.rope = Rope::from_str(&self.lines.join("\n"));
+        self.modified = true;
+        self.version += 1;
+    }
+
+    /// Revert the last change, returning the (row, col) where it happened
+    pub fn undo(&mut self) -> Option<(usize, usize)> {
+        let latest = self.lines.join("\n");
+        let (position, content) = self.undo_tree.undo(0, latest)?.clone();
+        self.restore_state(&content);
+        Some(self.get_row_col(position))
+    }
+
+    /// Reapply a change reverted by `undo`, returning the (row, col) where it happened
+    pub fn redo(&mut self) -> Option<(usize, usize)> {
+        let (position, content) = self.undo_tree.redo()?;
+        let content = content.to_string();
+        self.restore_state(&content);
+        Some(self.get_row_col(position))
+    }
+
+    fn restore_state(&mut self, content: &str) {
+        let old = std::mem::replace(&mut self.lines, content.split('\n').map(String::from).collect());
+        self.shift_folds_after(&old);
+        self.rope = Rope::from_str(content);
+        self.modified = true;
+        self.version += 1;
+    }
+
+    /// Save the current text as an undo state before a change at `position`
+    pub fn record_undo_state(&mut self, position: usize) {
+        let content = self.lines.join("\n");
+        self.undo_tree.push(position, content);
+        self.version += 1;
+    }
+
+    /// Whether there are changes to undo or redo
+    pub fn has_undo_history(&self) -> bool {
+        !self.undo_tree.history.is_empty()
+    }
+
+    /// Apply the undolevels option; `None` keeps unlimited history
+    pub fn set_undolevels(&mut self, limit: Option<usize>) {
+        if self.undo_tree.limit != limit {
+            self.undo_tree.set_limit(limit);
+        }
+    }
+
+    /// Write the undo history for this file into `dir`
+    pub fn write_undo_file(&self, dir: &Path) -> Result<()> {
+        let filename = self.filename.as_ref()
+            .ok_or_else(|| Error::Message("No filename specified".into()))?;
+        fs::create_dir_all(dir)?;
+        let undo_file = UndoFile {
+            text_hash: text_hash(&self.lines.join("\n")),
+            tree: self.undo_tree.clone(),
+        };
+        let json = serde_json::to_string(&undo_file)
+            .map_err(|e| Error::Message(format!("Failed to encode undo file: {}", e)))?;
+        fs::write(undo_file_path(dir, filename), json)?;
+        Ok(())
+    }
+
+    /// Restore undo history saved by `write_undo_file`. Returns false when there
+    /// is none, or when the file changed since it was written
+    pub fn read_undo_file(&mut self, dir: &Path) -> Result<bool> {
+        let Some(filename) = &self.filename else {
+            return Ok(false);
+        };
+        let path = undo_file_path(dir, filename);
+        if !path.exists() {
+            return Ok(false);
+        }
+        let json = fs::read_to_string(&path)?;
+        let undo_file: UndoFile = serde_json::from_str(&json)
+            .map_err(|e| Error::Message(format!("E822: Cannot read undo file {}: {}", path.display(), e)))?;
+        if undo_file.text_hash != text_hash(&self.lines.join("\n")) {
+            return Ok(false);
+        }
+        let limit = self.undo_tree.limit;
+        self.undo_tree = undo_file.tree;
+        self.undo_tree.set_limit(limit);
+        Ok(true)
+    }
+
     // Helper method to convert row/col to rope position
-    fn get_char_position(&self, row: usize, col: usize) -> usize {
+    pub fn get_char_position(&self, row: usize, col: usize) -> usize {
         let mut pos = 0;
         for i in 0..row {
             pos += self.lines[i].len() + 1; // +1 for newline
         }
         pos + col
     }
+
+    // Rope char index of (row, byte col); `get_char_position` counts bytes
+    fn rope_char_index(&self, row: usize, col: usize) -> usize {
+        self.rope.byte_to_char(self.get_char_position(row, col).min(self.rope.len_bytes()))
+    }
+
+    // Inverse of get_char_position, clamped to the last line
+    fn get_row_col(&self, mut pos: usize) -> (usize, usize) {
+        for (row, line) in self.lines.iter().enumerate() {
+            if pos <= line.len() || row + 1 == self.lines.len() {
+                return (row, pos.min(line.len()));
+            }
+            pos -= line.len() + 1;
+        }
+        (0, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(lines: &[&str]) -> Document {
+        let mut document = Document::new();
+        document.lines = lines.iter().map(|line| line.to_string()).collect();
+        document.rope = Rope::from_str(&document.lines.join("\n"));
+        document
+    }
+
+    fn assert_in_sync(document: &Document) {
+        assert_eq!(document.rope.to_string(), document.lines.join("\n"));
+    }
+
+    #[test]
+    fn replace_ranges_applies_edits_from_the_end() {
+        let mut document = document(&["use a;", "fn main() {}"]);
+        document.replace_ranges(&[
+            ((0, 4), (0, 5), "b::c".to_string()),
+            ((1, 11), (1, 11), "\n    run();\n".to_string()),
+            ((0, 0), (0, 0), "// x\n".to_string()),
+        ]);
+        assert_eq!(document.lines, ["// x", "use b::c;", "fn main() {", "    run();", "}"]);
+        assert_in_sync(&document);
+        document.undo();
+        assert_eq!(document.lines, ["use a;", "fn main() {}"]);
+    }
+
+    #[test]
+    fn replace_ranges_keeps_inserts_at_one_position_in_order() {
+        let mut document = document(&["fn f() {}"]);
+        document.replace_ranges(&[
+            ((0, 8), (0, 8), "a".to_string()),
+            ((0, 8), (0, 8), "b".to_string()),
+            ((0, 0), (0, 0), "1".to_string()),
+            ((0, 0), (0, 0), "2".to_string()),
+        ]);
+        assert_eq!(document.lines, ["12fn f() {ab}"]);
+        assert_in_sync(&document);
+    }
+
+    #[test]
+    fn delete_lines_keeps_one_line() {
+        let mut document = document(&["a", "b"]);
+        assert_eq!(document.delete_lines(0, 5), ["a", "b"]);
+        assert_eq!(document.lines, [""]);
+        assert_in_sync(&document);
+    }
+
+    #[test]
+    fn ranges_inside_multibyte_chars_take_the_whole_char() {
+        let mut document = document(&["a日b", "é"]);
+        assert_eq!(document.text_in_range((0, 2), (0, 3)), "日");
+        assert_eq!(document.text_in_range((0, 3), (1, 1)), "日b\né");
+        assert_eq!(document.delete_range((0, 1), (0, 2)), "日");
+        assert_eq!(document.lines, ["ab", "é"]);
+        assert_in_sync(&document);
+    }
+
+    #[test]
+    fn file_content_ends_with_newline_when_the_file_did() {
+        let mut document = document(&["a", "b"]);
+        assert_eq!(document.file_content(), "a\nb\n");
+        document.final_newline = false;
+        document.line_ending = LineEnding::Dos;
+        assert_eq!(document.file_content(), "a\r\nb");
+        assert_eq!(Document::new().file_content(), "");
+    }
+
+    #[test]
+    fn insert_line_at_keeps_rope_in_sync() {
+        let mut document = document(&["fn main() {", "}"]);
+        let indent = document.open_line_indent(0, true);
+        document.insert_line_at(1, indent);
+        document.insert_line_at(3, "// end".to_string());
+        document.insert_line_at(0, document.open_line_indent(0, false));
+        assert_eq!(document.lines, ["", "fn main() {", "    ", "}", "// end"]);
+        assert_in_sync(&document);
+    }
+
+    #[test]
+    fn split_line_moves_tail_down() {
+        let mut document = document(&["hello world", "next"]);
+        document.split_line(0, 5);
+        assert_eq!(document.lines, ["hello", " world", "next"]);
+        assert_in_sync(&document);
+    }
+
+    #[test]
+    fn split_line_indented_keeps_and_deepens_indent() {
+        let mut document = document(&["    let x = 1;", "    if x {"]);
+        assert_eq!(document.split_line_indented(0, 14), (1, 4));
+        assert_eq!(document.split_line_indented(2, 10), (3, 8));
+        assert_eq!(document.lines, ["    let x = 1;", "    ", "    if x {", "        "]);
+        assert_in_sync(&document);
+    }
+
+    #[test]
+    fn split_line_indented_between_brackets() {
+        let mut document = document(&["\tcall(x, {})"]);
+        assert_eq!(document.split_line_indented(0, 10), (1, 2));
+        assert_eq!(document.lines, ["\tcall(x, {", "\t\t", "\t})"]);
+        assert_in_sync(&document);
+    }
+
+    #[test]
+    fn split_line_after_multibyte_char() {
+        // "ü" and "é" are two bytes, "日" three
+        let mut document = document(&["über", "日本語", "café"]);
+        document.split_line(0, "ü".len());
+        document.split_line(2, "日".len());
+        document.split_line(4, "café".len());
+        assert_eq!(document.lines, ["ü", "ber", "日", "本語", "café", ""]);
+        assert_in_sync(&document);
+    }
+
+    #[test]
+    fn split_line_inside_multibyte_char_uses_char_start() {
+        let mut document = document(&["a日b"]);
+        document.split_line(0, 2);
+        assert_eq!(document.lines, ["a", "日b"]);
+        assert_in_sync(&document);
+    }
+
+    #[test]
+    fn join_lines_returns_join_point() {
+        let mut document = document(&["héllo", "wörld"]);
+        assert_eq!(document.join_lines(1), Some("héllo".len()));
+        assert_eq!(document.lines, ["héllowörld"]);
+        assert_in_sync(&document);
+        assert_eq!(document.join_lines(0), None);
+    }
+
+    #[test]
+    fn join_lines_after_multibyte_lines() {
+        let mut document = document(&["日本", "語", "🦀", "end"]);
+        assert_eq!(document.join_lines(3), Some("🦀".len()));
+        assert_eq!(document.join_lines(1), Some("日本".len()));
+        assert_eq!(document.lines, ["日本語", "🦀end"]);
+        assert_in_sync(&document);
+    }
+
+    #[test]
+    fn split_then_join_round_trips() {
+        let mut document = document(&["ünïcödé"]);
+        let col = "ünï".len();
+        document.split_line(0, col);
+        assert_eq!(document.join_lines(1), Some(col));
+        assert_eq!(document.lines, ["ünïcödé"]);
+        assert_in_sync(&document);
+    }
+
+    #[test]
+    fn detects_filetype_from_name_then_extension() {
+        assert_eq!(detect_filetype("src/main.rs").as_deref(), Some("rust"));
+        assert_eq!(detect_filetype("build/Makefile").as_deref(), Some("makefile"));
+        assert_eq!(detect_filetype("rules.mk").as_deref(), Some("makefile"));
+        assert_eq!(detect_filetype("notes.txt"), None);
+    }
+
+    #[test]
+    fn indents_with_tabs_without_expandtab() {
+        let mut document = document(&["fn main() {"]);
+        document.expandtab = Some(false);
+        assert_eq!(document.split_line_indented(0, "fn main() {".len()), (1, 1));
+        document.indent_lines(0, 1);
+        assert_eq!(document.lines, ["\tfn main() {", "\t\t"]);
+    }
+
+    #[test]
+    fn scroll_to_cursor_keeps_scrolloff_margin() {
+        let mut buffer = Buffer::new();
+        buffer.document = document(&["line"; 100]);
+        buffer.cursor_y = 20;
+        buffer.scroll_to_cursor(10, 80, 3);
+        assert_eq!(buffer.offset_y, 14);
+        buffer.cursor_y = 15;
+        buffer.scroll_to_cursor(10, 80, 3);
+        assert_eq!(buffer.offset_y, 12);
+        // No margin past the end of the file
+        buffer.cursor_y = 99;
+        buffer.scroll_to_cursor(10, 80, 3);
+        assert_eq!(buffer.offset_y, 90);
+    }
+
+    #[test]
+    fn wrapped_lines_map_to_screen_rows() {
+        let mut buffer = Buffer::new();
+        buffer.document = document(&[&"x".repeat(25), "short"]);
+        buffer.wrap = true;
+        buffer.showbreak = "> ".to_string();
+        // 10 chars on the first row, 8 after the showbreak on the others
+        assert_eq!(buffer.screen_rows(0, 10), 3);
+        assert_eq!(buffer.logical_to_screen_pos(0, 12, 10), (1, 4));
+        assert_eq!(buffer.logical_to_screen_pos(1, 0, 10), (3, 0));
+        assert_eq!(buffer.line_at_screen_row(2, 10), (0, 2));
+        assert_eq!(buffer.screen_row_to_logical(1, 10), (0, 10));
+        assert_eq!(buffer.screen_row_to_logical(2, 10), (0, 18));
+        assert_eq!(buffer.screen_row_to_logical(3, 10), (1, 0));
+        buffer.cursor_x = 3;
+        buffer.move_screen_row(true, 10);
+        assert_eq!((buffer.cursor_y, buffer.cursor_x), (0, 11));
+        buffer.move_screen_row(true, 10);
+        buffer.move_screen_row(true, 10);
+        assert_eq!((buffer.cursor_y, buffer.cursor_x), (1, 3));
+        // Up from the top row of the window to the last row of the line above
+        buffer.offset_y = 1;
+        buffer.move_screen_row(false, 10);
+        assert_eq!((buffer.cursor_y, buffer.cursor_x, buffer.offset_y), (0, 19, 0));
+    }
+
+    #[test]
+    fn closed_folds_show_as_one_line() {
+        let mut buffer = Buffer::new();
+        buffer.document = document(&["a", "b", "c", "d", "e", "f"]);
+        assert!(buffer.add_fold(1, 4));
+        assert!(buffer.add_fold(2, 3));
+        // Folds nest but do not cross
+        assert!(!buffer.add_fold(3, 5));
+        assert_eq!(buffer.closed_fold_at(3), Some((1, 4)));
+        assert_eq!(buffer.screen_rows(1, 10), 1);
+        assert_eq!(buffer.screen_rows(2, 10), 0);
+        assert_eq!(buffer.logical_to_screen_pos(5, 0, 10), (2, 0));
+        assert_eq!(buffer.line_at_screen_row(2, 10), (5, 0));
+        buffer.cursor_y = 1;
+        buffer.move_screen_row(true, 10);
+        assert_eq!(buffer.cursor_y, 5);
+        // Opening the outer fold leaves the inner one closed
+        assert!(buffer.open_fold(1));
+        assert_eq!(buffer.closed_fold_at(3), Some((2, 3)));
+        assert!(buffer.close_fold(1));
+        assert_eq!(buffer.fold_end(1), 4);
+    }
+
+    #[test]
+    fn folds_move_with_lines_added_and_removed_above_and_inside() {
+        let mut buffer = Buffer::new();
+        buffer.document = document(&["a", "b", "c", "d", "e", "f", "g"]);
+        assert!(buffer.add_fold(3, 5));
+        buffer.document.delete_lines(0, 0);
+        assert_eq!(buffer.closed_fold_at(2), Some((2, 4)));
+        buffer.document.insert_line_at(0, "x".to_string());
+        buffer.document.insert_line_at(0, "y".to_string());
+        assert_eq!(buffer.closed_fold_at(4), Some((4, 6)));
+        // A line opened inside the fold belongs to it
+        buffer.document.split_line(5, 1);
+        assert_eq!(buffer.closed_fold_at(4), Some((4, 7)));
+        buffer.document.undo();
+        assert_eq!(buffer.closed_fold_at(4), Some((4, 6)));
+        buffer.document.delete_lines(3, 4);
+        assert_eq!(buffer.closed_fold_at(3), Some((3, 4)));
+        buffer.document.delete_lines(3, 4);
+        assert!(buffer.document.folds.is_empty());
+    }
+
+    #[test]
+    fn closed_fold_counts_as_one_line_for_line_operators() {
+        let mut buffer = Buffer::new();
+        buffer.document = document(&["a", "b", "c", "d", "e"]);
+        assert!(buffer.add_fold(1, 3));
+        assert_eq!(buffer.fold_span(1, 1), (1, 3));
+        assert_eq!(buffer.fold_span(0, 2), (0, 3));
+        assert_eq!(buffer.fold_span(1, 5), (1, 4));
+        buffer.open_fold(1);
+        assert_eq!(buffer.fold_span(1, 1), (1, 1));
+    }
+
+    #[test]
+    fn screen_to_logical_pos_inverts_scrolling_tabs_and_wrapping() {
+        let mut buffer = Buffer::new();
+        buffer.document = document(&["a\tb", "héllo world", "x"]);
+        buffer.offset_y = 1;
+        buffer.offset_x = 2;
+        assert_eq!(buffer.screen_to_logical_pos(0, 1, 20), (1, 4));
+        // Past the end of the line and of the file
+        assert_eq!(buffer.screen_to_logical_pos(0, 30, 20), (1, 11));
+        assert_eq!(buffer.screen_to_logical_pos(5, 0, 20), (2, 0));
+        buffer.offset_x = 0;
+        buffer.offset_y = 0;
+        // Inside a tab
+        assert_eq!(buffer.screen_to_logical_pos(0, 2, 20), (0, 1));
+        buffer.wrap = true;
+        assert_eq!(buffer.screen_to_logical_pos(2, 1, 6), (1, 8));
+        assert_eq!(buffer.screen_to_logical_pos(3, 0, 6), (2, 0));
+    }
+
+    #[test]
+    fn tabs_expand_to_the_next_tab_stop() {
+        let drawn = |line: &str, tabstop| expand_tabs(line, tabstop).into_iter().map(|(_, c)| c).collect::<String>();
+        assert_eq!(drawn("a\tbc\td", 4), "a   bc  d");
+        assert_eq!(drawn("\tx", 8), "        x");
+        assert_eq!(expand_tabs("a\tb", 4)[2], (1, ' '));
+        let mut buffer = Buffer::new();
+        buffer.document = document(&["\t\tx"]);
+        buffer.cursor_x = 2;
+        assert_eq!(buffer.cursor_column(), 2 * buffer.document.tabstop);
+    }
+
+    #[test]
+    fn replace_lines_inserts_replaces_and_deletes() {
+        let mut document = document(&["a", "b", "c"]);
+        document.replace_lines(1..2, &["x".to_string(), "y".to_string()]);
+        assert_eq!(document.lines, ["a", "x", "y", "c"]);
+        document.replace_lines(0..0, &["top".to_string()]);
+        document.replace_lines(5..5, &["end".to_string()]);
+        assert_eq!(document.lines, ["top", "a", "x", "y", "c", "end"]);
+        document.replace_lines(1..3, &[]);
+        document.replace_lines(3..4, &[]);
+        assert_eq!(document.lines, ["top", "y", "c"]);
+        assert_in_sync(&document);
+        document.undo();
+        assert_eq!(document.lines, ["top", "y", "c", "end"]);
+    }
+
+    #[test]
+    fn sort_lines_in_range() {
+        let mut document = document(&["z", "c", "a", "b", "y"]);
+        document.sort_lines(1, 3, false);
+        assert_eq!(document.lines, ["z", "a", "b", "c", "y"]);
+        assert_in_sync(&document);
+        document.sort_lines(0, 4, true);
+        assert_eq!(document.lines, ["z", "y", "c", "b", "a"]);
+        assert_in_sync(&document);
+        document.undo();
+        assert_eq!(document.lines, ["z", "a", "b", "c", "y"]);
+    }
+
+    #[test]
+    fn replace_all_is_one_undo_step() {
+        let mut document = document(&["fn main(){", "}"]);
+        assert!(document.replace_all("fn main() {\n    todo!()\n}\n"));
+        assert_eq!(document.lines, ["fn main() {", "    todo!()", "}"]);
+        assert_in_sync(&document);
+        assert!(!document.replace_all("fn main() {\n    todo!()\n}"));
+        document.undo();
+        assert_eq!(document.lines, ["fn main(){", "}"]);
+    }
 }
@@ -0,0 +1,113 @@
+use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use log::info;
+use crate::error::{Error, Result};
+
+/// Programs that copy stdin to the system clipboard, in order of preference
+const COPY_COMMANDS: &[(&str, &[&str])] = &[
+    ("pbcopy", &[]),
+    ("xclip", &["-sel", "clip"]),
+    ("xsel", &["--clipboard", "--input"]),
+];
+
+/// Programs that print the system clipboard, in order of preference
+const PASTE_COMMANDS: &[(&str, &[&str])] = &[
+    ("pbpaste", &[]),
+    ("xclip", &["-sel", "clip", "-o"]),
+    ("xsel", &["--clipboard", "--output"]),
+];
+
+/// Copy `text` to the system clipboard with the first available tool, falling
+/// back to an OSC 52 escape sequence so the terminal (or a multiplexer such as
+/// tmux) sets the clipboard instead
+pub fn set_clipboard(text: &str) -> Result<()> {
+    if let Some((program, args)) = find_command(COPY_COMMANDS) {
+        info!("Copying to clipboard with {}", program);
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes())?;
+        }
+        let status = child.wait()?;
+        if status.success() {
+            return Ok(());
+        }
+        info!("{} exited with {}, falling back to OSC 52", program, status);
+    }
+    write_osc52(text)
+}
+
+/// Read the system clipboard with the first available tool
+pub fn get_clipboard() -> Result<String> {
+    let (program, args) = find_command(PASTE_COMMANDS)
+        .ok_or_else(|| Error::Message("No clipboard tool found (pbpaste, xclip or xsel)".to_string()))?;
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Message(format!("{} exited with {}", program, output.status)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// First command whose program is on PATH
+fn find_command(commands: &'static [(&'static str, &'static [&'static str])]) -> Option<(&'static str, &'static [&'static str])> {
+    let path = env::var_os("PATH")?;
+    commands.iter()
+        .find(|(program, _)| env::split_paths(&path).any(|dir| is_executable(&dir.join(program))))
+        .copied()
+}
+
+fn is_executable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata().is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
+
+// Ask the terminal to set the clipboard. tmux and screen swallow unknown
+// sequences, so wrap it in their passthrough escapes
+fn write_osc52(text: &str) -> Result<()> {
+    let sequence = format!("\x1b]52;c;{}\x07", base64_encode(text.as_bytes()));
+    let sequence = if env::var_os("TMUX").is_some() {
+        format!("\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b"))
+    } else if env::var("TERM").is_ok_and(|term| term.starts_with("screen")) {
+        format!("\x1bP{}\x1b\\", sequence)
+    } else {
+        sequence
+    };
+    let mut stdout = io::stdout();
+    stdout.write_all(sequence.as_bytes())?;
+    stdout.flush()?;
+    Ok(())
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,59 @@
+use std::ops::Range;
+use similar::{DiffTag, TextDiff};
+
+/// A run of lines that differs between two texts: `old` rows of the first are
+/// replaced by `new` rows of the second. One range is empty for a pure
+/// insertion or deletion
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffHunk {
+    pub old: Range<usize>,
+    pub new: Range<usize>,
+}
+
+impl DiffHunk {
+    /// Rows of the hunk on one side: the first text's when `old`
+    pub fn side(&self, old: bool) -> &Range<usize> {
+        if old { &self.old } else { &self.new }
+    }
+}
+
+/// Line-level hunks turning `old` into `new`, in order
+pub fn diff_lines(old: &[String], new: &[String]) -> Vec<DiffHunk> {
+    let old_text = old.join("\n") + "\n";
+    let new_text = new.join("\n") + "\n";
+    let diff = TextDiff::from_lines(&old_text, &new_text);
+    let mut hunks: Vec<DiffHunk> = Vec::new();
+    for op in diff.ops().iter().filter(|op| op.tag() != DiffTag::Equal) {
+        let (old, new) = (op.old_range(), op.new_range());
+        // A deletion next to an insertion is one changed block
+        match hunks.last_mut() {
+            Some(last) if last.old.end == old.start && last.new.end == new.start => {
+                last.old.end = old.end;
+                last.new.end = new.end;
+            },
+            _ => hunks.push(DiffHunk { old, new }),
+        }
+    }
+    hunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn changed_added_and_removed_lines() {
+        let old = lines("a\nb\nc\nd\ne");
+        let new = lines("a\nB\nc\ne\nf");
+        assert_eq!(diff_lines(&old, &new), [
+            DiffHunk { old: 1..2, new: 1..2 },
+            DiffHunk { old: 3..4, new: 3..3 },
+            DiffHunk { old: 5..5, new: 4..5 },
+        ]);
+        assert!(diff_lines(&old, &old).is_empty());
+    }
+}
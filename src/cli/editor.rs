@@ -1,26 +1,62 @@
 use std::error::Error as StdError;
 use std::fs;
 use std::io::{self, Write};
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use notify::Watcher;
+use std::time::{Duration, Instant};
 use crossterm::{
     cursor,
-    event::{self, Event, KeyCode, KeyEvent},
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
     execute,
     terminal::{self, ClearType},
     style::{Color, SetForegroundColor, SetBackgroundColor, ResetColor},
 };
 use crossterm::terminal::{EnterAlternateScreen, LeaveAlternateScreen};
-use mlua::Lua;
+use mlua::{Lua, LuaSerdeExt};
 use log::info;
+use log::error;
 
 use std::env;
 
-use crate::cli::filetree::FileTree;
+use crate::cli::filetree::{self, FileTree};
 use crate::cli::window::{Window, SplitType};
 use crate::cli::shell::Shell;
 use crate::cli::tabs::TabManager;
+use crate::cli::system::{JobRunner, SystemCommand};
+use crate::cli::profile::{Profiler, StartupTimes};
+use crate::cli::api::{self, ApiState, LeaderMapping, MapAction, SharedApiState};
+use crate::cli::options::{self, OptionValue, Options};
+use crate::cli::substitute::{Substitute, SubstituteRange};
+use crate::cli::clipboard;
+use crate::cli::ansi;
+use crate::cli::format;
+use crate::cli::blame::{self, BLAME_WIDTH};
+use crate::cli::autocmd::{self, Autocommand};
+use crate::cli::textobject::TextObject;
+use crate::cli::quickfix::{self, QuickfixEntry};
+use crate::cli::theme::HighlightTheme;
+use crate::cli::recent;
+use crate::cli::positions::{self, CursorPositions};
+use crate::cli::diff::{self, DiffHunk};
+use crate::config::toml_config::TomlConfig;
+use crate::cli::githunk::{self, HunkKind};
+use crate::cli::session::{self, Session, SessionBuffer, SessionFileTree, SessionWindow};
 use crate::error::{Error, Result};
-use crate::cli::buffer::Buffer; // Use the buffer module's Buffer type
+use crate::cli::buffer::{self, expand_tabs, Buffer, Fold, RangeEdit}; // Use the buffer module's Buffer type
+use crate::dap::{self, DapClient, DapMessage, StackFrame};
+use crate::lsp::{get_language_id_from_extension, path_to_uri, uri_to_path, CodeAction, CompletionItem, Diagnostic, DocumentHighlight, DocumentSymbol, LanguageServer, Location, LspManager, LspMessage, SignatureInformation, TextEdit, WorkspaceSymbol};
+use crate::cli::picker::{Picker, PickerItem, PickerStatus, PickerWidget};
+use crate::cli::markdown::{self, MarkdownRenderer, SpanStyle, StyledLine, StyledSpan};
+use regex::Regex;
+use ignore::WalkBuilder;
+use unicode_segmentation::UnicodeSegmentation;
 use fuzzy_matcher::FuzzyMatcher;
 use fuzzy_matcher::skim::SkimMatcherV2;
 
@@ -30,11 +66,763 @@ enum Mode {
     Normal,
     Insert,
     Visual,
+    VisualLine,
+    VisualBlock,
     Command,
     FileTree,
     Shell,
     Help,
     TabSwitcher, // Add new mode for tab switching
+    /// Typing a search pattern after `/` (forward) or `?` (backward)
+    Search { forward: bool },
+    Picker,
+    /// Asking about each match of `:s///c`
+    SubstituteConfirm,
+    /// Browsing the matches of `:grep` / `:rg`
+    Quickfix,
+    /// Start screen shown when RVim starts without a file
+    Welcome,
+}
+
+/// Shape of the terminal cursor, which shows the current mode at a glance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CursorShape {
+    Block,
+    Beam,
+    Underline,
+}
+
+/// A request sent to a language server whose response the editor is waiting for
+enum LspRequest {
+    Hover,
+    DocumentSymbols,
+    /// Symbols matching this query, for the workspace symbol picker
+    WorkspaceSymbols(String),
+    Definition,
+    CodeActions,
+    ExecuteCommand,
+    Rename,
+    Completion,
+    /// Highlights of the symbol at this buffer id, row and column
+    DocumentHighlight((usize, usize, usize)),
+    SignatureHelp,
+}
+
+/// How a notification is colored on the message line
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NotificationLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+impl NotificationLevel {
+    /// Level of a Vim style message: `E37: ...` is an error, `W10: ...` a warning
+    fn of(text: &str) -> Self {
+        let numbered = |prefix: char| text.strip_prefix(prefix)
+            .and_then(|rest| rest.split_once(':'))
+            .is_some_and(|(code, _)| !code.is_empty() && code.chars().all(|c| c.is_ascii_digit()));
+        if numbered('E') {
+            NotificationLevel::Error
+        } else if numbered('W') {
+            NotificationLevel::Warning
+        } else {
+            NotificationLevel::Info
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            NotificationLevel::Info => Color::White,
+            NotificationLevel::Warning => Color::Yellow,
+            NotificationLevel::Error => Color::Red,
+        }
+    }
+}
+
+/// A message shown on the message line until it times out
+struct Notification {
+    text: String,
+    level: NotificationLevel,
+    shown_at: Instant,
+    duration: Duration,
+}
+
+impl Notification {
+    fn expired(&self, now: Instant) -> bool {
+        now.duration_since(self.shown_at) >= self.duration
+    }
+}
+
+/// Command names or paths offered by Tab on the command line, cycled through in order
+struct Completion {
+    /// Command the paths are completed for, e.g. `e`; empty while completing
+    /// the command name itself
+    command: String,
+    candidates: Vec<String>,
+    index: usize,
+    /// The command line as typed; what a completion adds to it is shown in grey
+    typed: String,
+}
+
+impl Completion {
+    /// The command line with the current candidate in place
+    fn line(&self) -> String {
+        let candidate = &self.candidates[self.index];
+        if self.command.is_empty() { candidate.clone() } else { format!("{} {}", self.command, candidate) }
+    }
+}
+
+/// Completions for the word before the cursor in Insert mode, from the open
+/// buffers (Tab) or from the language server as you type
+struct CompletionState {
+    /// Line and byte column where the word being completed starts
+    row: usize,
+    start: usize,
+    items: Vec<CompletionItem>,
+    /// Selected item, inserted when the completion is accepted
+    selected: usize,
+    /// Opened by the language server; typing goes on instead of accepting
+    from_lsp: bool,
+}
+
+/// Signatures of the call being typed in Insert mode, shown above the cursor
+struct SignatureHelpState {
+    signatures: Vec<SignatureInformation>,
+    /// Signature shown first, moved with Ctrl+N / Ctrl+P
+    selected: usize,
+}
+
+/// A program being debugged through a debug adapter
+struct DebugSession {
+    client: DapClient,
+    /// Thread the program stopped in; None while it runs
+    thread_id: Option<u64>,
+    /// Call stack of the stopped thread, innermost first; empty while running
+    frames: Vec<StackFrame>,
+    /// Shown in the title of the debug panel, e.g. "Stopped (breakpoint)"
+    status: String,
+}
+
+/// Two buffers compared side by side with `:diffsplit`
+struct DiffView {
+    /// Ids of the buffer the diff was started from and of the file opened next to it
+    buffers: [usize; 2],
+    /// Hunks turning the first buffer into the second
+    hunks: Vec<DiffHunk>,
+}
+
+/// Operator waiting for its motion or text object, e.g. the `c` of `ciw`
+#[derive(Clone, Copy, PartialEq)]
+enum Operator {
+    Delete,
+    Change,
+    Yank,
+}
+
+impl Operator {
+    /// The key that starts the operator
+    fn key(self) -> char {
+        match self {
+            Self::Delete => 'd',
+            Self::Change => 'c',
+            Self::Yank => 'y',
+        }
+    }
+}
+
+/// A buffer a language server has been told about with `didOpen`
+struct LspDocument {
+    language_id: String,
+    uri: String,
+    /// Document version the server last got the text of
+    version: u64,
+    sent: Instant,
+}
+
+/// File tree operation waiting for its answer on the command line
+enum FileTreePrompt {
+    /// Create a file or directory in this directory
+    Create(PathBuf),
+    Rename(PathBuf),
+    Delete(PathBuf),
+}
+
+impl FileTreePrompt {
+    fn label(&self) -> String {
+        let name = |path: &PathBuf| path.file_name().map_or_else(String::new, |name| name.to_string_lossy().to_string());
+        match self {
+            FileTreePrompt::Create(_) => "New file (end with / for a directory): ".to_string(),
+            FileTreePrompt::Rename(path) => format!("Rename {} to: ", name(path)),
+            FileTreePrompt::Delete(path) => format!("Delete {}? (y/n): ", name(path)),
+        }
+    }
+}
+
+/// Progress of a `:s///c` substitution that asks before each replacement
+struct PendingSubstitute {
+    regex: Regex,
+    replacement: String,
+    global: bool,
+    last_row: usize,
+    // Where to look for the next match
+    row: usize,
+    col: usize,
+    // Byte range of the match being asked about
+    current: Option<Range<usize>>,
+    count: usize,
+    changed_rows: Vec<usize>,
+}
+
+/// How long a notification stays on the message line
+const NOTIFICATION_TIME: Duration = Duration::from_secs(4);
+
+/// Notifications kept at once; older ones are dropped
+const MAX_NOTIFICATIONS: usize = 20;
+
+/// Per-project config file, looked for from the first file's directory upwards
+const PROJECT_CONFIG: &str = ".rvim.lua";
+
+/// Most entries the Insert-mode completion popup offers
+const MAX_WORD_COMPLETIONS: usize = 10;
+
+/// Most entries the quickfix list shows at once
+const QUICKFIX_HEIGHT: usize = 10;
+
+/// Least time between two `didChange` notifications for a buffer
+const LSP_CHANGE_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Background of lines only the first buffer of a diff has
+const DIFF_REMOVED_COLOR: Color = Color::DarkRed;
+
+/// Background of lines only the second buffer of a diff has
+const DIFF_ADDED_COLOR: Color = Color::DarkGreen;
+
+/// Columns `<` and `>` narrow or widen the file tree by
+const FILE_TREE_RESIZE_STEP: usize = 2;
+
+/// Rows of the debug panel below the windows: a title and the call stack
+const DEBUG_PANEL_HEIGHT: usize = 6;
+
+/// Most signatures the signature help popup shows at once
+const SIGNATURE_HELP_HEIGHT: usize = 3;
+
+/// Pause in typing after which the language server is asked for completions
+const COMPLETION_DELAY: Duration = Duration::from_millis(150);
+
+/// How long the cursor rests on a word before its other references are highlighted
+const DOCUMENT_HIGHLIGHT_DELAY: Duration = Duration::from_millis(300);
+
+/// Background of references to the symbol under the cursor
+const DOCUMENT_HIGHLIGHT_COLOR: Color = Color::DarkGrey;
+
+/// How long Space waits for its second key before the list of leader bindings appears
+const WHICH_KEY_DELAY: Duration = Duration::from_millis(500);
+
+/// How long a Space binding that starts a longer one waits for its next key
+const LEADER_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Built-in Space bindings, as listed by the which-key popup. Keys that start a
+/// longer binding wait for the rest, and run after LEADER_TIMEOUT if they are
+/// a binding of their own
+const LEADER_BINDINGS: [(&str, &str); 18] = [
+    ("e", "Toggle file tree"),
+    ("s", "Split horizontally"),
+    ("v", "Split vertically"),
+    ("h", "Open shell"),
+    ("hs", "Stage git hunk"),
+    ("hr", "Reset git hunk"),
+    ("w", "Cycle windows"),
+    ("ws", "Workspace symbols"),
+    ("q", "Close window"),
+    ("x", "Close buffer"),
+    ("b", "Toggle git blame"),
+    ("m", "Toggle minimap"),
+    ("/", "Add search pattern"),
+    ("f", "Find files"),
+    ("a", "Code actions"),
+    ("rn", "Rename symbol"),
+    ("db", "Toggle breakpoint"),
+    ("tf", "Format Markdown table"),
+];
+
+/// Name of a tab whose buffer has no file yet
+const NO_NAME_TAB: &str = "[No Name]";
+
+/// Longest time between two clicks on the same cell that makes a double click
+const DOUBLE_CLICK_TIME: Duration = Duration::from_millis(400);
+
+/// Most commands kept in the command-line history
+const MAX_COMMAND_HISTORY: usize = 200;
+
+/// Most files the file finder collects, so huge trees stay responsive
+const MAX_FINDER_FILES: usize = 50_000;
+
+/// Number of best matches the file finder lists
+const FINDER_RESULTS: usize = 20;
+
+/// Most positions kept in the jump list; the oldest are dropped first
+const MAX_JUMPS: usize = 100;
+
+/// Register that yanks, deletes and `p` use when none is named
+const UNNAMED_REGISTER: char = '"';
+
+/// Columns of the diagnostic sign column left of the line numbers
+const SIGN_COLUMN_WIDTH: usize = 1;
+
+/// Most paths Tab completion cycles through
+const MAX_COMPLETIONS: usize = 50;
+
+/// Smallest width or height a split may leave a window, borders included
+const MIN_WINDOW_SIZE: usize = 3;
+
+/// Deepest nesting of macros playing other macros
+const MAX_MACRO_DEPTH: usize = 100;
+
+/// Background of the visual selection
+const SELECTION_COLOR: Color = Color::Grey;
+
+/// Maximum number of search patterns highlighted at the same time
+const MAX_SEARCH_PATTERNS: usize = 8;
+
+/// Background colors assigned to search patterns, in order of preference
+const SEARCH_COLORS: [Color; MAX_SEARCH_PATTERNS] = [
+    Color::Yellow,
+    Color::Cyan,
+    Color::Magenta,
+    Color::Green,
+    Color::Blue,
+    Color::Red,
+    Color::DarkYellow,
+    Color::DarkCyan,
+];
+
+/// Version shown on the start screen and returned by `rvim.api.get_version()`
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Logo at the top of the start screen
+const WELCOME_LOGO: [&str; 5] = [
+    r" ______     ___",
+    r"|  _ \ \   / (_)_ __ ___",
+    r"| |_) \ \ / /| | '_ ` _ \",
+    r"|  _ < \ V / | | | | | | |",
+    r"|_| \_\ \_/  |_|_| |_| |_|",
+];
+
+/// Compile a search pattern, treating it literally if it is not a valid regex
+fn compile_search_pattern(pattern: &str) -> Option<Regex> {
+    Regex::new(pattern)
+        .or_else(|_| Regex::new(&regex::escape(pattern)))
+        .ok()
+}
+
+/// Status message after a substitution, e.g. `3 substitutions on 2 lines`
+fn substitution_summary(count: usize, lines: usize) -> String {
+    let plural = |n: usize| if n == 1 { "" } else { "s" };
+    format!("{} substitution{} on {} line{}", count, plural(count), lines, plural(lines))
+}
+
+/// What `:bufdo` and `:cdo` report after running `cmd` on `total` targets
+fn run_summary(cmd: &str, succeeded: usize, total: usize, targets: &str) -> String {
+    if succeeded == total {
+        format!("Ran :{} on {} {}", cmd, total, targets)
+    } else {
+        format!("Ran :{} on {} of {} {}; see rvim.log for the errors", cmd, succeeded, total, targets)
+    }
+}
+
+/// Byte offset just past the character at byte column `col`, clamped to the line
+fn char_end(line: &str, col: usize) -> usize {
+    line.get(col..)
+        .and_then(|rest| rest.chars().next())
+        .map_or(line.len(), |c| col + c.len_utf8())
+}
+
+/// Width of the gutter left of a buffer's text: diagnostic signs, line numbers and a space
+fn gutter_width(buffer: &Buffer) -> usize {
+    if !buffer.number {
+        return SIGN_COLUMN_WIDTH;
+    }
+    SIGN_COLUMN_WIDTH + buffer.document.lines.len().to_string().len().max(2) + 1
+}
+
+/// Name of a mode as the status line shows it
+fn mode_name(mode: Mode) -> &'static str {
+    match mode {
+        Mode::Normal => "NORMAL",
+        Mode::Insert => "INSERT",
+        Mode::Visual => "VISUAL",
+        Mode::VisualLine => "V-LINE",
+        Mode::VisualBlock => "V-BLOCK",
+        Mode::SubstituteConfirm => "CONFIRM",
+        Mode::Command => "COMMAND",
+        Mode::FileTree => "FILETREE",
+        Mode::Shell => "SHELL",
+        Mode::Help => "HELP",
+        Mode::TabSwitcher => "TAB",
+        Mode::Search { .. } => "SEARCH",
+        Mode::Picker => "PICK",
+        Mode::Quickfix => "QUICKFIX",
+        Mode::Welcome => "WELCOME",
+    }
+}
+
+/// File the command-line history is kept in between sessions
+fn command_history_path() -> PathBuf {
+    dirs::data_local_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("rvim")
+        .join("command_history")
+}
+
+/// Command-line history saved by an earlier session, one command per line
+fn read_command_history() -> Vec<String> {
+    let mut history: Vec<String> = fs::read_to_string(command_history_path())
+        .map(|text| text.lines().filter(|line| !line.is_empty()).map(str::to_string).collect())
+        .unwrap_or_default();
+    let excess = history.len().saturating_sub(MAX_COMMAND_HISTORY);
+    history.drain(..excess);
+    history
+}
+
+fn write_command_history(history: &[String]) -> Result<()> {
+    let path = command_history_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut text = history.join("\n");
+    text.push('\n');
+    fs::write(path, text)?;
+    Ok(())
+}
+
+/// Session file named on the command line, or the default one
+fn session_path(arg: &str) -> PathBuf {
+    match arg.trim() {
+        "" => Session::default_path(),
+        file => PathBuf::from(file),
+    }
+}
+
+/// Whether a command name is `:w`, `:write` or `:saveas`, returning whether it
+/// has a `!` and whether it needs a file name
+fn parse_write_command(name: &str) -> Option<(bool, bool)> {
+    let (name, force) = match name.strip_suffix('!') {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+    match name {
+        "w" | "write" => Some((force, false)),
+        "sav" | "saveas" => Some((force, true)),
+        _ => None,
+    }
+}
+
+/// Whether a command name is `:e` or `:edit`, and if so whether it has a `!`
+fn parse_edit_command(name: &str) -> Option<bool> {
+    let (name, force) = match name.strip_suffix('!') {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+    matches!(name, "e" | "edit").then_some(force)
+}
+
+/// Built-in commands by their full names, for Tab on the command line
+const COMMAND_NAMES: &[&str] = &[
+    "Format", "LspFormat", "Picker", "TableFormat", "argdo", "bufdo", "cNext", "cd", "cdo", "chdir",
+    "cnext", "copen", "cprev", "cprevious", "debug", "diffoff", "diffsplit", "edit", "grep", "help", "lNext",
+    "lnext", "lprev", "lprevious", "mksession", "nohlsearch", "profile", "pwd", "qall", "quit", "quitall", "rg",
+    "saveas", "set", "sort", "source", "split", "tabclose", "tabedit", "tabfind", "tabnew", "tabnext",
+    "tabprevious", "vsplit", "vterminal", "wall", "wq", "write",
+];
+
+/// Whether the command `name` takes a file name that Tab can complete
+fn takes_file_argument(name: &str) -> bool {
+    parse_edit_command(name).is_some()
+        || parse_write_command(name).is_some()
+        || matches!(name, "tabnew" | "tabe" | "tabedit" | "diffs" | "diffsplit")
+}
+
+/// Files under `root` that are not ignored, as `.gitignore` aware as the file finder
+fn workspace_files(root: &Path) -> Vec<PathBuf> {
+    WalkBuilder::new(root)
+        .build()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_some_and(|ft| ft.is_file()))
+        .take(MAX_FINDER_FILES)
+        .map(|entry| entry.into_path())
+        .collect()
+}
+
+/// Files under `root` that fuzzy match `query`, best first, as paths relative to
+/// the working directory when they are inside it
+fn complete_paths(root: &Path, query: &str) -> Vec<String> {
+    let matcher = SkimMatcherV2::default();
+    let cwd = env::current_dir().unwrap_or_default();
+    let mut scored: Vec<(i64, String)> = workspace_files(root).into_iter()
+        .filter_map(|path| {
+            let relative = path.strip_prefix(root).unwrap_or(&path).to_string_lossy().to_string();
+            let score = matcher.fuzzy_match(&relative, query)?;
+            let display = path.strip_prefix(&cwd).unwrap_or(&path).to_string_lossy().to_string();
+            Some((score, display))
+        })
+        .collect();
+    scored.sort_by(|(a_score, a), (b_score, b)| b_score.cmp(a_score).then_with(|| a.len().cmp(&b.len())).then_with(|| a.cmp(b)));
+    scored.into_iter().take(MAX_COMPLETIONS).map(|(_, path)| path).collect()
+}
+
+/// Byte column of the LSP (UTF-16) column `character`, clamped to the line
+fn utf16_to_byte(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (idx, c) in line.char_indices() {
+        if units >= character {
+            return idx;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+/// Columns of text one minimap cell stands for
+const MINIMAP_CHARS_PER_CELL: usize = 4;
+
+/// Which of `width` minimap cells are shaded for `lines`: those where any of
+/// the lines has text in the columns the cell stands for
+fn minimap_cells<'a>(lines: impl Iterator<Item = &'a String>, width: usize) -> Vec<bool> {
+    let mut cells = vec![false; width];
+    for line in lines {
+        for (column, c) in line.chars().enumerate().take(width * MINIMAP_CHARS_PER_CELL) {
+            if !c.is_whitespace() {
+                cells[column / MINIMAP_CHARS_PER_CELL] = true;
+            }
+        }
+    }
+    cells
+}
+
+/// Rows of a `height` row scrollbar covered by its thumb, for a window scrolled
+/// `offset` lines into a file of `total` lines
+fn scrollbar_thumb(total: usize, height: usize, offset: usize) -> Range<usize> {
+    let total = total.max(1);
+    let thumb_height = (height * height / total).clamp(1, height.max(1));
+    let thumb_pos = (offset * height / total).min(height.saturating_sub(thumb_height));
+    thumb_pos..thumb_pos + thumb_height
+}
+
+/// The line a closed fold of `lines` lines shows in place of them, e.g.
+/// `+-- 12 lines: fn main() {---`, filled with dashes to `width` columns
+fn draw_fold_summary(first: &str, lines: usize, width: usize) -> Result<()> {
+    let summary: String = format!("+--{:>3} lines: {}", lines, first.trim())
+        .chars()
+        .map(|c| if c == '\t' { ' ' } else { c })
+        .chain(std::iter::repeat('-'))
+        .take(width)
+        .collect();
+    execute!(io::stdout(), SetForegroundColor(Color::Cyan), SetBackgroundColor(Color::DarkGrey))?;
+    print!("{}", summary);
+    execute!(io::stdout(), ResetColor)?;
+    Ok(())
+}
+
+/// Whether a terminal can change its cursor shape: emulators that name
+/// themselves in $TERM_PROGRAM can, bare consoles like the Linux one cannot
+fn cursor_style_supported(term: Option<&str>, term_program: Option<&str>) -> bool {
+    if term_program.is_some_and(|program| !program.is_empty()) {
+        return true;
+    }
+    match term {
+        None | Some("" | "dumb" | "linux") => false,
+        Some(term) => !term.starts_with("vt") && !term.starts_with("cons"),
+    }
+}
+
+/// Sign of a row in a git hunk: a green bar for added lines, a blue one for
+/// changed lines and a red mark under the line above removed ones
+fn git_hunk_sign(buffer: &Buffer, row: usize) -> Option<(char, Color)> {
+    let &(_, _, kind) = buffer.git_hunks.iter().find(|&&(start, end, _)| (start..end).contains(&row))?;
+    Some(match kind {
+        HunkKind::Added => ('▎', Color::Green),
+        HunkKind::Modified => ('▎', Color::Blue),
+        HunkKind::Removed => ('▁', Color::Red),
+    })
+}
+
+/// Up to SIGNATURE_HELP_HEIGHT signatures from the selected one on, each with
+/// its active parameter in bold and the others dimmed
+fn signature_help_lines(state: &SignatureHelpState) -> Vec<StyledLine> {
+    let dim = SpanStyle { fg: Some(Color::Grey), ..Default::default() };
+    let len = state.signatures.len();
+    (0..len.min(SIGNATURE_HELP_HEIGHT))
+        .map(|offset| {
+            let idx = (state.selected + offset) % len;
+            let signature = &state.signatures[idx];
+            let style = if offset == 0 { SpanStyle::default() } else { dim };
+            let mut line = match signature.active_parameter.clone()
+                .filter(|range| signature.label.get(range.clone()).is_some())
+            {
+                Some(range) => vec![
+                    StyledSpan { text: signature.label[..range.start].to_string(), style },
+                    StyledSpan { text: signature.label[range.clone()].to_string(), style: SpanStyle { bold: true, ..style } },
+                    StyledSpan { text: signature.label[range.end..].to_string(), style },
+                ],
+                None => vec![StyledSpan { text: signature.label.clone(), style }],
+            };
+            if offset == 0 && len > 1 {
+                line.push(StyledSpan { text: format!("  ({}/{})", idx + 1, len), style: dim });
+            }
+            line
+        })
+        .collect()
+}
+
+/// The cursor as an LSP `Position`; LSP columns count UTF-16 code units
+fn lsp_cursor_position(buffer: &Buffer) -> serde_json::Value {
+    let line = buffer.document.lines.get(buffer.cursor_y).map_or("", |line| line.as_str());
+    serde_json::json!({ "line": buffer.cursor_y, "character": byte_to_utf16(line, buffer.cursor_x) })
+}
+
+/// LSP (UTF-16) column of byte `col`; a column inside a character counts as its start
+fn byte_to_utf16(line: &str, col: usize) -> usize {
+    line[..line.floor_char_boundary(col)].encode_utf16().count()
+}
+
+/// LSP text edits as (start, end, text) with byte columns in `lines`
+fn byte_edits(lines: &[String], edits: &[TextEdit]) -> Vec<RangeEdit> {
+    let byte = |(row, character): (usize, usize)| {
+        (row, lines.get(row).map_or(0, |line| utf16_to_byte(line, character)))
+    };
+    edits.iter().map(|edit| (byte(edit.start), byte(edit.end), edit.new_text.clone())).collect()
+}
+
+/// Scroll `buffer` so its cursor line is in the middle of a window `height` rows
+/// tall, unless the line is already on screen
+fn center_cursor_if_hidden(buffer: &mut Buffer, height: usize) {
+    if buffer.cursor_y < buffer.offset_y || buffer.cursor_y >= buffer.offset_y + height {
+        buffer.offset_y = buffer.cursor_y.saturating_sub(height / 2);
+    }
+}
+
+/// Whether two paths name the same file, comparing canonical forms when both exist
+fn same_path(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Kind of a grapheme cluster for word motions
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum WordClass {
+    Blank,
+    /// Alphanumerics and `_`
+    Word,
+    /// Any other non-blank character
+    Punctuation,
+    /// Stands in for the whole of an empty line
+    EmptyLine,
+    /// The break after a line
+    LineBreak,
+}
+
+/// A grapheme cluster at bytes `col..end` of line `row`
+#[derive(Clone, Copy, Debug)]
+struct WordToken {
+    row: usize,
+    col: usize,
+    end: usize,
+    class: WordClass,
+}
+
+// Tokens of one line in order, ending with its line break
+fn line_word_tokens(line: &str, row: usize) -> Vec<WordToken> {
+    if line.is_empty() {
+        return vec![
+            WordToken { row, col: 0, end: 1, class: WordClass::EmptyLine },
+            WordToken { row, col: 0, end: 1, class: WordClass::LineBreak },
+        ];
+    }
+    let mut tokens: Vec<WordToken> = line.grapheme_indices(true)
+        .map(|(col, grapheme)| {
+            let first = grapheme.chars().next().unwrap_or(' ');
+            let class = if first.is_whitespace() {
+                WordClass::Blank
+            } else if first.is_alphanumeric() || first == '_' {
+                WordClass::Word
+            } else {
+                WordClass::Punctuation
+            };
+            WordToken { row, col, end: col + grapheme.len(), class }
+        })
+        .collect();
+    tokens.push(WordToken { row, col: line.len(), end: line.len() + 1, class: WordClass::LineBreak });
+    tokens
+}
+
+// Tokens from the one under (row, col) to the end of the document
+fn word_tokens_after(lines: &[String], row: usize, col: usize) -> impl Iterator<Item = WordToken> + '_ {
+    (row..lines.len())
+        .flat_map(move |r| line_word_tokens(&lines[r], r))
+        .skip_while(move |token| token.row == row && token.end <= col)
+}
+
+// Tokens before the one under (row, col), nearest first
+fn word_tokens_before(lines: &[String], row: usize, col: usize) -> impl Iterator<Item = WordToken> + '_ {
+    (0..=row.min(lines.len().saturating_sub(1)))
+        .rev()
+        .flat_map(move |r| line_word_tokens(&lines[r], r).into_iter().rev())
+        .skip_while(move |token| token.row == row && token.end > col)
+}
+
+// Skip blanks, then follow the word that comes next and return its far end.
+// With `stop_at_empty` an empty line counts as a word, as it does for `b`
+fn word_extent(tokens: &mut std::iter::Peekable<impl Iterator<Item = WordToken>>, stop_at_empty: bool) -> Option<WordToken> {
+    let first = loop {
+        let token = tokens.next()?;
+        match token.class {
+            WordClass::Blank | WordClass::LineBreak => {},
+            WordClass::EmptyLine if !stop_at_empty => {},
+            _ => break token,
+        }
+    };
+    if first.class == WordClass::EmptyLine {
+        return Some(first);
+    }
+    let mut last = first;
+    while let Some(token) = tokens.next_if(|token| token.class == first.class) {
+        last = token;
+    }
+    Some(last)
+}
+
+/// Byte offset where the word at the end of `before` starts
+fn word_start(before: &str) -> usize {
+    before.char_indices().rev()
+        .take_while(|&(_, c)| is_word_char(c))
+        .last()
+        .map_or(before.len(), |(idx, _)| idx)
+}
+
+/// Whether `c` can be part of a keyword
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Return the keyword (alphanumerics and `_`) that contains byte column `col`
+fn word_at(line: &str, col: usize) -> Option<String> {
+    // Like Vim, use the first keyword at or after the cursor
+    let col = line.char_indices()
+        .find(|(i, c)| *i >= col && is_word_char(*c))
+        .map(|(i, _)| i)?;
+    let start = line[..col].char_indices().rev()
+        .take_while(|(_, c)| is_word_char(*c))
+        .last()
+        .map(|(i, _)| i)
+        .unwrap_or(col);
+    let end = line[col..].char_indices()
+        .find(|(_, c)| !is_word_char(*c))
+        .map(|(i, _)| col + i)
+        .unwrap_or(line.len());
+    if start < end { Some(line[start..end].to_string()) } else { None }
 }
 
 // Document representation
@@ -120,18 +908,180 @@ pub struct Editor {
     terminal_width: usize,
     mode: Mode,
     command_line: String,
+    // Set while the command line is asking for a file tree operation's input
+    file_tree_prompt: Option<FileTreePrompt>,
+    // Symbol being renamed while the command line asks for its new name
+    rename_prompt: Option<String>,
+    // Files opened in this and earlier sessions, newest first, for the start screen
+    recent_files: Vec<PathBuf>,
+    // Where the cursor last was in each file, kept between sessions
+    cursor_positions: CursorPositions,
+    // Tab completion in progress on the command line
+    completion: Option<Completion>,
+    // Executed commands, oldest first, and the entry Up/Down is on
+    command_history: Vec<String>,
+    command_history_pos: usize,
     config_path: PathBuf,
+    // Watches the config directory so config.lua is reloaded when it is saved
+    config_watcher: Option<notify::RecommendedWatcher>,
+    config_events: Option<Receiver<notify::Result<notify::Event>>>,
     lua: Lua,
     quit: bool,
-    waiting_for_second_key: bool,
+    // Prefix key awaiting its second key: ' ' for leader mappings, 'g' for g-commands
+    pending_prefix: Option<char>,
+    // Register a macro is being recorded into with `q`
+    recording_macro: Option<char>,
+    // Recorded keys by register
+    macros: HashMap<char, Vec<KeyEvent>>,
+    // Register of the last played macro, for `@@`
+    last_macro: Option<char>,
+    // How many macros are playing inside each other; stops runaway recursion
+    macro_depth: usize,
+    // Count and keys of the last change, replayed by `.`
+    last_change: Option<(Option<usize>, Vec<KeyEvent>)>,
+    // Keys typed since Normal mode last waited for a new command, without the count
+    change_keys: Vec<KeyEvent>,
+    // Count typed for the command in `change_keys`
+    change_count: Option<usize>,
+    // Buffer id and document version when `change_keys` started
+    change_start: Option<(usize, u64)>,
     file_tree: Option<FileTree>,
     previous_mode: Mode,
     windows: Vec<Window>,
     active_window: usize,
     tab_manager: TabManager,
-    fuzzy_matcher: SkimMatcherV2,
-    fuzzy_results: Vec<(String, i64)>, // (path, score)
     command_palette_items: Vec<String>,
+    picker: Option<Box<dyn PickerWidget>>,
+    /// Query of the open workspace symbol picker, whose items come from the language server
+    workspace_symbol_query: Option<String>,
+    // Colors for tree-sitter highlights in buffers
+    theme: HighlightTheme,
+    hover_popup: Option<Vec<StyledLine>>,
+    // Code actions offered by the language server after Space+a, and the number typed so far
+    code_actions_popup: Option<Vec<CodeAction>>,
+    code_action_input: String,
+    // First hover line shown when the popup is taller than half the screen
+    hover_scroll: usize,
+    pending_substitute: Option<PendingSubstitute>,
+    // Pending language server requests by (language id, request id)
+    lsp_requests: HashMap<(String, u64), LspRequest>,
+    markdown: MarkdownRenderer,
+    diagnostics: HashMap<PathBuf, Vec<Diagnostic>>,
+    // Timed messages, newest last
+    notifications: VecDeque<Notification>,
+    mouse_x: u16,
+    mouse_y: u16,
+    /// Time and screen cell of the last left click, to tell a double click
+    last_click: Option<(Instant, usize, usize)>,
+    pending_count: Option<usize>,
+    // `i` or `a` typed after an operator, waiting for the text object
+    pending_text_object: Option<char>,
+    // `zf` typed, waiting for the motion that ends the fold
+    pending_fold: bool,
+    message: Option<String>,
+    search_patterns: Vec<(String, Color)>,
+    active_search_idx: usize,
+    search_query: String,
+    search_append: bool,
+    // Where visual mode was entered, as (row, byte col)
+    visual_anchor: Option<(usize, usize)>,
+    /// First and last line selected when Command mode was entered from Visual
+    /// mode, for commands such as `:sort` that act on a range of lines
+    visual_selection: Option<(usize, usize)>,
+    // Yanked and deleted text by register name; `"` is the unnamed register
+    registers: HashMap<char, String>,
+    // Direction of the last search; `n` repeats it and `N` reverses it
+    search_forward: bool,
+    // Positions left by jumps such as `gd`, as (file, row, byte col)
+    jump_list: Vec<(PathBuf, usize, usize)>,
+    // Index into `jump_list` that Ctrl+O/Ctrl+I move from; `jump_list.len()` when not travelling
+    jump_pos: usize,
+    jobs: JobRunner,
+    /// Space mappings registered with `rvim.map`; they win over the built-in ones
+    leader_mappings: Arc<Mutex<Vec<LeaderMapping>>>,
+    /// Space bindings that continue `leader_keys` and their descriptions, listed by the which-key popup
+    leader_bindings: Vec<(String, String)>,
+    /// When Space, or a key that starts a longer Space binding, was pressed in Normal mode
+    which_key_since: Option<Instant>,
+    show_which_key: bool,
+    /// Keys typed after Space so far for bindings longer than one key, e.g. `r` of Space r n
+    leader_keys: String,
+    /// Buffers open in a language server, by buffer id
+    lsp_documents: HashMap<usize, LspDocument>,
+    /// Callbacks registered with `rvim.autocmd`
+    autocommands: Arc<Mutex<Vec<Autocommand>>>,
+    /// Function from `rvim.set_statusline` that draws the status line
+    statusline_fn: Arc<Mutex<Option<mlua::RegistryKey>>>,
+    /// Branch of the active file's repository, for the status line
+    git_branch: Option<String>,
+    /// Buffer id and file name BufEnter last fired for
+    entered_buffer: Option<(usize, Option<String>)>,
+    /// Mode ModeChanged last fired for
+    autocmd_mode: Mode,
+    profiler: Option<Profiler>,
+    startup_times: Option<StartupTimes>,
+    tab_choices: Vec<usize>,
+    /// Blame column toggled by Space+b
+    show_git_blame: bool,
+    /// Author and date of the last change to each line, by 0-based line number
+    blame_data: HashMap<usize, String>,
+    /// Buffer `blame_data` was read for
+    blame_buffer: Option<usize>,
+    /// Completion popup in Insert mode
+    completion_popup: Option<CompletionState>,
+    /// Signature help popup in Insert mode
+    signature_help: Option<SignatureHelpState>,
+    /// Buffers compared with `:diffsplit`
+    diff_view: Option<DiffView>,
+    /// When typing last paused in a word, until completions are requested for it
+    completion_due: Option<Instant>,
+    /// Buffer id and cursor position the document highlights are for
+    highlight_position: Option<(usize, usize, usize)>,
+    /// When the cursor came to rest at `highlight_position`, until highlights are requested
+    highlight_due: Option<Instant>,
+    /// References to the symbol under the cursor as start line, start column,
+    /// end line and end column, with byte columns
+    highlight_ranges: Vec<(usize, usize, usize, usize)>,
+    /// Mirrors the `cursor_word_highlight` option
+    cursor_word_highlight: bool,
+    /// Mirrors the `scrollbar` option
+    show_scrollbar: bool,
+    /// Mirror the `minimap` and `minimap_width` options
+    show_minimap: bool,
+    minimap_width: usize,
+    /// The file tree's border is being dragged with the mouse
+    resizing_file_tree: bool,
+    /// Directory of the `.rvim.lua` loaded after config.lua, if any
+    project_root: Option<PathBuf>,
+    /// Working directory, changed with `:cd`
+    working_dir: PathBuf,
+    /// Directory before the last `:cd`, for `:cd -`
+    prev_working_dir: Option<PathBuf>,
+    /// Matches of the last `:grep` / `:rg`: file, line, column and text
+    quickfix_list: Vec<QuickfixEntry>,
+    /// Selected entry; `:cnext` and `:cprevious` move from it
+    quickfix_idx: usize,
+    /// Mirrors the `relativenumber` option
+    show_relative_numbers: bool,
+    /// `wrap_navigate` is `screen`: `j` and `k` move by screen row, `gj` and `gk` by line
+    screen_row_motions: bool,
+    /// Mirrors the `file_tree.width` option
+    file_tree_width: usize,
+    /// Closing character typed along with each opening one in Insert mode
+    auto_pairs: HashMap<char, char>,
+    /// Zero-based breakpoint rows by absolute file path, sent to the debug adapter
+    breakpoints: HashMap<PathBuf, Vec<usize>>,
+    debug_session: Option<DebugSession>,
+    /// Program and arguments of the last `:debug`, launched again by F5
+    debug_program: Option<(String, Vec<String>)>,
+    /// Set by the SIGCONT handler when the process is resumed after Ctrl+Z
+    resumed: Arc<AtomicBool>,
+    /// Whether the terminal understands the escape codes that change the cursor shape
+    cursor_style_supported: bool,
+    /// Shape last sent to the terminal; `None` while it shows the user's own shape
+    cursor_shape: Option<CursorShape>,
+    api_state: SharedApiState,
+    lsp_manager: Arc<Mutex<LspManager>>,
 }
 
 impl Editor {
@@ -155,6 +1105,13 @@ impl Editor {
         
         // Create initial buffer
         let initial_buffer = Buffer::new();
+
+        let working_dir = env::current_dir()?;
+        let lsp_manager = LspManager::new(working_dir.clone());
+
+        let resumed = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGCONT, Arc::clone(&resumed))?;
         
         let mut editor = Self {
             buffers: vec![initial_buffer],
@@ -167,26 +1124,124 @@ impl Editor {
             terminal_width: cols as usize,
             mode: Mode::Normal,
             command_line: String::new(),
+            file_tree_prompt: None,
+            rename_prompt: None,
+            recent_files: recent::read(&recent::recent_files_path()),
+            cursor_positions: positions::read(&positions::cursor_positions_path()),
+            completion: None,
+            command_history: Vec::new(),
+            command_history_pos: 0,
             config_path,
+            config_watcher: None,
+            config_events: None,
             lua,
             quit: false,
-            waiting_for_second_key: false,
+            pending_prefix: None,
+            recording_macro: None,
+            macros: HashMap::new(),
+            last_change: None,
+            change_keys: Vec::new(),
+            change_count: None,
+            change_start: None,
+            last_macro: None,
+            macro_depth: 0,
             file_tree: None,
             previous_mode: Mode::Normal,
             windows: vec![initial_window],
             active_window: 0,
             tab_manager: TabManager::new(),
-            fuzzy_matcher: SkimMatcherV2::default(),
-            fuzzy_results: Vec::new(),
             command_palette_items: Vec::new(),
+            picker: None,
+            workspace_symbol_query: None,
+            theme: HighlightTheme::default(),
+            hover_popup: None,
+            code_actions_popup: None,
+            code_action_input: String::new(),
+            hover_scroll: 0,
+            lsp_requests: HashMap::new(),
+            pending_substitute: None,
+            markdown: MarkdownRenderer::new(),
+            diagnostics: HashMap::new(),
+            notifications: VecDeque::new(),
+            mouse_x: 0,
+            mouse_y: 0,
+            last_click: None,
+            pending_count: None,
+            pending_text_object: None,
+            pending_fold: false,
+            message: None,
+            search_patterns: Vec::new(),
+            active_search_idx: 0,
+            search_query: String::new(),
+            search_append: false,
+            visual_anchor: None,
+            visual_selection: None,
+            registers: HashMap::new(),
+            search_forward: true,
+            jump_list: Vec::new(),
+            jump_pos: 0,
+            jobs: JobRunner::new(),
+            leader_mappings: Arc::new(Mutex::new(Vec::new())),
+            leader_bindings: Vec::new(),
+            which_key_since: None,
+            leader_keys: String::new(),
+            show_which_key: false,
+            lsp_documents: HashMap::new(),
+            autocommands: Arc::new(Mutex::new(Vec::new())),
+            statusline_fn: Arc::new(Mutex::new(None)),
+            git_branch: None,
+            entered_buffer: None,
+            autocmd_mode: Mode::Normal,
+            profiler: None,
+            startup_times: None,
+            tab_choices: Vec::new(),
+            show_git_blame: false,
+            blame_data: HashMap::new(),
+            blame_buffer: None,
+            completion_popup: None,
+            signature_help: None,
+            diff_view: None,
+            completion_due: None,
+            highlight_position: None,
+            highlight_due: None,
+            highlight_ranges: Vec::new(),
+            cursor_word_highlight: true,
+            show_scrollbar: true,
+            show_minimap: false,
+            minimap_width: Options::default().minimap_width,
+            resizing_file_tree: false,
+            project_root: None,
+            working_dir,
+            prev_working_dir: None,
+            quickfix_list: Vec::new(),
+            quickfix_idx: 0,
+            show_relative_numbers: false,
+            screen_row_motions: false,
+            file_tree_width: filetree::DEFAULT_WIDTH,
+            auto_pairs: Options::default().auto_pairs.into_iter().collect(),
+            breakpoints: HashMap::new(),
+            debug_session: None,
+            debug_program: None,
+            resumed,
+            cursor_style_supported: cursor_style_supported(
+                env::var("TERM").ok().as_deref(),
+                env::var("TERM_PROGRAM").ok().as_deref(),
+            ),
+            cursor_shape: None,
+            api_state: ApiState::new_shared(),
+            lsp_manager: Arc::new(Mutex::new(lsp_manager)),
         };
         
         // Load Lua configuration
+        editor.sync_api_state();
         editor.load_config()?;
+        editor.watch_config();
+        editor.command_history = read_command_history();
+        editor.command_history_pos = editor.command_history.len();
         
         // Initialize file tree with current directory
         let current_dir = env::current_dir()?;
-        editor.file_tree = Some(FileTree::new(&current_dir)?);
+        editor.file_tree = Some(editor.new_file_tree(&current_dir)?);
         
         // Initialize command palette items
         editor.command_palette_items = vec![
@@ -201,1032 +1256,6837 @@ impl Editor {
     }
     
     pub fn open_file(&mut self, filename: &str) -> Result<()> {
-        let buffer = Buffer::from_file(filename)?;
+        // A file that already has a tab is shown instead of opened twice
+        if let Some(idx) = self.tab_manager.tab_index(filename) {
+            self.tab_manager.switch_to_tab(idx)?;
+            return self.show_current_tab();
+        }
+
+        let mut buffer = Buffer::from_file(filename)?;
+        self.restore_undo_history(&mut buffer);
         
-        // Create a new tab for the file
-        self.tab_manager.create_tab(filename.to_string(), buffer)?;
+        // Create a new tab for the file and show it
+        let tab_idx = self.tab_manager.create_tab(filename.to_string(), buffer.id)?;
+        self.tab_manager.switch_to_tab(tab_idx)?;
+
+        // Reuse the untouched startup buffer instead of leaving it behind
+        let pristine = self.buffers.len() == 1 && self.buffers.first().is_some_and(|b| {
+            b.filename.is_none() && !b.is_shell && !b.document.modified
+                && b.document.lines.iter().all(|line| line.is_empty())
+        });
+        if pristine {
+            self.buffers[0] = buffer;
+            self.active_buffer = 0;
+        } else {
+            self.buffers.push(buffer);
+            self.active_buffer = self.buffers.len() - 1;
+        }
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+        self.restore_cursor_position();
+        self.refresh_git_hunks();
+        self.remember_recent_file(filename);
+        self.fire_autocmd("BufRead", Some(filename.to_string()), &[]);
+        self.unload_hidden_tabs();
         
         // Update file tree path to new file's directory
         let path = PathBuf::from(filename);
         if let Some(parent) = path.parent() {
-            self.file_tree = Some(FileTree::new(parent)?);
+            self.file_tree = Some(self.new_file_tree(parent)?);
+            self.fit_windows();
         }
         
         Ok(())
     }
     
-    fn open_shell(&mut self, is_horizontal: bool) -> Result<()> {
-        let shell_buffer = Buffer::from_shell(is_horizontal);
-        
-        // Add the new shell buffer
-        self.buffers.push(shell_buffer);
-        
-        // Make the new shell the active buffer
-        self.active_buffer = self.buffers.len() - 1;
-        
-        // Switch to shell mode
-        self.previous_mode = self.mode;
-        self.mode = Mode::Shell;
-        
-        info!("Opened {} shell", if is_horizontal { "horizontal" } else { "vertical" });
-        
+    /// Add a tab for a file without reading it; the file is loaded when the tab is shown
+    pub fn open_file_lazy(&mut self, filename: &str) -> Result<()> {
+        self.tab_manager.create_lazy_tab(filename.to_string(), PathBuf::from(filename))?;
+        self.remember_recent_file(filename);
         Ok(())
     }
-    
-    fn close_current_buffer(&mut self) -> Result<()> {
-        if self.buffers.len() <= 1 {
-            info!("Cannot close the last buffer");
-            return Ok(());
+
+    /// Put a file at the front of the recent files list and save the list
+    fn remember_recent_file(&mut self, filename: &str) {
+        recent::push(&mut self.recent_files, session::absolute(filename));
+        if let Err(e) = recent::write(&recent::recent_files_path(), &self.recent_files) {
+            error!("Failed to save recent files: {}", e);
         }
-        
-        // Remove the current buffer
-        self.buffers.remove(self.active_buffer);
-        
-        // Adjust the active buffer index if needed
-        if self.active_buffer >= self.buffers.len() {
-            self.active_buffer = self.buffers.len() - 1;
+    }
+
+    /// Put the cursor back where it was when the active buffer's file was last left
+    fn restore_cursor_position(&mut self) {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return;
+        };
+        let Some(&position) = buffer.filename.as_deref()
+            .and_then(|filename| self.cursor_positions.get(&session::absolute(filename)))
+        else {
+            return;
+        };
+        (buffer.cursor_y, buffer.cursor_x) = positions::clamp(&buffer.document.lines, position);
+        self.cursor_y = buffer.cursor_y;
+        self.cursor_x = buffer.cursor_x;
+        self.scroll_cursor_into_view();
+    }
+
+    /// Note where the cursor is in a buffer's file, to restore it when the file is opened again
+    fn remember_cursor_position(&mut self, idx: usize) {
+        if let Some(buffer) = self.buffers.get(idx).filter(|buffer| !buffer.is_shell)
+            && let Some(filename) = &buffer.filename
+        {
+            self.cursor_positions.insert(session::absolute(filename), (buffer.cursor_y, buffer.cursor_x));
         }
-        
-        info!("Closed buffer, now at buffer {}", self.active_buffer + 1);
-        
-        Ok(())
     }
-    
-    fn load_config(&mut self) -> Result<()> {
-        let config_file = self.config_path.join("config.lua");
-        
-        // Register API functions
-        self.register_api()?;
-        
-        // Load config file if exists
-        if config_file.exists() {
-            info!("Loading config from: {:?}", config_file);
-            let config_content = fs::read_to_string(config_file)?;
-            self.lua.load(&config_content).exec()?;
-        } else {
-            info!("No config file found at: {:?}", config_file);
+
+    fn options(&self) -> Options {
+        self.api_state.lock().unwrap().options.clone()
+    }
+
+    /// Load the buffer's persistent undo history when undofile is set
+    fn restore_undo_history(&mut self, buffer: &mut Buffer) {
+        let options = self.options();
+        buffer.document.set_undolevels(options.undolevels);
+        if !options.undofile {
+            return;
         }
-        
+        match buffer.document.read_undo_file(&options.undodir) {
+            Ok(true) => info!("Restored undo history for {:?}", buffer.filename),
+            Ok(false) => {},
+            Err(e) => self.message = Some(e.to_string()),
+        }
+    }
+
+    /// Save the active buffer, writing its undo file too when undofile is set
+    fn write_active_buffer(&mut self) -> Result<()> {
+        let options = self.options();
+        // A running language server formats in place of the external formatter
+        if options.format_on_save_lsp && let Some(server) = self.lsp_formatting_server() {
+            self.format_with_lsp(server);
+        } else if options.format_on_save {
+            self.format_buffer()?;
+        }
+        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
+            buffer.save()?;
+            if options.undofile {
+                buffer.document.write_undo_file(&options.undodir)?;
+            }
+        }
+        self.remember_cursor_position(self.active_buffer);
+        self.refresh_git_hunks();
+        // Saved lines may have moved, so read the blame again
+        self.blame_buffer = None;
+        self.refresh_diff();
+        let filename = self.buffers.get(self.active_buffer).and_then(|buffer| buffer.filename.clone());
+        self.fire_autocmd("BufWrite", filename, &[]);
         Ok(())
     }
-    
-    fn register_api(&mut self) -> Result<()> {
-        // Create a global 'rvim' table
-        let rvim_table = self.lua.create_table()?;
-        
-        // Add the map function (similar to Neovim's vim.keymap.set)
-        let map_fn = self.lua.create_function(|_, (mode, key, action): (String, String, String)| {
-            // This would actually set keybindings
-            info!("Mapping in mode '{}': {} -> {}", mode, key, action);
-            Ok(())
-        })?;
-        
-        rvim_table.set("map", map_fn)?;
-        
-        // Create an API module
-        let api_table = self.lua.create_table()?;
-        
-        // Example API function
-        let get_version_fn = self.lua.create_function(|_, ()| {
-            Ok("rvim 0.1.0")
-        })?;
-        
-        api_table.set("get_version", get_version_fn)?;
-        rvim_table.set("api", api_table)?;
-        
-        // Set the global rvim table
-        self.lua.globals().set("rvim", rvim_table)?;
-        
-        Ok(())
+
+    /// Whether any buffer has changes that were not written
+    pub fn has_unsaved_changes(&self) -> bool {
+        self.buffers.iter().any(|buffer| !buffer.is_shell && buffer.document.modified)
     }
-    
-    pub fn set_plugin_manager(&mut self, plugin_manager: crate::cli::plugin::PluginManager) -> Result<()> {
-        // Register the plugin manager's Lua functions
-        let plugin_table = self.lua.create_table()?;
-        
-        // Add function to get installed plugins
-        let get_plugins_fn = self.lua.create_function(|_, ()| {
-            Ok("List of installed plugins")
-        })?;
-        plugin_table.set("get_plugins", get_plugins_fn)?;
-        
-        // Add function to install a plugin
-        let install_plugin_fn = self.lua.create_function(move |_, plugin_url: String| {
-            info!("Installing plugin: {}", plugin_url);
-            // In a real implementation, this would call plugin_manager.install_plugin(...)
-            Ok(())
-        })?;
-        plugin_table.set("install", install_plugin_fn)?;
-        
-        // Set the plugins table in the global rvim table
-        let globals = self.lua.globals();
-        let rvim_table: mlua::Table = globals.get("rvim")?;
-        rvim_table.set("plugins", plugin_table)?;
-        
-        info!("Plugin manager initialized");
+
+    /// `:wa`: write every modified buffer that has a file name
+    fn write_all_buffers(&mut self) -> Result<()> {
+        let active = self.active_buffer;
+        let modified: Vec<usize> = (0..self.buffers.len())
+            .filter(|&idx| {
+                let buffer = &self.buffers[idx];
+                !buffer.is_shell && buffer.document.modified && buffer.filename.is_some()
+            })
+            .collect();
+        for idx in modified {
+            self.active_buffer = idx;
+            let written = self.write_active_buffer();
+            if written.is_err() {
+                self.active_buffer = active;
+            }
+            written?;
+        }
+        self.active_buffer = active;
         Ok(())
     }
-    
-    pub fn run(&mut self) -> Result<()> {
-        self.refresh_screen()?;
-        
-        while !self.quit {
-            self.process_keypress()?;
-            self.refresh_screen()?;
+
+    /// `:bufdo {cmd}` / `:argdo {cmd}`: run an Ex command in every open buffer.
+    /// A failure in one buffer is logged and the others still run
+    fn buffer_do(&mut self, cmd: &str) -> Result<()> {
+        if cmd.is_empty() {
+            return Err(Error::Message("E471: Argument required".to_string()));
         }
-        
-        // Cleanup terminal on exit
-        execute!(
-            io::stdout(),
-            LeaveAlternateScreen,
-            event::DisableMouseCapture,  // Disable mouse capture when exiting
-            cursor::Show
-        )?;
-        terminal::disable_raw_mode()?;
-        
+        self.load_all_tabs();
+        if let Some(parsed) = Substitute::parse(cmd) {
+            return self.substitute_all_buffers(parsed?);
+        }
+        let active = self.buffers.get(self.active_buffer).map(|buffer| buffer.id);
+        // By id, since the command may open or close buffers
+        let ids: Vec<usize> = self.buffers.iter()
+            .filter(|buffer| !buffer.is_shell)
+            .map(|buffer| buffer.id)
+            .collect();
+        let mut failed = 0;
+        for id in &ids {
+            let Some(idx) = self.buffers.iter().position(|buffer| buffer.id == *id) else {
+                continue;
+            };
+            self.active_buffer = idx;
+            self.command_line = cmd.to_string();
+            if let Err(e) = self.execute_command() {
+                error!(":bufdo {} failed in buffer {}: {}", cmd, id, e);
+                failed += 1;
+            }
+        }
+        if let Some(idx) = self.buffers.iter().position(|buffer| Some(buffer.id) == active) {
+            self.active_buffer = idx;
+        }
+        self.active_buffer = self.active_buffer.min(self.buffers.len().saturating_sub(1));
+        self.clamp_cursor();
+        self.notify(run_summary(cmd, ids.len() - failed, ids.len(), "buffers"), NOTIFICATION_TIME);
         Ok(())
     }
-    
-    fn draw_tabs(&self) -> Result<()> {
-        let start_x = 0;
-        let start_y = 0;
-        let tab_list = self.tab_manager.tab_list();
-        let mut current_x = start_x;
 
-        execute!(io::stdout(), cursor::MoveTo(0, 0))?;
+    /// Read the files of tabs that were never shown into the buffer list, for
+    /// commands that work on every buffer
+    fn load_all_tabs(&mut self) {
+        let active = self.active_buffer;
+        for id in self.tab_manager.unloaded_buffer_ids() {
+            let Some(idx) = self.tab_manager.tab_index_of_buffer(id) else {
+                continue;
+            };
+            match self.tab_manager.load_tab_buffer(idx) {
+                Ok(None) => {},
+                Ok(Some(mut buffer)) => {
+                    self.restore_undo_history(&mut buffer);
+                    self.buffers.push(buffer);
+                    self.active_buffer = self.buffers.len() - 1;
+                    self.refresh_git_hunks();
+                    let filename = self.buffers[self.active_buffer].filename.clone();
+                    self.fire_autocmd("BufRead", filename, &[]);
+                },
+                Err(e) => error!("Failed to load tab {}: {}", idx, e),
+            }
+        }
+        self.active_buffer = active;
+    }
 
-        // Draw tab bar background
-        execute!(
-            io::stdout(),
-            SetBackgroundColor(Color::DarkGrey),
-            SetForegroundColor(Color::White)
-        )?;
+    /// `:bufdo s/old/new/`: substitute in every line of each buffer, as `%s` would
+    fn substitute_all_buffers(&mut self, substitute: Substitute) -> Result<()> {
+        if substitute.confirm {
+            return Err(Error::Message("E488: The c flag is not supported with :bufdo".to_string()));
+        }
+        let last_pattern = self.search_patterns.get(self.active_search_idx).map(|(pattern, _)| pattern.as_str());
+        let regex = substitute.regex(last_pattern)?;
+        let (mut count, mut changed) = (0, 0);
+        for buffer in self.buffers.iter_mut().filter(|buffer| !buffer.is_shell) {
+            let last = buffer.document.lines.len().saturating_sub(1);
+            let (replaced, _) = buffer.document.substitute(0, last, &regex, &substitute.replacement, substitute.global);
+            count += replaced;
+            changed += usize::from(replaced > 0);
+        }
+        if count == 0 {
+            return Err(Error::Message(format!("E486: Pattern not found: {}", regex.as_str())));
+        }
+        self.notify(format!("Replaced {} occurrences across {} buffers", count, changed), NOTIFICATION_TIME);
+        Ok(())
+    }
 
-        for x in 0..self.terminal_width {
-            execute!(io::stdout(), cursor::MoveTo(x as u16, start_y as u16))?;
-            print!(" ");
+    /// `:cdo {cmd}`: go to each quickfix entry in turn and run an Ex command there,
+    /// e.g. `:cdo s/old/new/` to replace on every matched line
+    fn quickfix_do(&mut self, cmd: &str) -> Result<()> {
+        if cmd.is_empty() {
+            return Err(Error::Message("E471: Argument required".to_string()));
+        }
+        if self.quickfix_list.is_empty() {
+            return Err(Error::Message("E42: No Errors".to_string()));
         }
+        let total = self.quickfix_list.len();
+        let mut failed = 0;
+        for idx in 0..total {
+            self.go_to_quickfix_entry(idx);
+            // Running the command in whatever buffer is open would change the wrong file
+            let path = &self.quickfix_list[idx].0;
+            if !self.current_position().is_some_and(|(current, _, _)| same_path(&current, path)) {
+                error!(":cdo could not open {}", path.display());
+                failed += 1;
+                continue;
+            }
+            self.command_line = cmd.to_string();
+            if let Err(e) = self.execute_command() {
+                error!(":cdo {} failed on quickfix entry {}: {}", cmd, idx + 1, e);
+                failed += 1;
+            }
+        }
+        self.notify(run_summary(cmd, total - failed, total, "quickfix entries"), NOTIFICATION_TIME);
+        Ok(())
+    }
 
-        // Draw each tab
-        for (idx, (id, name)) in tab_list.iter().enumerate() {
-            let is_current = idx == self.tab_manager.current_tab();
-            let tab_style = if is_current {
-                execute!(
-                    io::stdout(),
-                    SetBackgroundColor(Color::Blue),
-                    SetForegroundColor(Color::White)
-                )
-            } else {
-                execute!(
-                    io::stdout(),
-                    SetBackgroundColor(Color::DarkGrey),
-                    SetForegroundColor(Color::White)
-                )
-            }?;
+    /// `:cd`: make `dir` the working directory, or the home directory without one;
+    /// `-` goes back to the previous one. The file tree and the language servers'
+    /// workspace follow it
+    fn change_directory(&mut self, dir: &str) -> Result<()> {
+        let target = match dir {
+            "" => options::expand_home("~"),
+            "-" => self.prev_working_dir.clone()
+                .ok_or_else(|| Error::Message("E186: No previous directory".into()))?,
+            _ => self.working_dir.join(options::expand_home(dir)),
+        };
+        let target = fs::canonicalize(&target).ok()
+            .filter(|path| path.is_dir())
+            .ok_or_else(|| Error::Message(format!("E344: Can't find directory \"{}\"", dir)))?;
+        // Relative file names would point into the new directory otherwise
+        for buffer in &mut self.buffers {
+            buffer.make_path_absolute(&self.working_dir);
+        }
+        self.tab_manager.make_paths_absolute(&self.working_dir);
+        env::set_current_dir(&target)?;
+        self.prev_working_dir = Some(std::mem::replace(&mut self.working_dir, target.clone()));
+        self.file_tree = Some(self.new_file_tree(&target)?);
+        self.fit_windows();
+        if let Err(e) = self.lsp_manager.lock().unwrap().set_workspace_root(target.clone()) {
+            error!("Failed to move the LSP workspace to {:?}: {}", target, e);
+        }
+        self.notify(target.display().to_string(), NOTIFICATION_TIME);
+        Ok(())
+    }
 
-            let tab_text = format!(" {} ", name);
-            execute!(io::stdout(), cursor::MoveTo(current_x as u16, start_y as u16))?;
-            print!("{}", tab_text);
-            
-            current_x += tab_text.len();
+    /// `:Format`: pipe the active buffer through the formatter for its filetype.
+    /// A missing or failing formatter is reported and leaves the text alone
+    pub fn format_buffer(&mut self) -> Result<()> {
+        let Some(buffer) = self.buffers.get(self.active_buffer).filter(|buffer| !buffer.is_shell) else {
+            return Ok(());
+        };
+        let filename = buffer.filename.clone().unwrap_or_default();
+        let Some((program, args)) = buffer.filetype.as_deref()
+            .and_then(|filetype| format::formatter_for(filetype, &filename))
+        else {
+            let filetype = buffer.filetype.clone().unwrap_or_else(|| "none".to_string());
+            self.notify_level(NotificationLevel::Warning, format!("No formatter for filetype {}", filetype), NOTIFICATION_TIME);
+            return Ok(());
+        };
+        let input = buffer.document.lines.join("\n") + "\n";
+        match format::run_formatter(program, &args, &input) {
+            Ok(output) => {
+                if self.buffers[self.active_buffer].document.replace_all(&output) {
+                    self.clamp_cursor();
+                }
+            },
+            Err(e) => self.notify_level(NotificationLevel::Error, e.to_string(), NOTIFICATION_TIME),
         }
+        Ok(())
+    }
 
-        execute!(io::stdout(), ResetColor)?;
+    /// `:LspFormat`: have the active buffer's language server format it
+    pub fn lsp_format(&mut self) -> Result<()> {
+        // Like the other LSP commands, start the server if it is not running yet
+        if let Some(filename) = self.buffers.get(self.active_buffer).and_then(|buffer| buffer.filename.clone()) {
+            self.lsp_manager.lock().unwrap().start_server_for_file(Path::new(&filename))?;
+        }
+        match self.lsp_formatting_server() {
+            Some(server) => self.format_with_lsp(server),
+            None => self.notify_level(NotificationLevel::Warning, "No language server formats this file", NOTIFICATION_TIME),
+        }
         Ok(())
     }
 
-    fn refresh_screen(&mut self) -> Result<()> {
-        // Poll shell output if in shell mode and buffer exists
-        if self.mode == Mode::Shell {
-            if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
-                if let Some(shell) = buffer.shell.as_mut() {
-                    shell.poll_output();
-                    if !shell.running { // If shell terminated, switch mode
-                        self.mode = self.previous_mode;
-                        // Consider closing the shell buffer or marking it as non-interactive
-                        // For now, just switch mode. The buffer remains.
-                        info!("Shell terminated, switching to mode: {:?}", self.mode);
-                    }
-                }
-            }
+    /// Running language server for the active buffer that supports `textDocument/formatting`
+    fn lsp_formatting_server(&self) -> Option<Arc<Mutex<LanguageServer>>> {
+        let (_, server) = self.running_lsp_server()?;
+        let supported = {
+            let server = server.lock().unwrap();
+            let provider = &server.capabilities()["documentFormattingProvider"];
+            server.is_initialized() && (provider.is_object() || provider.as_bool() == Some(true))
+        };
+        supported.then_some(server)
+    }
+
+    /// Send `textDocument/formatting` for the active buffer and apply the edits
+    /// in the answer as one undo step. Indentation follows the buffer's filetype
+    /// settings; failures are reported and leave the text alone
+    fn format_with_lsp(&mut self, server: Arc<Mutex<LanguageServer>>) {
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return;
+        };
+        let Some(filename) = buffer.filename.clone() else {
+            return;
+        };
+        let params = serde_json::json!({
+            "textDocument": { "uri": path_to_uri(Path::new(&filename)) },
+            "options": {
+                "tabSize": buffer.document.shiftwidth,
+                "insertSpaces": buffer.document.expandtab != Some(false),
+            },
+        });
+        // The server formats the text as it is now
+        self.sync_lsp_documents(true);
+        let result = server.lock().unwrap().send_request("textDocument/formatting", params);
+        match result {
+            Ok(result) => {
+                let document = &mut self.buffers[self.active_buffer].document;
+                let edits = byte_edits(&document.lines, &TextEdit::from_response(&result));
+                document.replace_ranges(&edits);
+                self.clamp_cursor();
+            },
+            Err(e) => self.notify_level(NotificationLevel::Error, e.to_string(), NOTIFICATION_TIME),
         }
+    }
 
-        execute!(
-            io::stdout(),
-            terminal::Clear(ClearType::All),
-            cursor::MoveTo(0, 0)
-        )?;
+    /// `:w {file}` / `:saveas {file}`: write the active buffer to `file` and keep
+    /// editing it under that name. An existing file is only overwritten with `!`
+    fn write_active_buffer_as(&mut self, filename: &str, force: bool) -> Result<()> {
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return Ok(());
+        };
+        let buffer_id = buffer.id;
+        if buffer.filename.as_deref() == Some(filename) {
+            return self.write_active_buffer();
+        }
+        if self.tab_manager.tab_index(filename).is_some() {
+            self.notify(format!("E139: File is loaded in another buffer: {}", filename), NOTIFICATION_TIME);
+            return Ok(());
+        }
+        if Path::new(filename).exists() && !force {
+            self.notify("E13: File exists (add ! to override)", NOTIFICATION_TIME);
+            return Ok(());
+        }
 
-        // Draw tabs at the top
-        self.draw_tabs()?;
+        let options = self.options();
+        self.blame_buffer = None;
+        let buffer = &mut self.buffers[self.active_buffer];
+        buffer.save_as(filename)?;
+        if options.undofile {
+            buffer.document.write_undo_file(&options.undodir)?;
+        }
+        let lines = buffer.document.lines.len();
+        self.fire_autocmd("BufWrite", Some(filename.to_string()), &[]);
 
-        // Adjust other content to start below tabs
-        let content_offset = 1; // Height of tab bar
+        match self.tab_manager.tab_index_of_buffer(buffer_id) {
+            Some(idx) => self.tab_manager.rename_tab(idx, filename.to_string())?,
+            None => {
+                let idx = self.tab_manager.create_tab(filename.to_string(), buffer_id)?;
+                self.tab_manager.switch_to_tab(idx)?;
+            },
+        }
+        self.notify(format!("\"{}\" {}L written", filename, lines), NOTIFICATION_TIME);
+        Ok(())
+    }
 
-        if self.mode == Mode::Help {
-            self.draw_help_screen()?;
-        } else {
-            // Adjust filetree and windows to start below tabs
-            let filetree_offset = if let Some(tree) = &self.file_tree {
-                if tree.visible {
-                    self.draw_file_tree()?;
-                    tree.width + 1
-                } else {
-                    0
-                }
-            } else {
-                0
-            };
-            
-            // Draw each window
-            for (idx, window) in self.windows.iter().enumerate() {
-                // Adjust for file tree
-                let adjusted_x = window.x + filetree_offset;
-                
-                // Draw window borders if there are multiple windows
-                if self.windows.len() > 1 {
-                    self.draw_window_borders(window, adjusted_x, idx == self.active_window)?;
+    /// Write the open files, cursors, tabs, splits and file tree to `path` as JSON
+    pub fn save_session(&self, path: &Path) -> Result<()> {
+        let api_state = self.api_state.lock().unwrap();
+        let buffers = self.buffers.iter()
+            .filter(|buffer| !buffer.is_shell)
+            .filter_map(|buffer| Some(SessionBuffer {
+                path: session::absolute(buffer.filename.as_ref()?),
+                cursor_x: buffer.cursor_x,
+                cursor_y: buffer.cursor_y,
+                offset_y: buffer.offset_y,
+                marks: api_state.marks.get(&buffer.id)
+                    .map(|marks| marks.iter()
+                        .filter(|(name, _)| name.is_ascii_lowercase())
+                        .map(|(&name, &position)| (name, position))
+                        .collect())
+                    .unwrap_or_default(),
+                folds: buffer.document.folds.iter().map(|fold| (fold.start, fold.end, fold.closed)).collect(),
+            }))
+            .collect();
+        let active_file = self.buffers.get(self.active_buffer).and_then(|buffer| buffer.filename.as_ref());
+        // Tabs by their index in the list; tab_list gives their ids
+        let tabs: Vec<(usize, &str)> = self.tab_manager.tab_list().into_iter()
+            .map(|(_, name)| name)
+            .enumerate()
+            .filter(|(_, name)| !name.starts_with(NO_NAME_TAB))
+            .collect();
+        let session = Session {
+            buffers,
+            active_buffer: active_file
+                .and_then(|name| self.buffers.iter().filter(|b| !b.is_shell && b.filename.is_some())
+                    .position(|b| b.filename.as_ref() == Some(name)))
+                .unwrap_or(0),
+            current_tab: tabs.iter().position(|&(idx, _)| idx == self.tab_manager.current_tab()).unwrap_or(0),
+            tabs: tabs.iter().map(|(_, name)| session::absolute(name)).collect(),
+            windows: self.windows.iter()
+                .map(|w| SessionWindow { x: w.x, y: w.y, width: w.width, height: w.height })
+                .collect(),
+            active_window: self.active_window,
+            file_tree: self.file_tree.as_ref()
+                .map(|tree| SessionFileTree {
+                    root: fs::canonicalize(&tree.root).unwrap_or_else(|_| tree.root.clone()),
+                    visible: tree.visible,
+                }),
+        };
+        session.write(path)
+    }
+
+    /// Open the files of a session saved by `save_session` and restore its cursors,
+    /// tabs, splits and file tree. Files that no longer exist are skipped
+    pub fn load_session(&mut self, path: &Path) -> Result<()> {
+        let session = Session::read(path)?;
+        let mut missing = 0;
+        // Files that exist but could not be opened are logged and left out
+        let mut failed = 0;
+
+        for saved in &session.buffers {
+            let name = session::display_name(&saved.path);
+            if !Path::new(&name).exists() {
+                continue;
+            }
+            if let Err(e) = self.open_file(&name) {
+                error!("Session {}: could not open {}: {}", path.display(), name, e);
+                failed += 1;
+                continue;
+            }
+            if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
+                buffer.cursor_y = saved.cursor_y;
+                buffer.cursor_x = saved.cursor_x;
+                buffer.offset_y = saved.offset_y.min(saved.cursor_y);
+                buffer.document.folds = saved.folds.iter()
+                    .map(|&(start, end, closed)| Fold { start, end, closed })
+                    .collect();
+                let mut api_state = self.api_state.lock().unwrap();
+                for (&name, &position) in &saved.marks {
+                    api_state.set_mark(buffer.id, name, position);
                 }
-                
-                // Draw window content
-                self.draw_window_content(window, adjusted_x)?;
             }
+            self.clamp_cursor();
         }
-        
-        self.draw_status_line()?;
-        self.draw_message_line()?;
-        
-        // Position cursor based on mode
-        match self.mode {
-            Mode::Help => {
-                // Hide cursor or move to a non-obtrusive place for help screen
-                execute!(io::stdout(), cursor::Hide)?;
+        // Tabs whose files were not loaded when the session was saved stay unread
+        for name in &session.tabs {
+            let name = session::display_name(name);
+            if self.tab_manager.tab_index(&name).is_some() {
+                continue;
             }
-            Mode::FileTree => {
-                execute!(io::stdout(), cursor::Show)?;
-                if let Some(tree) = &self.file_tree {
-                    let tree_cursor_y = tree.cursor.min(self.terminal_height - 3);
-                    execute!(io::stdout(), cursor::MoveTo(2, tree_cursor_y as u16))?;
-                }
-            },
-            Mode::Shell => {
-                if let Some(buffer) = self.buffers.get_mut(self.active_buffer) { 
-                    if let Some(shell) = buffer.shell.as_mut() { 
-                        shell.poll_output(); 
-                        if !shell.running && self.mode == Mode::Shell { 
-                             self.mode = self.previous_mode;
-                        } else if self.mode == Mode::Shell { 
-                            let window = &self.windows[self.active_window];
-                            let effective_height = if self.windows.len() > 1 { window.height - 2 } else { window.height };
-
-                            let content_y_start = if self.windows.len() > 1 { 
-                                window.y + 1 
-                            } else { 
-                                0 
-                            };
-                            
-                            let filetree_width = if let Some(tree) = &self.file_tree { 
-                                if tree.visible { tree.width + 1 } else { 0 } 
-                            } else { 0 };
-                            
-                            let content_x_start = if self.windows.len() > 1 { 
-                                window.x + filetree_width + 1 
-                            } else { 
-                                filetree_width 
-                            };
-                            
-                            // Calculate the Y position for RVim's input line.
-                            // This is the number of output lines from the shell that will actually be displayed.
-                            let displayed_output_lines_count = shell.lines.len().min(effective_height.saturating_sub(1));
-                            let rvim_input_line_screen_y = content_y_start + displayed_output_lines_count;
-                            
-                            // Cursor position for RVim's input_line
-                            let rvim_input_cursor_screen_x = content_x_start + shell.cursor_pos + 2; // +2 for "$ " visual prefix
-                            
-                            execute!(io::stdout(), cursor::MoveTo(
-                                rvim_input_cursor_screen_x as u16, 
-                                rvim_input_line_screen_y as u16
-                            ))?;
-                        }
-                    }
-                }
-            },
-            _ => {
-                if !self.buffers.is_empty() && self.active_buffer < self.buffers.len() {
-                    let buffer = &self.buffers[self.active_buffer];
-                    let window = &self.windows[self.active_window];
-                    
-                    let filetree_width = if let Some(tree) = &self.file_tree { 
-                        if tree.visible { tree.width + 1 } else { 0 } 
-                    } else { 0 };
-                    
-                    let adjusted_x = window.x + filetree_width;
-                    let content_x = if self.windows.len() > 1 { adjusted_x + 1 } else { adjusted_x };
-                    let content_y = if self.windows.len() > 1 { window.y + 1 } else { window.y };
-                    
-                    let screen_x = content_x + buffer.cursor_x.saturating_sub(buffer.offset_x);
-                    let screen_y = content_y + buffer.cursor_y.saturating_sub(buffer.offset_y);
-                    execute!(io::stdout(), cursor::MoveTo(screen_x as u16, screen_y as u16))?;
-                }
+            if !Path::new(&name).exists() {
+                missing += 1;
+                continue;
+            }
+            if let Err(e) = self.open_file_lazy(&name) {
+                error!("Session {}: could not open {}: {}", path.display(), name, e);
+                failed += 1;
             }
         }
-        
-        io::stdout().flush()?;
-        
+        if let Some(active) = session.buffers.get(session.active_buffer) {
+            let name = session::display_name(&active.path);
+            if self.tab_manager.tab_index(&name).is_some()
+                && let Err(e) = self.open_file(&name)
+            {
+                error!("Session {}: could not open {}: {}", path.display(), name, e);
+            }
+        }
+        if let Some(idx) = session.tabs.get(session.current_tab)
+            .and_then(|name| self.tab_manager.tab_index(&session::display_name(name)))
+            && let Err(e) = self.tab_manager.switch_to_tab(idx).and_then(|_| self.show_current_tab())
+        {
+            error!("Session {}: could not show tab {}: {}", path.display(), idx + 1, e);
+        }
+
+        if !session.windows.is_empty() {
+            self.windows = session.windows.iter()
+                .map(|w| Window::new(w.x, w.y, w.width.max(1), w.height.max(1)))
+                .collect();
+            self.active_window = session.active_window.min(self.windows.len() - 1);
+            self.fit_windows();
+        }
+        // Opening files moves the tree to their directory, so it is restored last
+        if let Some(saved) = &session.file_tree
+            && saved.root.is_dir()
+        {
+            let mut tree = self.new_file_tree(&saved.root)?;
+            tree.visible = saved.visible;
+            self.file_tree = Some(tree);
+            self.fit_windows();
+        }
+
+        let mut message = format!("Loaded session {}", path.display());
+        if missing > 0 {
+            message.push_str(&format!(" ({} missing files skipped)", missing));
+        }
+        if failed > 0 {
+            message.push_str(&format!("; {} files could not be opened, see rvim.log", failed));
+        }
+        self.message = Some(message);
         Ok(())
     }
-    
-    fn draw_file_tree(&self) -> Result<()> {
-        if let Some(tree) = &self.file_tree {
-            let tree_width = tree.width;
-            let display_height = self.terminal_height.saturating_sub(2);
-            
-            // Draw tree border
-            for y in 0..display_height {
-                execute!(
-                    io::stdout(),
-                    cursor::MoveTo(tree_width as u16, y as u16),
-                    SetForegroundColor(Color::DarkGrey)
-                )?;
-                print!("│");
-            }
-            execute!(io::stdout(), ResetColor)?;
-            
-            // Draw file tree entries
-            for (idx, entry) in tree.entries.iter().enumerate() {
-                if idx >= display_height {
-                    break;
-                }
-                
-                let prefix = if entry.is_dir {
-                    if entry.is_expanded { "▼ " } else { "▶ " }
-                } else {
-                    "  "
-                };
-                
-                let indent = "  ".repeat(entry.level);
-                let name = if entry.is_dir {
-                    format!("{}/ ", entry.name)
-                } else {
-                    entry.name.clone()
-                };
-                
-                // Format the line with proper indentation
-                let line = format!("{}{}{}", indent, prefix, name);
-                
-                // Truncate if too long
-                let display_line = if line.len() > tree_width - 1 {
-                    format!("{}…", &line[0..tree_width - 2])
-                } else {
-                    line
-                };
-                
-                execute!(
-                    io::stdout(),
-                    cursor::MoveTo(0, idx as u16)
-                )?;
-                
-                // Highlight current selection
-                if idx == tree.cursor {
-                    execute!(
-                        io::stdout(),
-                        SetBackgroundColor(Color::DarkBlue),
-                        SetForegroundColor(Color::White)
-                    )?;
-                } else if entry.is_dir {
-                    execute!(
-                        io::stdout(),
-                        SetForegroundColor(Color::Blue)
-                    )?;
-                }
-                
-                print!("{:width$}", display_line, width = tree_width);
-                execute!(io::stdout(), ResetColor)?;
-            }
-        }
+
+    fn open_shell(&mut self, is_horizontal: bool) -> Result<()> {
+        let shell_buffer = Buffer::from_shell(is_horizontal);
+        
+        // Add the new shell buffer
+        self.buffers.push(shell_buffer);
+        
+        // Make the new shell the active buffer
+        self.active_buffer = self.buffers.len() - 1;
+        
+        // Switch to shell mode
+        self.previous_mode = self.mode;
+        self.mode = Mode::Shell;
+        
+        info!("Opened {} shell", if is_horizontal { "horizontal" } else { "vertical" });
         
         Ok(())
     }
     
-    fn draw_window_borders(&self, window: &Window, adjusted_x: usize, is_active: bool) -> Result<()> {
-        let border_color = if is_active { Color::Green } else { Color::Grey };
-        
-        // Draw horizontal borders
-        for x in 0..window.width {
-            // Top border
-            execute!(
-                io::stdout(),
-                cursor::MoveTo((adjusted_x + x) as u16, window.y as u16),
-                SetForegroundColor(border_color)
-            )?;
-            print!("─");
-            
-            // Bottom border
-            execute!(
-                io::stdout(),
-                cursor::MoveTo((adjusted_x + x) as u16, (window.y + window.height - 1) as u16)
-            )?;
-            print!("─");
+    fn close_current_buffer(&mut self) -> Result<()> {
+        if self.buffers.len() <= 1 {
+            info!("Cannot close the last buffer");
+            return Ok(());
         }
         
-        // Draw vertical borders
-        for y in 0..window.height {
-            // Left border
-            execute!(
-                io::stdout(),
-                cursor::MoveTo(adjusted_x as u16, (window.y + y) as u16),
-                SetForegroundColor(border_color)
-            )?;
-            print!("│");
-            
-            // Right border
-            execute!(
-                io::stdout(),
-                cursor::MoveTo((adjusted_x + window.width - 1) as u16, (window.y + y) as u16)
-            )?;
-            print!("│");
-        }
+        // Remove the current buffer
+        self.remember_cursor_position(self.active_buffer);
+        self.buffers.remove(self.active_buffer);
         
-        // Draw corners
-        execute!(io::stdout(), cursor::MoveTo(adjusted_x as u16, window.y as u16))?;
-        print!("┌");
-        execute!(io::stdout(), cursor::MoveTo((adjusted_x + window.width - 1) as u16, window.y as u16))?;
-        print!("┐");
-        execute!(io::stdout(), cursor::MoveTo(adjusted_x as u16, (window.y + window.height - 1) as u16))?;
-        print!("└");
-        execute!(io::stdout(), cursor::MoveTo((adjusted_x + window.width - 1) as u16, (window.y + window.height - 1) as u16))?;
-        print!("┘");
+        // Adjust the active buffer index if needed
+        if self.active_buffer >= self.buffers.len() {
+            self.active_buffer = self.buffers.len() - 1;
+        }
         
-        execute!(io::stdout(), ResetColor)?;
+        info!("Closed buffer, now at buffer {}", self.active_buffer + 1);
         
         Ok(())
     }
     
-    fn draw_window_content(&self, window: &Window, adjusted_x: usize) -> Result<()> {
-        let effective_width = if self.windows.len() > 1 { window.width - 2 } else { window.width };
-        let effective_height = if self.windows.len() > 1 { window.height - 2 } else { window.height };
+    fn load_config(&mut self) -> Result<()> {
+        let config_file = self.config_path.join("config.lua");
         
-        // Adjust starting position if window has borders
-        let content_x = if self.windows.len() > 1 { adjusted_x + 1 } else { adjusted_x };
-        let content_y = if self.windows.len() > 1 { window.y + 1 } else { window.y };
+        // Register API functions
+        self.register_api()?;
         
-        // Get the active buffer
-        if self.buffers.is_empty() || self.active_buffer >= self.buffers.len() {
-            return Ok(());
+        // config.toml goes first so config.lua can override it
+        let toml_file = self.config_path.join("config.toml");
+        if toml_file.exists() {
+            info!("Loading config from: {:?}", toml_file);
+            TomlConfig::from_file(&toml_file)?.apply_to_editor(self)?;
         }
-        
-        let buffer = &self.buffers[self.active_buffer];
-        
-        if buffer.is_shell {
-            // Draw shell content
-            if let Some(shell) = &buffer.shell { // No mut needed for drawing
-                let mut line_counter = 0;
-                // Display previous lines from the shell's actual output
-                // Consider viewport scrolling for shell.lines here
-                let start_line_idx = shell.lines.len().saturating_sub(effective_height.saturating_sub(1)); // Show last lines, leave one for input
 
-                for (idx, line_content) in shell.lines.iter().skip(start_line_idx).enumerate() {
-                    if line_counter >= effective_height -1 { // Reserve last line for input
-                        break;
-                    }
-                    execute!(io::stdout(), cursor::MoveTo(content_x as u16, (content_y + line_counter) as u16))?;
-                    let display_line = if line_content.len() > effective_width {
-                        &line_content[0..effective_width]
-                    } else {
-                        line_content
-                    };
-                    print!("{}", display_line);
-                    line_counter += 1;
-                }
-                
-                // Draw RVim's current input line with a visual prompt
-                if line_counter < effective_height {
-                    execute!(io::stdout(), cursor::MoveTo(content_x as u16, (content_y + line_counter) as u16))?;
-                    let prompt_and_input = format!("$ {}", shell.input_line); // RVim's visual prompt
-                    let display_prompt_input = if prompt_and_input.len() > effective_width {
-                        &prompt_and_input[0..effective_width]
-                    } else {
-                        &prompt_and_input
-                    };
-                    print!("{}", display_prompt_input);
-                }
-            }
+        // Load config file if exists
+        if config_file.exists() {
+            info!("Loading config from: {:?}", config_file);
+            self.exec_lua_file(&config_file)?;
         } else {
-            let total_lines = buffer.document.lines.len();
-            let gutter_width = total_lines.to_string().len().max(2);
-            for y in 0..effective_height {
-                let file_row = y + buffer.offset_y;
-                execute!(io::stdout(),
-                    cursor::MoveTo(content_x as u16, (content_y + y) as u16)
-                )?;
-                // line-number gutter
-                if file_row < total_lines {
-                    print!("{:>width$} ", file_row + 1, width = gutter_width);
-                } else {
-                    print!("{:width$} ", "", width = gutter_width);
-                }
-                // then the text
-                if file_row >= buffer.document.lines.len() {
-                    print!(" ");
-                } else {
-                    let line = &buffer.document.lines[file_row];
-                    let start = buffer.offset_x.min(line.len());
-                    let end = (buffer.offset_x + effective_width - gutter_width - 1).min(line.len());
-                    if start < end {
-                        print!("{}", &line[start..end]);
-                    }
-                }
-            }
+            info!("No config file found at: {:?}", config_file);
+        }
+
+        // Project settings go last so they override the global ones
+        if let Some(root) = self.project_root.clone() {
+            self.exec_lua_file(&root.join(PROJECT_CONFIG))?;
         }
         
         Ok(())
     }
-    
-    fn draw_status_line(&self) -> Result<()> {
-        // File and position info
-        let (line, col, total) = if let Some(buf) = self.buffers.get(self.active_buffer) {
-            let l = buf.cursor_y + 1;
-            let c = buf.cursor_x + 1;
-            let t = buf.document.lines.len();
-            (l, c, t)
-        } else { (0,0,0) };
-        let pct = if total > 0 { (line as f32 / total as f32) * 100.0 } else { 0.0 };
-        let pos_info = format!("L{}/C{}  {}/{} ({:.0}%)", line, col, line, total, pct);
 
-        let status = match self.mode {
-            Mode::Normal => "NORMAL",
-            Mode::Insert => "INSERT",
-            Mode::Visual => "VISUAL",
-            Mode::Command => "COMMAND",
-            Mode::FileTree => "FILETREE",
-            Mode::Shell => "SHELL",
-            Mode::Help => "HELP",
-            Mode::TabSwitcher => "TAB",
-        };
-        let fname = self.buffers
-            .get(self.active_buffer)
-            .and_then(|b| b.filename.clone())
-            .unwrap_or("[No Name]".into());
-        let modified = if let Some(b) = self.buffers.get(self.active_buffer) {
-            if b.document.modified { "[+]" } else { "" }
-        } else { "" };
-        let status_line = format!(" {} | {}{} | {} ",
-            status, fname, modified, pos_info);
+    /// Set an option as `:set` would, for config.toml
+    pub(crate) fn set_option(&mut self, name: &str, value: OptionValue) -> Result<()> {
+        self.api_state.lock().unwrap().options.set(name, value)
+    }
 
-        execute!(
-            io::stdout(),
-            cursor::MoveTo(0, self.terminal_height as u16 - 2),
-            SetForegroundColor(Color::Black),
-            SetBackgroundColor(Color::White)
-        )?;
-        let pad = self.terminal_width.saturating_sub(status_line.len());
-        print!("{}{}", status_line, " ".repeat(pad));
-        execute!(io::stdout(), ResetColor)?;
+    /// Recolor syntax highlighting with the colorscheme `name`
+    pub(crate) fn set_colorscheme(&mut self, name: &str) -> Result<()> {
+        self.theme = HighlightTheme::from_name(name)
+            .ok_or_else(|| Error::Message(format!("Unknown colorscheme: {}", name)))?;
         Ok(())
     }
-    
-    fn draw_message_line(&self) -> Result<()> {
-        execute!(
-            io::stdout(),
-            cursor::MoveTo(0, self.terminal_height as u16 - 1),
-            terminal::Clear(ClearType::CurrentLine)
-        )?;
-        
-        if let Mode::Command = self.mode {
-            print!(":{}", self.command_line);
-        } else if self.mode == Mode::Help {
-            let help_msg = "Press any key to close help.";
-            let padding = self.terminal_width.saturating_sub(help_msg.len()) / 2;
-            print!("{}{}", " ".repeat(padding), help_msg);
-        }
-        
+
+    /// Map `<space>{key}` to an action string as `rvim.map('n', keys, action)` would
+    pub(crate) fn map_leader(&mut self, keys: &str, action: &str) -> Result<()> {
+        let key = api::parse_leader_key(keys)
+            .ok_or_else(|| Error::ConfigError(format!("Only <space> mappings are supported: {}", keys)))?;
+        let mut mappings = self.leader_mappings.lock().unwrap();
+        mappings.retain(|mapping| mapping.key != key);
+        mappings.push(LeaderMapping { key, action: api::map_action(action), desc: action.to_string() });
         Ok(())
     }
-    
-    fn process_insert_mode(&mut self, key: KeyEvent) -> Result<()> {
-        if self.buffers.is_empty() || self.active_buffer >= self.buffers.len() {
-            return Ok(());
+
+    fn exec_lua_file(&self, path: &Path) -> Result<()> {
+        let content = fs::read_to_string(path)?;
+        // Name the chunk after the file so errors and :profile output point at it
+        self.lua.load(&content)
+            .set_name(format!("@{}", path.display()))
+            .exec()?;
+        Ok(())
+    }
+
+    /// Look for a `.rvim.lua` in `start` and the directories above it and run it
+    /// after config.lua when `exrc` is set. Its directory becomes the project root
+    pub fn load_project_config(&mut self, start: &Path) {
+        let Some(root) = start.ancestors().find(|dir| dir.join(PROJECT_CONFIG).is_file()) else {
+            return;
+        };
+        // Any checkout or download may carry one, so it only runs once the user opts in
+        if !self.options().exrc {
+            info!("Not loading {:?}: exrc is off", root.join(PROJECT_CONFIG));
+            return;
         }
-        
-        let buffer = &mut self.buffers[self.active_buffer];
-        
-        if buffer.is_shell {
-            // If the buffer is a shell, switch to shell mode
-            self.mode = Mode::Shell;
-            return self.process_shell_mode(key);
+        info!("Loading project config from: {:?}", root.join(PROJECT_CONFIG));
+        self.project_root = Some(root.to_path_buf());
+        self.api_state.lock().unwrap().project_root = self.project_root.clone();
+        // A broken project file should not keep the editor from starting
+        if let Err(e) = self.exec_lua_file(&root.join(PROJECT_CONFIG)) {
+            self.notify_level(NotificationLevel::Error, format!("Error in {}: {}", PROJECT_CONFIG, e), NOTIFICATION_TIME);
         }
-        
-        match key.code {
-            KeyCode::Esc => self.mode = Mode::Normal,
-            KeyCode::Char(c) => {
-                buffer.document.insert_char(buffer.cursor_y, buffer.cursor_x, c);
-                buffer.cursor_x += 1;
-            },
-            KeyCode::Backspace => {
-                if buffer.cursor_x > 0 {
-                    buffer.cursor_x -= 1;
-                    buffer.document.delete_char(buffer.cursor_y, buffer.cursor_x);
-                }
-            },
-            KeyCode::Enter => {
-                // Handle enter in insert mode (split line)
-                let new_line = String::new();
-                buffer.document.lines.insert(buffer.cursor_y + 1, new_line);
-                buffer.cursor_y += 1;
-                buffer.cursor_x = 0;
+    }
+    
+    /// Start watching the config directory; without it config.lua is only read at startup
+    fn watch_config(&mut self) {
+        if !self.config_path.is_dir() {
+            return;
+        }
+        let (tx, rx) = mpsc::channel();
+        let watcher = notify::recommended_watcher(move |res| {
+            let _ = tx.send(res);
+        }).and_then(|mut watcher| {
+            watcher.watch(&self.config_path, notify::RecursiveMode::NonRecursive)?;
+            Ok(watcher)
+        });
+        match watcher {
+            Ok(watcher) => {
+                self.config_watcher = Some(watcher);
+                self.config_events = Some(rx);
             },
-            _ => {}
+            Err(e) => error!("Cannot watch {:?} for config changes: {}", self.config_path, e),
         }
-        
-        Ok(())
     }
-    
-    fn process_shell_mode(&mut self, key: KeyEvent) -> Result<()> {
-        if self.buffers.is_empty() || self.active_buffer >= self.buffers.len() {
-            return Ok(());
+
+    /// Whether config.lua or config.toml changed on disk since the last call
+    fn config_changed(&self) -> bool {
+        let Some(rx) = &self.config_events else {
+            return false;
+        };
+        let mut changed = false;
+        // An editor saving the file may send several events; they all end in one reload
+        while let Ok(event) = rx.try_recv() {
+            changed |= event.is_ok_and(|event| {
+                matches!(event.kind, notify::EventKind::Create(_) | notify::EventKind::Modify(_))
+                    && event.paths.iter().any(|path| path.file_name().is_some_and(|name| name == "config.lua" || name == "config.toml"))
+            });
+        }
+        changed
+    }
+
+    /// Run the config again on a fresh `rvim` table, so mappings and settings from
+    /// the old version do not pile up. Errors are shown instead of ending the editor
+    fn reload_config(&mut self) {
+        // rvim.plugins and rvim.on are registered once by set_plugin_manager, so carry them over
+        let kept: Vec<_> = ["plugins", "on"].into_iter()
+            .map(|name| {
+                let key = self.lua.globals().get::<_, mlua::Table>("rvim")
+                    .and_then(|rvim| rvim.get::<_, mlua::Value>(name))
+                    .and_then(|value| self.lua.create_registry_value(value));
+                (name, key)
+            })
+            .collect();
+        let result = self.load_config();
+        // register_api made the new table even if the config itself failed
+        for (name, key) in kept {
+            let Ok(key) = key else {
+                continue;
+            };
+            let restored = self.lua.globals().get::<_, mlua::Table>("rvim")
+                .and_then(|rvim| rvim.set(name, self.lua.registry_value::<mlua::Value>(&key)?))
+                .and_then(|()| self.lua.remove_registry_value(key));
+            if let Err(e) = restored {
+                error!("Cannot restore rvim.{} after reloading config: {}", name, e);
+            }
         }
+        match result {
+            Ok(()) => self.notify("Reloaded config", NOTIFICATION_TIME),
+            Err(e) => self.notify_level(NotificationLevel::Error, format!("Error in config: {}", e), NOTIFICATION_TIME),
+        }
+    }
+
+    fn register_api(&mut self) -> Result<()> {
+        // Create a global 'rvim' table
+        let rvim_table = self.lua.create_table()?;
         
-        let buffer = &mut self.buffers[self.active_buffer];
+        // Add the map function (similar to Neovim's vim.keymap.set). Normal-mode
+        // Space mappings are supported; `opts.desc` is shown by the which-key popup
+        self.leader_mappings.lock().unwrap().clear();
+        let leader_mappings = self.leader_mappings.clone();
+        let map_fn = self.lua.create_function(move |lua, (mode, keys, action, opts): (String, String, mlua::Value, Option<mlua::Table>)| {
+            let Some(key) = (mode == "n").then(|| api::parse_leader_key(&keys)).flatten() else {
+                info!("Mapping in mode '{}': {} is not supported", mode, keys);
+                return Ok(());
+            };
+            let (action, default_desc) = match action {
+                mlua::Value::String(action) => {
+                    let action = action.to_str()?;
+                    (api::map_action(action), action.to_string())
+                },
+                mlua::Value::Function(function) => (MapAction::Lua(lua.create_registry_value(function)?), "Lua function".to_string()),
+                other => return Err(mlua::Error::RuntimeError(
+                    format!("rvim.map: expected string or function, got {}", other.type_name())
+                )),
+            };
+            let desc = opts.map(|opts| opts.get::<_, Option<String>>("desc")).transpose()?.flatten().unwrap_or(default_desc);
+            let mut mappings = leader_mappings.lock().unwrap();
+            mappings.retain(|mapping| mapping.key != key);
+            mappings.push(LeaderMapping { key, action, desc });
+            Ok(())
+        })?;
         
-        if !buffer.is_shell || buffer.shell.is_none() {
-            self.mode = self.previous_mode; 
-            return Ok(());
-        }
+        rvim_table.set("map", map_fn)?;
         
-        let shell = buffer.shell.as_mut().unwrap();
-        shell.poll_output(); // Poll output before processing key
+        // Create an API module
+        let api_table = self.lua.create_table()?;
+        
+        // Example API function
+        let get_version_fn = self.lua.create_function(|_, ()| {
+            Ok(format!("rvim {}", VERSION))
+        })?;
+        
+        api_table.set("get_version", get_version_fn)?;
 
-        if !shell.running {
-            self.mode = self.previous_mode;
-            info!("Shell is not running. Switching to mode: {:?}", self.mode);
-            // Optionally close the buffer or mark as non-interactive
-            // For now, if the user tries to type into a dead shell, they'll just switch out.
-            return Ok(());
-        }
+        let get_clipboard_fn = self.lua.create_function(|_, ()| {
+            clipboard::get_clipboard().map_err(mlua::Error::external)
+        })?;
+        api_table.set("get_clipboard", get_clipboard_fn)?;
+
+        let set_clipboard_fn = self.lua.create_function(|_, text: String| {
+            clipboard::set_clipboard(&text).map_err(mlua::Error::external)
+        })?;
+        api_table.set("set_clipboard", set_clipboard_fn)?;
+
+        let state = self.api_state.clone();
+        let project_root_fn = self.lua.create_function(move |_, ()| {
+            Ok(state.lock().unwrap().project_root.as_ref().map(|root| root.display().to_string()))
+        })?;
+        api_table.set("project_root", project_root_fn)?;
+
+        let state = self.api_state.clone();
+        let get_git_branch_fn = self.lua.create_function(move |_, ()| {
+            Ok(state.lock().unwrap().git_branch.clone())
+        })?;
+        api_table.set("get_git_branch", get_git_branch_fn)?;
+        rvim_table.set("api", api_table)?;
+
+        // rvim.set_statusline(fn) draws the status line with fn(context); nil
+        // goes back to the built-in one
+        *self.statusline_fn.lock().unwrap() = None;
+        let statusline_fn = self.statusline_fn.clone();
+        let set_statusline_fn = self.lua.create_function(move |lua, function: Option<mlua::Function>| {
+            let key = function.map(|function| lua.create_registry_value(function)).transpose()?;
+            *statusline_fn.lock().unwrap() = key;
+            Ok(())
+        })?;
+        rvim_table.set("set_statusline", set_statusline_fn)?;
+
+        // JSON module backed by serde_json
+        let json_table = self.lua.create_table()?;
+
+        let encode_fn = self.lua.create_function(|lua, value: mlua::Value| {
+            let json: serde_json::Value = lua.from_value(value)?;
+            serde_json::to_string(&json)
+                .map_err(|e| mlua::Error::RuntimeError(format!("JSON encode error: {}", e)))
+        })?;
+        json_table.set("encode", encode_fn)?;
+
+        let encode_pretty_fn = self.lua.create_function(|lua, value: mlua::Value| {
+            let json: serde_json::Value = lua.from_value(value)?;
+            serde_json::to_string_pretty(&json)
+                .map_err(|e| mlua::Error::RuntimeError(format!("JSON encode error: {}", e)))
+        })?;
+        json_table.set("encode_pretty", encode_pretty_fn)?;
+
+        let decode_fn = self.lua.create_function(|lua, text: String| {
+            let json: serde_json::Value = serde_json::from_str(&text)
+                .map_err(|e| mlua::Error::RuntimeError(format!("JSON decode error: {}", e)))?;
+            lua.to_value(&json)
+        })?;
+        json_table.set("decode", decode_fn)?;
+
+        // Sentinel returned by decode for JSON null, usable when encoding too
+        json_table.set("null", self.lua.null())?;
+        rvim_table.set("json", json_table)?;
         
-        match key.code {
-            KeyCode::Esc => {
-                self.mode = self.previous_mode; // Revert to previous mode
-            },
-            KeyCode::Enter => {
-                shell.execute_command()?; // This now sends to the child shell
-                // poll_output will be called at the start of the next refresh_screen or keypress
-            },
-            KeyCode::Char(c) => {
-                shell.input_char(c);
-            },
-            KeyCode::Backspace => {
-                shell.input_backspace();
-            },
-            KeyCode::Delete => {
-                shell.input_delete();
-            },
-            KeyCode::Left => {
-                shell.move_cursor_left();
-            },
-            KeyCode::Right => {
-                shell.move_cursor_right();
-            },
-            KeyCode::Up => {
-                shell.history_up();
-            },
-            KeyCode::Down => {
-                shell.history_down();
-            },
-            _ => {}
-        }
+        // Run external commands: rvim.system(cmd, opts) -> { stdout, stderr, code }
+        let system_fn = self.lua.create_function(|lua, (cmd, opts): (mlua::Value, Option<mlua::Table>)| {
+            let command = SystemCommand::from_lua(cmd, opts)?;
+            let output = command.run().map_err(mlua::Error::external)?;
+            output.to_lua_table(lua)
+        })?;
+        rvim_table.set("system", system_fn)?;
+
+        // rvim.system_async(cmd, opts, callback) runs in the background and
+        // calls back with the same result table once the command exits
+        let jobs = self.jobs.handle();
+        let system_async_fn = self.lua.create_function(move |lua, (cmd, opts, callback): (mlua::Value, Option<mlua::Table>, mlua::Function)| {
+            let command = SystemCommand::from_lua(cmd, opts)?;
+            let key = lua.create_registry_value(callback)?;
+            Ok(jobs.spawn(command, key))
+        })?;
+        rvim_table.set("system_async", system_async_fn)?;
+
+        // Filetype settings: rvim.filetype.set(name, { shiftwidth = 2, ... })
+        let filetype_table = self.lua.create_table()?;
+        let state = self.api_state.clone();
+        let filetype_set_fn = self.lua.create_function(move |_, (name, settings): (String, mlua::Table)| {
+            let width = |key: &str| -> mlua::Result<Option<usize>> {
+                match settings.get::<_, Option<usize>>(key)? {
+                    Some(0) => Err(mlua::Error::RuntimeError(format!("rvim.filetype.set: {} must be positive", key))),
+                    width => Ok(width),
+                }
+            };
+            let (tabstop, shiftwidth) = (width("tabstop")?, width("shiftwidth")?);
+            let expandtab = settings.get::<_, Option<bool>>("expandtab")?;
+            let commentstring = settings.get::<_, Option<String>>("commentstring")?;
+            // Calling set again for a filetype only changes the fields given
+            let mut state = state.lock().unwrap();
+            let config = state.filetypes.entry(name).or_default();
+            config.tabstop = tabstop.or(config.tabstop);
+            config.shiftwidth = shiftwidth.or(config.shiftwidth);
+            config.expandtab = expandtab.or(config.expandtab);
+            config.commentstring = commentstring.or(config.commentstring.take());
+            Ok(())
+        })?;
+        filetype_table.set("set", filetype_set_fn)?;
+        rvim_table.set("filetype", filetype_table)?;
+
+        // rvim.autocmd(event, pattern, callback); a reload registers them again
+        self.autocommands.lock().unwrap().retain(|autocommand| autocommand.plugin);
+        let autocommands = self.autocommands.clone();
+        let autocmd_fn = self.lua.create_function(move |lua, (event, pattern, callback): (String, String, mlua::Function)| {
+            if !autocmd::EVENTS.contains(&event.as_str()) {
+                return Err(mlua::Error::RuntimeError(format!("rvim.autocmd: unknown event {}", event)));
+            }
+            let callback = lua.create_registry_value(callback)?;
+            autocommands.lock().unwrap().push(Autocommand { event, pattern, callback, plugin: false });
+            Ok(())
+        })?;
+        rvim_table.set("autocmd", autocmd_fn)?;
+
+        // Options: rvim.set(name, value) and rvim.get(name)
+        let state = self.api_state.clone();
+        let set_fn = self.lua.create_function(move |_, (name, value): (String, mlua::Value)| {
+            let value = match value {
+                mlua::Value::Boolean(b) => OptionValue::Bool(b),
+                mlua::Value::Integer(n) => OptionValue::Int(n),
+                mlua::Value::Number(n) if n.fract() == 0.0 => OptionValue::Int(n as i64),
+                mlua::Value::String(s) => OptionValue::Str(s.to_str()?.to_string()),
+                // A table of pairs such as auto_pairs, { ['('] = ')' }, as `(:)`
+                mlua::Value::Table(table) => {
+                    let mut pairs = Vec::new();
+                    for pair in table.pairs::<String, String>() {
+                        let (key, value) = pair?;
+                        pairs.push(format!("{}:{}", key, value));
+                    }
+                    pairs.sort();
+                    OptionValue::Str(pairs.join(","))
+                },
+                other => return Err(mlua::Error::RuntimeError(
+                    format!("rvim.set: unsupported value type {} for {}", other.type_name(), name)
+                )),
+            };
+            state.lock().unwrap().options.set(&name, value).map_err(mlua::Error::external)
+        })?;
+        rvim_table.set("set", set_fn)?;
+
+        let state = self.api_state.clone();
+        let get_fn = self.lua.create_function(move |lua, name: String| {
+            let value = state.lock().unwrap().options.get(&name).map_err(mlua::Error::external)?;
+            Ok(match value {
+                OptionValue::Bool(b) => mlua::Value::Boolean(b),
+                OptionValue::Int(n) => mlua::Value::Integer(n),
+                OptionValue::Str(s) => mlua::Value::String(lua.create_string(&s)?),
+            })
+        })?;
+        rvim_table.set("get", get_fn)?;
+
+        // Buffer functions; buffer id 0 means the current buffer
+        let buf_table = self.lua.create_table()?;
+
+        // rvim.buf.get_mark(buf_id, name) -> { row, col }, row 1-based and
+        // col 0-based; { 0, 0 } when the mark is not set
+        let state = self.api_state.clone();
+        let get_mark_fn = self.lua.create_function(move |lua, (buf_id, name): (usize, String)| {
+            let name = api::parse_mark_name(&name)?;
+            let state = state.lock().unwrap();
+            let id = state.resolve_buffer(buf_id)?;
+            let (row, col) = match state.get_mark(id, name) {
+                Some((row, col)) => (row + 1, col),
+                None => (0, 0),
+            };
+            lua.create_sequence_from([row, col])
+        })?;
+        buf_table.set("get_mark", get_mark_fn)?;
+
+        let state = self.api_state.clone();
+        let set_mark_fn = self.lua.create_function(move |_, (buf_id, name, row, col): (usize, String, usize, usize)| {
+            let name = api::parse_mark_name(&name)?;
+            if row == 0 {
+                return Err(mlua::Error::RuntimeError("Mark row is 1-based".to_string()));
+            }
+            let mut state = state.lock().unwrap();
+            let id = state.resolve_buffer(buf_id)?;
+            state.set_mark(id, name, (row - 1, col));
+            Ok(())
+        })?;
+        buf_table.set("set_mark", set_mark_fn)?;
+
+        // rvim.buf.del_mark(buf_id, name) -> true if the mark was set
+        let state = self.api_state.clone();
+        let del_mark_fn = self.lua.create_function(move |_, (buf_id, name): (usize, String)| {
+            let name = api::parse_mark_name(&name)?;
+            let mut state = state.lock().unwrap();
+            let id = state.resolve_buffer(buf_id)?;
+            Ok(state.del_mark(id, name))
+        })?;
+        buf_table.set("del_mark", del_mark_fn)?;
+
+        // rvim.buf.is_loaded(buf_id) -> false for tabs whose file has not been read yet
+        let state = self.api_state.clone();
+        let is_loaded_fn = self.lua.create_function(move |_, buf_id: usize| {
+            Ok(state.lock().unwrap().is_loaded(buf_id))
+        })?;
+        buf_table.set("is_loaded", is_loaded_fn)?;
+
+        rvim_table.set("buf", buf_table)?;
+
+        // LSP functions
+        let lsp_table = self.lua.create_table()?;
+
+        let lsp_manager = self.lsp_manager.clone();
+        let add_folder_fn = self.lua.create_function(move |_, path: String| {
+            lsp_manager.lock().unwrap()
+                .add_workspace_folder(PathBuf::from(path))
+                .map_err(mlua::Error::external)
+        })?;
+        lsp_table.set("add_workspace_folder", add_folder_fn)?;
+
+        let lsp_manager = self.lsp_manager.clone();
+        let remove_folder_fn = self.lua.create_function(move |_, path: String| {
+            lsp_manager.lock().unwrap()
+                .remove_workspace_folder(&PathBuf::from(path))
+                .map_err(mlua::Error::external)
+        })?;
+        lsp_table.set("remove_workspace_folder", remove_folder_fn)?;
+
+        let lsp_manager = self.lsp_manager.clone();
+        let list_folders_fn = self.lua.create_function(move |_, ()| {
+            Ok(lsp_manager.lock().unwrap()
+                .workspace_folders()
+                .iter()
+                .map(|folder| folder.display().to_string())
+                .collect::<Vec<_>>())
+        })?;
+        lsp_table.set("list_workspace_folders", list_folders_fn)?;
+
+        rvim_table.set("lsp", lsp_table)?;
+
+        // Set the global rvim table
+        self.lua.globals().set("rvim", rvim_table)?;
         
         Ok(())
     }
     
-    fn process_keypress(&mut self) -> Result<()> {
-        match event::read()? {
-            Event::Key(key_event) => {
-                match self.mode {
-                    Mode::Normal => {
-                        if self.waiting_for_second_key {
-                            self.process_second_key(key_event)?;
-                        } else {
-                            self.process_normal_mode(key_event)?;
-                        }
-                    },
-                    Mode::Insert => self.process_insert_mode(key_event)?,
-                    Mode::Visual => self.process_visual_mode(key_event)?,
-                    Mode::Command => self.process_command_mode(key_event)?,
-                    Mode::FileTree => self.process_file_tree_mode(key_event)?,
-                    Mode::Shell => self.process_shell_mode(key_event)?,
-                    Mode::Help => self.process_help_mode(key_event)?,
-                    Mode::TabSwitcher => self.process_tab_switcher_mode(key_event)?,
-                }
-            },
-            Event::Mouse(mouse_event) => {
-                self.process_mouse_event(mouse_event)?;
-            },
-            _ => {}
-        }
+    pub fn set_plugin_manager(&mut self, plugin_manager: crate::cli::plugin::PluginManager) -> Result<()> {
+        // Register the plugin manager's Lua functions
+        let plugin_table = self.lua.create_table()?;
+        // Plugin code may call back into these functions, so borrows are checked
+        let plugin_manager = Rc::new(RefCell::new(plugin_manager));
+
+        // Add function to get installed plugins
+        let manager = plugin_manager.clone();
+        let get_plugins_fn = self.lua.create_function(move |_, ()| {
+            Ok(manager.try_borrow().map_err(mlua::Error::external)?.plugin_names())
+        })?;
+        plugin_table.set("get_plugins", get_plugins_fn)?;
+
+        // Set while plugin code runs, so rvim.on can tell plugin hooks from the config's
+        let loading_plugin = Rc::new(Cell::new(false));
+
+        // Clone a plugin and load it right away
+        let manager = plugin_manager.clone();
+        let loading = loading_plugin.clone();
+        let install_plugin_fn = self.lua.create_function(move |lua, plugin_url: String| {
+            let name = manager.try_borrow_mut().map_err(mlua::Error::external)?
+                .install_plugin(&plugin_url).map_err(mlua::Error::external)?;
+            loading.set(true);
+            let loaded = manager.try_borrow().map_err(mlua::Error::external)
+                .and_then(|manager| manager.load_plugin_named(lua, &name).map_err(mlua::Error::external));
+            loading.set(false);
+            loaded?;
+            Ok(name)
+        })?;
+        plugin_table.set("install", install_plugin_fn)?;
+
+        // Pull a plugin's latest commits; returns whether anything changed
+        let manager = plugin_manager.clone();
+        let update_plugin_fn = self.lua.create_function(move |_, name: String| {
+            manager.try_borrow_mut().map_err(mlua::Error::external)?
+                .update_plugin(&name).map_err(mlua::Error::external)
+        })?;
+        plugin_table.set("update", update_plugin_fn)?;
+
+        let manager = plugin_manager;
+        let uninstall_plugin_fn = self.lua.create_function(move |_, name: String| {
+            manager.try_borrow_mut().map_err(mlua::Error::external)?
+                .uninstall_plugin(&name).map_err(mlua::Error::external)
+        })?;
+        plugin_table.set("uninstall", uninstall_plugin_fn)?;
+
+        // Set the plugins table in the global rvim table
+        let globals = self.lua.globals();
+        let rvim_table: mlua::Table = globals.get("rvim")?;
+        rvim_table.set("plugins", plugin_table)?;
+
+        // rvim.on(event, callback): a hook run for every buffer. Only hooks added while a
+        // plugin loads outlive a config reload; config.lua registers its own again
+        let autocommands = self.autocommands.clone();
+        let on_fn = self.lua.create_function(move |lua, (event, callback): (String, mlua::Function)| {
+            if !autocmd::EVENTS.contains(&event.as_str()) {
+                return Err(mlua::Error::RuntimeError(format!("rvim.on: unknown event {}", event)));
+            }
+            let callback = lua.create_registry_value(callback)?;
+            autocommands.lock().unwrap().push(Autocommand { event, pattern: "*".to_string(), callback, plugin: loading_plugin.get() });
+            Ok(())
+        })?;
+        rvim_table.set("on", on_fn)?;
         
+        info!("Plugin manager initialized");
         Ok(())
     }
     
-    fn process_normal_mode(&mut self, key: KeyEvent) -> Result<()> {
-        match key.code {
-            KeyCode::Char(' ') => {
-                self.waiting_for_second_key = true;
-                Ok(())
-            },
-            KeyCode::Char('q') => {
-                self.quit = true;
-                Ok(())
-            },
-            KeyCode::Char(':') => {
-                self.mode = Mode::Command;
-                self.command_line.clear();
-                Ok(())
-            },
-            KeyCode::Char('i') => {
-                self.mode = Mode::Insert;
-                Ok(())
-            },
-            KeyCode::Char('v') => {
-                self.mode = Mode::Visual;
-                Ok(())
-            },
-            KeyCode::Char('h') => self.move_cursor_left(),
-            KeyCode::Char('j') => self.move_cursor_down(),
-            KeyCode::Char('k') => self.move_cursor_up(),
-            KeyCode::Char('l') => self.move_cursor_right(),
-            KeyCode::Char('w') => self.move_to_next_word_start(),
-            KeyCode::Char('e') => self.move_to_next_word_end(),
-            KeyCode::Char('b') => self.move_to_prev_word_start(),
-            KeyCode::Char('d') => {
-                self.delete_current_line()?;
-                Ok(())
-            },
-            KeyCode::Char('x') => {
-                self.delete_char_under_cursor()?;
-                Ok(())
-            },
-            _ => Ok(())
+    /// Publish the open buffers to the Lua API state
+    fn sync_api_state(&mut self) {
+        self.shift_marks();
+        let mut state = self.api_state.lock().unwrap();
+        state.current_buffer = self.buffers.get(self.active_buffer).map_or(0, |buffer| buffer.id);
+        state.unloaded_buffers = self.tab_manager.unloaded_buffer_ids();
+        state.retain_buffers(self.buffers.iter().map(|buffer| buffer.id).collect());
+        self.show_relative_numbers = state.options.relativenumber;
+        self.screen_row_motions = state.options.wrap_navigate == "screen";
+        self.cursor_word_highlight = state.options.cursor_word_highlight;
+        self.show_scrollbar = state.options.scrollbar;
+        self.show_minimap = state.options.minimap;
+        self.minimap_width = state.options.minimap_width;
+        self.auto_pairs = state.options.auto_pairs.iter().copied().collect();
+        for buffer in &mut self.buffers {
+            buffer.document.set_undolevels(state.options.undolevels);
+            buffer.wrap = state.options.wrap && !buffer.is_shell;
+            buffer.showbreak.clone_from(&state.options.showbreak);
+            buffer.show_tabs = state.options.show_tabs;
+            buffer.number = state.options.number || state.options.relativenumber;
+            // Filetype settings win over the global options
+            let filetype = buffer.filetype.as_ref().and_then(|filetype| state.filetypes.get(filetype));
+            let document = &mut buffer.document;
+            document.shiftwidth = filetype.and_then(|config| config.shiftwidth).unwrap_or(state.options.shiftwidth);
+            document.tabstop = filetype.and_then(|config| config.tabstop).unwrap_or(state.options.tabstop);
+            document.expandtab = filetype.and_then(|config| config.expandtab).or(state.options.expandtab);
         }
-    }
-    
-    fn process_visual_mode(&mut self, key: KeyEvent) -> Result<()> {
-        match key.code {
-            KeyCode::Esc => {
-                self.mode = Mode::Normal;
-                Ok(())
-            },
-            KeyCode::Char('h') => {
-                self.move_cursor_left()?;
-                Ok(())
-            },
-            KeyCode::Char('j') => {
-                self.move_cursor_down()?;
-                Ok(())
-            },
-            KeyCode::Char('k') => {
-                self.move_cursor_up()?;
-                Ok(())
-            },
-            KeyCode::Char('l') => {
-                self.move_cursor_right()?;
-                Ok(())
-            },
-            _ => Ok(())
+        // Kept in the options so it outlives the tree, which is rebuilt when its root changes
+        if let Some(tree) = &mut self.file_tree
+            && tree.show_hidden != state.options.file_tree_show_hidden
+        {
+            tree.show_hidden = state.options.file_tree_show_hidden;
+            if let Err(e) = tree.refresh() {
+                error!("Failed to refresh file tree: {}", e);
+            }
+        }
+        if self.file_tree_width != state.options.file_tree_width {
+            self.file_tree_width = state.options.file_tree_width;
+            if let Some(tree) = &mut self.file_tree {
+                tree.fit_width(self.file_tree_width, self.terminal_width);
+            }
+            drop(state);
+            self.fit_windows();
         }
     }
-    
-    fn process_command_mode(&mut self, key: KeyEvent) -> Result<()> {
-        match key.code {
-            KeyCode::Esc => self.mode = Mode::Normal,
-            KeyCode::Enter => {
-                self.execute_command()?;
-                // execute_command might change the mode (e.g. to Help)
-                // so only switch to Normal if not already changed.
-                if self.mode == Mode::Command {
-                    self.mode = Mode::Normal;
+
+    /// File tree rooted at `root`, sized for the terminal
+    fn new_file_tree(&self, root: &Path) -> Result<FileTree> {
+        let mut tree = FileTree::new(root)?;
+        tree.fit_width(self.file_tree_width, self.terminal_width);
+        Ok(tree)
+    }
+
+    /// Record startup milestones and report them once the first screen is drawn
+    pub fn set_startup_times(&mut self, startup_times: StartupTimes) {
+        self.startup_times = Some(startup_times);
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        if self.mode == Mode::Normal && self.buffers.iter().all(|buffer| buffer.filename.is_none() && !buffer.is_shell) {
+            self.mode = Mode::Welcome;
+        }
+        // BufEnter for the files opened on the command line
+        self.sync_api_state();
+        self.fire_state_autocmds();
+        self.refresh_screen()?;
+
+        if let Some(mut startup_times) = self.startup_times.take() {
+            startup_times.mark("first buffer drawn");
+            startup_times.write()?;
+            self.message = Some(startup_times.summary());
+            self.refresh_screen()?;
+        }
+        
+        while !self.quit {
+            self.sync_api_state();
+            let mut needs_redraw = false;
+            if event::poll(Duration::from_millis(50))? {
+                let mode = self.mode;
+                let started = Instant::now();
+                if let Err(e) = self.process_keypress() {
+                    error!("{}", e);
+                    self.notify_level(NotificationLevel::Error, e.to_string(), NOTIFICATION_TIME);
                 }
-            },
-            KeyCode::Backspace => {
-                self.command_line.pop();
-            },
-            KeyCode::Char(c) => {
-                self.command_line.push(c);
-            },
-            _ => {}
+                if let Some(profiler) = &self.profiler {
+                    profiler.record_rust(&format!("process_keypress ({:?})", mode), started.elapsed());
+                }
+                needs_redraw = true;
+            }
+            if self.resumed.swap(false, Ordering::Relaxed) {
+                self.resume_terminal()?;
+                needs_redraw = true;
+            }
+            if self.poll_background()? {
+                needs_redraw = true;
+            }
+            if self.pending_prefix == Some(' ')
+                && !self.show_which_key
+                && self.which_key_since.is_some_and(|since| since.elapsed() >= WHICH_KEY_DELAY)
+            {
+                self.refresh_leader_bindings();
+                self.show_which_key = true;
+                needs_redraw = true;
+            }
+            if self.pending_prefix == Some(' ')
+                && LEADER_BINDINGS.iter().any(|(keys, _)| *keys == self.leader_keys)
+                && self.which_key_since.is_some_and(|since| since.elapsed() >= LEADER_TIMEOUT)
+            {
+                self.pending_prefix = None;
+                self.show_which_key = false;
+                let keys = std::mem::take(&mut self.leader_keys);
+                if let Err(e) = self.run_leader_keys(&keys) {
+                    self.notify_level(NotificationLevel::Error, e.to_string(), NOTIFICATION_TIME);
+                }
+                needs_redraw = true;
+            }
+            if self.completion_due.is_some_and(|since| since.elapsed() >= COMPLETION_DELAY) {
+                self.completion_due = None;
+                if self.mode == Mode::Insert {
+                    self.request_completion();
+                }
+            }
+            if self.update_document_highlight() {
+                needs_redraw = true;
+            }
+            if self.poll_debug_session() {
+                needs_redraw = true;
+            }
+            if self.fire_state_autocmds() {
+                // Callbacks may have changed options
+                self.sync_api_state();
+                needs_redraw = true;
+            }
+            if needs_redraw && !self.quit {
+                let started = Instant::now();
+                self.refresh_screen()?;
+                if let Some(profiler) = &self.profiler {
+                    profiler.record_rust("refresh_screen", started.elapsed());
+                }
+            }
         }
+
+        if let Some(profiler) = self.profiler.take() {
+            profiler.stop(&self.lua)?;
+        }
+
+        // Remember the layout so `:source` can bring it back next time
+        if let Err(e) = self.save_session(&Session::default_path()) {
+            error!("Failed to save session: {}", e);
+        }
+        for idx in 0..self.buffers.len() {
+            self.remember_cursor_position(idx);
+        }
+        if let Err(e) = positions::write(&positions::cursor_positions_path(), &self.cursor_positions) {
+            error!("Failed to save cursor positions: {}", e);
+        }
+        
+        // Cleanup terminal on exit
+        self.restore_cursor_shape()?;
+        execute!(
+            io::stdout(),
+            LeaveAlternateScreen,
+            event::DisableMouseCapture,  // Disable mouse capture when exiting
+            cursor::Show
+        )?;
+        terminal::disable_raw_mode()?;
         
         Ok(())
     }
     
-    fn process_file_tree_mode(&mut self, key: KeyEvent) -> Result<()> {
-        if let Some(tree) = &mut self.file_tree {
-            match key.code {
-                KeyCode::Esc | KeyCode::Char('q') => { // Added 'q' to close file tree
-                    tree.toggle_visible();
-                    self.mode = self.previous_mode;
-                },
-                KeyCode::Char('j') => {
-                    tree.move_cursor_down();
-                },
-                KeyCode::Char('k') => {
-                    tree.move_cursor_up();
-                },
-                KeyCode::Enter | KeyCode::Char('l') => {
-                    // Store values for later use to avoid borrow conflicts
-                    let opt_path_result = if let Some(path) = tree.get_selected_path() {
-                        if path.is_dir() {
-                            tree.toggle_expand()?;
-                            None
-                        } else {
-                            Some(path)
-                        }
-                    } else {
-                        None
-                    };
-                    
-                    // Now handle file opening if needed
-                    if let Some(path) = opt_path_result {
-                        // Open the selected file
-                        match Buffer::from_file(path.to_str().unwrap()) {
-                            Ok(buffer) => {
-                                if !self.buffers.is_empty() {
-                                    self.buffers[self.active_buffer] = buffer;
-                                } else {
-                                    self.buffers.push(buffer);
-                                    self.active_buffer = 0;
-                                }
-                                tree.toggle_visible();
-                                self.mode = self.previous_mode;
-                            },
-                            Err(e) => {
-                                return Err(e);
-                            }
-                        }
-                    }
-                },
-                KeyCode::Char('h') => {
-                    // First get the required information
-                    let (is_dir, is_expanded, path_clone) = if let Some(path) = tree.get_selected_path() {
-                        let is_expanded = tree.is_directory_expanded(path.clone());
-                        (path.is_dir(), is_expanded, path)
-                    } else {
-                        (false, false, PathBuf::new())
-                    };
-                    
-                    // Then perform the actions
-                    if is_dir && is_expanded {
-                        // If directory is expanded, collapse it
-                        tree.toggle_expand()?;
-                    } else {
-                        // Otherwise move to parent directory if possible
-                        tree.move_to_parent()?;
-                    }
-                },
-                _ => {}
+    /// Fire BufLeave/BufEnter when the active buffer changed and InsertEnter,
+    /// InsertLeave and ModeChanged when the mode did. Returns whether any changed
+    fn fire_state_autocmds(&mut self) -> bool {
+        let mut fired = false;
+        let current = self.buffers.get(self.active_buffer).map(|buffer| (buffer.id, buffer.filename.clone()));
+        if current.as_ref().map(|(id, _)| id) != self.entered_buffer.as_ref().map(|(id, _)| id) {
+            if let Some((id, filename)) = self.entered_buffer.take() {
+                if let Some(idx) = self.buffers.iter().position(|buffer| buffer.id == id) {
+                    self.remember_cursor_position(idx);
+                }
+                self.fire_autocmd("BufLeave", filename, &[]);
+            }
+            // Before BufEnter, so that its callbacks see the new buffer's branch
+            self.refresh_git_branch();
+            if let Some((_, filename)) = &current {
+                self.fire_autocmd("BufEnter", filename.clone(), &[]);
             }
+            self.entered_buffer = current;
+            fired = true;
         }
-        
-        Ok(())
+        let (old, new) = (self.autocmd_mode, self.mode);
+        if old != new {
+            self.autocmd_mode = new;
+            let filename = self.entered_buffer.as_ref().and_then(|(_, filename)| filename.clone());
+            if old == Mode::Insert {
+                self.fire_autocmd("InsertLeave", filename.clone(), &[]);
+            }
+            if new == Mode::Insert {
+                self.fire_autocmd("InsertEnter", filename.clone(), &[]);
+            }
+            self.fire_autocmd("ModeChanged", filename, &[("old_mode", mode_name(old).to_lowercase())]);
+            fired = true;
+        }
+        fired
     }
-    
-    fn process_second_key(&mut self, key: KeyEvent) -> Result<()> {
-        self.waiting_for_second_key = false;
-        
-        match key.code {
-            KeyCode::Char('e') => {
-                if let Some(tree) = &mut self.file_tree {
-                    tree.toggle_visible();
-                    if tree.visible {
-                        self.previous_mode = self.mode;
-                        self.mode = Mode::FileTree;
-                    } else {
-                        self.mode = self.previous_mode;
-                    }
+
+    /// Call the `rvim.autocmd` callbacks for `event` whose pattern matches
+    /// `filename`. They get a table with the event, file, buffer id, mode and `extra`
+    fn fire_autocmd(&mut self, event: &str, filename: Option<String>, extra: &[(&str, String)]) {
+        let mut failures = Vec::new();
+        {
+            // Collect first: a callback may register more autocommands
+            let callbacks: Vec<mlua::Function> = self.autocommands.lock().unwrap().iter()
+                .filter(|autocommand| autocommand.matches(event, filename.as_deref()))
+                .filter_map(|autocommand| self.lua.registry_value(&autocommand.callback).ok())
+                .collect();
+            if callbacks.is_empty() {
+                return;
+            }
+            let buffer = self.buffers.get(self.active_buffer);
+            let buf = buffer.map_or(0, |buffer| buffer.id);
+            let context = self.lua.create_table().and_then(|table| {
+                table.set("event", event)?;
+                table.set("file", filename.as_deref().unwrap_or(""))?;
+                table.set("filename", filename.as_deref().unwrap_or(""))?;
+                table.set("buf", buf)?;
+                table.set("bufnr", buf)?;
+                table.set("filetype", buffer.and_then(|buffer| buffer.filetype.as_deref()))?;
+                table.set("mode", mode_name(self.mode).to_lowercase())?;
+                for (key, value) in extra {
+                    table.set(*key, value.as_str())?;
                 }
-                Ok(())
-            },
-            KeyCode::Char('v') => {
-                self.open_shell(false)
-            },
-            KeyCode::Char('h') => {
-                self.open_shell(true)
-            },
-            KeyCode::Char('w') => {
-                self.cycle_window()
+                Ok(table)
+            });
+            for callback in callbacks {
+                if let Err(e) = context.clone().and_then(|context| callback.call::<_, ()>(context)) {
+                    failures.push(format!("{} autocommand failed: {}", event, e));
+                }
+            }
+        }
+        for failure in failures {
+            self.notify_level(NotificationLevel::Error, failure, NOTIFICATION_TIME);
+        }
+    }
+
+    /// Handle work that completes outside of key handling. Returns true when the
+    /// screen needs to be redrawn.
+    fn poll_background(&mut self) -> Result<bool> {
+        let mut changed = false;
+
+        // Pick up new shell output while the shell is focused
+        if self.mode == Mode::Shell
+            && let Some(shell) = self.buffers.get_mut(self.active_buffer).and_then(|b| b.shell.as_mut())
+        {
+            let before = shell.lines.len();
+            shell.poll_output();
+            changed |= shell.lines.len() != before || !shell.running;
+        }
+
+        // Deliver results of rvim.system_async jobs to their Lua callbacks
+        let mut failures = Vec::new();
+        for (key, result) in self.jobs.drain() {
+            changed = true;
+            let callback: mlua::Function = self.lua.registry_value(&key)?;
+            self.lua.remove_registry_value(key)?;
+            let outcome = match result {
+                Ok(output) => output.to_lua_table(&self.lua)
+                    .and_then(|table| callback.call::<_, ()>(table)),
+                Err(e) => {
+                    let table = self.lua.create_table()?;
+                    table.set("stdout", "")?;
+                    table.set("stderr", e.to_string())?;
+                    table.set("code", -1)?;
+                    callback.call::<_, ()>(table)
+                }
+            };
+            if let Err(e) = outcome {
+                failures.push(format!("rvim.system_async callback failed: {}", e));
+            }
+        }
+        for failure in failures {
+            self.notify_level(NotificationLevel::Error, failure, NOTIFICATION_TIME);
+        }
+
+        self.sync_lsp_documents(false);
+
+        // Messages from language servers
+        let messages = self.lsp_manager.lock().unwrap().poll_responses();
+        for message in messages {
+            changed |= self.handle_lsp_message(message);
+        }
+
+        if self.config_changed() {
+            self.reload_config();
+            changed = true;
+        }
+
+        // Take timed out notifications off the message line
+        let now = Instant::now();
+        let before = self.notifications.len();
+        self.notifications.retain(|notification| !notification.expired(now));
+        changed |= self.notifications.len() != before;
+
+        Ok(changed)
+    }
+
+    /// Show `msg` on the message line for `duration`, colored by its level: Vim
+    /// style `E123:` messages are errors and `W123:` messages warnings
+    pub fn notify(&mut self, msg: impl Into<String>, duration: Duration) {
+        let text = msg.into();
+        let level = NotificationLevel::of(&text);
+        self.notify_level(level, text, duration);
+    }
+
+    fn notify_level(&mut self, level: NotificationLevel, msg: impl Into<String>, duration: Duration) {
+        let text = msg.into();
+        info!("Notification ({:?}): {}", level, text);
+        if self.notifications.len() >= MAX_NOTIFICATIONS {
+            self.notifications.pop_front();
+        }
+        self.notifications.push_back(Notification { text, level, shown_at: Instant::now(), duration });
+    }
+
+    /// React to a message from a language server; returns true when the screen changed
+    fn handle_lsp_message(&mut self, message: LspMessage) -> bool {
+        match message {
+            LspMessage::Notification { method, params, .. } if method == "textDocument/publishDiagnostics" => {
+                let Some(path) = params["uri"].as_str().and_then(uri_to_path) else {
+                    return false;
+                };
+                let diagnostics: Vec<Diagnostic> = params["diagnostics"].as_array()
+                    .map(|items| items.iter().filter_map(Diagnostic::from_json).collect())
+                    .unwrap_or_default();
+                if diagnostics.is_empty() {
+                    self.diagnostics.remove(&path);
+                } else {
+                    self.diagnostics.insert(path, diagnostics);
+                }
+                true
             },
-            KeyCode::Char('q') => {
-                self.close_window()
+            LspMessage::Notification { language_id, method, params } if method == "window/showMessage" => {
+                let Some(text) = params["message"].as_str() else {
+                    return false;
+                };
+                // MessageType: 1 error, 2 warning, 3 info, 4 log
+                let level = match params["type"].as_u64() {
+                    Some(1) => NotificationLevel::Error,
+                    Some(2) => NotificationLevel::Warning,
+                    _ => NotificationLevel::Info,
+                };
+                self.notify_level(level, format!("{}: {}", language_id, text), NOTIFICATION_TIME);
+                true
             },
-            KeyCode::Char('x') => {
-                self.close_current_buffer()
+            LspMessage::Notification { method, params, .. } if method == "workspace/applyEdit" => {
+                self.apply_workspace_edit(&params["edit"]);
+                true
             },
-            KeyCode::Tab => {
-                self.tab_manager.switch_to_next_tab()
+            LspMessage::Notification { language_id, method, .. } => {
+                info!("Unhandled {} notification from {} server", method, language_id);
+                false
             },
-            KeyCode::BackTab => {
-                self.tab_manager.switch_to_prev_tab()
+            LspMessage::Response { language_id, id, result } => {
+                let request = self.lsp_requests.remove(&(language_id.clone(), id));
+                let result = match result {
+                    Ok(result) => result,
+                    Err(e) => {
+                        self.notify_level(NotificationLevel::Error, format!("{} server: {}", language_id, e), NOTIFICATION_TIME);
+                        return true;
+                    },
+                };
+                match request {
+                    Some(LspRequest::Hover) => self.show_hover(&result),
+                    Some(LspRequest::Definition) => match Location::from_response(&result).into_iter().next() {
+                        Some(location) => {
+                            self.push_jump();
+                            self.go_to_location(&location.path, location.line, Some(location.character));
+                        },
+                        None => self.message = Some("No definition found".to_string()),
+                    },
+                    Some(LspRequest::CodeActions) => {
+                        let actions = CodeAction::from_response(&result);
+                        if actions.is_empty() {
+                            self.message = Some("No code actions available".to_string());
+                        } else {
+                            self.code_action_input.clear();
+                            self.code_actions_popup = Some(actions);
+                        }
+                    },
+                    Some(LspRequest::ExecuteCommand) => return false,
+                    Some(LspRequest::Rename) if result.is_null() => self.message = Some("Nothing to rename".to_string()),
+                    Some(LspRequest::Rename) => self.apply_workspace_edit(&result),
+                    Some(LspRequest::Completion) => self.show_completions(&result),
+                    Some(LspRequest::SignatureHelp) => self.show_signature_help(&result),
+                    Some(LspRequest::DocumentHighlight(position)) => {
+                        // Stale once the cursor has moved on
+                        if self.highlight_position != Some(position) {
+                            return false;
+                        }
+                        self.set_document_highlights(&DocumentHighlight::from_response(&result));
+                    },
+                    Some(LspRequest::DocumentSymbols) => {
+                        let symbols = DocumentSymbol::from_response(&result);
+                        if symbols.is_empty() {
+                            self.message = Some("No symbols found".to_string());
+                        } else {
+                            self.open_symbol_picker(symbols);
+                        }
+                    },
+                    Some(LspRequest::WorkspaceSymbols(query)) => {
+                        // Stale once the picker was closed or its query changed
+                        if self.workspace_symbol_query.as_ref() != Some(&query) {
+                            return false;
+                        }
+                        self.show_workspace_symbols(&query, WorkspaceSymbol::from_response(&result));
+                    },
+                    None => {
+                        info!("Unhandled response {} from {} server", id, language_id);
+                        return false;
+                    },
+                }
+                true
             },
-            _ => Ok(()),
         }
     }
 
-    fn execute_command(&mut self) -> Result<()> {
-        let cmd = self.command_line.trim();
-        match cmd {
-            "q" | "quit" => {
-                self.quit = true;
-                Ok(())
-            },
-            "w" | "write" => {
-                if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
-                    buffer.save()?;
-                }
-                Ok(())
-            },
-            "wq" => {
-                if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
-                    buffer.save()?;
-                }
-                self.quit = true;
-                Ok(())
-            },
-            "help" => {
-                self.previous_mode = self.mode;
-                self.mode = Mode::Help;
-                Ok(())
-            },
-            _ => Ok(()) // Unknown command just returns Ok
-        }
+    fn draw_tabs(&self) -> Result<()> {
+        let start_x = 0;
+        let start_y = 0;
+        let tab_list = self.tab_manager.tab_list();
+        let mut current_x = start_x;
+
+        execute!(io::stdout(), cursor::MoveTo(0, 0))?;
+
+        // Draw tab bar background
+        execute!(
+            io::stdout(),
+            SetBackgroundColor(Color::DarkGrey),
+            SetForegroundColor(Color::White)
+        )?;
+
+        for x in 0..self.terminal_width {
+            execute!(io::stdout(), cursor::MoveTo(x as u16, start_y as u16))?;
+            print!(" ");
+        }
+
+        // Draw each tab
+        for (idx, (id, name)) in tab_list.iter().enumerate() {
+            let is_current = idx == self.tab_manager.current_tab();
+            let tab_style = if is_current {
+                execute!(
+                    io::stdout(),
+                    SetBackgroundColor(Color::Blue),
+                    SetForegroundColor(Color::White)
+                )
+            } else {
+                execute!(
+                    io::stdout(),
+                    SetBackgroundColor(Color::DarkGrey),
+                    SetForegroundColor(Color::White)
+                )
+            }?;
+
+            let tab_text = format!(" {}:{} ", idx + 1, name);
+            execute!(io::stdout(), cursor::MoveTo(current_x as u16, start_y as u16))?;
+            print!("{}", tab_text);
+            
+            current_x += tab_text.len();
+        }
+
+        execute!(io::stdout(), ResetColor)?;
+        Ok(())
+    }
+
+    fn refresh_screen(&mut self) -> Result<()> {
+        if self.show_git_blame {
+            self.refresh_blame();
+        }
+        if let Some(buffer) = self.buffers.get_mut(self.active_buffer)
+            && let Err(e) = buffer.refresh_syntax_tree()
+        {
+            info!("Failed to parse buffer: {}", e);
+        }
+
+        // Poll shell output if in shell mode and buffer exists
+        if self.mode == Mode::Shell {
+            if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
+                if let Some(shell) = buffer.shell.as_mut() {
+                    shell.poll_output();
+                    if !shell.running { // If shell terminated, switch mode
+                        self.mode = self.previous_mode;
+                        // Consider closing the shell buffer or marking it as non-interactive
+                        // For now, just switch mode. The buffer remains.
+                        info!("Shell terminated, switching to mode: {:?}", self.mode);
+                    }
+                }
+            }
+        }
+
+        execute!(
+            io::stdout(),
+            terminal::Clear(ClearType::All),
+            cursor::MoveTo(0, 0)
+        )?;
+
+        // Draw tabs at the top
+        self.draw_tabs()?;
+
+        // Adjust other content to start below tabs
+        let content_offset = 1; // Height of tab bar
+
+        if self.mode == Mode::Help {
+            self.draw_help_screen()?;
+        } else {
+            // Adjust filetree and windows to start below tabs
+            let filetree_offset = if let Some(tree) = &self.file_tree {
+                if tree.visible {
+                    self.draw_file_tree()?;
+                    tree.width + 1
+                } else {
+                    0
+                }
+            } else {
+                0
+            };
+            
+            // Draw each window
+            for (idx, window) in self.windows.iter().enumerate() {
+                // Adjust for file tree
+                let adjusted_x = window.x + filetree_offset;
+                
+                // Draw window borders if there are multiple windows
+                if self.windows.len() > 1 {
+                    self.draw_window_borders(window, adjusted_x, idx == self.active_window)?;
+                }
+                
+                // Draw window content
+                self.draw_window_content(window, idx == self.active_window, adjusted_x)?;
+            }
+
+            if let Some(lines) = &self.hover_popup {
+                let (cursor_x, cursor_y) = self.cursor_screen_position().unwrap_or((0, 1));
+                let visible = self.hover_visible_lines(lines);
+                self.draw_popup(&visible, cursor_x, cursor_y, self.options().hoverwidth)?;
+            }
+
+            if let Some(actions) = &self.code_actions_popup {
+                let (cursor_x, cursor_y) = self.cursor_screen_position().unwrap_or((0, 1));
+                self.draw_popup(&self.code_action_lines(actions), cursor_x, cursor_y, self.terminal_width)?;
+            }
+
+            if let Some(state) = &self.completion_popup
+                && let Some((cursor_x, cursor_y)) = self.cursor_screen_position()
+            {
+                let selected = SpanStyle { fg: Some(Color::Yellow), bold: true, ..Default::default() };
+                let dim = SpanStyle { fg: Some(Color::Grey), ..Default::default() };
+                let lines: Vec<StyledLine> = state.items.iter().enumerate()
+                    .map(|(idx, item)| {
+                        let mut line = vec![StyledSpan {
+                            text: item.label.clone(),
+                            style: if idx == state.selected { selected } else { SpanStyle::default() },
+                        }];
+                        let about = item.detail.as_deref().unwrap_or(item.kind_name());
+                        if !about.is_empty() {
+                            line.push(StyledSpan { text: format!("  {}", about), style: dim });
+                        }
+                        line
+                    })
+                    .collect();
+                // Line the words up with the start of the word being completed
+                let typed = self.buffers.get(self.active_buffer)
+                    .and_then(|buffer| buffer.document.lines.get(state.row)?.get(state.start..buffer.cursor_x))
+                    .map_or(0, |typed| typed.chars().count());
+                self.draw_popup(&lines, cursor_x.saturating_sub(typed + 1), cursor_y, self.terminal_width / 2)?;
+            }
+
+            if let Some(shell) = self.buffers.get(self.active_buffer).and_then(|buffer| buffer.shell.as_ref())
+                && !shell.completion_candidates.is_empty()
+                && let Some((cursor_x, cursor_y)) = self.shell_cursor_screen_position()
+            {
+                let selected = SpanStyle { fg: Some(Color::Yellow), bold: true, ..Default::default() };
+                let lines: Vec<StyledLine> = shell.completion_candidates.iter().enumerate()
+                    .map(|(idx, candidate)| vec![StyledSpan {
+                        text: candidate.clone(),
+                        style: if idx == shell.completion_selected { selected } else { SpanStyle::default() },
+                    }])
+                    .collect();
+                // Line the candidates up with the start of the word being completed
+                let typed = shell.input_line[..shell.cursor_pos].rsplit(char::is_whitespace).next().map_or(0, |word| word.chars().count());
+                self.draw_popup(&lines, cursor_x.saturating_sub(typed + 1), cursor_y, self.terminal_width / 2)?;
+            }
+
+            if let Some(state) = &self.signature_help
+                && let Some((cursor_x, cursor_y)) = self.cursor_screen_position()
+            {
+                self.draw_popup_at(&signature_help_lines(state), cursor_x, cursor_y, self.terminal_width, true)?;
+            }
+
+            // Diagnostic tooltip while the mouse rests on the gutter of a line with diagnostics
+            if let Some(lines) = self.diagnostic_tooltip() {
+                self.draw_popup(&lines, self.mouse_x as usize + 1, self.mouse_y as usize, self.terminal_width)?;
+            }
+
+            if let Some(picker) = &self.picker {
+                picker.draw_picker(self.terminal_width, self.terminal_height)?;
+            }
+
+            if self.debug_session.is_some() {
+                self.draw_debug_panel()?;
+            }
+
+            if self.mode == Mode::Quickfix {
+                self.draw_quickfix()?;
+            }
+
+            if self.show_which_key && self.pending_prefix == Some(' ') {
+                self.draw_which_key_popup()?;
+            }
+        }
+        
+        self.draw_status_line()?;
+        self.draw_message_line()?;
+        
+        // Position cursor based on mode
+        match self.mode {
+            Mode::Help | Mode::Welcome => {
+                // Hide cursor or move to a non-obtrusive place for help screen
+                execute!(io::stdout(), cursor::Hide)?;
+            }
+            Mode::Search { .. } => {
+                let prompt_x = self.search_query.chars().count() + 1;
+                execute!(io::stdout(), cursor::MoveTo(prompt_x as u16, self.terminal_height as u16 - 1))?;
+            }
+            Mode::Picker => {
+                if let Some((x, y)) = self.picker.as_ref()
+                    .and_then(|picker| picker.prompt_cursor(self.terminal_width, self.terminal_height))
+                {
+                    execute!(io::stdout(), cursor::Show, cursor::MoveTo(x, y))?;
+                }
+            }
+            Mode::Quickfix => {
+                let (top, first, _) = self.quickfix_layout();
+                let row = top + 1 + self.quickfix_idx - first;
+                execute!(io::stdout(), cursor::MoveTo(0, row as u16))?;
+            },
+            Mode::FileTree => {
+                execute!(io::stdout(), cursor::Show)?;
+                if let Some(tree) = &self.file_tree {
+                    let tree_cursor_y = tree.cursor.min(self.terminal_height - 3);
+                    execute!(io::stdout(), cursor::MoveTo(2, tree_cursor_y as u16))?;
+                }
+            },
+            Mode::Shell => {
+                if let Some(shell) = self.buffers.get_mut(self.active_buffer).and_then(|buffer| buffer.shell.as_mut()) {
+                    shell.poll_output();
+                    if !shell.running {
+                        self.mode = self.previous_mode;
+                    }
+                }
+                if self.mode == Mode::Shell && let Some((x, y)) = self.shell_cursor_screen_position() {
+                    execute!(io::stdout(), cursor::MoveTo(x as u16, y as u16))?;
+                }
+            },
+            _ => {
+                if let Some((screen_x, screen_y)) = self.cursor_screen_position() {
+                    execute!(io::stdout(), cursor::MoveTo(screen_x as u16, screen_y as u16))?;
+                }
+            }
+        }
+        self.set_cursor_shape(self.mode_cursor_shape())?;
+        
+        io::stdout().flush()?;
+        
+        Ok(())
+    }
+
+    /// Screen cell of the cursor on the input line of the active shell buffer
+    fn shell_cursor_screen_position(&self) -> Option<(usize, usize)> {
+        let shell = self.buffers.get(self.active_buffer)?.shell.as_ref()?;
+        let window = self.windows.get(self.active_window)?;
+        let effective_height = if self.windows.len() > 1 { window.height.saturating_sub(2) } else { window.height };
+        let content_y_start = if self.windows.len() > 1 { window.y + 1 } else { 0 };
+        let filetree_width = if let Some(tree) = &self.file_tree {
+            if tree.visible { tree.width + 1 } else { 0 }
+        } else { 0 };
+        let content_x_start = if self.windows.len() > 1 { window.x + filetree_width + 1 } else { filetree_width };
+        // The input line comes right after the output lines that fit
+        let displayed_output_lines_count = shell.lines.len().min(effective_height.saturating_sub(1));
+        // +2 for the "$ " prefix
+        Some((content_x_start + shell.cursor_pos + 2, content_y_start + displayed_output_lines_count))
+    }
+
+    /// Screen cell of the cursor in the active buffer
+    fn cursor_screen_position(&self) -> Option<(usize, usize)> {
+        let buffer = self.buffers.get(self.active_buffer)?;
+        let window = self.windows.get(self.active_window)?;
+
+        let filetree_width = if let Some(tree) = &self.file_tree {
+            if tree.visible { tree.width + 1 } else { 0 }
+        } else { 0 };
+
+        let adjusted_x = window.x + filetree_width;
+        let content_x = if self.windows.len() > 1 { adjusted_x + 1 } else { adjusted_x };
+        let content_y = if self.windows.len() > 1 { window.y + 1 } else { window.y };
+
+        let gutter = if buffer.is_shell { 0 } else { self.blame_column_width() + gutter_width(buffer) };
+        let (row, column) = buffer.logical_to_screen_pos(buffer.cursor_y, buffer.cursor_column(), self.window_text_width());
+        Some((content_x + gutter + column, content_y + row))
+    }
+
+    /// File row whose gutter is at screen cell (x, y), if any
+    fn gutter_row_at(&self, x: usize, y: usize) -> Option<usize> {
+        let buffer = self.buffers.get(self.active_buffer).filter(|buffer| !buffer.is_shell)?;
+        let filetree_width = match &self.file_tree {
+            Some(tree) if tree.visible => tree.width + 1,
+            _ => 0,
+        };
+        let total_lines = buffer.document.lines.len();
+        let gutter_width = gutter_width(buffer);
+
+        for window in &self.windows {
+            let bordered = self.windows.len() > 1;
+            let content_x = window.x + filetree_width + usize::from(bordered) + self.blame_column_width();
+            let content_y = window.y + usize::from(bordered);
+            let height = if bordered { window.height.saturating_sub(2) } else { window.height };
+            if (content_x..content_x + gutter_width).contains(&x) && (content_y..content_y + height).contains(&y) {
+                let (row, _) = buffer.line_at_screen_row(y - content_y, self.window_text_width());
+                return (row < total_lines).then_some(row);
+            }
+        }
+        None
+    }
+
+    fn buffer_diagnostics(&self, buffer: &Buffer) -> Option<&Vec<Diagnostic>> {
+        let filename = buffer.filename.as_ref()?;
+        self.diagnostics.get(Path::new(filename))
+            .or_else(|| fs::canonicalize(filename).ok().and_then(|path| self.diagnostics.get(&path)))
+    }
+
+    /// Most severe diagnostic on the cursor line
+    fn cursor_diagnostic(&self) -> Option<&Diagnostic> {
+        let buffer = self.buffers.get(self.active_buffer)?;
+        self.buffer_diagnostics(buffer)?.iter()
+            .filter(|d| d.range_start_line == buffer.cursor_y)
+            .min_by_key(|d| d.severity)
+    }
+
+    /// `:lnext` / `:lprevious`: move to the next or previous diagnostic in the buffer
+    fn jump_to_diagnostic(&mut self, forward: bool) {
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return;
+        };
+        let Some(diagnostics) = self.buffer_diagnostics(buffer).filter(|d| !d.is_empty()) else {
+            self.message = Some("E42: No Errors".to_string());
+            return;
+        };
+        let mut positions: Vec<(usize, usize, String)> = diagnostics.iter()
+            .map(|d| {
+                let col = buffer.document.lines.get(d.range_start_line)
+                    .map_or(0, |line| utf16_to_byte(line, d.range_start_col));
+                (d.range_start_line, col, d.message.lines().next().unwrap_or("").to_string())
+            })
+            .collect();
+        positions.sort_by_key(|&(row, col, _)| (row, col));
+        let cursor = (buffer.cursor_y, buffer.cursor_x);
+        let found = if forward {
+            positions.iter().position(|&(row, col, _)| (row, col) > cursor)
+        } else {
+            positions.iter().rposition(|&(row, col, _)| (row, col) < cursor)
+        };
+        let Some(idx) = found else {
+            self.message = Some("E553: No more items".to_string());
+            return;
+        };
+        let total = positions.len();
+        let (row, col, text) = positions.swap_remove(idx);
+        self.push_jump();
+        self.move_cursor_to_change((row, col));
+        self.scroll_cursor_into_view();
+        self.message = Some(format!("({} of {}): {}", idx + 1, total, text));
+    }
+
+    /// `:grep {pattern}` / `:rg {pattern}`: fill the quickfix list with the matches
+    /// in the working directory and show it
+    fn run_grep(&mut self, program: &str, pattern: &str) {
+        if pattern.is_empty() {
+            self.notify("E471: Argument required", NOTIFICATION_TIME);
+            return;
+        }
+        match quickfix::grep(program, pattern) {
+            Ok(entries) if entries.is_empty() => self.message = Some(format!("E480: No match: {}", pattern)),
+            Ok(entries) => {
+                let count = entries.len();
+                self.quickfix_list = entries;
+                // As in Vim, go to the first match so :cnext moves on to the second
+                self.go_to_quickfix_entry(0);
+                self.message = Some(format!("{} matches for {}", count, pattern));
+                self.mode = Mode::Quickfix;
+            },
+            Err(e) => self.notify(e.to_string(), NOTIFICATION_TIME),
+        }
+    }
+
+    /// `:cnext` / `:cprevious`: go to the quickfix entry after or before the selected one
+    fn quickfix_step(&mut self, forward: bool) {
+        if self.quickfix_list.is_empty() {
+            self.message = Some("E42: No Errors".to_string());
+            return;
+        }
+        let idx = if forward { self.quickfix_idx + 1 } else { self.quickfix_idx.wrapping_sub(1) };
+        if idx >= self.quickfix_list.len() {
+            self.message = Some("E553: No more items".to_string());
+            return;
+        }
+        self.go_to_quickfix_entry(idx);
+    }
+
+    /// Open the file of quickfix entry `idx` and put the cursor on the match
+    fn go_to_quickfix_entry(&mut self, idx: usize) {
+        let Some((path, row, col, text)) = self.quickfix_list.get(idx).cloned() else {
+            return;
+        };
+        self.quickfix_idx = idx;
+        self.push_jump();
+        self.go_to_location(&path, row, None);
+        if !self.current_position().is_some_and(|(current, _, _)| same_path(&current, &path)) {
+            return;
+        }
+        self.move_cursor_to_change((row, col));
+        self.clamp_cursor();
+        self.scroll_cursor_into_view();
+        self.message = Some(format!("({} of {}): {}", idx + 1, self.quickfix_list.len(), text.trim()));
+    }
+
+    /// Screen row of the quickfix title, the first entry shown and how many are shown
+    fn quickfix_layout(&self) -> (usize, usize, usize) {
+        let rows = self.quickfix_list.len().min(QUICKFIX_HEIGHT);
+        // The list sits right above the status line
+        let top = self.terminal_height.saturating_sub(rows + 3);
+        let first = (self.quickfix_idx + 1).saturating_sub(rows);
+        (top, first, rows)
+    }
+
+    /// The quickfix list, across the bottom of the screen with the selected entry highlighted
+    fn draw_quickfix(&self) -> Result<()> {
+        let (top, first, rows) = self.quickfix_layout();
+        let width = self.terminal_width;
+        let title = format!(" Quickfix ({} of {})", self.quickfix_idx + 1, self.quickfix_list.len());
+        execute!(io::stdout(), cursor::MoveTo(0, top as u16),
+            SetForegroundColor(Color::Black), SetBackgroundColor(Color::Grey))?;
+        print!("{:<width$}", title, width = width);
+        execute!(io::stdout(), ResetColor)?;
+
+        for (i, (path, row, col, text)) in self.quickfix_list.iter().enumerate().skip(first).take(rows) {
+            let entry = format!("{}:{}:{}: {}", path.display(), row + 1, col + 1, text.trim());
+            let entry: String = entry.chars().take(width).collect();
+            execute!(io::stdout(), cursor::MoveTo(0, (top + 1 + i - first) as u16))?;
+            if i == self.quickfix_idx {
+                execute!(io::stdout(), SetBackgroundColor(Color::DarkGrey))?;
+            }
+            print!("{:<width$}", entry, width = width);
+            execute!(io::stdout(), ResetColor)?;
+        }
+        Ok(())
+    }
+
+    fn process_quickfix_mode(&mut self, key: KeyEvent) -> Result<()> {
+        match key.code {
+            KeyCode::Esc | KeyCode::Char('q') => self.mode = Mode::Normal,
+            KeyCode::Char('j') | KeyCode::Down => {
+                self.quickfix_idx = (self.quickfix_idx + 1).min(self.quickfix_list.len().saturating_sub(1));
+            },
+            KeyCode::Char('k') | KeyCode::Up => self.quickfix_idx = self.quickfix_idx.saturating_sub(1),
+            KeyCode::Enter => {
+                self.mode = Mode::Normal;
+                self.go_to_quickfix_entry(self.quickfix_idx);
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// Tooltip lines for the diagnostics on the line under the mouse, when it is over the gutter
+    fn diagnostic_tooltip(&self) -> Option<Vec<StyledLine>> {
+        if !matches!(self.mode, Mode::Normal | Mode::Insert | Mode::Visual | Mode::VisualLine | Mode::VisualBlock) {
+            return None;
+        }
+        let row = self.gutter_row_at(self.mouse_x as usize, self.mouse_y as usize)?;
+        let buffer = self.buffers.get(self.active_buffer)?;
+        let diagnostics = self.buffer_diagnostics(buffer)?;
+
+        let mut lines = Vec::new();
+        for diagnostic in diagnostics.iter().filter(|d| d.range_start_line == row) {
+            let color = match diagnostic.severity {
+                1 => Color::Red,
+                2 => Color::Yellow,
+                3 => Color::Cyan,
+                _ => Color::Green,
+            };
+            let mut header = diagnostic.severity_name().to_string();
+            if let Some(code) = &diagnostic.code {
+                header.push_str(&format!("[{}]", code));
+            }
+            let mut line = vec![StyledSpan {
+                text: header,
+                style: SpanStyle { fg: Some(color), bold: true, ..Default::default() },
+            }];
+            if let Some(source) = &diagnostic.source {
+                line.push(StyledSpan { text: format!(" {}", source), style: SpanStyle::default() });
+            }
+            lines.push(line);
+
+            for message_line in diagnostic.message.lines() {
+                lines.push(vec![StyledSpan { text: message_line.to_string(), style: SpanStyle::default() }]);
+            }
+            for related in &diagnostic.related {
+                lines.push(vec![StyledSpan {
+                    text: format!("  {}", related),
+                    style: SpanStyle { italic: true, ..Default::default() },
+                }]);
+            }
+        }
+        (!lines.is_empty()).then_some(lines)
+    }
+
+    /// Ask the language server for documentation of the word under the cursor (`K`)
+    fn show_lsp_hover(&mut self) -> Result<()> {
+        let word = self.buffers.get(self.active_buffer)
+            .and_then(|buffer| word_at(buffer.document.lines.get(buffer.cursor_y)?, buffer.cursor_x));
+        if word.is_none() {
+            self.message = Some("E349: No identifier under cursor".to_string());
+            return Ok(());
+        }
+        self.send_lsp_request("textDocument/hover", true, LspRequest::Hover)
+    }
+
+    fn go_to_definition(&mut self) -> Result<()> {
+        let word = self.buffers.get(self.active_buffer)
+            .and_then(|buffer| word_at(buffer.document.lines.get(buffer.cursor_y)?, buffer.cursor_x));
+        if word.is_none() {
+            self.message = Some("E349: No identifier under cursor".to_string());
+            return Ok(());
+        }
+        self.send_lsp_request("textDocument/definition", true, LspRequest::Definition)
+    }
+
+    /// Space+a: ask the language server for code actions at the cursor, passing
+    /// along the diagnostics on the cursor line
+    pub fn show_code_actions(&mut self) -> Result<()> {
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return Ok(());
+        };
+        let Some(filename) = buffer.filename.clone() else {
+            self.message = Some("E32: No file name".to_string());
+            return Ok(());
+        };
+        let position = lsp_cursor_position(buffer);
+        let diagnostics: Vec<serde_json::Value> = self.buffer_diagnostics(buffer)
+            .map(|diagnostics| diagnostics.iter()
+                .filter(|d| d.range_start_line == buffer.cursor_y)
+                .map(|d| d.json.clone())
+                .collect())
+            .unwrap_or_default();
+        let path = PathBuf::from(&filename);
+        let params = serde_json::json!({
+            "textDocument": { "uri": path_to_uri(&path) },
+            "range": { "start": position, "end": position },
+            "context": { "diagnostics": diagnostics },
+        });
+        self.send_lsp_params(&path, "textDocument/codeAction", params, LspRequest::CodeActions)
+    }
+
+    /// Space r n: ask on the command line for a new name for the symbol under the cursor
+    pub fn rename_symbol(&mut self) -> Result<()> {
+        let word = self.buffers.get(self.active_buffer)
+            .and_then(|buffer| word_at(buffer.document.lines.get(buffer.cursor_y)?, buffer.cursor_x));
+        let Some(word) = word else {
+            self.message = Some("E349: No identifier under cursor".to_string());
+            return Ok(());
+        };
+        // Start from the current name so small edits are easy
+        self.command_line = word.clone();
+        self.rename_prompt = Some(word);
+        self.mode = Mode::Command;
+        Ok(())
+    }
+
+    /// Send `textDocument/rename` for the symbol under the cursor; the edit in the
+    /// answer is applied with `apply_workspace_edit`
+    fn request_rename(&mut self, new_name: &str) -> Result<()> {
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return Ok(());
+        };
+        let Some(filename) = buffer.filename.clone() else {
+            self.message = Some("E32: No file name".to_string());
+            return Ok(());
+        };
+        let path = PathBuf::from(&filename);
+        let params = serde_json::json!({
+            "textDocument": { "uri": path_to_uri(&path) },
+            "position": lsp_cursor_position(buffer),
+            "newName": new_name,
+        });
+        self.send_lsp_params(&path, "textDocument/rename", params, LspRequest::Rename)
+    }
+
+    /// The numbered list shown while choosing a code action
+    fn code_action_lines(&self, actions: &[CodeAction]) -> Vec<StyledLine> {
+        let dim = SpanStyle { fg: Some(Color::Grey), ..Default::default() };
+        let mut lines = vec![vec![StyledSpan { text: "Code actions".to_string(), style: SpanStyle { bold: true, ..Default::default() } }]];
+        for (idx, action) in actions.iter().enumerate() {
+            let mut line = vec![StyledSpan { text: format!("{:>2}. {}", idx + 1, action.title), style: SpanStyle::default() }];
+            if let Some(kind) = &action.kind {
+                line.push(StyledSpan { text: format!(" ({})", kind), style: dim });
+            }
+            lines.push(line);
+        }
+        lines.push(vec![StyledSpan { text: format!("Number and Enter: {}", self.code_action_input), style: dim }]);
+        lines
+    }
+
+    /// Digits pick a code action, Enter runs it and Esc closes the list
+    fn process_code_actions_key(&mut self, key: KeyEvent) -> Result<()> {
+        let count = self.code_actions_popup.as_ref().map_or(0, Vec::len);
+        match key.code {
+            KeyCode::Char(c) if c.is_ascii_digit() => {
+                let mut input = self.code_action_input.clone();
+                input.push(c);
+                if input.parse::<usize>().is_ok_and(|n| n <= count) {
+                    self.code_action_input = input;
+                }
+            },
+            KeyCode::Backspace => {
+                self.code_action_input.pop();
+            },
+            KeyCode::Esc => self.code_actions_popup = None,
+            KeyCode::Enter => {
+                // Enter alone takes the first action
+                let choice = self.code_action_input.parse::<usize>().unwrap_or(1);
+                let Some(action) = self.code_actions_popup.take()
+                    .and_then(|mut actions| (choice >= 1 && choice <= actions.len()).then(|| actions.swap_remove(choice - 1)))
+                else {
+                    return Ok(());
+                };
+                self.run_code_action(action)?;
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// Apply a code action's edit, then send its command to the language server
+    fn run_code_action(&mut self, action: CodeAction) -> Result<()> {
+        if let Some(edit) = &action.edit {
+            self.apply_workspace_edit(edit);
+        }
+        if let Some(command) = action.command
+            && let Some(path) = self.current_position().map(|(path, _, _)| path)
+        {
+            let params = serde_json::json!({
+                "command": command["command"],
+                "arguments": command.get("arguments").cloned().unwrap_or_else(|| serde_json::json!([])),
+            });
+            self.send_lsp_params(&path, "workspace/executeCommand", params, LspRequest::ExecuteCommand)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the text edits of a `WorkspaceEdit`. Open buffers are edited in place
+    /// (one undo step each); other files are changed on disk
+    fn apply_workspace_edit(&mut self, edit: &serde_json::Value) {
+        let mut changed = 0;
+        for (path, edits) in TextEdit::from_workspace_edit(edit) {
+            let buffer = self.buffers.iter_mut()
+                .find(|buffer| buffer.filename.as_ref().is_some_and(|name| same_path(Path::new(name), &path)));
+            let result = match buffer {
+                Some(buffer) => {
+                    let edits = byte_edits(&buffer.document.lines, &edits);
+                    buffer.document.replace_ranges(&edits);
+                    Ok(())
+                },
+                None => buffer::Document::from_file(&path.to_string_lossy()).and_then(|mut document| {
+                    let edits = byte_edits(&document.lines, &edits);
+                    document.replace_ranges(&edits);
+                    document.save()
+                }),
+            };
+            match result {
+                Ok(()) => changed += 1,
+                Err(e) => self.notify_level(NotificationLevel::Error, format!("Cannot edit {}: {}", path.display(), e), NOTIFICATION_TIME),
+            }
+        }
+        self.clamp_cursor();
+        if changed > 1 {
+            self.message = Some(format!("Changed {} files", changed));
+        }
+    }
+
+    // Current file and cursor, when the buffer has a file
+    fn current_position(&self) -> Option<(PathBuf, usize, usize)> {
+        let buffer = self.buffers.get(self.active_buffer)?;
+        let filename = buffer.filename.as_ref()?;
+        Some((PathBuf::from(filename), buffer.cursor_y, buffer.cursor_x))
+    }
+
+    /// Record the cursor before a jump, dropping any positions ahead of the
+    /// current place in the list
+    fn push_jump(&mut self) {
+        let Some(position) = self.current_position() else {
+            return;
+        };
+        self.jump_list.truncate(self.jump_pos);
+        self.jump_list.push(position);
+        if self.jump_list.len() > MAX_JUMPS {
+            self.jump_list.remove(0);
+        }
+        self.jump_pos = self.jump_list.len();
+    }
+
+    /// Ctrl+O: go to the previous position in the jump list
+    fn jump_back(&mut self) {
+        if self.jump_pos == 0 {
+            return;
+        }
+        // Remember where we came from so Ctrl+I can return to it
+        if self.jump_pos == self.jump_list.len() {
+            self.push_jump();
+            self.jump_pos = self.jump_list.len() - 1;
+        }
+        self.jump_pos -= 1;
+        let (path, row, col) = self.jump_list[self.jump_pos].clone();
+        self.go_to_location(&path, row, None);
+        self.move_cursor_to_change((row, col));
+        self.clamp_cursor();
+    }
+
+    /// Ctrl+I: go to the next position in the jump list
+    fn jump_forward(&mut self) {
+        if self.jump_pos + 1 >= self.jump_list.len() {
+            return;
+        }
+        self.jump_pos += 1;
+        let (path, row, col) = self.jump_list[self.jump_pos].clone();
+        self.go_to_location(&path, row, None);
+        self.move_cursor_to_change((row, col));
+        self.clamp_cursor();
+    }
+
+    /// Move each buffer's marks along with the lines its edits added or removed
+    fn shift_marks(&mut self) {
+        let mut state = self.api_state.lock().unwrap();
+        for buffer in &mut self.buffers {
+            for (at, removed, inserted) in buffer.document.line_shifts.drain(..) {
+                state.shift_marks(buffer.id, at, removed, inserted);
+            }
+        }
+    }
+
+    /// `m{a-z}`: remember the cursor position in the active buffer
+    fn set_mark_at_cursor(&mut self, name: char) {
+        if !name.is_ascii_lowercase() {
+            return;
+        }
+        if let Some(buffer) = self.buffers.get(self.active_buffer) {
+            self.api_state.lock().unwrap().set_mark(buffer.id, name, (buffer.cursor_y, buffer.cursor_x));
+        }
+    }
+
+    /// `'{a-z}` / `` `{a-z} ``: jump to a mark's line, or with `exact` to its
+    /// column too. `''` and ``` `` ``` go back to where the last jump started
+    fn jump_to_mark(&mut self, name: char, exact: bool) {
+        if name == '\'' || name == '`' {
+            let Some((path, row, col)) = self.jump_list[..self.jump_pos].last().cloned() else {
+                self.message = Some("E20: Mark not set".to_string());
+                return;
+            };
+            self.push_jump();
+            self.go_to_location(&path, row, None);
+            if exact {
+                self.move_cursor_to_change((row, col));
+                self.clamp_cursor();
+            } else {
+                self.cursor_to_first_non_blank();
+            }
+            return;
+        }
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return;
+        };
+        let mark = name.is_ascii_lowercase()
+            .then(|| self.api_state.lock().unwrap().get_mark(buffer.id, name))
+            .flatten();
+        let Some((row, col)) = mark else {
+            self.message = Some("E20: Mark not set".to_string());
+            return;
+        };
+        self.push_jump();
+        self.move_cursor_to_change((row, col));
+        self.clamp_cursor();
+        if !exact {
+            self.cursor_to_first_non_blank();
+        }
+        self.scroll_cursor_into_view();
+    }
+
+    /// Move to the first non-blank character of the cursor line
+    fn cursor_to_first_non_blank(&mut self) {
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return;
+        };
+        let row = buffer.cursor_y;
+        let line = buffer.document.lines.get(row).map_or("", String::as_str);
+        let col = line.len() - line.trim_start().len();
+        self.move_cursor_to_change((row, col));
+    }
+
+    /// Show `path`, opening it if needed, and put the cursor on `row`. `character`
+    /// is an LSP (UTF-16) column
+    fn go_to_location(&mut self, path: &Path, row: usize, character: Option<usize>) {
+        let same_file = self.current_position()
+            .is_some_and(|(current, _, _)| same_path(&current, path));
+        if !same_file {
+            // Reuse the tab of a file that is open under another spelling of its path
+            let name = self.tab_manager.tab_list().into_iter()
+                .map(|(_, name)| name.to_string())
+                .find(|name| same_path(Path::new(name), path))
+                .unwrap_or_else(|| path.to_string_lossy().to_string());
+            if let Err(e) = self.open_file(&name) {
+                self.message = Some(format!("Cannot open {}: {}", name, e));
+                return;
+            }
+        }
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return;
+        };
+        let row = row.min(buffer.document.lines.len().saturating_sub(1));
+        let col = buffer.document.lines.get(row)
+            .map_or(0, |line| utf16_to_byte(line, character.unwrap_or(0)));
+        self.move_cursor_to_change((row, col));
+        self.scroll_cursor_into_view();
+    }
+
+    /// Scroll so the cursor line is centred when it is outside the window
+    fn scroll_cursor_into_view(&mut self) {
+        let Some(window) = self.windows.get(self.active_window) else {
+            return;
+        };
+        let height = if self.windows.len() > 1 { window.height.saturating_sub(2) } else { window.height };
+        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
+            center_cursor_if_hidden(buffer, height);
+        }
+    }
+
+    /// Send a request about the active buffer to its language server, starting the
+    /// server if needed. With `at_cursor` the cursor position is included
+    fn send_lsp_request(&mut self, method: &str, at_cursor: bool, kind: LspRequest) -> Result<()> {
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return Ok(());
+        };
+        let Some(filename) = buffer.filename.clone() else {
+            self.message = Some("E32: No file name".to_string());
+            return Ok(());
+        };
+        let path = PathBuf::from(&filename);
+        let mut params = serde_json::json!({ "textDocument": { "uri": path_to_uri(&path) } });
+        if at_cursor {
+            params["position"] = lsp_cursor_position(buffer);
+        }
+        self.send_lsp_params(&path, method, params, kind)
+    }
+
+    /// Send a request with `params` to the language server for `path`, starting
+    /// the server if needed
+    fn send_lsp_params(&mut self, path: &Path, method: &str, params: serde_json::Value, kind: LspRequest) -> Result<()> {
+        let mut manager = self.lsp_manager.lock().unwrap();
+        let server = match manager.start_server_for_file(path)? {
+            Some(lang_id) => manager.get_server(&lang_id).map(|server| (lang_id, server)),
+            None => None,
+        };
+        drop(manager);
+        // The server answers about the text as it is now
+        self.sync_lsp_documents(true);
+        let Some((lang_id, server)) = server else {
+            let language = get_language_id_from_extension(path.extension().and_then(|ext| ext.to_str()).unwrap_or(""))
+                .unwrap_or("this file type");
+            self.notify_level(NotificationLevel::Warning, format!("No language server found for {}", language), NOTIFICATION_TIME);
+            return Ok(());
+        };
+        let mut server = server.lock().unwrap();
+        if !server.is_initialized() {
+            self.message = Some(format!("{} language server is not running", lang_id));
+            return Ok(());
+        }
+        let id = server.request(method, params)?;
+        self.lsp_requests.insert((lang_id, id), kind);
+        Ok(())
+    }
+
+    /// Keep running language servers up to date with the open buffers: `didOpen`
+    /// for new ones, `didChange` with the full text for edited ones (at most every
+    /// LSP_CHANGE_DEBOUNCE unless `flush`) and `didClose` for closed or renamed ones
+    fn sync_lsp_documents(&mut self, flush: bool) {
+        let manager = self.lsp_manager.lock().unwrap();
+        let closed: Vec<usize> = self.lsp_documents.iter()
+            .filter(|(id, document)| {
+                !self.buffers.iter().any(|buffer| buffer.id == **id
+                    && buffer.filename.as_ref().is_some_and(|filename| path_to_uri(Path::new(filename)) == document.uri))
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in closed {
+            let Some(document) = self.lsp_documents.remove(&id) else {
+                continue;
+            };
+            if let Some(server) = manager.get_server(&document.language_id)
+                && let Err(e) = server.lock().unwrap().did_close(&document.uri)
+            {
+                error!("Failed to send didClose: {}", e);
+            }
+        }
+
+        for buffer in self.buffers.iter().filter(|buffer| !buffer.is_shell) {
+            let Some(path) = buffer.filename.as_deref().map(Path::new) else {
+                continue;
+            };
+            let Some(language_id) = manager.get_language_id_for_file(path) else {
+                continue;
+            };
+            let Some(server) = manager.get_server(&language_id) else {
+                continue;
+            };
+            let mut server = server.lock().unwrap();
+            if !server.is_initialized() {
+                continue;
+            }
+            let version = buffer.document.version;
+            let result = match self.lsp_documents.get_mut(&buffer.id) {
+                None => {
+                    let uri = path_to_uri(path);
+                    let result = server.did_open(&uri, &language_id, version, &buffer.document.lines.join("\n"));
+                    self.lsp_documents.insert(buffer.id, LspDocument { language_id, uri, version, sent: Instant::now() });
+                    result
+                },
+                Some(document) if document.version != version
+                    && (flush || document.sent.elapsed() >= LSP_CHANGE_DEBOUNCE) =>
+                {
+                    document.version = version;
+                    document.sent = Instant::now();
+                    server.did_change(&document.uri, version, &buffer.document.lines.join("\n"))
+                },
+                Some(_) => Ok(()),
+            };
+            if let Err(e) = result {
+                error!("Failed to sync {} with its language server: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Part of the hover popup that fits in half the screen, with a scroll
+    /// indicator when there is more
+    fn hover_visible_lines(&self, lines: &[StyledLine]) -> Vec<StyledLine> {
+        let max_height = (self.terminal_height / 2).max(2);
+        if lines.len() <= max_height {
+            return lines.to_vec();
+        }
+        let first = self.hover_scroll.min(lines.len() - (max_height - 1));
+        let last = first + max_height - 1;
+        let mut visible = lines[first..last].to_vec();
+        visible.push(vec![StyledSpan {
+            text: format!("[{}-{}/{}] Ctrl+E/Ctrl+Y to scroll", first + 1, last, lines.len()),
+            style: SpanStyle { fg: Some(Color::Grey), italic: true, ..Default::default() },
+        }]);
+        visible
+    }
+
+    /// Render a `textDocument/hover` result into the hover popup
+    pub fn show_hover(&mut self, hover: &serde_json::Value) {
+        self.hover_scroll = 0;
+        self.hover_popup = LspManager::get_hover_markup(hover)
+            .map(|markup| self.markdown.render(&markup))
+            .filter(|lines| !lines.is_empty());
+        if self.hover_popup.is_none() {
+            self.message = Some("No information available".to_string());
+        }
+    }
+
+    /// Draw a popup below the anchor row, or above it when there is more room there
+    fn draw_popup(&self, lines: &[StyledLine], anchor_x: usize, anchor_y: usize, max_width: usize) -> Result<()> {
+        self.draw_popup_at(lines, anchor_x, anchor_y, max_width, false)
+    }
+
+    /// Like `draw_popup`, but `above` puts the popup above the anchor when it fits there
+    fn draw_popup_at(&self, lines: &[StyledLine], anchor_x: usize, anchor_y: usize, max_width: usize, above: bool) -> Result<()> {
+        // Leave the tab bar and the status and message lines alone
+        let space_below = self.terminal_height.saturating_sub(anchor_y + 3);
+        let space_above = anchor_y.saturating_sub(1);
+        let max_height = space_below.max(space_above);
+        let height = lines.len().min(max_height);
+        if height == 0 {
+            return Ok(());
+        }
+        let fits_below = space_below >= height && !(above && space_above >= height);
+        let top = if fits_below { anchor_y + 1 } else { anchor_y - height };
+
+        let width = lines.iter().map(markdown::line_width).max().unwrap_or(0)
+            .min(max_width)
+            .min(self.terminal_width.saturating_sub(2))
+            .max(1);
+        let left = anchor_x.min(self.terminal_width.saturating_sub(width + 2));
+
+        for (row, line) in lines.iter().take(height).enumerate() {
+            execute!(
+                io::stdout(),
+                cursor::MoveTo(left as u16, (top + row) as u16),
+                SetBackgroundColor(Color::DarkGrey),
+                SetForegroundColor(Color::White)
+            )?;
+            print!(" ");
+            markdown::print_line(line, width)?;
+            print!("{:pad$} ", "", pad = width.saturating_sub(markdown::line_width(line)));
+        }
+        execute!(io::stdout(), ResetColor)?;
+        Ok(())
+    }
+    
+    fn draw_file_tree(&self) -> Result<()> {
+        if let Some(tree) = &self.file_tree {
+            let tree_width = tree.width;
+            let display_height = self.terminal_height.saturating_sub(2);
+            
+            // Draw tree border
+            for y in 0..display_height {
+                execute!(
+                    io::stdout(),
+                    cursor::MoveTo(tree_width as u16, y as u16),
+                    SetForegroundColor(Color::DarkGrey)
+                )?;
+                print!("│");
+            }
+            execute!(io::stdout(), ResetColor)?;
+            
+            // Draw file tree entries
+            for (idx, entry) in tree.entries.iter().enumerate() {
+                if idx >= display_height {
+                    break;
+                }
+                
+                let prefix = if entry.is_dir {
+                    if entry.is_expanded { "▼ " } else { "▶ " }
+                } else {
+                    "  "
+                };
+                
+                let indent = "  ".repeat(entry.level);
+                let name = if entry.is_dir {
+                    format!("{}/ ", entry.name)
+                } else {
+                    entry.name.clone()
+                };
+                
+                // Format the line with proper indentation
+                let line = format!("{}{}{}", indent, prefix, name);
+                
+                // Truncate if too long
+                let display_line = if line.len() > tree_width - 1 {
+                    format!("{}…", &line[0..tree_width - 2])
+                } else {
+                    line
+                };
+                
+                execute!(
+                    io::stdout(),
+                    cursor::MoveTo(0, idx as u16)
+                )?;
+                
+                // Highlight current selection
+                if idx == tree.cursor {
+                    execute!(
+                        io::stdout(),
+                        SetBackgroundColor(Color::DarkBlue),
+                        SetForegroundColor(Color::White)
+                    )?;
+                } else if filetree::is_hidden(&entry.name) {
+                    execute!(
+                        io::stdout(),
+                        SetForegroundColor(Color::DarkGrey)
+                    )?;
+                } else if entry.is_dir {
+                    execute!(
+                        io::stdout(),
+                        SetForegroundColor(Color::Blue)
+                    )?;
+                }
+                
+                print!("{:width$}", display_line, width = tree_width);
+                execute!(io::stdout(), ResetColor)?;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn draw_window_borders(&self, window: &Window, adjusted_x: usize, is_active: bool) -> Result<()> {
+        let border_color = if is_active { Color::Green } else { Color::Grey };
+        
+        // Draw horizontal borders
+        for x in 0..window.width {
+            // Top border
+            execute!(
+                io::stdout(),
+                cursor::MoveTo((adjusted_x + x) as u16, window.y as u16),
+                SetForegroundColor(border_color)
+            )?;
+            print!("─");
+            
+            // Bottom border
+            execute!(
+                io::stdout(),
+                cursor::MoveTo((adjusted_x + x) as u16, (window.y + window.height - 1) as u16)
+            )?;
+            print!("─");
+        }
+        
+        // Draw vertical borders
+        for y in 0..window.height {
+            // Left border
+            execute!(
+                io::stdout(),
+                cursor::MoveTo(adjusted_x as u16, (window.y + y) as u16),
+                SetForegroundColor(border_color)
+            )?;
+            print!("│");
+            
+            // Right border
+            execute!(
+                io::stdout(),
+                cursor::MoveTo((adjusted_x + window.width - 1) as u16, (window.y + y) as u16)
+            )?;
+            print!("│");
+        }
+        
+        // Draw corners
+        execute!(io::stdout(), cursor::MoveTo(adjusted_x as u16, window.y as u16))?;
+        print!("┌");
+        execute!(io::stdout(), cursor::MoveTo((adjusted_x + window.width - 1) as u16, window.y as u16))?;
+        print!("┐");
+        execute!(io::stdout(), cursor::MoveTo(adjusted_x as u16, (window.y + window.height - 1) as u16))?;
+        print!("└");
+        execute!(io::stdout(), cursor::MoveTo((adjusted_x + window.width - 1) as u16, (window.y + window.height - 1) as u16))?;
+        print!("┘");
+        
+        execute!(io::stdout(), ResetColor)?;
+        
+        Ok(())
+    }
+    
+    /// The start screen: logo, version, recent files with the letter that opens
+    /// each and a few hints, centred in the window
+    fn draw_welcome(&self, x: usize, y: usize, width: usize, height: usize) -> Result<()> {
+        let mut lines: Vec<(String, Color)> = WELCOME_LOGO.iter()
+            .map(|line| (line.to_string(), Color::Green))
+            .collect();
+        lines.push((String::new(), Color::Reset));
+        lines.push((format!("version {}", VERSION), Color::DarkGrey));
+        lines.push((String::new(), Color::Reset));
+        if !self.recent_files.is_empty() {
+            lines.push(("Recent files".to_string(), Color::Cyan));
+            for (letter, file) in ('a'..='z').zip(&self.recent_files) {
+                lines.push((format!("  {}  {}", letter, session::display_name(file)), Color::Reset));
+            }
+            lines.push((String::new(), Color::Reset));
+        }
+        lines.push(("  Enter  Empty buffer".to_string(), Color::DarkGrey));
+        lines.push(("  :      Command line (:e {file}, :q)".to_string(), Color::DarkGrey));
+
+        let block_width = lines.iter().map(|(line, _)| line.chars().count()).max().unwrap_or(0).min(width);
+        let left = (width - block_width) / 2;
+        let top = height.saturating_sub(lines.len()) / 2;
+        for row in 0..height {
+            execute!(io::stdout(), cursor::MoveTo(x as u16, (y + row) as u16))?;
+            match row.checked_sub(top).and_then(|idx| lines.get(idx)) {
+                Some((line, color)) => {
+                    let line: String = line.chars().take(block_width).collect();
+                    execute!(io::stdout(), SetForegroundColor(*color))?;
+                    print!("{:left$}{:<rest$}", "", line, rest = width - left);
+                    execute!(io::stdout(), ResetColor)?;
+                },
+                None => print!("{:width$}", ""),
+            }
+        }
+        Ok(())
+    }
+
+    fn draw_window_content(&self, window: &Window, active: bool, adjusted_x: usize) -> Result<()> {
+        let effective_width = if self.windows.len() > 1 { window.width.saturating_sub(2) } else { window.width };
+        let effective_height = if self.windows.len() > 1 { window.height.saturating_sub(2) } else { window.height };
+        
+        // Adjust starting position if window has borders
+        let content_x = if self.windows.len() > 1 { adjusted_x + 1 } else { adjusted_x };
+        let content_y = if self.windows.len() > 1 { window.y + 1 } else { window.y };
+        
+        if self.mode == Mode::Welcome {
+            return self.draw_welcome(content_x, content_y, effective_width, effective_height);
+        }
+
+        let Some(buffer) = self.window_buffer(window, active) else {
+            return Ok(());
+        };
+        
+        if buffer.is_shell {
+            // Draw shell content
+            if let Some(shell) = &buffer.shell { // No mut needed for drawing
+                let mut line_counter = 0;
+                // Display previous lines from the shell's actual output
+                // Consider viewport scrolling for shell.lines here
+                let start_line_idx = shell.lines.len().saturating_sub(effective_height.saturating_sub(1)); // Show last lines, leave one for input
+
+                for (idx, line_content) in shell.lines.iter().skip(start_line_idx).enumerate() {
+                    if line_counter >= effective_height.saturating_sub(1) { // Reserve last line for input
+                        break;
+                    }
+                    ansi::render_styled_line(content_x, content_y + line_counter, line_content, effective_width)?;
+                    line_counter += 1;
+                }
+                
+                // Draw RVim's current input line with a visual prompt
+                if line_counter < effective_height {
+                    execute!(io::stdout(), cursor::MoveTo(content_x as u16, (content_y + line_counter) as u16))?;
+                    let prompt_and_input = format!("$ {}", shell.input_line); // RVim's visual prompt
+                    let display_prompt_input = if prompt_and_input.len() > effective_width {
+                        &prompt_and_input[0..effective_width]
+                    } else {
+                        &prompt_and_input
+                    };
+                    print!("{}", display_prompt_input);
+                }
+            }
+        } else {
+            let total_lines = buffer.document.lines.len();
+            // The minimap takes the rightmost columns and the scrollbar the one before them
+            let minimap = self.minimap_columns(buffer, effective_width);
+            let effective_width = effective_width - minimap;
+            let scrollbar = self.scrollbar_shown(buffer, effective_height);
+            let effective_width = effective_width.saturating_sub(usize::from(scrollbar));
+            let gutter = gutter_width(buffer);
+            let number_width = gutter.saturating_sub(SIGN_COLUMN_WIDTH + 1);
+            let debug_signs = self.debug_signs(buffer);
+            let mut signs: HashMap<usize, u8> = HashMap::new();
+            for diagnostic in self.buffer_diagnostics(buffer).into_iter().flatten() {
+                let severity = signs.entry(diagnostic.range_start_line).or_insert(diagnostic.severity);
+                *severity = (*severity).min(diagnostic.severity);
+            }
+            let mut search_regexes: Vec<(Regex, Color)> = self.search_patterns.iter()
+                .filter_map(|(pattern, color)| compile_search_pattern(pattern).map(|re| (re, *color)))
+                .collect();
+            // Highlight the pattern being typed; drawn last so it wins over older patterns
+            if matches!(self.mode, Mode::Search { .. })
+                && !self.search_query.is_empty()
+                && let Some(re) = compile_search_pattern(&self.search_query)
+            {
+                search_regexes.push((re, Color::Yellow));
+            }
+            let mut highlights: HashMap<usize, Vec<(usize, usize, Color)>> = HashMap::new();
+            for (row, start, end, name) in buffer.highlight_spans(buffer.offset_y, buffer.offset_y + effective_height) {
+                if let Some(color) = self.theme.color(name) {
+                    highlights.entry(row).or_default().push((start, end, color));
+                }
+            }
+            let text_width = effective_width.saturating_sub(self.blame_column_width() + gutter);
+            // Lines inside closed folds take no rows
+            let shown = |mut row: usize| {
+                while row < total_lines && buffer.screen_rows(row, text_width) == 0 {
+                    row += 1;
+                }
+                row
+            };
+            let (mut file_row, mut segment) = (shown(buffer.offset_y), 0);
+            for y in 0..effective_height {
+                // Move on to the next line once this one has no more wrapped rows
+                if y > 0 {
+                    segment += 1;
+                    if segment >= buffer.screen_rows(file_row, text_width) {
+                        file_row = shown(file_row + 1);
+                        segment = 0;
+                    }
+                }
+                execute!(io::stdout(),
+                    cursor::MoveTo(content_x as u16, (content_y + y) as u16)
+                )?;
+                if segment > 0 {
+                    // Continuation rows leave the gutters blank and start with the showbreak
+                    let gutters = self.blame_column_width() + gutter;
+                    execute!(io::stdout(), SetForegroundColor(Color::DarkGrey))?;
+                    print!("{:gutters$}{}", "", buffer.showbreak);
+                    execute!(io::stdout(), ResetColor)?;
+                    let start = buffer.segment_start(segment, text_width);
+                    let width = text_width.saturating_sub(buffer.segment_indent(segment)).max(1);
+                    let selection = self.selection_in_line(file_row, &buffer.document.lines[file_row]).filter(|_| active);
+                    let line_highlights = highlights.get(&file_row).map_or(&[][..], Vec::as_slice);
+                    self.draw_text_line(buffer, file_row, start..start + width, line_highlights, &search_regexes, selection)?;
+                    continue;
+                }
+                if self.show_git_blame {
+                    // The blame is read for the active buffer; other windows get an empty column
+                    let blame = self.blame_data.get(&file_row)
+                        .filter(|_| self.blame_buffer == Some(buffer.id))
+                        .map_or("", String::as_str);
+                    execute!(io::stdout(), SetForegroundColor(Color::DarkGrey))?;
+                    print!("{:<width$.width$} ", blame, width = BLAME_WIDTH);
+                    execute!(io::stdout(), ResetColor)?;
+                }
+                // sign column with the debugger's marker, else the most severe
+                // diagnostic of the line, else its diff marker, else its git hunk
+                let marker = debug_signs.get(&file_row).copied()
+                    .or_else(|| match signs.get(&file_row) {
+                        Some(1) => Some(('●', Color::Red)),
+                        Some(2) => Some(('●', Color::Yellow)),
+                        _ => None,
+                    })
+                    .or_else(|| self.diff_sign(buffer.id, file_row))
+                    .or_else(|| git_hunk_sign(buffer, file_row));
+                match marker {
+                    Some((marker, color)) => {
+                        execute!(io::stdout(), SetForegroundColor(color))?;
+                        print!("{}", marker);
+                        execute!(io::stdout(), ResetColor)?;
+                    },
+                    None => print!(" "),
+                }
+                // line-number gutter
+                // with relativenumber only the cursor line keeps its own number
+                if !buffer.number {
+                    // the numbers are hidden, so the gutter ends with the sign column
+                } else if file_row < total_lines && self.show_relative_numbers && file_row != buffer.cursor_y {
+                    print!("{:>width$} ", file_row.abs_diff(buffer.cursor_y), width = number_width);
+                } else if file_row < total_lines {
+                    print!("{:>width$} ", file_row + 1, width = number_width);
+                } else {
+                    print!("{:width$} ", "", width = number_width);
+                }
+                // then the text
+                if file_row >= buffer.document.lines.len() {
+                    print!(" ");
+                } else if let Some((start, end)) = buffer.closed_fold_at(file_row) {
+                    draw_fold_summary(&buffer.document.lines[start], end + 1 - start, text_width)?;
+                } else {
+                    let selection = self.selection_in_line(file_row, &buffer.document.lines[file_row]).filter(|_| active);
+                    let line_highlights = highlights.get(&file_row).map_or(&[][..], Vec::as_slice);
+                    self.draw_text_line(buffer, file_row, buffer.offset_x..buffer.offset_x + text_width, line_highlights, &search_regexes, selection)?;
+                }
+            }
+            if scrollbar {
+                let thumb = scrollbar_thumb(total_lines, effective_height, buffer.offset_y);
+                execute!(io::stdout(), SetForegroundColor(Color::DarkGrey))?;
+                for y in 0..effective_height {
+                    execute!(io::stdout(), cursor::MoveTo((content_x + effective_width) as u16, (content_y + y) as u16))?;
+                    print!("{}", if thumb.contains(&y) { '▓' } else { '░' });
+                }
+                execute!(io::stdout(), ResetColor)?;
+            }
+            if minimap > 0 {
+                let x = content_x + effective_width + usize::from(scrollbar);
+                self.draw_minimap(buffer, x, content_y, minimap, effective_height)?;
+            }
+        }
+        
+        Ok(())
+    }
+
+    /// Columns the minimap takes in a window `width` columns wide showing
+    /// `buffer`; narrow windows go without
+    fn minimap_columns(&self, buffer: &Buffer, width: usize) -> usize {
+        if self.show_minimap && !buffer.is_shell && width >= self.minimap_width * 3 {
+            self.minimap_width
+        } else {
+            0
+        }
+    }
+
+    /// The minimap: each row stands for an equal share of the file's lines. The
+    /// rows of the lines in the window get a grey background and rows with git
+    /// changes are yellow
+    fn draw_minimap(&self, buffer: &Buffer, x: usize, y: usize, width: usize, height: usize) -> Result<()> {
+        let lines = &buffer.document.lines;
+        let per_row = lines.len().div_ceil(height.max(1)).max(1);
+        let shown = buffer.offset_y..buffer.offset_y + height;
+        for row in 0..height {
+            let start = (row * per_row).min(lines.len());
+            let end = ((row + 1) * per_row).min(lines.len());
+            let changed = buffer.git_hunks.iter().any(|&(first, last, _)| first < end && start < last);
+            let color = if changed { Color::Yellow } else { Color::DarkGrey };
+            execute!(io::stdout(), cursor::MoveTo(x as u16, (y + row) as u16), SetForegroundColor(color))?;
+            if start < end && start < shown.end && shown.start < end {
+                let color = if changed { Color::Yellow } else { Color::Grey };
+                execute!(io::stdout(), SetForegroundColor(color), SetBackgroundColor(Color::DarkGrey))?;
+            }
+            let cells: String = minimap_cells(lines[start..end].iter(), width).into_iter()
+                .map(|shaded| if shaded { '▒' } else { ' ' })
+                .collect();
+            print!("{}", cells);
+            execute!(io::stdout(), ResetColor)?;
+        }
+        Ok(())
+    }
+
+    /// Whether a window `height` rows tall showing `buffer` has a scrollbar: only
+    /// files longer than the window get one
+    fn scrollbar_shown(&self, buffer: &Buffer, height: usize) -> bool {
+        self.show_scrollbar && !buffer.is_shell && buffer.document.lines.len() > height
+    }
+    
+    /// Print screen `columns` of line `row` in its syntax colors, shading every search
+    /// match with its pattern's color and the visual selection on top. Lines that
+    /// differ in a diff get a background across the whole width
+    fn draw_text_line(&self, buffer: &Buffer, row: usize, columns: Range<usize>, highlights: &[(usize, usize, Color)],
+        search_regexes: &[(Regex, Color)], selection: Option<Range<usize>>) -> Result<()> {
+        let line = &buffer.document.lines[row];
+        let mut foregrounds: Vec<Option<Color>> = vec![None; line.len()];
+        for &(start, end, color) in highlights {
+            for fg in &mut foregrounds[start.min(line.len())..end.min(line.len())] {
+                *fg = Some(color);
+            }
+        }
+        let mut backgrounds: Vec<Option<Color>> = vec![None; line.len()];
+        for (re, color) in search_regexes {
+            for m in re.find_iter(line) {
+                for bg in &mut backgrounds[m.start()..m.end()] {
+                    *bg = Some(*color);
+                }
+            }
+        }
+        if let Some(selection) = selection {
+            for bg in &mut backgrounds[selection] {
+                *bg = Some(SELECTION_COLOR);
+            }
+        }
+        // References to the symbol under the cursor keep their colors
+        let mut referenced = vec![false; line.len()];
+        if self.highlight_position.is_some_and(|(id, _, _)| id == buffer.id) {
+            for &(start_row, start_col, end_row, end_col) in &self.highlight_ranges {
+                if (start_row..=end_row).contains(&row) {
+                    let start = if row == start_row { start_col.min(line.len()) } else { 0 };
+                    let end = if row == end_row { end_col.min(line.len()) } else { line.len() };
+                    for shaded in &mut referenced[start..end.max(start)] {
+                        *shaded = true;
+                    }
+                }
+            }
+        }
+
+        let cells = expand_tabs(line, buffer.document.tabstop);
+        let line_background = self.diff_sign(buffer.id, row).map(|(marker, _)| match marker {
+            '-' => DIFF_REMOVED_COLOR,
+            _ => DIFF_ADDED_COLOR,
+        });
+
+        // Search matches and the selection are drawn black so they stay readable
+        let mut current: (Option<Color>, Option<Color>) = (None, None);
+        for (column, &(byte_idx, c)) in cells.iter().enumerate().skip(columns.start).take(columns.len()) {
+            // With show_tabs the first column of a tab shows an arrow
+            let tab_start = buffer.show_tabs && line.as_bytes()[byte_idx] == b'\t'
+                && (column == 0 || cells[column - 1].0 != byte_idx);
+            let c = if tab_start { '→' } else { c };
+            let style = match backgrounds[byte_idx] {
+                Some(bg) => (Some(Color::Black), Some(bg)),
+                None if tab_start => (Some(Color::DarkGrey), line_background),
+                None if referenced[byte_idx] => (foregrounds[byte_idx], Some(DOCUMENT_HIGHLIGHT_COLOR)),
+                None => (foregrounds[byte_idx], line_background),
+            };
+            if style != current {
+                execute!(io::stdout(), ResetColor)?;
+                if let Some(fg) = style.0 {
+                    execute!(io::stdout(), SetForegroundColor(fg))?;
+                }
+                if let Some(bg) = style.1 {
+                    execute!(io::stdout(), SetBackgroundColor(bg))?;
+                }
+                current = style;
+            }
+            print!("{}", c);
+        }
+        if let Some(bg) = line_background {
+            let drawn = cells.len().saturating_sub(columns.start).min(columns.len());
+            execute!(io::stdout(), ResetColor, SetBackgroundColor(bg))?;
+            print!("{:pad$}", "", pad = columns.len() - drawn);
+            current = (None, Some(bg));
+        }
+        if current != (None, None) {
+            execute!(io::stdout(), ResetColor)?;
+        }
+        Ok(())
+    }
+
+    /// Buffer a window shows: the active buffer in the active window
+    fn window_buffer(&self, window: &Window, active: bool) -> Option<&Buffer> {
+        window.buffer_id.filter(|_| !active)
+            .and_then(|id| self.buffers.iter().find(|buffer| buffer.id == id))
+            .or_else(|| self.buffers.get(self.active_buffer))
+    }
+
+    /// Diff marker and its color for a row of a buffer in the diff view: `-` for
+    /// lines only the first buffer has, `+` for lines only the second has
+    fn diff_sign(&self, buffer_id: usize, row: usize) -> Option<(char, Color)> {
+        let view = self.diff_view.as_ref()?;
+        let old = view.buffers.iter().position(|&id| id == buffer_id)? == 0;
+        view.hunks.iter().any(|hunk| hunk.side(old).contains(&row))
+            .then_some(if old { ('-', Color::Red) } else { ('+', Color::Green) })
+    }
+
+    fn draw_status_line(&self) -> Result<()> {
+        // File and position info
+        let (line, col, total) = if let Some(buf) = self.buffers.get(self.active_buffer) {
+            let l = buf.cursor_y + 1;
+            let c = buf.cursor_x + 1;
+            let t = buf.document.lines.len();
+            (l, c, t)
+        } else { (0,0,0) };
+        let pct = if total > 0 { (line as f32 / total as f32) * 100.0 } else { 0.0 };
+        let pos_info = format!("L{}/C{}  {}/{} ({:.0}%)", line, col, line, total, pct);
+
+        let status = mode_name(self.mode);
+        let fname = self.buffers
+            .get(self.active_buffer)
+            .and_then(|b| b.filename.clone())
+            .unwrap_or("[No Name]".into());
+        let modified = if let Some(b) = self.buffers.get(self.active_buffer) {
+            if b.document.modified { "[+]" } else { "" }
+        } else { "" };
+        let recording = self.recording_macro
+            .map_or_else(String::new, |register| format!(" recording @{}", register));
+        let status_line = match self.lua_status_line(&fname, line, col, total) {
+            Some(custom) => custom.chars().take(self.terminal_width).collect(),
+            None => format!(" {}{} | {}{} | {} ", status, recording, fname, modified, pos_info),
+        };
+
+        execute!(
+            io::stdout(),
+            cursor::MoveTo(0, self.terminal_height as u16 - 2),
+            SetForegroundColor(Color::Black),
+            SetBackgroundColor(Color::White)
+        )?;
+        let pad = self.terminal_width.saturating_sub(status_line.chars().count());
+        print!("{}{}", status_line, " ".repeat(pad));
+        // Grey arrows at the ends when the cursor line is scrolled past either edge
+        let (left, right) = self.cursor_line_overflow();
+        execute!(io::stdout(), SetForegroundColor(Color::DarkGrey))?;
+        if left {
+            execute!(io::stdout(), cursor::MoveTo(0, self.terminal_height as u16 - 2))?;
+            print!("<");
+        }
+        if right {
+            execute!(io::stdout(), cursor::MoveTo(self.terminal_width.saturating_sub(1) as u16, self.terminal_height as u16 - 2))?;
+            print!(">");
+        }
+        execute!(io::stdout(), ResetColor)?;
+        Ok(())
+    }
+
+    /// Status line from the `rvim.set_statusline` function, or `None` without
+    /// one. Errors take the place of the text so a broken function is noticed
+    fn lua_status_line(&self, filename: &str, line: usize, col: usize, total: usize) -> Option<String> {
+        let statusline_fn = self.statusline_fn.lock().unwrap();
+        let function: mlua::Function = self.lua.registry_value(statusline_fn.as_ref()?).ok()?;
+        let buffer = self.buffers.get(self.active_buffer);
+        let context = self.lua.create_table().and_then(|table| {
+            table.set("mode", mode_name(self.mode).to_lowercase())?;
+            table.set("filename", filename)?;
+            table.set("modified", buffer.is_some_and(|buffer| buffer.document.modified))?;
+            table.set("line", line)?;
+            table.set("col", col)?;
+            table.set("total", total)?;
+            table.set("filetype", buffer.and_then(|buffer| buffer.filetype.as_deref()))?;
+            table.set("git_branch", self.git_branch.as_deref())?;
+            Ok(table)
+        });
+        let text = context.and_then(|context| function.call::<_, String>(context));
+        Some(text.unwrap_or_else(|e| format!(" statusline: {}", e.to_string().lines().next().unwrap_or(""))))
+    }
+
+    /// Whether the cursor line has text left and right of the columns the
+    /// window shows
+    fn cursor_line_overflow(&self) -> (bool, bool) {
+        let Some(buffer) = self.buffers.get(self.active_buffer).filter(|buffer| !buffer.wrap && !buffer.is_shell) else {
+            return (false, false);
+        };
+        let columns = buffer.document.lines.get(buffer.cursor_y)
+            .map_or(0, |line| expand_tabs(line, buffer.document.tabstop).len());
+        (buffer.offset_x > 0 && columns > 0, columns > buffer.offset_x + self.window_text_width())
+    }
+    
+    fn draw_message_line(&self) -> Result<()> {
+        execute!(
+            io::stdout(),
+            cursor::MoveTo(0, self.terminal_height as u16 - 1),
+            terminal::Clear(ClearType::CurrentLine)
+        )?;
+        
+        if let (Mode::Command, Some(prompt)) = (self.mode, &self.file_tree_prompt) {
+            print!("{}{}", prompt.label(), self.command_line);
+        } else if let (Mode::Command, Some(name)) = (self.mode, &self.rename_prompt) {
+            print!("Rename {} to: {}", name, self.command_line);
+        } else if let Mode::Command = self.mode {
+            // The part a Tab completion added is grey
+            match self.completion.as_ref().and_then(|completion| self.command_line.strip_prefix(completion.typed.as_str())) {
+                Some(added) => {
+                    print!(":{}", &self.command_line[..self.command_line.len() - added.len()]);
+                    execute!(io::stdout(), SetForegroundColor(Color::DarkGrey))?;
+                    print!("{}", added);
+                    execute!(io::stdout(), ResetColor)?;
+                },
+                None => print!(":{}", self.command_line),
+            }
+        } else if let Mode::Search { forward } = self.mode {
+            print!("{}{}", if forward { '/' } else { '?' }, self.search_query);
+        } else if self.mode == Mode::Help {
+            let help_msg = "Press any key to close help.";
+            let padding = self.terminal_width.saturating_sub(help_msg.len()) / 2;
+            print!("{}{}", " ".repeat(padding), help_msg);
+        } else if let Some(notification) = self.notifications.iter().rev().find(|n| !n.expired(Instant::now())) {
+            let display: String = notification.text.chars().take(self.terminal_width).collect();
+            execute!(io::stdout(), SetForegroundColor(notification.level.color()))?;
+            print!("{}", display);
+            execute!(io::stdout(), ResetColor)?;
+        } else if let Some(message) = &self.message {
+            let display: String = message.chars().take(self.terminal_width).collect();
+            print!("{}", display);
+        } else if let Some(diagnostic) = self.cursor_diagnostic() {
+            // Diagnostics on the cursor line stand in for an empty message
+            let color = if diagnostic.severity == 1 { Color::Red } else { Color::Yellow };
+            let text = format!("{}: {}", diagnostic.severity_name(), diagnostic.message.lines().next().unwrap_or(""));
+            let display: String = text.chars().take(self.terminal_width).collect();
+            execute!(io::stdout(), SetForegroundColor(color))?;
+            print!("{}", display);
+            execute!(io::stdout(), ResetColor)?;
+        }
+        
+        Ok(())
+    }
+    
+    fn process_insert_mode(&mut self, key: KeyEvent) -> Result<()> {
+        if self.buffers.is_empty() || self.active_buffer >= self.buffers.len() {
+            return Ok(());
+        }
+        if self.completion_popup.is_some() && self.process_completion_key(key) {
+            return Ok(());
+        }
+        if self.signature_help.is_some() && self.process_signature_help_key(key) {
+            return Ok(());
+        }
+        // Tab completes the word before the cursor; Ctrl+Tab inserts a tab
+        if key.code == KeyCode::Tab
+            && !key.modifiers.contains(KeyModifiers::CONTROL)
+            && !self.buffers[self.active_buffer].is_shell
+            && self.start_word_completion()
+        {
+            return Ok(());
+        }
+        
+        let buffer = &mut self.buffers[self.active_buffer];
+        
+        if buffer.is_shell {
+            // If the buffer is a shell, switch to shell mode
+            self.mode = Mode::Shell;
+            return self.process_shell_mode(key);
+        }
+        
+        match key.code {
+            KeyCode::Esc => self.mode = Mode::Normal,
+            KeyCode::Char(c) => {
+                let line = buffer.document.lines.get(buffer.cursor_y).map_or("", String::as_str);
+                let previous = line.get(..buffer.cursor_x).and_then(|before| before.chars().next_back());
+                let next = line.get(buffer.cursor_x..).and_then(|after| after.chars().next());
+                if next == Some(c) && self.auto_pairs.values().any(|&close| close == c) {
+                    // Type over the closing character rather than doubling it
+                    buffer.cursor_x += c.len_utf8();
+                } else if let Some(&close) = self.auto_pairs.get(&c)
+                    // A quote right after a word is an apostrophe, as in don't
+                    && !(close == c && previous.is_some_and(is_word_char))
+                {
+                    buffer.document.insert_text(buffer.cursor_y, buffer.cursor_x, &format!("{}{}", c, close));
+                    buffer.cursor_x += c.len_utf8();
+                } else {
+                    buffer.document.insert_char(buffer.cursor_y, buffer.cursor_x, c);
+                    buffer.cursor_x += c.len_utf8();
+                }
+            },
+            KeyCode::Tab if buffer.document.expandtab == Some(true) && !key.modifiers.contains(KeyModifiers::CONTROL) => {
+                // Spaces up to the next tab stop
+                let line = buffer.document.lines.get(buffer.cursor_y).map_or("", String::as_str);
+                let column = line.get(..buffer.cursor_x).map_or(0, |before| before.chars().count());
+                let tabstop = buffer.document.tabstop.max(1);
+                let spaces = " ".repeat(tabstop - column % tabstop);
+                let (_, col) = buffer.document.insert_text(buffer.cursor_y, buffer.cursor_x, &spaces);
+                buffer.cursor_x = col;
+            },
+            KeyCode::Tab => {
+                buffer.document.insert_char(buffer.cursor_y, buffer.cursor_x, '\t');
+                buffer.cursor_x += 1;
+            },
+            KeyCode::Backspace => {
+                let previous = buffer.document.lines.get(buffer.cursor_y)
+                    .and_then(|line| line.get(..buffer.cursor_x))
+                    .and_then(|before| before.chars().next_back());
+                let next = buffer.document.lines.get(buffer.cursor_y)
+                    .and_then(|line| line.get(buffer.cursor_x..))
+                    .and_then(|after| after.chars().next());
+                if let Some(c) = previous {
+                    buffer.cursor_x -= c.len_utf8();
+                    buffer.document.delete_char(buffer.cursor_y, buffer.cursor_x);
+                    // Between a pair, as in (|), both go
+                    if next.is_some_and(|next| self.auto_pairs.get(&c) == Some(&next)) {
+                        buffer.document.delete_char(buffer.cursor_y, buffer.cursor_x);
+                    }
+                } else if buffer.cursor_x == 0 && let Some(col) = buffer.document.join_lines(buffer.cursor_y) {
+                    // At the start of a line, join it to the one above
+                    buffer.cursor_y -= 1;
+                    buffer.cursor_x = col;
+                }
+            },
+            KeyCode::Enter => {
+                let (row, col) = buffer.document.split_line_indented(buffer.cursor_y, buffer.cursor_x);
+                buffer.cursor_y = row;
+                buffer.cursor_x = col;
+            },
+            _ => {}
+        }
+        match key.code {
+            KeyCode::Char(c) => self.schedule_completion(c),
+            _ => self.completion_due = None,
+        }
+        match key.code {
+            KeyCode::Char('(' | ',') => self.request_signature_help(),
+            KeyCode::Char(')') => self.signature_help = None,
+            _ => {},
+        }
+        
+        Ok(())
+    }
+
+    /// Ctrl+N / Ctrl+P scroll through the signatures. Returns whether the key was used up
+    fn process_signature_help_key(&mut self, key: KeyEvent) -> bool {
+        let Some(state) = self.signature_help.as_mut() else {
+            return false;
+        };
+        if !key.modifiers.contains(KeyModifiers::CONTROL) {
+            return false;
+        }
+        let len = state.signatures.len();
+        match key.code {
+            KeyCode::Char('n') => state.selected = (state.selected + 1) % len,
+            KeyCode::Char('p') => state.selected = (state.selected + len - 1) % len,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Send `textDocument/signatureHelp` for the cursor position to a running
+    /// server that supports it
+    fn request_signature_help(&mut self) {
+        let Some((lang_id, server)) = self.running_lsp_server() else {
+            return;
+        };
+        let Some(filename) = self.buffers.get(self.active_buffer).and_then(|buffer| buffer.filename.clone()) else {
+            return;
+        };
+        self.sync_lsp_documents(true);
+        let buffer = &self.buffers[self.active_buffer];
+        let params = serde_json::json!({
+            "textDocument": { "uri": path_to_uri(Path::new(&filename)) },
+            "position": lsp_cursor_position(buffer),
+        });
+        let mut server = server.lock().unwrap();
+        if !server.is_initialized() || server.capabilities()["signatureHelpProvider"].is_null() {
+            return;
+        }
+        match server.request("textDocument/signatureHelp", params) {
+            Ok(id) => {
+                self.lsp_requests.insert((lang_id, id), LspRequest::SignatureHelp);
+            },
+            Err(e) => error!("Signature help request failed: {}", e),
+        }
+    }
+
+    /// Open the signature help popup on the server's active signature, or close
+    /// it when the cursor is not in a call. Ignored once Insert mode was left
+    fn show_signature_help(&mut self, result: &serde_json::Value) {
+        if self.mode != Mode::Insert {
+            return;
+        }
+        let (signatures, selected) = SignatureInformation::from_response(result);
+        self.signature_help = (!signatures.is_empty()).then_some(SignatureHelpState { signatures, selected });
+    }
+
+    /// Language server already running for the active buffer's file, if any
+    fn running_lsp_server(&self) -> Option<(String, Arc<Mutex<LanguageServer>>)> {
+        let filename = self.buffers.get(self.active_buffer)?.filename.as_ref()?;
+        let manager = self.lsp_manager.lock().unwrap();
+        let lang_id = manager.get_language_id_for_file(Path::new(filename))?;
+        let server = manager.get_server(&lang_id)?;
+        Some((lang_id, server))
+    }
+
+    /// After `typed` is inserted, ask the language server for completions at once
+    /// if it ends one of the server's trigger characters, or when typing pauses
+    /// if it is part of a word
+    fn schedule_completion(&mut self, typed: char) {
+        self.completion_due = None;
+        let Some((_, server)) = self.running_lsp_server() else {
+            return;
+        };
+        let triggers = server.lock().unwrap().completion_triggers();
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return;
+        };
+        let before = buffer.document.lines.get(buffer.cursor_y)
+            .and_then(|line| line.get(..buffer.cursor_x))
+            .unwrap_or("");
+        if triggers.iter().any(|trigger| !trigger.is_empty() && before.ends_with(trigger.as_str())) {
+            self.request_completion();
+        } else if is_word_char(typed) {
+            self.completion_due = Some(Instant::now());
+        }
+    }
+
+    /// Send `textDocument/completion` for the cursor position to a running server.
+    /// Quiet when there is none, as it is requested while typing
+    fn request_completion(&mut self) {
+        let Some((lang_id, server)) = self.running_lsp_server() else {
+            return;
+        };
+        let Some(filename) = self.buffers.get(self.active_buffer).and_then(|buffer| buffer.filename.clone()) else {
+            return;
+        };
+        // The server answers about the text as it is now
+        self.sync_lsp_documents(true);
+        let buffer = &self.buffers[self.active_buffer];
+        let params = serde_json::json!({
+            "textDocument": { "uri": path_to_uri(Path::new(&filename)) },
+            "position": lsp_cursor_position(buffer),
+        });
+        let mut server = server.lock().unwrap();
+        if !server.is_initialized() {
+            return;
+        }
+        match server.request("textDocument/completion", params) {
+            Ok(id) => {
+                self.lsp_requests.insert((lang_id, id), LspRequest::Completion);
+            },
+            Err(e) => error!("Completion request failed: {}", e),
+        }
+    }
+
+    /// Clear the document highlights when the cursor moves, and request new ones
+    /// once it has rested on a word in Normal mode. Returns true when the
+    /// highlights were cleared
+    fn update_document_highlight(&mut self) -> bool {
+        let position = self.buffers.get(self.active_buffer)
+            .map(|buffer| (buffer.id, buffer.cursor_y, buffer.cursor_x));
+        if position != self.highlight_position {
+            self.highlight_position = position;
+            self.highlight_due = Some(Instant::now());
+            return !std::mem::take(&mut self.highlight_ranges).is_empty();
+        }
+        if self.highlight_due.is_some_and(|since| since.elapsed() >= DOCUMENT_HIGHLIGHT_DELAY) {
+            self.highlight_due = None;
+            if self.mode == Mode::Normal && self.cursor_word_highlight {
+                self.request_document_highlight();
+            }
+        }
+        false
+    }
+
+    /// Send `textDocument/documentHighlight` for the cursor position to a running
+    /// server that supports it, if the cursor is on a word
+    fn request_document_highlight(&mut self) {
+        let Some((lang_id, server)) = self.running_lsp_server() else {
+            return;
+        };
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return;
+        };
+        let Some(filename) = buffer.filename.clone() else {
+            return;
+        };
+        let on_word = buffer.document.lines.get(buffer.cursor_y)
+            .and_then(|line| line.get(buffer.cursor_x..))
+            .and_then(|rest| rest.chars().next())
+            .is_some_and(is_word_char);
+        if !on_word {
+            return;
+        }
+        let position = (buffer.id, buffer.cursor_y, buffer.cursor_x);
+        self.sync_lsp_documents(true);
+        let buffer = &self.buffers[self.active_buffer];
+        let params = serde_json::json!({
+            "textDocument": { "uri": path_to_uri(Path::new(&filename)) },
+            "position": lsp_cursor_position(buffer),
+        });
+        let mut server = server.lock().unwrap();
+        let supported = !matches!(server.capabilities()["documentHighlightProvider"],
+            serde_json::Value::Null | serde_json::Value::Bool(false));
+        if !server.is_initialized() || !supported {
+            return;
+        }
+        match server.request("textDocument/documentHighlight", params) {
+            Ok(id) => {
+                self.lsp_requests.insert((lang_id, id), LspRequest::DocumentHighlight(position));
+            },
+            Err(e) => error!("Document highlight request failed: {}", e),
+        }
+    }
+
+    /// Store the server's highlights with their UTF-16 columns turned into byte columns
+    fn set_document_highlights(&mut self, highlights: &[DocumentHighlight]) {
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return;
+        };
+        let lines = &buffer.document.lines;
+        let byte = |(row, character): (usize, usize)| {
+            lines.get(row).map_or(0, |line| utf16_to_byte(line, character))
+        };
+        self.highlight_ranges = highlights.iter()
+            .map(|highlight| (highlight.start.0, byte(highlight.start), highlight.end.0, byte(highlight.end)))
+            .collect();
+    }
+
+    /// Open the completion popup with the server's items that match the word
+    /// before the cursor, unless Insert mode was left or Tab opened a popup meanwhile
+    fn show_completions(&mut self, result: &serde_json::Value) {
+        if self.mode != Mode::Insert || self.completion_popup.as_ref().is_some_and(|state| !state.from_lsp) {
+            return;
+        }
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return;
+        };
+        let line = buffer.document.lines.get(buffer.cursor_y).map_or("", String::as_str);
+        let before = line.get(..buffer.cursor_x).unwrap_or(line);
+        let start = word_start(before);
+        let prefix = before[start..].to_lowercase();
+        let items: Vec<CompletionItem> = CompletionItem::from_response(result).into_iter()
+            .filter(|item| item.label.to_lowercase().starts_with(&prefix))
+            .take(MAX_WORD_COMPLETIONS)
+            .collect();
+        self.completion_popup = (!items.is_empty())
+            .then_some(CompletionState { row: buffer.cursor_y, start, items, selected: 0, from_lsp: true });
+    }
+    
+    /// Open the completion popup with the words of the open buffers that start with
+    /// the word before the cursor. Returns false when there is no word to complete
+    fn start_word_completion(&mut self) -> bool {
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return false;
+        };
+        let line = buffer.document.lines.get(buffer.cursor_y).map_or("", String::as_str);
+        let before = line.get(..buffer.cursor_x).unwrap_or(line);
+        let start = word_start(before);
+        let prefix = &before[start..];
+        if prefix.is_empty() {
+            return false;
+        }
+        // Words of the active buffer come first
+        let mut seen = HashSet::new();
+        let items: Vec<CompletionItem> = std::iter::once(buffer)
+            .chain(self.buffers.iter().filter(|other| other.id != buffer.id))
+            .filter(|buffer| !buffer.is_shell)
+            .flat_map(|buffer| buffer.document.lines.iter())
+            .flat_map(|line| line.split(|c: char| !is_word_char(c)))
+            .filter(|word| word.len() > prefix.len() && word.starts_with(prefix))
+            .filter(|word| seen.insert(*word))
+            .take(MAX_WORD_COMPLETIONS)
+            .map(|word| CompletionItem { label: word.to_string(), insert_text: word.to_string(), kind: 0, detail: None })
+            .collect();
+        if items.is_empty() {
+            self.message = Some("Pattern not found".to_string());
+        } else {
+            self.completion_popup = Some(CompletionState { row: buffer.cursor_y, start, items, selected: 0, from_lsp: false });
+        }
+        true
+    }
+
+    /// Tab / Shift+Tab cycle through the popup, Enter accepts the selected item
+    /// and Esc closes it. Any other key accepts a word from the buffers, or closes
+    /// the language server's popup so typing goes on. Returns whether the key was used up
+    fn process_completion_key(&mut self, key: KeyEvent) -> bool {
+        let Some(state) = self.completion_popup.as_mut() else {
+            return false;
+        };
+        let len = state.items.len();
+        match key.code {
+            KeyCode::Tab => state.selected = (state.selected + 1) % len,
+            KeyCode::BackTab => state.selected = (state.selected + len - 1) % len,
+            KeyCode::Esc => self.completion_popup = None,
+            KeyCode::Enter => self.accept_completion(),
+            _ if state.from_lsp => {
+                self.completion_popup = None;
+                return false;
+            },
+            _ => {
+                self.accept_completion();
+                return false;
+            },
+        }
+        true
+    }
+
+    /// Replace the typed part of the word with the selected item and close the popup
+    fn accept_completion(&mut self) {
+        let Some(state) = self.completion_popup.take() else {
+            return;
+        };
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return;
+        };
+        // The cursor is still at the end of the typed part unless something moved it
+        let Some(item) = state.items.get(state.selected)
+            .filter(|_| buffer.cursor_y == state.row && buffer.cursor_x >= state.start)
+        else {
+            return;
+        };
+        let (row, start) = (state.row, state.start);
+        buffer.document.replace_ranges(&[((row, start), (row, buffer.cursor_x), item.insert_text.clone())]);
+        let inserted_lines = item.insert_text.matches('\n').count();
+        buffer.cursor_y = row + inserted_lines;
+        buffer.cursor_x = match item.insert_text.rsplit_once('\n') {
+            Some((_, last)) => last.len(),
+            None => start + item.insert_text.len(),
+        };
+    }
+
+    fn process_shell_mode(&mut self, key: KeyEvent) -> Result<()> {
+        if self.buffers.is_empty() || self.active_buffer >= self.buffers.len() {
+            return Ok(());
+        }
+        
+        let buffer = &mut self.buffers[self.active_buffer];
+        
+        if !buffer.is_shell || buffer.shell.is_none() {
+            self.mode = self.previous_mode; 
+            return Ok(());
+        }
+        
+        let shell = buffer.shell.as_mut().unwrap();
+        shell.poll_output(); // Poll output before processing key
+
+        if !shell.running {
+            self.mode = self.previous_mode;
+            info!("Shell is not running. Switching to mode: {:?}", self.mode);
+            // Optionally close the buffer or mark as non-interactive
+            // For now, if the user tries to type into a dead shell, they'll just switch out.
+            return Ok(());
+        }
+        
+        // With completions listed, Tab cycles, Esc closes the list, Enter takes
+        // the selected one and other keys take it before doing their work
+        if !shell.completion_candidates.is_empty() && key.code != KeyCode::Tab {
+            match key.code {
+                KeyCode::Esc => {
+                    shell.dismiss_completion();
+                    return Ok(());
+                },
+                KeyCode::Enter => {
+                    shell.accept_completion();
+                    return Ok(());
+                },
+                _ => shell.accept_completion(),
+            }
+        }
+
+        match key.code {
+            KeyCode::Esc => {
+                self.mode = self.previous_mode; // Revert to previous mode
+            },
+            KeyCode::Tab => {
+                shell.complete()?;
+            },
+            KeyCode::Enter => {
+                shell.execute_command()?; // This now sends to the child shell
+                // poll_output will be called at the start of the next refresh_screen or keypress
+            },
+            KeyCode::Char(c) => {
+                shell.input_char(c);
+            },
+            KeyCode::Backspace => {
+                shell.input_backspace();
+            },
+            KeyCode::Delete => {
+                shell.input_delete();
+            },
+            KeyCode::Left => {
+                shell.move_cursor_left();
+            },
+            KeyCode::Right => {
+                shell.move_cursor_right();
+            },
+            KeyCode::Up => {
+                shell.history_up();
+            },
+            KeyCode::Down => {
+                shell.history_down();
+            },
+            _ => {}
+        }
+        
+        Ok(())
+    }
+    
+    fn process_keypress(&mut self) -> Result<()> {
+        match event::read()? {
+            Event::Key(key_event) => {
+                self.show_which_key = false;
+                // Ctrl+E/Ctrl+Y scroll the hover popup, any other key closes it; Esc does nothing else
+                if let Some(lines) = &self.hover_popup {
+                    let ctrl = key_event.modifiers.contains(KeyModifiers::CONTROL);
+                    match key_event.code {
+                        KeyCode::Char('e') if ctrl => {
+                            self.hover_scroll = (self.hover_scroll + 1).min(lines.len().saturating_sub(1));
+                            return Ok(());
+                        },
+                        KeyCode::Char('y') if ctrl => {
+                            self.hover_scroll = self.hover_scroll.saturating_sub(1);
+                            return Ok(());
+                        },
+                        _ => {},
+                    }
+                }
+                if self.hover_popup.take().is_some() && key_event.code == KeyCode::Esc {
+                    return Ok(());
+                }
+                if let Some(register) = self.recording_macro {
+                    self.macros.entry(register).or_default().push(key_event);
+                }
+                // Only typed keys count towards `.`, so macro and `.` playback are not recorded
+                if self.waiting_for_command() {
+                    self.change_keys.clear();
+                    self.change_count = None;
+                    self.change_start = self.buffer_version();
+                }
+                let count = self.pending_count;
+                self.change_keys.push(key_event);
+                self.handle_key(key_event)?;
+                // Count digits are kept apart so that `.` can be given a new count
+                if matches!(key_event.code, KeyCode::Char('0'..='9'))
+                    && self.pending_count.is_some()
+                    && self.pending_count != count
+                {
+                    self.change_keys.pop();
+                    self.change_count = self.pending_count;
+                }
+                self.record_change();
+            },
+            Event::Mouse(mouse_event) => {
+                self.process_mouse_event(mouse_event)?;
+            },
+            Event::Resize(cols, rows) => self.resize(cols as usize, rows as usize),
+            _ => {}
+        }
+        
+        Ok(())
+    }
+
+    /// Dispatch a key to the current mode; used for typed keys and macro playback
+    fn handle_key(&mut self, key_event: KeyEvent) -> Result<()> {
+        match self.mode {
+            Mode::Normal if self.code_actions_popup.is_some() => self.process_code_actions_key(key_event)?,
+            Mode::Normal => {
+                if let Some(prefix) = self.pending_prefix.take() {
+                    self.process_second_key(prefix, key_event)?;
+                } else {
+                    self.process_normal_mode(key_event)?;
+                }
+            },
+            Mode::Insert => self.process_insert_mode(key_event)?,
+            Mode::Visual | Mode::VisualLine | Mode::VisualBlock => self.process_visual_mode(key_event)?,
+            Mode::Command => self.process_command_mode(key_event)?,
+            Mode::FileTree => self.process_file_tree_mode(key_event)?,
+            Mode::Shell => self.process_shell_mode(key_event)?,
+            Mode::Help => self.process_help_mode(key_event)?,
+            Mode::TabSwitcher => self.process_tab_switcher_mode(key_event)?,
+            Mode::Search { forward } => self.process_search_mode(key_event, forward)?,
+            Mode::Picker => self.process_picker_mode(key_event)?,
+            Mode::SubstituteConfirm => self.process_substitute_confirm(key_event)?,
+            Mode::Quickfix => self.process_quickfix_mode(key_event)?,
+            Mode::Welcome => self.process_welcome_mode(key_event)?,
+        }
+        // Before a later key in a macro or `.` jumps to a mark
+        self.shift_marks();
+        // Signature help belongs to the call being typed
+        if self.mode != Mode::Insert {
+            self.signature_help = None;
+        }
+        // Whatever moved the cursor, keep it on screen
+        if matches!(self.mode, Mode::Normal | Mode::Insert | Mode::Visual | Mode::VisualLine | Mode::VisualBlock
+            | Mode::SubstituteConfirm)
+        {
+            self.scroll_to_cursor();
+        }
+        Ok(())
+    }
+    
+    fn process_normal_mode(&mut self, key: KeyEvent) -> Result<()> {
+        // Accumulate a count prefix; a leading '0' is not part of a count
+        if let KeyCode::Char(c @ '0'..='9') = key.code
+            && (c != '0' || self.pending_count.is_some())
+        {
+            let digit = c.to_digit(10).unwrap_or(0) as usize;
+            self.pending_count = Some(self.pending_count.unwrap_or(0) * 10 + digit);
+            return Ok(());
+        }
+        let count = self.pending_count.take();
+
+        match key.code {
+            KeyCode::Char('g') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                let verbose = count.is_some_and(|n| n > 1);
+                self.message = Some(self.show_file_info(verbose));
+                Ok(())
+            },
+            KeyCode::Char('z') if key.modifiers.contains(KeyModifiers::CONTROL) => self.suspend(),
+            KeyCode::F(5) if key.modifiers.contains(KeyModifiers::SHIFT) => self.stop_debugging(),
+            KeyCode::F(5) if self.debug_session.is_none() => self.start_debugging(None),
+            KeyCode::F(5) => self.debug_step(DapClient::continue_thread),
+            KeyCode::F(10) => self.debug_step(DapClient::next),
+            KeyCode::F(11) if key.modifiers.contains(KeyModifiers::SHIFT) => self.debug_step(DapClient::step_out),
+            KeyCode::F(11) => self.debug_step(DapClient::step_in),
+            KeyCode::Char(c @ ('d' | 'u' | 'f' | 'b')) if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                // Half a window for Ctrl+D / Ctrl+U, a whole one for Ctrl+F / Ctrl+B
+                let page = self.window_text_height().max(2);
+                let lines = if matches!(c, 'd' | 'u') { page / 2 } else { page };
+                self.scroll_lines(lines * count.unwrap_or(1), matches!(c, 'd' | 'f'));
+                Ok(())
+            },
+            KeyCode::Char(' ') => {
+                self.pending_prefix = Some(' ');
+                self.which_key_since = Some(Instant::now());
+                Ok(())
+            },
+            // {count}G goes to line {count}, plain G to the last line
+            KeyCode::Char('G') => {
+                self.go_to_line(count, true);
+                Ok(())
+            },
+            KeyCode::Home if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.go_to_line(count, false);
+                Ok(())
+            },
+            KeyCode::End if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.go_to_line(count, true);
+                Ok(())
+            },
+            KeyCode::Char('g') => {
+                // Keep the count for the g-command, e.g. 3gt
+                self.pending_count = count;
+                self.pending_prefix = Some('g');
+                Ok(())
+            },
+            KeyCode::Char('z') => {
+                self.pending_prefix = Some('z');
+                Ok(())
+            },
+            KeyCode::Char('q') => {
+                match self.recording_macro.take() {
+                    Some(register) => {
+                        // Drop the `q` that ended the recording
+                        if let Some(keys) = self.macros.get_mut(&register) {
+                            keys.pop();
+                        }
+                    },
+                    None => self.pending_prefix = Some('q'),
+                }
+                Ok(())
+            },
+            KeyCode::Char(c @ (']' | '[')) => {
+                self.pending_prefix = Some(c);
+                Ok(())
+            },
+            KeyCode::Char(c @ ('m' | '\'' | '`')) => {
+                self.pending_prefix = Some(c);
+                Ok(())
+            },
+            KeyCode::Char(c @ ('>' | '<')) => {
+                // Keep the count for the operator, e.g. 3>>
+                self.pending_count = count;
+                self.pending_prefix = Some(c);
+                Ok(())
+            },
+            KeyCode::Char('@') => {
+                // Keep the count for the macro, e.g. 3@a
+                self.pending_count = count;
+                self.pending_prefix = Some('@');
+                Ok(())
+            },
+            KeyCode::Char(':') => {
+                self.mode = Mode::Command;
+                self.command_line.clear();
+                self.message = None;
+                Ok(())
+            },
+            KeyCode::Char('o') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.jump_back();
+                Ok(())
+            },
+            // Terminals send Ctrl+I as Tab
+            KeyCode::Char('i') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.jump_forward();
+                Ok(())
+            },
+            KeyCode::Tab => {
+                self.jump_forward();
+                Ok(())
+            },
+            KeyCode::Char(c @ ('i' | 'I' | 'a' | 'A')) => {
+                self.enter_insert(c);
+                Ok(())
+            },
+            KeyCode::Char(c @ ('o' | 'O')) => {
+                self.open_line(c == 'o');
+                Ok(())
+            },
+            KeyCode::Char('v') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.enter_visual(Mode::VisualBlock);
+                Ok(())
+            },
+            KeyCode::Char('v') => {
+                self.enter_visual(Mode::Visual);
+                Ok(())
+            },
+            KeyCode::Char('V') => {
+                self.enter_visual(Mode::VisualLine);
+                Ok(())
+            },
+            KeyCode::Char('h') => self.repeat_motion(count, Self::move_cursor_left),
+            KeyCode::Char('j') => self.repeat_motion(count, Self::move_down),
+            KeyCode::Char('k') => self.repeat_motion(count, Self::move_up),
+            KeyCode::Char('l') => self.repeat_motion(count, Self::move_cursor_right),
+            KeyCode::Char('w') => self.repeat_motion(count, Self::move_to_next_word_start),
+            KeyCode::Char('e') => self.repeat_motion(count, Self::move_to_next_word_end),
+            KeyCode::Char('b') => self.repeat_motion(count, Self::move_to_prev_word_start),
+            KeyCode::Char(c @ ('0' | '^' | '$' | '|')) => self.move_in_line(c, count),
+            KeyCode::Char('d') => {
+                // Keep the count for the operator, e.g. 3dd
+                self.pending_count = count;
+                self.pending_prefix = Some('d');
+                Ok(())
+            },
+            KeyCode::Char('c') => {
+                // Keep the count for the operator, e.g. 2cw
+                self.pending_count = count;
+                self.pending_prefix = Some('c');
+                Ok(())
+            },
+            KeyCode::Char('y') => {
+                // Keep the count for the operator, e.g. 3yy
+                self.pending_count = count;
+                self.pending_prefix = Some('y');
+                Ok(())
+            },
+            KeyCode::Char('x') => self.repeat_motion(count, Self::delete_char_under_cursor),
+            KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => self.redo(),
+            KeyCode::Char('u') => self.undo(),
+            KeyCode::Char('.') => self.repeat_last_change(count),
+            KeyCode::Char('p') => self.paste(true, count.unwrap_or(1)),
+            KeyCode::Char('P') => self.paste(false, count.unwrap_or(1)),
+            KeyCode::Char('K') => self.show_lsp_hover(),
+            KeyCode::Char('/') => {
+                self.start_search(false, true);
+                Ok(())
+            },
+            KeyCode::Char('?') => {
+                self.start_search(false, false);
+                Ok(())
+            },
+            KeyCode::Char('n') => self.search_next(self.search_forward),
+            KeyCode::Char('N') => self.search_next(!self.search_forward),
+            KeyCode::Char('*') => self.search_word_under_cursor(),
+            _ => Ok(())
+        }
+    }
+    
+    fn process_visual_mode(&mut self, key: KeyEvent) -> Result<()> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        // zf folds the selected lines
+        if self.pending_prefix.take() == Some('z') {
+            if key.code == KeyCode::Char('f')
+                && let Some(((start, _), (end, _))) = self.visual_bounds()
+            {
+                self.exit_visual();
+                self.create_fold(start, end);
+            }
+            return Ok(());
+        }
+        match key.code {
+            KeyCode::Esc => {
+                self.exit_visual();
+                Ok(())
+            },
+            // v, V and Ctrl+V switch between the visual modes, or leave when repeated
+            KeyCode::Char('v') if ctrl => {
+                self.switch_visual(Mode::VisualBlock);
+                Ok(())
+            },
+            KeyCode::Char('v') => {
+                self.switch_visual(Mode::Visual);
+                Ok(())
+            },
+            KeyCode::Char('V') => {
+                self.switch_visual(Mode::VisualLine);
+                Ok(())
+            },
+            KeyCode::Char('y') => self.yank_selection(),
+            KeyCode::Char('d') | KeyCode::Char('x') => self.delete_selection(),
+            KeyCode::Char('>') => self.indent_selection(true),
+            KeyCode::Char('<') => self.indent_selection(false),
+            KeyCode::Char('z') => {
+                self.pending_prefix = Some('z');
+                Ok(())
+            },
+            KeyCode::Char('h') => {
+                self.move_cursor_left()?;
+                Ok(())
+            },
+            KeyCode::Char('j') => self.move_down(),
+            KeyCode::Char('k') => self.move_up(),
+            KeyCode::Char('l') => {
+                self.move_cursor_right()?;
+                Ok(())
+            },
+            KeyCode::Char(c @ ('0' | '^' | '$')) => self.move_in_line(c, None),
+            KeyCode::Char(':') => {
+                self.visual_selection = self.visual_bounds().map(|((start_row, _), (end_row, _))| (start_row, end_row));
+                self.exit_visual();
+                self.mode = Mode::Command;
+                self.command_line.clear();
+                self.message = None;
+                Ok(())
+            },
+            _ => Ok(())
+        }
+    }
+    
+    fn enter_visual(&mut self, mode: Mode) {
+        if let Some(buffer) = self.buffers.get(self.active_buffer) {
+            self.visual_anchor = Some((buffer.cursor_y, buffer.cursor_x));
+            self.mode = mode;
+        }
+    }
+
+    fn switch_visual(&mut self, mode: Mode) {
+        if self.mode == mode {
+            self.exit_visual();
+        } else {
+            self.mode = mode;
+        }
+    }
+
+    fn exit_visual(&mut self) {
+        self.visual_anchor = None;
+        self.mode = Mode::Normal;
+    }
+
+    /// Ordered (row, byte col) corners of the selection between the anchor and the cursor
+    fn visual_bounds(&self) -> Option<((usize, usize), (usize, usize))> {
+        let anchor = self.visual_anchor?;
+        let buffer = self.buffers.get(self.active_buffer)?;
+        let cursor = (buffer.cursor_y, buffer.cursor_x);
+        let (start, end) = (anchor.min(cursor), anchor.max(cursor));
+        if self.mode == Mode::VisualLine {
+            // Whole closed folds, as they show as one line
+            return Some(((buffer.fold_start(start.0), start.1), (buffer.fold_end(end.0), end.1)));
+        }
+        Some((start, end))
+    }
+
+    /// Character columns covered by a block selection, end exclusive
+    fn visual_block_columns(&self) -> Option<(usize, usize)> {
+        let anchor = self.visual_anchor?;
+        let buffer = self.buffers.get(self.active_buffer)?;
+        let char_col = |(row, col): (usize, usize)| {
+            buffer.document.lines.get(row)
+                .map_or(0, |line| line[..line.floor_char_boundary(col)].chars().count())
+        };
+        let (a, b) = (char_col(anchor), char_col((buffer.cursor_y, buffer.cursor_x)));
+        Some((a.min(b), a.max(b) + 1))
+    }
+
+    /// Byte range of `line` (at `row`) covered by the visual selection
+    fn selection_in_line(&self, row: usize, line: &str) -> Option<Range<usize>> {
+        let ((start_row, start_col), (end_row, end_col)) = self.visual_bounds()?;
+        if row < start_row || row > end_row {
+            return None;
+        }
+        match self.mode {
+            Mode::VisualLine => Some(0..line.len()),
+            Mode::VisualBlock => {
+                let (first, last) = self.visual_block_columns()?;
+                Some(buffer::char_range_to_bytes(line, first, last))
+            },
+            Mode::Visual => {
+                let start = if row == start_row { start_col.min(line.len()) } else { 0 };
+                let end = if row == end_row { char_end(line, end_col) } else { line.len() };
+                Some(start..end.max(start))
+            },
+            _ => None,
+        }
+    }
+
+    /// Copy the selection into the yank register and leave visual mode
+    fn yank_selection(&mut self) -> Result<()> {
+        let Some(((start_row, start_col), (end_row, end_col))) = self.visual_bounds() else {
+            return Ok(());
+        };
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return Ok(());
+        };
+        let lines = &buffer.document.lines;
+        let last_row = end_row.min(lines.len().saturating_sub(1));
+        let text = match self.mode {
+            Mode::VisualLine => {
+                let mut text = lines[start_row..=last_row].join("\n");
+                text.push('\n');
+                text
+            },
+            Mode::VisualBlock => {
+                let (first, last) = self.visual_block_columns().unwrap_or((0, 0));
+                lines[start_row..=last_row].iter()
+                    .map(|line| &line[buffer::char_range_to_bytes(line, first, last)])
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            },
+            _ => {
+                let end = lines.get(end_row).map_or(end_col, |line| char_end(line, end_col));
+                buffer.document.text_in_range((start_row, start_col), (end_row, end))
+            },
+        };
+        let rows = last_row + 1 - start_row;
+        self.message = (rows > 2).then(|| format!("{} lines yanked", rows));
+        self.yank_text(text);
+        self.exit_visual();
+        self.move_cursor_to_change((start_row, start_col));
+        Ok(())
+    }
+
+    /// Delete the selection into the yank register and leave visual mode
+    fn delete_selection(&mut self) -> Result<()> {
+        let Some(((start_row, start_col), (end_row, end_col))) = self.visual_bounds() else {
+            return Ok(());
+        };
+        let block = self.visual_block_columns();
+        let mode = self.mode;
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return Ok(());
+        };
+        let document = &mut buffer.document;
+        let (text, cursor) = match mode {
+            Mode::VisualLine => {
+                let mut text = document.delete_lines(start_row, end_row).join("\n");
+                text.push('\n');
+                let row = start_row.min(document.lines.len().saturating_sub(1));
+                (text, (row, 0))
+            },
+            Mode::VisualBlock => {
+                let (first, last) = block.unwrap_or((0, 0));
+                let text = document.delete_block(start_row, end_row, first, last).join("\n");
+                let col = document.lines.get(start_row)
+                    .map_or(0, |line| buffer::char_range_to_bytes(line, first, first).start);
+                (text, (start_row, col))
+            },
+            _ => {
+                let end = document.lines.get(end_row).map_or(end_col, |line| char_end(line, end_col));
+                (document.delete_range((start_row, start_col), (end_row, end)), (start_row, start_col))
+            },
+        };
+        self.registers.insert(UNNAMED_REGISTER, text);
+        self.exit_visual();
+        self.move_cursor_to_change(cursor);
+        self.clamp_cursor();
+        Ok(())
+    }
+
+    /// Shift the selected lines one indent level right or left and leave visual mode
+    fn indent_selection(&mut self, right: bool) -> Result<()> {
+        let Some(((start_row, _), (end_row, _))) = self.visual_bounds() else {
+            return Ok(());
+        };
+        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
+            if right {
+                buffer.document.indent_lines(start_row, end_row);
+            } else {
+                buffer.document.unindent_lines(start_row, end_row);
+            }
+        }
+        self.exit_visual();
+        self.move_cursor_to_change((start_row, 0));
+        Ok(())
+    }
+
+    /// `>>` / `<<`: shift `count` lines from the cursor one indent level right or
+    /// left and put the cursor on the first non-blank of the first one
+    fn indent_current_lines(&mut self, right: bool, count: usize) {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return;
+        };
+        let (row, last_row) = buffer.fold_span(buffer.cursor_y, count.max(1));
+        if right {
+            buffer.document.indent_lines(row, last_row);
+        } else {
+            buffer.document.unindent_lines(row, last_row);
+        }
+        let line = buffer.document.lines.get(row).map_or("", String::as_str);
+        let col = line.len() - line.trim_start().len();
+        self.move_cursor_to_change((row, col));
+    }
+
+    /// `o` / `O`: open a line below or above the cursor line, indented like
+    /// Enter would, and start Insert mode on it
+    fn open_line(&mut self, below: bool) {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return;
+        };
+        let row = buffer.cursor_y;
+        let indent = buffer.document.open_line_indent(row, below);
+        let new_row = if below { row + 1 } else { row };
+        let col = indent.len();
+        buffer.document.insert_line_at(new_row, indent);
+        self.move_cursor_to_change((new_row, col));
+        self.mode = Mode::Insert;
+    }
+
+    /// Start Insert mode: `i` at the cursor, `I` at the first non-blank,
+    /// `a` after the cursor and `A` at the end of the line
+    fn enter_insert(&mut self, key: char) {
+        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
+            let line = buffer.document.lines.get(buffer.cursor_y).map_or("", String::as_str);
+            buffer.cursor_x = match key {
+                'I' => line.len() - line.trim_start().len(),
+                // Past the character under the cursor, even when the cursor is inside it
+                'a' => line.ceil_char_boundary(buffer.cursor_x + 1),
+                'A' => line.len(),
+                _ => buffer.cursor_x,
+            };
+        }
+        self.mode = Mode::Insert;
+    }
+
+    /// Keep the cursor on an existing line and column after text was removed
+    fn clamp_cursor(&mut self) {
+        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
+            let lines = &buffer.document.lines;
+            buffer.cursor_y = buffer.cursor_y.min(lines.len().saturating_sub(1));
+            let len = lines.get(buffer.cursor_y).map_or(0, |line| line.len());
+            buffer.cursor_x = buffer.cursor_x.min(len);
+            self.cursor_y = buffer.cursor_y;
+            self.cursor_x = buffer.cursor_x;
+        }
+    }
+
+    /// Keys of the start screen: a letter opens that recent file, Enter or Esc
+    /// leaves the empty buffer and `:` opens the command line
+    fn process_welcome_mode(&mut self, key: KeyEvent) -> Result<()> {
+        match key.code {
+            KeyCode::Char(c @ 'a'..='z') => {
+                let Some(file) = self.recent_files.get(c as usize - 'a' as usize).cloned() else {
+                    return Ok(());
+                };
+                self.mode = Mode::Normal;
+                let name = session::display_name(&file);
+                if let Err(e) = self.open_file(&name) {
+                    self.message = Some(format!("Cannot open {}: {}", name, e));
+                }
+            },
+            KeyCode::Enter | KeyCode::Esc => self.mode = Mode::Normal,
+            KeyCode::Char(':') => {
+                self.command_line.clear();
+                self.mode = Mode::Command;
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn process_command_mode(&mut self, key: KeyEvent) -> Result<()> {
+        if !matches!(key.code, KeyCode::Tab | KeyCode::BackTab) {
+            self.completion = None;
+        }
+        let prompting = self.file_tree_prompt.is_some() || self.rename_prompt.is_some();
+        match key.code {
+            KeyCode::Tab if !prompting => self.complete_command_line(true),
+            KeyCode::BackTab if !prompting => self.complete_command_line(false),
+            KeyCode::Esc if self.file_tree_prompt.take().is_some() => self.mode = Mode::FileTree,
+            KeyCode::Esc if self.rename_prompt.take().is_some() => {
+                self.command_line.clear();
+                self.mode = Mode::Normal;
+            },
+            KeyCode::Esc => {
+                self.command_history_pos = self.command_history.len();
+                self.visual_selection = None;
+                self.mode = Mode::Normal;
+            },
+            KeyCode::Up if !prompting && self.command_history_pos > 0 => {
+                self.command_history_pos -= 1;
+                self.command_line = self.command_history[self.command_history_pos].clone();
+            },
+            KeyCode::Down if !prompting && self.command_history_pos < self.command_history.len() => {
+                self.command_history_pos += 1;
+                // Past the newest entry the line is empty again
+                self.command_line = self.command_history.get(self.command_history_pos).cloned().unwrap_or_default();
+            },
+            KeyCode::Enter if self.file_tree_prompt.is_some() => {
+                if let Some(prompt) = self.file_tree_prompt.take() {
+                    self.answer_file_tree_prompt(prompt);
+                }
+                self.mode = Mode::FileTree;
+            },
+            KeyCode::Enter if self.rename_prompt.is_some() => {
+                self.rename_prompt = None;
+                let new_name = std::mem::take(&mut self.command_line).trim().to_string();
+                self.mode = Mode::Normal;
+                if !new_name.is_empty() {
+                    self.request_rename(&new_name)?;
+                }
+            },
+            KeyCode::Enter => {
+                let command = self.command_line.trim().to_string();
+                self.command_history_pos = self.command_history.len();
+                self.execute_command()?;
+                self.add_command_history(command);
+                // execute_command might change the mode (e.g. to Help)
+                // so only switch to Normal if not already changed.
+                if self.mode == Mode::Command {
+                    self.mode = Mode::Normal;
+                }
+            },
+            KeyCode::Backspace => {
+                self.command_line.pop();
+            },
+            KeyCode::Char(c) => {
+                self.command_line.push(c);
+            },
+            _ => {}
+        }
+        
+        Ok(())
+    }
+    
+    fn process_file_tree_mode(&mut self, key: KeyEvent) -> Result<()> {
+        if let Some(tree) = &mut self.file_tree {
+            match key.code {
+                KeyCode::Esc | KeyCode::Char('q') => { // Added 'q' to close file tree
+                    tree.toggle_visible();
+                    self.mode = self.previous_mode;
+                },
+                KeyCode::Char('j') => {
+                    tree.move_cursor_down();
+                },
+                KeyCode::Char('k') => {
+                    tree.move_cursor_up();
+                },
+                KeyCode::Enter | KeyCode::Char('l') => {
+                    // Store values for later use to avoid borrow conflicts
+                    let opt_path_result = if let Some(path) = tree.get_selected_path() {
+                        if path.is_dir() {
+                            tree.toggle_expand()?;
+                            None
+                        } else {
+                            Some(path)
+                        }
+                    } else {
+                        None
+                    };
+                    
+                    // Now handle file opening if needed
+                    if let Some(path) = opt_path_result {
+                        // Open the selected file
+                        match Buffer::from_file(path.to_str().unwrap()) {
+                            Ok(buffer) => {
+                                if !self.buffers.is_empty() {
+                                    self.buffers[self.active_buffer] = buffer;
+                                } else {
+                                    self.buffers.push(buffer);
+                                    self.active_buffer = 0;
+                                }
+                                tree.toggle_visible();
+                                self.mode = self.previous_mode;
+                            },
+                            Err(e) => {
+                                return Err(e);
+                            }
+                        }
+                    }
+                },
+                KeyCode::Char('H') => {
+                    let mut state = self.api_state.lock().unwrap();
+                    state.options.file_tree_show_hidden = !state.options.file_tree_show_hidden;
+                    tree.show_hidden = state.options.file_tree_show_hidden;
+                    tree.refresh()?;
+                },
+                KeyCode::Char('a') => {
+                    self.file_tree_prompt = Some(FileTreePrompt::Create(tree.selected_directory()));
+                    self.command_line.clear();
+                    self.mode = Mode::Command;
+                },
+                KeyCode::Char('r') => {
+                    if let Some(path) = tree.get_selected_path() {
+                        // Start from the current name so small edits are easy
+                        self.command_line = path.file_name()
+                            .map_or_else(String::new, |name| name.to_string_lossy().to_string());
+                        self.file_tree_prompt = Some(FileTreePrompt::Rename(path));
+                        self.mode = Mode::Command;
+                    }
+                },
+                KeyCode::Char('d') => {
+                    if let Some(path) = tree.get_selected_path() {
+                        self.file_tree_prompt = Some(FileTreePrompt::Delete(path));
+                        self.command_line.clear();
+                        self.mode = Mode::Command;
+                    }
+                },
+                KeyCode::Char('h') => {
+                    // First get the required information
+                    let (is_dir, is_expanded, path_clone) = if let Some(path) = tree.get_selected_path() {
+                        let is_expanded = tree.is_directory_expanded(path.clone());
+                        (path.is_dir(), is_expanded, path)
+                    } else {
+                        (false, false, PathBuf::new())
+                    };
+                    
+                    // Then perform the actions
+                    if is_dir && is_expanded {
+                        // If directory is expanded, collapse it
+                        tree.toggle_expand()?;
+                    } else {
+                        // Otherwise move to parent directory if possible
+                        tree.move_to_parent()?;
+                    }
+                },
+                // < / >: narrow or widen the tree
+                KeyCode::Char('<') => tree.set_width(tree.width.saturating_sub(FILE_TREE_RESIZE_STEP), self.terminal_width),
+                KeyCode::Char('>') => tree.set_width(tree.width + FILE_TREE_RESIZE_STEP, self.terminal_width),
+                _ => {}
+            }
+        }
+        // Closing or resizing the tree changes the room the windows have
+        self.fit_windows();
+        
+        Ok(())
+    }
+    
+    /// Complete the command name, or the file name after commands such as `:e`
+    /// (fuzzy matched under the file tree root), cycling through the matches on
+    /// each Tab, backwards with Shift+Tab
+    fn complete_command_line(&mut self, forward: bool) {
+        if let Some(completion) = &mut self.completion {
+            let len = completion.candidates.len();
+            completion.index = if forward { (completion.index + 1) % len } else { (completion.index + len - 1) % len };
+        } else {
+            let typed = self.command_line.clone();
+            let (command, candidates) = match typed.split_once(' ') {
+                None => {
+                    let names = COMMAND_NAMES.iter()
+                        .filter(|name| name.starts_with(typed.as_str()))
+                        .map(|name| name.to_string())
+                        .collect();
+                    (String::new(), names)
+                },
+                Some((command, query)) if takes_file_argument(command) => {
+                    let root = match &self.file_tree {
+                        Some(tree) => tree.root.clone(),
+                        None => env::current_dir().unwrap_or_default(),
+                    };
+                    (command.to_string(), complete_paths(&root, query.trim()))
+                },
+                Some(_) => return,
+            };
+            if candidates.is_empty() {
+                self.message = Some("E480: No match".to_string());
+                return;
+            }
+            let index = if forward { 0 } else { candidates.len() - 1 };
+            self.completion = Some(Completion { command, candidates, index, typed });
+        }
+        if let Some(completion) = &self.completion {
+            self.command_line = completion.line();
+        }
+    }
+
+    /// Remember an executed command, moving a repeated one to the end, and save the history
+    fn add_command_history(&mut self, command: String) {
+        if command.is_empty() {
+            return;
+        }
+        self.command_history.retain(|old| *old != command);
+        self.command_history.push(command);
+        if self.command_history.len() > MAX_COMMAND_HISTORY {
+            let excess = self.command_history.len() - MAX_COMMAND_HISTORY;
+            self.command_history.drain(..excess);
+        }
+        self.command_history_pos = self.command_history.len();
+        if let Err(e) = write_command_history(&self.command_history) {
+            error!("Failed to save command history: {}", e);
+        }
+    }
+
+    /// `:e[dit][!] [file]`: show a file in the current tab, or reload the current
+    /// one. Unsaved changes are only thrown away with `!`
+    fn edit_file(&mut self, file: Option<&str>, force: bool) -> Result<()> {
+        let current = self.buffers.get(self.active_buffer);
+        let Some(filename) = file.map(str::to_string).or_else(|| current.and_then(|b| b.filename.clone())) else {
+            self.message = Some("E32: No file name".to_string());
+            return Ok(());
+        };
+        if current.is_some_and(|b| b.document.modified) && !force {
+            self.message = Some("E37: No write since last change (add ! to override)".to_string());
+            return Ok(());
+        }
+        // A file shown in another tab is switched to rather than opened twice
+        if let Some(idx) = self.tab_manager.tab_index(&filename)
+            && idx != self.tab_manager.current_tab()
+        {
+            self.tab_manager.switch_to_tab(idx)?;
+            return self.show_current_tab();
+        }
+
+        let exists = Path::new(&filename).exists();
+        let mut buffer = if exists {
+            match Buffer::from_file(&filename) {
+                Ok(buffer) => buffer,
+                Err(e) => {
+                    self.message = Some(format!("\"{}\" {}", filename, e));
+                    return Ok(());
+                },
+            }
+        } else {
+            let mut buffer = Buffer::new();
+            buffer.filename = Some(filename.clone());
+            buffer.document.filename = Some(filename.clone());
+            buffer
+        };
+        self.restore_undo_history(&mut buffer);
+        self.message = Some(if exists {
+            format!("\"{}\" {}L", filename, buffer.document.lines.len())
+        } else {
+            format!("\"{}\" [New]", filename)
+        });
+        self.tab_manager.replace_current(filename.clone(), buffer.id)?;
+        match self.buffers.get_mut(self.active_buffer) {
+            Some(slot) => *slot = buffer,
+            None => {
+                self.buffers.push(buffer);
+                self.active_buffer = self.buffers.len() - 1;
+            },
+        }
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+        if exists {
+            self.fire_autocmd("BufRead", Some(filename), &[]);
+        }
+        Ok(())
+    }
+
+    /// Carry out a file tree operation with the text typed at its prompt
+    fn answer_file_tree_prompt(&mut self, prompt: FileTreePrompt) {
+        let Some(tree) = &mut self.file_tree else {
+            return;
+        };
+        let input = self.command_line.trim().to_string();
+        self.command_line.clear();
+        let result = match prompt {
+            FileTreePrompt::Create(_) | FileTreePrompt::Rename(_) if input.is_empty() => return,
+            FileTreePrompt::Create(dir) => tree.create_entry(&dir, &input)
+                .map(|path| format!("Created {}", path.display())),
+            FileTreePrompt::Rename(path) => tree.rename_entry(&path, &input).map(|target| {
+                // Keep open buffers pointing at the file
+                let (old, new) = (path.to_string_lossy().to_string(), target.to_string_lossy().to_string());
+                for buffer in &mut self.buffers {
+                    if buffer.filename.as_deref() == Some(old.as_str()) {
+                        buffer.filename = Some(new.clone());
+                        buffer.document.filename = Some(new.clone());
+                    }
+                }
+                format!("Renamed to {}", target.display())
+            }),
+            FileTreePrompt::Delete(path) if input.starts_with(['y', 'Y']) => tree.delete_entry(&path)
+                .map(|()| format!("Deleted {}", path.display())),
+            FileTreePrompt::Delete(_) => return,
+        };
+        self.message = Some(match result {
+            Ok(message) => message,
+            Err(e) => e.to_string(),
+        });
+    }
+
+    fn process_second_key(&mut self, prefix: char, key: KeyEvent) -> Result<()> {
+        match prefix {
+            'g' => return self.process_g_command(key),
+            'z' => return self.process_z_command(key),
+            'd' => return self.process_operator(Operator::Delete, key),
+            'c' => return self.process_operator(Operator::Change, key),
+            'y' => return self.process_operator(Operator::Yank, key),
+            'q' => {
+                self.start_recording(key);
+                return Ok(());
+            },
+            '@' => return self.play_macro(key),
+            // >> / <<: shift lines one indent level
+            '>' | '<' => {
+                let count = self.pending_count.take().unwrap_or(1);
+                if key.code == KeyCode::Char(prefix) {
+                    self.indent_current_lines(prefix == '>', count);
+                }
+                return Ok(());
+            },
+            'm' => {
+                if let KeyCode::Char(c) = key.code {
+                    self.set_mark_at_cursor(c);
+                }
+                return Ok(());
+            },
+            // 'a jumps to the mark's line, `a to its exact position
+            '\'' | '`' => {
+                if let KeyCode::Char(c) = key.code {
+                    self.jump_to_mark(c, prefix == '`');
+                }
+                return Ok(());
+            },
+            // ]q / [q: next/previous quickfix entry, ]c / [c: diff hunk, ]h / [h: git hunk
+            ']' | '[' => {
+                match key.code {
+                    KeyCode::Char('q') => self.quickfix_step(prefix == ']'),
+                    KeyCode::Char('c') => self.diff_hunk_step(prefix == ']'),
+                    KeyCode::Char('h') => self.git_hunk_step(prefix == ']'),
+                    _ => {},
+                }
+                return Ok(());
+            },
+            _ => {},
+        }
+
+        if prefix == ' ' && self.leader_keys.is_empty()
+            && let KeyCode::Char(c) = key.code
+            && self.leader_mappings.lock().unwrap().iter().any(|mapping| mapping.key == c)
+        {
+            return self.run_leader_mapping(c);
+        }
+        // Space bindings of more than one key: wait while the keys start a longer
+        // one, and drop keys that match no binding
+        if prefix == ' ' && let KeyCode::Char(c) = key.code {
+            let mut keys = std::mem::take(&mut self.leader_keys);
+            keys.push(c);
+            if LEADER_BINDINGS.iter().any(|(binding, _)| binding.len() > keys.len() && binding.starts_with(&keys)) {
+                self.leader_keys = keys;
+                self.pending_prefix = Some(' ');
+                self.which_key_since = Some(Instant::now());
+                return Ok(());
+            }
+            if keys.len() > 1 {
+                return self.run_leader_keys(&keys);
+            }
+        } else if prefix == ' ' && !self.leader_keys.is_empty() {
+            self.leader_keys.clear();
+            return Ok(());
+        }
+        match key.code {
+            KeyCode::Char('e') => {
+                if let Some(tree) = &mut self.file_tree {
+                    tree.toggle_visible();
+                    if tree.visible {
+                        self.previous_mode = self.mode;
+                        self.mode = Mode::FileTree;
+                    } else {
+                        self.mode = self.previous_mode;
+                    }
+                }
+                self.fit_windows();
+                Ok(())
+            },
+            KeyCode::Char('s') => self.split_window(SplitType::Horizontal),
+            KeyCode::Char('v') => self.split_window(SplitType::Vertical),
+            KeyCode::Char('q') => {
+                self.close_window()
+            },
+            KeyCode::Char('x') => {
+                self.close_current_buffer()
+            },
+            KeyCode::Char('b') => {
+                self.toggle_git_blame();
+                Ok(())
+            },
+            KeyCode::Char('m') => {
+                let mut state = self.api_state.lock().unwrap();
+                state.options.minimap = !state.options.minimap;
+                drop(state);
+                self.sync_api_state();
+                Ok(())
+            },
+            KeyCode::Char('a') => self.show_code_actions(),
+            KeyCode::Tab => {
+                self.tab_manager.switch_to_next_tab()?;
+                self.show_current_tab()
+            },
+            KeyCode::BackTab => {
+                self.tab_manager.switch_to_prev_tab()?;
+                self.show_current_tab()
+            },
+            KeyCode::Char('/') => {
+                self.start_search(true, true);
+                Ok(())
+            },
+            KeyCode::Char('f') => self.open_file_picker(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run the built-in Space binding `keys` that is longer than one key, or that
+    /// starts a longer one
+    fn run_leader_keys(&mut self, keys: &str) -> Result<()> {
+        match keys {
+            "h" => self.open_shell(true),
+            "hs" => self.stage_git_hunk(),
+            "hr" => self.reset_git_hunk(),
+            "w" => self.cycle_window(),
+            "ws" => self.workspace_symbol_search(""),
+            "rn" => self.rename_symbol(),
+            "db" => self.toggle_breakpoint(),
+            "tf" => self.format_table_at_cursor().or_else(|e| {
+                self.notify(e.to_string(), NOTIFICATION_TIME);
+                Ok(())
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run the `rvim.map` mapping for Space+`key`
+    fn run_leader_mapping(&mut self, key: char) -> Result<()> {
+        let mappings = self.leader_mappings.clone();
+        let mappings = mappings.lock().unwrap();
+        let Some(mapping) = mappings.iter().find(|mapping| mapping.key == key) else {
+            return Ok(());
+        };
+        match &mapping.action {
+            MapAction::Command(command) => {
+                self.command_line = command.clone();
+                drop(mappings);
+                self.execute_command()
+            },
+            MapAction::Keys(keys) => {
+                let keys = keys.clone();
+                drop(mappings);
+                for c in keys.chars() {
+                    self.handle_key(KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE))?;
+                }
+                Ok(())
+            },
+            MapAction::Lua(callback) => {
+                // Unlocked while it runs, so the function may call rvim.map
+                let callback = self.lua.registry_value::<mlua::Function>(callback);
+                drop(mappings);
+                if let Err(e) = callback.and_then(|callback| callback.call::<_, ()>(())) {
+                    self.notify_level(NotificationLevel::Error, format!("Mapping <Space>{} failed: {}", key, e), NOTIFICATION_TIME);
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// Collect the built-in and `rvim.map` Space bindings that continue the keys
+    /// typed so far, without those keys, for the which-key popup
+    fn refresh_leader_bindings(&mut self) {
+        let mappings = self.leader_mappings.lock().unwrap();
+        let typed = self.leader_keys.as_str();
+        let mut bindings: Vec<(String, String)> = LEADER_BINDINGS.iter()
+            .filter(|(keys, _)| keys.len() > typed.len() && keys.starts_with(typed))
+            .filter(|(keys, _)| !mappings.iter().any(|mapping| keys.starts_with(mapping.key)))
+            .map(|(keys, desc)| (keys[typed.len()..].to_string(), desc.to_string()))
+            .collect();
+        if typed.is_empty() {
+            bindings.extend(mappings.iter().map(|mapping| (mapping.key.to_string(), mapping.desc.clone())));
+        }
+        bindings.sort();
+        self.leader_bindings = bindings;
+    }
+
+    /// The Space bindings in columns, in a box right above the status line
+    fn draw_which_key_popup(&self) -> Result<()> {
+        let entries: Vec<String> = self.leader_bindings.iter()
+            .map(|(key, desc)| format!("{} → {}", key, desc))
+            .collect();
+        let inner = self.terminal_width.saturating_sub(2);
+        let column_width = entries.iter().map(|entry| entry.chars().count() + 2).max().unwrap_or(1).min(inner.max(1));
+        let columns = (inner / column_width).max(1);
+        let rows = entries.len().div_ceil(columns);
+        // Status line, message line and the two borders
+        let top = self.terminal_height.saturating_sub(rows + 4);
+
+        execute!(io::stdout(), cursor::MoveTo(0, top as u16), SetForegroundColor(Color::Cyan))?;
+        let title = if self.leader_keys.is_empty() {
+            " Space ".to_string()
+        } else {
+            format!(" Space {} ", self.leader_keys)
+        };
+        print!("┌{}{}┐", title, "─".repeat(inner.saturating_sub(title.len())));
+        for row in 0..rows {
+            execute!(io::stdout(), cursor::MoveTo(0, (top + 1 + row) as u16), SetForegroundColor(Color::Cyan))?;
+            print!("│");
+            execute!(io::stdout(), ResetColor)?;
+            let line: String = (0..columns)
+                .filter_map(|column| entries.get(column * rows + row))
+                .map(|entry| format!("{:<width$}", entry, width = column_width))
+                .collect();
+            let line: String = line.chars().take(inner).collect();
+            print!("{:<width$}", line, width = inner);
+            execute!(io::stdout(), SetForegroundColor(Color::Cyan))?;
+            print!("│");
+        }
+        execute!(io::stdout(), cursor::MoveTo(0, (top + 1 + rows) as u16))?;
+        print!("└{}┘", "─".repeat(inner));
+        execute!(io::stdout(), ResetColor)?;
+        Ok(())
+    }
+
+    /// Run a motion or edit `count` times, once without a count
+    fn repeat_motion(&mut self, count: Option<usize>, motion: fn(&mut Self) -> Result<()>) -> Result<()> {
+        for _ in 0..count.unwrap_or(1) {
+            motion(self)?;
+        }
+        Ok(())
+    }
+
+    // q{register}: record keys into a register; an uppercase register appends to
+    // its lowercase one
+    fn start_recording(&mut self, key: KeyEvent) {
+        let KeyCode::Char(c) = key.code else {
+            return;
+        };
+        if !c.is_ascii_alphabetic() {
+            return;
+        }
+        let register = c.to_ascii_lowercase();
+        if c.is_ascii_lowercase() {
+            self.macros.insert(register, Vec::new());
+        }
+        self.recording_macro = Some(register);
+    }
+
+    // @{register} plays a macro `count` times, @@ the last one played
+    fn play_macro(&mut self, key: KeyEvent) -> Result<()> {
+        let count = self.pending_count.take().unwrap_or(1);
+        let register = match key.code {
+            KeyCode::Char('@') => match self.last_macro {
+                Some(register) => register,
+                None => {
+                    self.message = Some("E748: No previously used register".to_string());
+                    return Ok(());
+                },
+            },
+            KeyCode::Char(c) if c.is_ascii_alphabetic() => c.to_ascii_lowercase(),
+            _ => return Ok(()),
+        };
+        let Some(keys) = self.macros.get(&register).cloned() else {
+            return Ok(());
+        };
+        self.last_macro = Some(register);
+        if self.macro_depth >= MAX_MACRO_DEPTH {
+            self.message = Some("E132: Macro nested too deeply".to_string());
+            return Ok(());
+        }
+        self.macro_depth += 1;
+        let mut result = Ok(());
+        'play: for _ in 0..count {
+            for key in &keys {
+                result = self.handle_key(*key);
+                if result.is_err() || self.quit {
+                    break 'play;
+                }
+            }
+        }
+        self.macro_depth -= 1;
+        result
+    }
+
+    /// Whether Normal mode is waiting for the first key of a command
+    fn waiting_for_command(&self) -> bool {
+        self.mode == Mode::Normal && self.pending_prefix.is_none() && self.pending_count.is_none()
+    }
+
+    fn buffer_version(&self) -> Option<(usize, u64)> {
+        self.buffers.get(self.active_buffer).map(|buffer| (buffer.id, buffer.document.version))
+    }
+
+    /// Once a command is complete, keep its keys for `.` if they changed the text.
+    /// An insert counts from the key that started it through `Esc`
+    fn record_change(&mut self) {
+        if !self.waiting_for_command() {
+            return;
+        }
+        let keys = std::mem::take(&mut self.change_keys);
+        let count = self.change_count.take();
+        let changed = matches!((self.change_start, self.buffer_version()),
+            (Some((start_id, start)), Some((id, version))) if start_id == id && start != version);
+        // Undo, redo, ex commands and macros change text but are not repeated
+        let repeatable = keys.first().is_some_and(|key| match key.code {
+            KeyCode::Char('r') => !key.modifiers.contains(KeyModifiers::CONTROL),
+            KeyCode::Char(c) => !matches!(c, 'u' | ':' | '.' | '@' | 'q'),
+            _ => true,
+        });
+        if changed && repeatable {
+            self.last_change = Some((count, keys));
+        }
+    }
+
+    /// `.`: replay the keys of the last change, with `count` in place of the
+    /// count it was typed with
+    fn repeat_last_change(&mut self, count: Option<usize>) -> Result<()> {
+        let Some((recorded, keys)) = self.last_change.clone() else {
+            return Ok(());
+        };
+        self.pending_count = count.or(recorded);
+        for key in &keys {
+            self.handle_key(*key)?;
+            if self.quit {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// The key after `d` or `c`: the operator again for whole lines, a word motion
+    /// or `i`/`a` and then a text object
+    fn process_operator(&mut self, operator: Operator, key: KeyEvent) -> Result<()> {
+        let count = self.pending_count.take();
+        if let Some(scope) = self.pending_text_object.take() {
+            let range = match key.code {
+                KeyCode::Char(c) => TextObject::from_key(scope == 'i', c)
+                    .and_then(|object| self.text_object_range(object)),
+                _ => None,
+            };
+            return match range {
+                Some((start_row, start_col, end_row, end_col)) => {
+                    self.apply_operator(operator, (start_row, start_col), (end_row, end_col));
+                    Ok(())
+                },
+                None => Ok(()),
+            };
+        }
+        match (operator, key.code) {
+            (Operator::Delete, KeyCode::Char('d')) => self.delete_current_line(count.unwrap_or(1)),
+            (Operator::Delete, KeyCode::Char('p')) => {
+                self.diff_put();
+                Ok(())
+            },
+            (Operator::Change, KeyCode::Char('c')) => {
+                self.change_current_lines(count.unwrap_or(1));
+                Ok(())
+            },
+            (Operator::Yank, KeyCode::Char('y')) => self.yank_current_lines(count.unwrap_or(1)),
+            (_, KeyCode::Char(scope @ ('i' | 'a'))) => {
+                self.pending_count = count;
+                self.pending_text_object = Some(scope);
+                self.pending_prefix = Some(operator.key());
+                Ok(())
+            },
+            // A count after the operator, e.g. d2w
+            (_, KeyCode::Char(digit @ '0'..='9')) if digit != '0' || count.is_some() => {
+                self.pending_count = Some(count.unwrap_or(0) * 10 + digit.to_digit(10).unwrap_or(0) as usize);
+                self.pending_prefix = Some(operator.key());
+                Ok(())
+            },
+            (_, KeyCode::Char(motion @ ('w' | 'e' | 'b'))) => {
+                if let Some((start, end)) = self.word_motion_range(operator, motion, count.unwrap_or(1)) {
+                    self.apply_operator(operator, start, end);
+                }
+                Ok(())
+            },
+            // Anything else cancels the operator
+            _ => Ok(()),
+        }
+    }
+
+    /// Start and end (row, col) of a text object around the cursor; the end is exclusive
+    fn text_object_range(&self, kind: TextObject) -> Option<(usize, usize, usize, usize)> {
+        let buffer = self.buffers.get(self.active_buffer)?;
+        kind.range(&buffer.document.lines, buffer.cursor_y, buffer.cursor_x)
+    }
+
+    /// Text an operator with `w`, `e` or `b` covers, from where the motion moves
+    /// the cursor `count` times. As in Vim, `cw` on a word changes to its end and
+    /// `w` stops at the end of the line
+    fn word_motion_range(&mut self, operator: Operator, motion: char, count: usize) -> Option<((usize, usize), (usize, usize))> {
+        let buffer = self.buffers.get(self.active_buffer)?;
+        let (row, col) = (buffer.cursor_y, buffer.cursor_x);
+        let line = buffer.document.lines.get(row)?;
+        let on_word = line.get(col..).and_then(|rest| rest.chars().next()).is_some_and(|c| !c.is_whitespace());
+        let mut steps = count.max(1);
+        let motion = if motion == 'w' && operator == Operator::Change && on_word {
+            // The first word ends where its run of characters does, even when the
+            // cursor is already on its last character
+            let (_, _, _, end) = TextObject::Word { inner: true }.range(&buffer.document.lines, row, col)?;
+            let last = line[..end].chars().next_back().map_or(0, char::len_utf8);
+            self.buffers[self.active_buffer].cursor_x = end - last;
+            steps -= 1;
+            'e'
+        } else {
+            motion
+        };
+        let step: fn(&mut Self) -> Result<()> = match motion {
+            'w' => Self::move_to_next_word_start,
+            'e' => Self::move_to_next_word_end,
+            _ => Self::move_to_prev_word_start,
+        };
+        for _ in 0..steps {
+            step(self).ok()?;
+        }
+        let buffer = self.buffers.get_mut(self.active_buffer)?;
+        let target = (buffer.cursor_y, buffer.cursor_x);
+        buffer.cursor_y = row;
+        buffer.cursor_x = col;
+        let lines = &buffer.document.lines;
+        let range = match motion {
+            'b' => (target, (row, col)),
+            'e' => ((row, col), (target.0, lines.get(target.0).map_or(target.1, |line| char_end(line, target.1)))),
+            _ if target.0 > row => ((row, col), (row, lines[row].len())),
+            _ => ((row, col), target),
+        };
+        (range.0 != range.1).then_some(range)
+    }
+
+    /// Delete the text from `start` up to `end` into the unnamed register; for
+    /// Change continue in Insert mode where it was. Yank copies it instead
+    fn apply_operator(&mut self, operator: Operator, start: (usize, usize), end: (usize, usize)) {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return;
+        };
+        if operator == Operator::Yank {
+            let text = buffer.document.text_in_range(start, end);
+            self.yank_text(text);
+            self.move_cursor_to_change(start);
+            return;
+        }
+        let text = buffer.document.delete_range(start, end);
+        if !text.is_empty() {
+            self.registers.insert(UNNAMED_REGISTER, text);
+        }
+        self.move_cursor_to_change(start);
+        match operator {
+            Operator::Delete | Operator::Yank => self.clamp_cursor(),
+            Operator::Change => self.mode = Mode::Insert,
+        }
+    }
+
+    /// `cc`: replace `count` lines with one empty line, keeping the first one's
+    /// indentation, and start inserting there
+    fn change_current_lines(&mut self, count: usize) {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return;
+        };
+        let lines = &buffer.document.lines;
+        let (row, last_row) = buffer.fold_span(buffer.cursor_y.min(lines.len().saturating_sub(1)), count.max(1));
+        let Some(changed) = lines.get(row..=last_row) else {
+            return;
+        };
+        let text = changed.join("\n") + "\n";
+        let indent = lines[row].len() - lines[row].trim_start().len();
+        let end = (last_row, lines[last_row].len());
+        buffer.document.delete_range((row, indent), end);
+        self.registers.insert(UNNAMED_REGISTER, text);
+        self.move_cursor_to_change((row, indent));
+        self.mode = Mode::Insert;
+    }
+
+    /// Fold commands: `zf{motion}` folds lines, `zo` / `zc` / `za` open, close
+    /// and toggle the fold at the cursor, `zR` / `zM` open and close all folds
+    fn process_z_command(&mut self, key: KeyEvent) -> Result<()> {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer).filter(|buffer| !buffer.is_shell) else {
+            return Ok(());
+        };
+        let row = buffer.cursor_y;
+        if std::mem::take(&mut self.pending_fold) {
+            let count = self.pending_count.take();
+            let last = buffer.document.lines.len().saturating_sub(1);
+            let end = match key.code {
+                KeyCode::Char(digit @ '0'..='9') if digit != '0' || count.is_some() => {
+                    self.pending_count = Some(count.unwrap_or(0) * 10 + digit.to_digit(10).unwrap_or(0) as usize);
+                    self.pending_fold = true;
+                    self.pending_prefix = Some('z');
+                    return Ok(());
+                },
+                KeyCode::Char('j') => buffer.fold_end(row) + count.unwrap_or(1),
+                KeyCode::Char('k') => row.saturating_sub(count.unwrap_or(1)),
+                // zfG folds to the last line, zf{count}G to line {count}
+                KeyCode::Char('G') => count.map_or(last, |n| n.saturating_sub(1)),
+                _ => return Ok(()),
+            };
+            self.create_fold(row, end.min(last));
+            return Ok(());
+        }
+        let changed = match key.code {
+            KeyCode::Char('f') => {
+                self.pending_fold = true;
+                self.pending_prefix = Some('z');
+                return Ok(());
+            },
+            KeyCode::Char('o') => buffer.open_fold(row),
+            KeyCode::Char('c') => buffer.close_fold(row),
+            KeyCode::Char('a') => buffer.open_fold(row) || buffer.close_fold(row),
+            KeyCode::Char(c @ ('R' | 'M')) => {
+                for fold in &mut buffer.document.folds {
+                    fold.closed = c == 'M';
+                }
+                true
+            },
+            // Any other key cancels
+            _ => true,
+        };
+        if !changed {
+            self.message = Some("E490: No fold found".to_string());
+        }
+        Ok(())
+    }
+
+    /// Fold lines `start..=end` of the active buffer, closed
+    fn create_fold(&mut self, start: usize, end: usize) {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return;
+        };
+        if !buffer.add_fold(start, end) {
+            self.message = Some("Folds can nest but not overlap".to_string());
+        }
+    }
+
+    fn process_g_command(&mut self, key: KeyEvent) -> Result<()> {
+        let count = self.pending_count.take();
+
+        let switched = match key.code {
+            // {count}gt goes to tab {count}, plain gt to the next tab
+            KeyCode::Char('t') => match count {
+                Some(n) if n == 0 || n > self.tab_manager.tab_list().len() => {
+                    self.message = Some("E16: Invalid range".to_string());
+                    return Ok(());
+                },
+                Some(n) => self.tab_manager.switch_to_tab(n - 1),
+                None => self.tab_manager.switch_to_next_tab(),
+            },
+            KeyCode::Char('T') => self.tab_manager.switch_to_prev_tab(),
+            KeyCode::Tab => self.tab_manager.switch_to_last_accessed(),
+            KeyCode::Char('d') => return self.go_to_definition(),
+            KeyCode::Char('j') => return self.repeat_motion(count, Self::move_down_g),
+            KeyCode::Char('k') => return self.repeat_motion(count, Self::move_up_g),
+            KeyCode::Char('g') => {
+                self.go_to_line(count, false);
+                return Ok(());
+            },
+            _ => return Ok(()),
+        };
+
+        match switched {
+            Ok(()) => self.show_current_tab(),
+            Err(e) => {
+                self.message = Some(e.to_string());
+                Ok(())
+            },
+        }
+    }
+
+    /// `gg` / `G`: go to line `count` (1-based), or without a count to the first
+    /// line, or the last with `to_end`. The position left goes on the jump list
+    fn go_to_line(&mut self, count: Option<usize>, to_end: bool) {
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return;
+        };
+        let last = buffer.document.lines.len().saturating_sub(1);
+        let row = match count {
+            Some(n) => n.saturating_sub(1).min(last),
+            None if to_end => last,
+            None => 0,
+        };
+        self.push_jump();
+        self.move_cursor_to_change((row, 0));
+        self.scroll_cursor_into_view();
+    }
+
+    /// Space+b: show or hide who last changed each line
+    fn toggle_git_blame(&mut self) {
+        self.show_git_blame = !self.show_git_blame;
+        self.blame_data.clear();
+        self.blame_buffer = None;
+        if self.show_git_blame {
+            self.refresh_blame();
+        }
+    }
+
+    /// Read the blame for the active buffer unless it is already loaded. Failures
+    /// are reported once and leave the column empty
+    fn refresh_blame(&mut self) {
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return;
+        };
+        if self.blame_buffer == Some(buffer.id) {
+            return;
+        }
+        self.blame_buffer = Some(buffer.id);
+        let result = match &buffer.filename {
+            Some(filename) => blame::blame(Path::new(filename)),
+            None => Err(Error::Message("No file name to blame".to_string())),
+        };
+        match result {
+            Ok(data) => self.blame_data = data,
+            Err(e) => {
+                self.blame_data.clear();
+                self.notify_level(NotificationLevel::Warning, format!("git blame: {}", e), NOTIFICATION_TIME);
+            },
+        }
+    }
+
+    /// Columns the blame column and its separator take, when shown
+    fn blame_column_width(&self) -> usize {
+        if self.show_git_blame { BLAME_WIDTH + 1 } else { 0 }
+    }
+
+    /// Lines of text the active window shows
+    fn window_text_height(&self) -> usize {
+        let Some(window) = self.windows.get(self.active_window) else {
+            return self.terminal_height.saturating_sub(2);
+        };
+        if self.windows.len() > 1 { window.height.saturating_sub(2) } else { window.height }
+    }
+
+    /// Columns of text the active window shows beside its gutters
+    fn window_text_width(&self) -> usize {
+        let (Some(window), Some(buffer)) = (self.windows.get(self.active_window), self.buffers.get(self.active_buffer)) else {
+            return 0;
+        };
+        let width = if self.windows.len() > 1 { window.width.saturating_sub(2) } else { window.width };
+        let width = width - self.minimap_columns(buffer, width);
+        let scrollbar = usize::from(self.scrollbar_shown(buffer, self.window_text_height()));
+        width.saturating_sub(self.blame_column_width() + gutter_width(buffer) + scrollbar)
+    }
+
+    /// Scroll the active window so the cursor is visible, `scrolloff` lines from its edges
+    fn scroll_to_cursor(&mut self) {
+        let height = self.window_text_height();
+        let width = self.window_text_width();
+        let scrolloff = self.options().scrolloff;
+        if let Some(buffer) = self.buffers.get_mut(self.active_buffer).filter(|buffer| !buffer.is_shell) {
+            buffer.scroll_to_cursor(height, width, scrolloff);
+        }
+    }
+
+    /// Ctrl+D / Ctrl+U / Ctrl+F / Ctrl+B: scroll `lines` down or up and move the
+    /// cursor by as much, so it stays at the same place on screen
+    fn scroll_lines(&mut self, lines: usize, down: bool) {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return;
+        };
+        let last = buffer.document.lines.len().saturating_sub(1);
+        if down {
+            buffer.offset_y = (buffer.offset_y + lines).min(last);
+            buffer.cursor_y = (buffer.cursor_y + lines).min(last);
+        } else {
+            buffer.offset_y = buffer.offset_y.saturating_sub(lines);
+            buffer.cursor_y = buffer.cursor_y.saturating_sub(lines);
+        }
+        self.clamp_cursor();
+    }
+
+    /// Make the buffer for the current tab the active one, loading it into
+    /// the buffer list if it isn't open yet
+    fn show_current_tab(&mut self) -> Result<()> {
+        let tab = match self.tab_manager.get_current_tab() {
+            Some(tab) => tab,
+            None => return Ok(()),
+        };
+
+        // Tabs of unnamed buffers are only found by buffer id
+        let existing = self.buffers.iter()
+            .position(|buffer| buffer.id == tab.buffer().id() || buffer.filename.as_deref() == Some(tab.name()));
+        match existing {
+            Some(idx) => self.active_buffer = idx,
+            None => {
+                let Some(mut buffer) = self.tab_manager.load_tab_buffer(self.tab_manager.current_tab())? else {
+                    return Ok(());
+                };
+                self.restore_undo_history(&mut buffer);
+                self.buffers.push(buffer);
+                self.active_buffer = self.buffers.len() - 1;
+                self.restore_cursor_position();
+                self.refresh_git_hunks();
+                let filename = self.buffers[self.active_buffer].filename.clone();
+                self.fire_autocmd("BufRead", filename, &[]);
+            },
+        }
+
+        if let Some(buffer) = self.buffers.get(self.active_buffer) {
+            self.cursor_x = buffer.cursor_x;
+            self.cursor_y = buffer.cursor_y;
+        }
+        self.unload_hidden_tabs();
+        Ok(())
+    }
+
+    /// Drop the buffers of tabs that are not shown anywhere from memory, when
+    /// they can be read back as they are: saved, and with no undo history that
+    /// only lives in memory. The cursor position is remembered for their return
+    fn unload_hidden_tabs(&mut self) {
+        self.shift_marks();
+        let undofile = self.options().undofile;
+        let current = self.tab_manager.current_tab();
+        for (tab_idx, id) in self.tab_manager.loaded_tabs() {
+            let shown = tab_idx == current
+                || self.windows.iter().any(|window| window.buffer_id == Some(id))
+                || self.diff_view.as_ref().is_some_and(|view| view.buffers.contains(&id));
+            let Some(idx) = self.buffers.iter().position(|buffer| buffer.id == id) else {
+                continue;
+            };
+            let buffer = &self.buffers[idx];
+            if shown || idx == self.active_buffer || buffer.is_shell || buffer.document.modified
+                || (buffer.document.has_undo_history() && !undofile)
+            {
+                continue;
+            }
+            let Some(path) = buffer.filename.as_ref().map(PathBuf::from) else {
+                continue;
+            };
+            self.remember_cursor_position(idx);
+            self.buffers.remove(idx);
+            if self.active_buffer > idx {
+                self.active_buffer -= 1;
+            }
+            self.tab_manager.unload_tab_buffer(tab_idx, path);
+        }
+    }
+
+    /// `:tabnew [file]`: open a file, or an empty buffer, in a new tab
+    fn tab_new(&mut self, file: &str) -> Result<()> {
+        if !file.is_empty() {
+            if !Path::new(file).exists() {
+                self.message = Some(format!("E484: Can't open file {}", file));
+                return Ok(());
+            }
+            return self.open_file(file);
+        }
+        let name = (1..)
+            .map(|n| if n == 1 { NO_NAME_TAB.to_string() } else { format!("{} ({})", NO_NAME_TAB, n) })
+            .find(|name| self.tab_manager.tab_index(name).is_none())
+            .unwrap_or_else(|| NO_NAME_TAB.to_string());
+        let mut buffer = Buffer::new();
+        self.restore_undo_history(&mut buffer);
+        let idx = self.tab_manager.create_tab(name, buffer.id)?;
+        self.tab_manager.switch_to_tab(idx)?;
+        self.buffers.push(buffer);
+        self.active_buffer = self.buffers.len() - 1;
+        self.move_cursor_to_change((0, 0));
+        self.unload_hidden_tabs();
+        Ok(())
+    }
+
+    /// `:tabclose[!]`: close the current tab and drop its buffer. Unsaved changes
+    /// need `!`, and the last tab stays open
+    fn tab_close(&mut self, force: bool) -> Result<()> {
+        let Some(tab) = self.tab_manager.get_current_tab() else {
+            return Ok(());
+        };
+        let buffer_id = tab.buffer().id();
+        let buffer = self.buffers.iter().position(|buffer| buffer.id == buffer_id);
+        if !force && buffer.is_some_and(|idx| self.buffers[idx].document.modified) {
+            self.message = Some("E37: No write since last change (add ! to override)".to_string());
+            return Ok(());
+        }
+        match self.tab_manager.close_current_tab() {
+            Ok(_) => {
+                if let Some(idx) = buffer {
+                    self.remember_cursor_position(idx);
+                    self.buffers.remove(idx);
+                }
+                self.show_current_tab()
+            },
+            Err(e) => {
+                self.message = Some(e.to_string());
+                Ok(())
+            },
+        }
+    }
+
+    /// `:tabnext [n]` / `:tabprevious`: go to the next or previous tab, or with a
+    /// number to tab `n` (counted from 1)
+    fn tab_go(&mut self, arg: &str, forward: bool) -> Result<()> {
+        let switched = match arg.parse::<usize>() {
+            Ok(n) if forward && (1..=self.tab_manager.tab_list().len()).contains(&n) => self.tab_manager.switch_to_tab(n - 1),
+            Ok(_) => {
+                self.message = Some("E16: Invalid range".to_string());
+                return Ok(());
+            },
+            Err(_) if !arg.is_empty() => {
+                self.message = Some(format!("E474: Invalid argument: {}", arg));
+                return Ok(());
+            },
+            Err(_) if forward => self.tab_manager.switch_to_next_tab(),
+            Err(_) => self.tab_manager.switch_to_prev_tab(),
+        };
+        match switched {
+            Ok(()) => self.show_current_tab(),
+            Err(e) => {
+                self.message = Some(e.to_string());
+                Ok(())
+            },
+        }
+    }
+
+    /// `:tabfind {partial}`: switch to the tab whose name contains `partial`,
+    /// or list the candidates when several match
+    fn tab_find(&mut self, partial: &str) -> Result<()> {
+        if partial.is_empty() {
+            self.message = Some("E471: Argument required".to_string());
+            return Ok(());
+        }
+
+        let matches = self.tab_manager.find_tabs(partial);
+        match matches.as_slice() {
+            [] => {
+                self.message = Some(format!("E94: No matching tab for {}", partial));
+            },
+            [idx] => {
+                self.tab_manager.switch_to_tab(*idx)?;
+                self.show_current_tab()?;
+            },
+            _ => {
+                let tab_list = self.tab_manager.tab_list();
+                let choices: Vec<String> = matches.iter()
+                    .enumerate()
+                    .filter_map(|(n, &idx)| tab_list.get(idx).map(|(_, name)| format!("{}:{}", n + 1, name)))
+                    .collect();
+                self.message = Some(format!("Select tab: {} (Esc to cancel)", choices.join("  ")));
+                self.tab_choices = matches;
+                self.mode = Mode::TabSwitcher;
+            },
+        }
+        Ok(())
+    }
+
+    fn execute_command(&mut self) -> Result<()> {
+        let command_line = self.command_line.clone();
+        let cmd = command_line.trim();
+        let selection = self.visual_selection.take();
+        if let Some(parsed) = Substitute::parse(cmd) {
+            match parsed.and_then(|substitute| self.substitute(substitute)) {
+                Ok(()) => {},
+                Err(e) => self.message = Some(e.to_string()),
+            }
+            return Ok(());
+        }
+        let (name, arg) = cmd.split_once(char::is_whitespace).unwrap_or((cmd, ""));
+        if let Some(force) = parse_edit_command(name) {
+            let arg = arg.trim();
+            return self.edit_file((!arg.is_empty()).then_some(arg), force);
+        }
+        if let Some((force, needs_name)) = parse_write_command(name) {
+            return match arg.trim() {
+                "" if needs_name => {
+                    self.notify("E471: Argument required", NOTIFICATION_TIME);
+                    Ok(())
+                },
+                "" => self.write_active_buffer(),
+                file => self.write_active_buffer_as(file, force),
+            };
+        }
+        match cmd {
+            "q" | "quit" | "qa" | "qall" | "quita" | "quitall" => {
+                if self.has_unsaved_changes() {
+                    self.message = Some(format!("E37: No write since last change (use :{}! to force)", name));
+                } else {
+                    self.quit = true;
+                }
+                Ok(())
+            },
+            "q!" | "quit!" | "qa!" | "qall!" | "quita!" | "quitall!" => {
+                self.quit = true;
+                Ok(())
+            },
+            "wa" | "wall" => self.write_all_buffers(),
+            "sp" | "split" => self.split_window(SplitType::Horizontal),
+            "vs" | "vsplit" => self.split_window(SplitType::Vertical),
+            "sort" | "sort!" => {
+                let last = self.buffers.get(self.active_buffer).map_or(0, |buffer| buffer.document.lines.len().saturating_sub(1));
+                let (start, end) = selection.unwrap_or((0, last));
+                self.sort_range(start, end, cmd.ends_with('!'));
+                Ok(())
+            },
+            "diffoff" | "diffo" => {
+                self.diff_view = None;
+                Ok(())
+            },
+            "vterm" | "vterminal" => self.open_shell(false),
+            _ if matches!(name, "diffs" | "diffsplit") => self.diff_split(arg.trim()),
+            _ if matches!(name, "deb" | "debug") => {
+                let mut words = arg.split_whitespace().map(str::to_string);
+                let program = words.next().map(|program| (program, words.collect()));
+                self.start_debugging(program)
+            },
+            _ if matches!(name, "mks" | "mksession" | "mks!" | "mksession!") => {
+                let path = session_path(arg);
+                match self.save_session(&path) {
+                    Ok(()) => self.message = Some(format!("Session saved to {}", path.display())),
+                    Err(e) => self.notify(e.to_string(), NOTIFICATION_TIME),
+                }
+                Ok(())
+            },
+            _ if matches!(name, "so" | "source") => {
+                if let Err(e) = self.load_session(&session_path(arg)) {
+                    self.notify(e.to_string(), NOTIFICATION_TIME);
+                }
+                Ok(())
+            },
+            _ if matches!(name, "rg" | "grep") => {
+                self.run_grep(name, arg.trim());
+                Ok(())
+            },
+            "cn" | "cnext" => {
+                self.quickfix_step(true);
+                Ok(())
+            },
+            "cp" | "cprev" | "cprevious" | "cN" | "cNext" => {
+                self.quickfix_step(false);
+                Ok(())
+            },
+            _ if matches!(name, "bufdo" | "argdo") => {
+                if let Err(e) = self.buffer_do(arg.trim()) {
+                    self.notify(e.to_string(), NOTIFICATION_TIME);
+                }
+                Ok(())
+            },
+            _ if name == "cdo" => {
+                if let Err(e) = self.quickfix_do(arg.trim()) {
+                    self.notify(e.to_string(), NOTIFICATION_TIME);
+                }
+                Ok(())
+            },
+            "cope" | "copen" => {
+                if self.quickfix_list.is_empty() {
+                    self.message = Some("E42: No Errors".to_string());
+                } else {
+                    self.mode = Mode::Quickfix;
+                }
+                Ok(())
+            },
+            "Format" => self.format_buffer(),
+            "TableFormat" => {
+                let result = match selection {
+                    Some((start, end)) => self.format_table(start, end),
+                    None => self.format_table_at_cursor(),
+                };
+                if let Err(e) = result {
+                    self.notify(e.to_string(), NOTIFICATION_TIME);
+                }
+                Ok(())
+            },
+            "LspFormat" => self.lsp_format(),
+            _ if matches!(name, "cd" | "chdir") => {
+                if let Err(e) = self.change_directory(arg.trim()) {
+                    self.notify(e.to_string(), NOTIFICATION_TIME);
+                }
+                Ok(())
+            },
+            "pwd" => {
+                self.notify(self.working_dir.display().to_string(), NOTIFICATION_TIME);
+                Ok(())
+            },
+            "lne" | "lnext" => {
+                self.jump_to_diagnostic(true);
+                Ok(())
+            },
+            "lp" | "lprev" | "lprevious" | "lN" | "lNext" => {
+                self.jump_to_diagnostic(false);
+                Ok(())
+            },
+            "wq" => {
+                self.write_active_buffer()?;
+                // Other buffers may still have changes
+                if self.has_unsaved_changes() {
+                    self.message = Some("E37: No write since last change (use :q! to force)".to_string());
+                } else {
+                    self.quit = true;
+                }
+                Ok(())
+            },
+            "help" => {
+                self.previous_mode = self.mode;
+                self.mode = Mode::Help;
+                Ok(())
+            },
+            _ if cmd.starts_with("prof") => {
+                let mut parts = cmd.split_whitespace();
+                let name = parts.next().unwrap_or("");
+                if !"profile".starts_with(name) {
+                    return Ok(());
+                }
+                match (parts.next(), parts.next()) {
+                    (Some("start"), Some(file)) => {
+                        if let Some(profiler) = self.profiler.take() {
+                            profiler.stop(&self.lua)?;
+                        }
+                        self.profiler = Some(Profiler::start(&self.lua, PathBuf::from(file)));
+                        self.message = Some(format!("Profiling to {}", file));
+                    },
+                    (Some("stop"), None) => match self.profiler.take() {
+                        Some(profiler) => {
+                            let output = profiler.output().display().to_string();
+                            profiler.stop(&self.lua)?;
+                            self.message = Some(format!("Profile written to {}", output));
+                        },
+                        None => self.message = Some("E750: First use \":profile start {fname}\"".to_string()),
+                    },
+                    (Some("dump"), None) => match &self.profiler {
+                        Some(profiler) => {
+                            profiler.dump()?;
+                            self.message = Some(format!("Profile written to {}", profiler.output().display()));
+                        },
+                        None => self.message = Some("E750: First use \":profile start {fname}\"".to_string()),
+                    },
+                    _ => self.message = Some("Usage: :profile start {file} | stop | dump".to_string()),
+                }
+                Ok(())
+            },
+            _ if cmd.starts_with("se") => {
+                let (name, args) = cmd.split_once(' ').unwrap_or((cmd, ""));
+                if !"set".starts_with(name) {
+                    return Ok(());
+                }
+                let mut shown = Vec::new();
+                let mut state = self.api_state.lock().unwrap();
+                for arg in args.split_whitespace() {
+                    match state.options.apply_set_arg(arg) {
+                        Ok(Some(value)) => shown.push(value),
+                        Ok(None) => {},
+                        Err(e) => {
+                            shown.push(e.to_string());
+                            break;
+                        },
+                    }
+                }
+                drop(state);
+                if !shown.is_empty() {
+                    self.message = Some(shown.join("  "));
+                }
+                Ok(())
+            },
+            _ if cmd.starts_with("Picker") => {
+                match cmd.split_whitespace().nth(1) {
+                    Some("files") => self.open_file_picker(),
+                    Some("buffers") => self.open_buffer_picker(),
+                    Some("symbols") => self.request_symbol_picker(),
+                    Some("colorschemes") => self.open_colorscheme_picker(),
+                    Some("commands") => self.open_command_palette(),
+                    _ => {
+                        self.message = Some("Usage: :Picker files | buffers | symbols | colorschemes | commands".to_string());
+                        Ok(())
+                    },
+                }
+            },
+            _ if matches!(name, "tabnew" | "tabe" | "tabedit") => self.tab_new(arg.trim()),
+            _ if matches!(name, "tabc" | "tabclose" | "tabc!" | "tabclose!") => self.tab_close(name.ends_with('!')),
+            _ if matches!(name, "tabn" | "tabnext") => self.tab_go(arg.trim(), true),
+            _ if matches!(name, "tabp" | "tabprevious" | "tabN" | "tabNext") => self.tab_go(arg.trim(), false),
+            // :{n}gt goes to tab n, like {n}gt in normal mode
+            _ if cmd.strip_suffix("gt").is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())) => {
+                self.tab_go(&cmd[..cmd.len() - 2], true)
+            },
+            _ if cmd.starts_with("tabf") => {
+                let (name, arg) = cmd.split_once(' ').unwrap_or((cmd, ""));
+                if !"tabfind".starts_with(name) {
+                    return Ok(());
+                }
+                self.tab_find(arg.trim())
+            },
+            _ if cmd.starts_with("noh") => {
+                let (name, arg) = cmd.split_once(' ').unwrap_or((cmd, ""));
+                if !"nohlsearch".starts_with(name) {
+                    return Ok(());
+                }
+                self.clear_search_highlight(arg.trim());
+                Ok(())
+            },
+            _ => Ok(()) // Unknown command just returns Ok
+        }
+    }
+
+    /// Run `:s`, either at once or asking about each match with the `c` flag
+    fn substitute(&mut self, substitute: Substitute) -> Result<()> {
+        let last_pattern = self.search_patterns.get(self.active_search_idx).map(|(pattern, _)| pattern.as_str());
+        let regex = substitute.regex(last_pattern)?;
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return Ok(());
+        };
+        let (first, last) = match substitute.range {
+            SubstituteRange::CurrentLine => (buffer.cursor_y, buffer.cursor_y),
+            SubstituteRange::WholeFile => (0, buffer.document.lines.len().saturating_sub(1)),
+        };
+
+        if substitute.confirm {
+            self.pending_substitute = Some(PendingSubstitute {
+                regex,
+                replacement: substitute.replacement,
+                global: substitute.global,
+                last_row: last,
+                row: first,
+                col: 0,
+                current: None,
+                count: 0,
+                changed_rows: Vec::new(),
+            });
+            self.mode = Mode::SubstituteConfirm;
+            return self.next_substitute_match();
+        }
+
+        let (count, lines) = buffer.document.substitute(first, last, &regex, &substitute.replacement, substitute.global);
+        if count == 0 {
+            return Err(Error::Message(format!("E486: Pattern not found: {}", regex.as_str())));
+        }
+        self.message = Some(substitution_summary(count, lines));
+        Ok(())
+    }
+
+    /// Move to the next match of the pending `:s///c` and ask about it, or finish
+    fn next_substitute_match(&mut self) -> Result<()> {
+        let (Some(pending), Some(buffer)) = (self.pending_substitute.as_mut(), self.buffers.get_mut(self.active_buffer)) else {
+            return Ok(());
+        };
+        let lines = &buffer.document.lines;
+        while pending.row <= pending.last_row && pending.row < lines.len() {
+            let line = &lines[pending.row];
+            if pending.col <= line.len()
+                && let Some(m) = pending.regex.find_at(line, pending.col)
+            {
+                buffer.cursor_y = pending.row;
+                buffer.cursor_x = m.start();
+                pending.current = Some(m.range());
+                let mut replacement = String::new();
+                if let Some(captures) = pending.regex.captures_at(line, m.start()) {
+                    captures.expand(&pending.replacement, &mut replacement);
+                }
+                self.message = Some(format!("replace with {} (y/n/a/q/l)?", replacement));
+                return Ok(());
+            }
+            pending.row += 1;
+            pending.col = 0;
+        }
+        self.finish_substitute();
+        Ok(())
+    }
+
+    /// Replace the match being asked about and move past it
+    fn replace_substitute_match(&mut self) {
+        let (Some(pending), Some(buffer)) = (self.pending_substitute.as_mut(), self.buffers.get_mut(self.active_buffer)) else {
+            return;
+        };
+        let Some(range) = pending.current.take() else {
+            return;
+        };
+        let document = &mut buffer.document;
+        let line = &document.lines[pending.row];
+        let mut text = String::new();
+        if let Some(captures) = pending.regex.captures_at(line, range.start) {
+            captures.expand(&pending.replacement, &mut text);
+        }
+        // The whole substitution is one undo step
+        if pending.count == 0 {
+            document.record_undo_state(document.get_char_position(pending.row, range.start));
+        }
+        document.replace_in_line(pending.row, range.clone(), &text);
+        pending.count += 1;
+        if !pending.changed_rows.contains(&pending.row) {
+            pending.changed_rows.push(pending.row);
+        }
+        pending.col = range.start + text.len() + usize::from(range.is_empty());
+        if !pending.global {
+            pending.row += 1;
+            pending.col = 0;
+        }
+    }
+
+    fn skip_substitute_match(&mut self) {
+        if let Some(pending) = self.pending_substitute.as_mut()
+            && let Some(range) = pending.current.take()
+        {
+            pending.col = range.end + usize::from(range.is_empty());
+            if !pending.global {
+                pending.row += 1;
+                pending.col = 0;
+            }
+        }
+    }
+
+    fn finish_substitute(&mut self) {
+        self.mode = Mode::Normal;
+        if let Some(pending) = self.pending_substitute.take() {
+            self.message = Some(if pending.count == 0 {
+                format!("E486: Pattern not found: {}", pending.regex.as_str())
+            } else {
+                substitution_summary(pending.count, pending.changed_rows.len())
+            });
+        }
+    }
+
+    /// Keys while confirming `:s///c`: y replaces, n skips, a replaces the rest,
+    /// l replaces this one and stops, q or Esc stops
+    fn process_substitute_confirm(&mut self, key: KeyEvent) -> Result<()> {
+        match key.code {
+            KeyCode::Char('y') => self.replace_substitute_match(),
+            KeyCode::Char('n') => self.skip_substitute_match(),
+            KeyCode::Char('l') => {
+                self.replace_substitute_match();
+                self.finish_substitute();
+                return Ok(());
+            },
+            KeyCode::Char('a') => {
+                while self.pending_substitute.as_ref().is_some_and(|pending| pending.current.is_some()) {
+                    self.replace_substitute_match();
+                    self.next_substitute_match()?;
+                }
+                return Ok(());
+            },
+            KeyCode::Char('q') | KeyCode::Esc => {
+                self.finish_substitute();
+                return Ok(());
+            },
+            _ => return Ok(()),
+        }
+        self.next_substitute_match()
+    }
+
+    /// Build the Ctrl+G summary for the active buffer, e.g.
+    /// `"src/main.rs" line 42 of 512 --8%-- col 10 [utf-8] [unix] [rust]`
+    pub fn show_file_info(&self, verbose: bool) -> String {
+        let buffer = match self.buffers.get(self.active_buffer) {
+            Some(buffer) => buffer,
+            None => return "[No Name]".to_string(),
+        };
+
+        let name = match &buffer.filename {
+            Some(filename) if verbose => {
+                let path = PathBuf::from(filename);
+                fs::canonicalize(&path)
+                    .or_else(|_| env::current_dir().map(|dir| dir.join(&path)))
+                    .unwrap_or(path)
+                    .to_string_lossy()
+                    .to_string()
+            },
+            Some(filename) => filename.clone(),
+            None => "[No Name]".to_string(),
+        };
+        let modified = if buffer.document.modified { " [Modified]" } else { "" };
+
+        let total = buffer.document.lines.len();
+        let line = (buffer.cursor_y + 1).min(total.max(1));
+        let pct = (line * 100).checked_div(total).unwrap_or(0);
+
+        let filetype = buffer.filename.as_deref()
+            .and_then(|f| PathBuf::from(f).extension().map(|e| e.to_string_lossy().to_string()))
+            .and_then(|ext| get_language_id_from_extension(&ext))
+            .map(|ft| format!(" [{}]", ft))
+            .unwrap_or_default();
+
+        format!("\"{}\"{} line {} of {} --{}%-- col {} [utf-8] [{}]{}",
+            name, modified, line, total, pct, buffer.cursor_x + 1,
+            buffer.document.line_ending.name(), filetype)
+    }
+
+    /// Enter the search prompt. With `append` the pattern is added alongside the
+    /// existing ones instead of replacing the primary pattern.
+    fn start_search(&mut self, append: bool, forward: bool) {
+        self.search_query.clear();
+        self.search_append = append;
+        self.message = None;
+        self.mode = Mode::Search { forward };
+    }
+
+    fn process_search_mode(&mut self, key: KeyEvent, forward: bool) -> Result<()> {
+        match key.code {
+            KeyCode::Esc => {
+                self.search_query.clear();
+                self.mode = Mode::Normal;
+            },
+            KeyCode::Enter => {
+                self.mode = Mode::Normal;
+                self.search_forward = forward;
+                let query = std::mem::take(&mut self.search_query);
+                if query.is_empty() {
+                    // An empty pattern repeats the last search
+                    return self.search_next(forward);
+                }
+                if self.search_append {
+                    self.add_search_pattern(query);
+                } else {
+                    self.set_primary_search_pattern(query);
+                }
+                self.search_next(forward)?;
+            },
+            KeyCode::Backspace => {
+                // Backspace on an empty prompt leaves search, like in Vim
+                if self.search_query.is_empty() {
+                    self.mode = Mode::Normal;
+                } else {
+                    self.search_query.pop();
+                }
+            },
+            KeyCode::Char(c) => self.search_query.push(c),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Pick the first highlight color that no current pattern is using
+    fn next_search_color(&self) -> Color {
+        SEARCH_COLORS.iter()
+            .find(|color| !self.search_patterns.iter().any(|(_, used)| used == *color))
+            .copied()
+            .unwrap_or(SEARCH_COLORS[0])
+    }
+
+    fn set_primary_search_pattern(&mut self, pattern: String) {
+        if self.search_patterns.is_empty() {
+            self.search_patterns.push((pattern, SEARCH_COLORS[0]));
+        } else {
+            self.search_patterns[0].0 = pattern;
+        }
+        self.active_search_idx = 0;
+    }
+
+    fn add_search_pattern(&mut self, pattern: String) {
+        if let Some(idx) = self.search_patterns.iter().position(|(p, _)| *p == pattern) {
+            self.active_search_idx = idx;
+            return;
+        }
+        if self.search_patterns.len() >= MAX_SEARCH_PATTERNS {
+            // Drop the oldest additional pattern, keeping the primary one
+            self.search_patterns.remove(1);
+        }
+        let color = self.next_search_color();
+        self.search_patterns.push((pattern, color));
+        self.active_search_idx = self.search_patterns.len() - 1;
+    }
+
+    /// Remove the highlight for all patterns, or only for the 1-based pattern given in `arg`
+    fn clear_search_highlight(&mut self, arg: &str) {
+        if arg.is_empty() {
+            self.search_patterns.clear();
+            self.active_search_idx = 0;
+            return;
+        }
+        match arg.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= self.search_patterns.len() => {
+                self.search_patterns.remove(n - 1);
+                if self.active_search_idx >= self.search_patterns.len() {
+                    self.active_search_idx = self.search_patterns.len().saturating_sub(1);
+                }
+            },
+            _ => self.message = Some(format!("E939: Invalid search pattern number: {}", arg)),
+        }
+    }
+
+    /// Add the word under the cursor as a new search pattern and jump to its next occurrence
+    fn search_word_under_cursor(&mut self) -> Result<()> {
+        let word = match self.buffers.get(self.active_buffer) {
+            Some(buffer) => match buffer.document.lines.get(buffer.cursor_y) {
+                Some(line) => word_at(line, buffer.cursor_x),
+                None => None,
+            },
+            None => None,
+        };
+        match word {
+            Some(word) => {
+                self.add_search_pattern(format!(r"\b{}\b", regex::escape(&word)));
+                self.search_forward = true;
+                self.search_next(true)
+            },
+            None => {
+                self.message = Some("E348: No string under cursor".to_string());
+                Ok(())
+            }
+        }
+    }
+
+    /// Move the cursor to the next (or previous) match of the active search pattern,
+    /// wrapping around the ends of the buffer
+    fn search_next(&mut self, forward: bool) -> Result<()> {
+        let pattern = match self.search_patterns.get(self.active_search_idx) {
+            Some((pattern, _)) => pattern.clone(),
+            None => {
+                self.message = Some("E35: No previous regular expression".to_string());
+                return Ok(());
+            }
+        };
+        let re = match compile_search_pattern(&pattern) {
+            Some(re) => re,
+            None => return Ok(()),
+        };
+        let buffer = match self.buffers.get_mut(self.active_buffer) {
+            Some(buffer) => buffer,
+            None => return Ok(()),
+        };
+
+        let lines = &buffer.document.lines;
+        let total = lines.len();
+        if total == 0 {
+            return Ok(());
+        }
+        let (row, col) = (buffer.cursor_y.min(total - 1), buffer.cursor_x);
+        let mut found = None;
+        let mut wrapped = false;
+
+        // Visit every line once starting from the cursor, then the cursor line again
+        for step in 0..=total {
+            let idx = if forward { (row + step) % total } else { (row + total - step % total) % total };
+            let line = &lines[idx];
+            let starts: Vec<usize> = re.find_iter(line).map(|m| m.start()).collect();
+            let hit = if step == 0 {
+                if forward { starts.into_iter().find(|&s| s > col) } else { starts.into_iter().rev().find(|&s| s < col) }
+            } else if step == total {
+                // Back on the starting line after wrapping around
+                if forward { starts.into_iter().find(|&s| s <= col) } else { starts.into_iter().rev().find(|&s| s >= col) }
+            } else if forward {
+                starts.into_iter().next()
+            } else {
+                starts.into_iter().next_back()
+            };
+            if let Some(start) = hit {
+                wrapped = if forward { idx < row || step == total } else { idx > row || step == total };
+                found = Some((idx, start));
+                break;
+            }
+        }
+
+        match found {
+            Some((y, x)) => {
+                buffer.cursor_y = y;
+                buffer.cursor_x = x;
+                self.message = if wrapped {
+                    Some(if forward {
+                        "search hit BOTTOM, continuing at TOP".to_string()
+                    } else {
+                        "search hit TOP, continuing at BOTTOM".to_string()
+                    })
+                } else {
+                    None
+                };
+            },
+            None => self.message = Some(format!("E486: Pattern not found: {}", pattern)),
+        }
+        Ok(())
+    }
+
+    fn move_cursor_left(&mut self) -> Result<()> {
+        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
+            if buffer.cursor_x > 0 {
+                buffer.cursor_x -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn move_cursor_right(&mut self) -> Result<()> {
+        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
+            let line_len = buffer.document.lines.get(buffer.cursor_y)
+                .map(|line| line.len())
+                .unwrap_or(0);
+            if buffer.cursor_x < line_len {
+                buffer.cursor_x += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// `0`, `^`, `$` and `{count}|`: column 0, the first non-blank, the last
+    /// character or screen column `count` of the line. `{count}$` first goes
+    /// down `count - 1` lines
+    fn move_in_line(&mut self, motion: char, count: Option<usize>) -> Result<()> {
+        if motion == '$' {
+            self.repeat_motion(Some(count.unwrap_or(1) - 1), Self::move_cursor_down)?;
+        }
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return Ok(());
+        };
+        let line = buffer.document.lines.get(buffer.cursor_y).map_or("", String::as_str);
+        // Start of the last character; the cursor goes no further in Normal mode
+        let last = line.char_indices().last().map_or(0, |(idx, _)| idx);
+        buffer.cursor_x = match motion {
+            '^' => (line.len() - line.trim_start().len()).min(last),
+            '$' => last,
+            '|' => line.char_indices()
+                .nth(count.unwrap_or(1).saturating_sub(1))
+                .map_or(last, |(idx, _)| idx),
+            _ => 0,
+        };
+        Ok(())
+    }
+
+    // A closed fold counts as one line
+    fn move_cursor_up(&mut self) -> Result<()> {
+        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
+            if buffer.cursor_y > 0 {
+                buffer.cursor_y = buffer.fold_start(buffer.cursor_y - 1);
+            }
+        }
+        Ok(())
+    }
+
+    fn move_cursor_down(&mut self) -> Result<()> {
+        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
+            let next = buffer.fold_end(buffer.cursor_y) + 1;
+            if next < buffer.document.lines.len() {
+                buffer.cursor_y = next;
+            }
+        }
+        Ok(())
+    }
+
+    /// j: down a line, or a screen row when `wrap_navigate` is `screen`
+    fn move_down(&mut self) -> Result<()> {
+        if self.screen_row_motions { self.move_screen_row_down() } else { self.move_cursor_down() }
+    }
+
+    /// k: up a line, or a screen row when `wrap_navigate` is `screen`
+    fn move_up(&mut self) -> Result<()> {
+        if self.screen_row_motions { self.move_screen_row_up() } else { self.move_cursor_up() }
+    }
+
+    /// gj: down a screen row, or a line when `wrap_navigate` is `screen`
+    fn move_down_g(&mut self) -> Result<()> {
+        if self.screen_row_motions { self.move_cursor_down() } else { self.move_screen_row_down() }
+    }
+
+    /// gk: up a screen row, or a line when `wrap_navigate` is `screen`
+    fn move_up_g(&mut self) -> Result<()> {
+        if self.screen_row_motions { self.move_cursor_up() } else { self.move_screen_row_up() }
+    }
+
+    /// Down a screen row when lines wrap, otherwise down a line
+    fn move_screen_row_down(&mut self) -> Result<()> {
+        let width = self.window_text_width();
+        match self.buffers.get_mut(self.active_buffer) {
+            Some(buffer) if buffer.wrap => buffer.move_screen_row(true, width),
+            _ => return self.move_cursor_down(),
+        }
+        Ok(())
+    }
+
+    /// Up a screen row when lines wrap, otherwise up a line
+    fn move_screen_row_up(&mut self) -> Result<()> {
+        let width = self.window_text_width();
+        match self.buffers.get_mut(self.active_buffer) {
+            Some(buffer) if buffer.wrap => buffer.move_screen_row(false, width),
+            _ => return self.move_cursor_up(),
+        }
+        Ok(())
+    }
+
+    // w: past the rest of the current word and any whitespace to the next word,
+    // stopping at empty lines
+    fn move_to_next_word_start(&mut self) -> Result<()> {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return Ok(());
+        };
+        let lines = &buffer.document.lines;
+        let mut tokens = word_tokens_after(lines, buffer.cursor_y, buffer.cursor_x);
+        let Some(start) = tokens.next() else {
+            return Ok(());
+        };
+        let mut last = start;
+        let mut after_blank = false;
+        let target = loop {
+            let Some(token) = tokens.next() else {
+                // No further word: stop on the last character of the file
+                break last;
+            };
+            match token.class {
+                WordClass::Blank | WordClass::LineBreak => after_blank = true,
+                WordClass::EmptyLine => break token,
+                class if after_blank || class != start.class => break token,
+                _ => {},
+            }
+            if token.class != WordClass::LineBreak {
+                last = token;
+            }
+        };
+        buffer.cursor_y = target.row;
+        buffer.cursor_x = target.col;
+        Ok(())
+    }
+
+    // e: to the last character of the current word, or of the next one when
+    // already at the end of a word
+    fn move_to_next_word_end(&mut self) -> Result<()> {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return Ok(());
+        };
+        let lines = &buffer.document.lines;
+        let mut tokens = word_tokens_after(lines, buffer.cursor_y, buffer.cursor_x).skip(1).peekable();
+        if let Some(target) = word_extent(&mut tokens, false) {
+            buffer.cursor_y = target.row;
+            buffer.cursor_x = target.col;
+        }
+        Ok(())
+    }
+
+    // b: to the start of the current word, or of the previous one when already
+    // at the start of a word, stopping at empty lines
+    fn move_to_prev_word_start(&mut self) -> Result<()> {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return Ok(());
+        };
+        let lines = &buffer.document.lines;
+        let mut tokens = word_tokens_before(lines, buffer.cursor_y, buffer.cursor_x).peekable();
+        if let Some(target) = word_extent(&mut tokens, true) {
+            buffer.cursor_y = target.row;
+            buffer.cursor_x = target.col;
+        }
+        Ok(())
+    }
+
+    /// Split the active window in two and make the new half active
+    fn split_window(&mut self, split_type: SplitType) -> Result<()> {
+        let Some(window) = self.windows.get(self.active_window) else {
+            return Ok(());
+        };
+        // Each half needs room for its border and some text
+        let room = match split_type {
+            SplitType::Horizontal => window.height / 2,
+            SplitType::Vertical => window.width / 2,
+        };
+        if room < MIN_WINDOW_SIZE {
+            self.message = Some("E36: Not enough room".to_string());
+            return Ok(());
+        }
+        let (mut first, mut second) = window.split(&split_type)?;
+        first.file_path = window.file_path.clone();
+        let shown = self.buffers.get(self.active_buffer).map(|buffer| buffer.id);
+        first.buffer_id = shown;
+        second.buffer_id = shown;
+        self.windows[self.active_window] = first;
+        self.active_window += 1;
+        self.windows.insert(self.active_window, second);
+        for (idx, window) in self.windows.iter_mut().enumerate() {
+            window.is_active = idx == self.active_window;
+        }
+        self.fit_windows();
+        Ok(())
+    }
+
+    /// Columns the file tree and its border take on the left, if it is shown
+    fn file_tree_offset(&self) -> usize {
+        match &self.file_tree {
+            Some(tree) if tree.visible => tree.width + 1,
+            _ => 0,
+        }
+    }
+
+    /// Scale the window layout so it covers the area above the status line
+    /// beside the file tree
+    fn fit_windows(&mut self) {
+        let width = self.terminal_width.saturating_sub(self.file_tree_offset()).max(1);
+        let height = self.terminal_height.saturating_sub(2 + self.debug_panel_height()).max(1);
+        let left = self.windows.iter().map(|w| w.x).min().unwrap_or(0);
+        let top = self.windows.iter().map(|w| w.y).min().unwrap_or(0);
+        let right = self.windows.iter().map(|w| w.x + w.width).max().unwrap_or(0);
+        let bottom = self.windows.iter().map(|w| w.y + w.height).max().unwrap_or(0);
+        if right <= left || bottom <= top {
+            return;
+        }
+        // Scaling edges rather than sizes keeps neighbouring windows touching
+        let scale = |value: usize, start: usize, span: usize, target: usize| (value - start) * target / span;
+        for window in &mut self.windows {
+            let (x0, x1) = (scale(window.x, left, right - left, width), scale(window.x + window.width, left, right - left, width));
+            let (y0, y1) = (scale(window.y, top, bottom - top, height), scale(window.y + window.height, top, bottom - top, height));
+            window.x = x0;
+            window.width = x1 - x0;
+            window.y = y0;
+            window.height = y1 - y0;
+        }
+    }
+
+    /// Ctrl+Z: give the terminal back to the shell and stop until `fg`, which
+    /// sends SIGCONT and so has the run loop call resume_terminal
+    #[cfg(unix)]
+    fn suspend(&mut self) -> Result<()> {
+        self.restore_cursor_shape()?;
+        execute!(
+            io::stdout(),
+            LeaveAlternateScreen,
+            event::DisableMouseCapture,
+            cursor::Show
+        )?;
+        terminal::disable_raw_mode()?;
+        // SAFETY: raise only sends a signal to this process
+        unsafe {
+            libc::raise(libc::SIGTSTP);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn suspend(&mut self) -> Result<()> {
+        self.message = Some("Suspending is not supported on this platform".to_string());
+        Ok(())
+    }
+
+    /// Cursor shape for the current mode: a beam while typing text, an
+    /// underline while an operator waits for its motion, a block otherwise
+    fn mode_cursor_shape(&self) -> CursorShape {
+        match self.mode {
+            Mode::Insert | Mode::Command | Mode::Search { .. } | Mode::Shell => CursorShape::Beam,
+            Mode::Normal if matches!(self.pending_prefix, Some('d' | 'c' | 'y')) => CursorShape::Underline,
+            _ => CursorShape::Block,
+        }
+    }
+
+    /// Switch the terminal cursor to `shape`, skipping terminals that do not
+    /// support it and shapes that are already showing
+    fn set_cursor_shape(&mut self, shape: CursorShape) -> Result<()> {
+        if !self.cursor_style_supported || self.cursor_shape == Some(shape) {
+            return Ok(());
+        }
+        let style = match shape {
+            CursorShape::Block => cursor::SetCursorStyle::SteadyBlock,
+            CursorShape::Beam => cursor::SetCursorStyle::SteadyBar,
+            CursorShape::Underline => cursor::SetCursorStyle::SteadyUnderScore,
+        };
+        execute!(io::stdout(), style)?;
+        self.cursor_shape = Some(shape);
+        Ok(())
+    }
+
+    /// Give the terminal its user's cursor shape back before handing it over
+    fn restore_cursor_shape(&mut self) -> Result<()> {
+        if self.cursor_shape.take().is_some() {
+            execute!(io::stdout(), cursor::SetCursorStyle::DefaultUserShape)?;
+        }
+        Ok(())
+    }
+
+    /// Take the terminal back after the process was stopped; it may have been
+    /// resized meanwhile
+    fn resume_terminal(&mut self) -> Result<()> {
+        terminal::enable_raw_mode()?;
+        execute!(
+            io::stdout(),
+            EnterAlternateScreen,
+            event::EnableMouseCapture
+        )?;
+        let (cols, rows) = terminal::size()?;
+        self.resize(cols as usize, rows as usize);
+        Ok(())
+    }
+
+    /// Lay the screen out again for a terminal of `cols` x `rows`; the next
+    /// refresh redraws everything at the new size
+    fn resize(&mut self, cols: usize, rows: usize) {
+        // The status and message lines need two rows of their own
+        self.terminal_width = cols.max(1);
+        self.terminal_height = rows.max(3);
+        if let Some(tree) = &mut self.file_tree {
+            tree.fit_width(self.file_tree_width, self.terminal_width);
+            tree.cursor = tree.cursor.min(tree.entries.len().saturating_sub(1));
+        }
+        self.fit_windows();
+        // The other windows' buffers keep their own cursors, which must fit too
+        let split = self.windows.len() > 1;
+        for (idx, window) in self.windows.iter().enumerate() {
+            let Some(buffer) = window.buffer_id.filter(|_| idx != self.active_window)
+                .and_then(|id| self.buffers.iter_mut().find(|buffer| buffer.id == id))
+            else {
+                continue;
+            };
+            let lines = &buffer.document.lines;
+            buffer.cursor_y = buffer.cursor_y.min(lines.len().saturating_sub(1));
+            buffer.cursor_x = buffer.cursor_x.min(lines.get(buffer.cursor_y).map_or(0, |line| line.len()));
+            center_cursor_if_hidden(buffer, if split { window.height.saturating_sub(2) } else { window.height });
+        }
+        self.clamp_cursor();
+        self.scroll_cursor_into_view();
+        self.hover_popup = None;
+    }
+
+    fn cycle_window(&mut self) -> Result<()> {
+        if !self.windows.is_empty() {
+            self.focus_window((self.active_window + 1) % self.windows.len());
+        }
+        Ok(())
+    }
+
+    /// Make window `idx` the active one
+    fn focus_window(&mut self, idx: usize) {
+        // The window being left keeps showing its buffer
+        let shown = self.buffers.get(self.active_buffer).map(|buffer| buffer.id);
+        if let Some(window) = self.windows.get_mut(self.active_window) {
+            window.buffer_id = shown;
+        }
+        self.active_window = idx;
+        self.show_window_buffer();
+    }
+
+    fn close_window(&mut self) -> Result<()> {
+        if self.windows.len() > 1 {
+            self.windows.remove(self.active_window);
+            if self.active_window >= self.windows.len() {
+                self.active_window = self.windows.len() - 1;
+            }
+            self.show_window_buffer();
+        }
+        Ok(())
+    }
+
+    /// Make the buffer the active window was showing the active buffer, along
+    /// with its tab
+    fn show_window_buffer(&mut self) {
+        for (idx, window) in self.windows.iter_mut().enumerate() {
+            window.is_active = idx == self.active_window;
+        }
+        let Some(id) = self.windows.get(self.active_window).and_then(|window| window.buffer_id) else {
+            return;
+        };
+        let Some(idx) = self.buffers.iter().position(|buffer| buffer.id == id) else {
+            return;
+        };
+        if let Some(tab) = self.buffers[idx].filename.as_deref().and_then(|name| self.tab_manager.tab_index(name))
+            && let Err(e) = self.tab_manager.switch_to_tab(tab)
+        {
+            error!("Failed to switch tab: {}", e);
+        }
+        self.active_buffer = idx;
+    }
+
+    /// `:sort` / `:sort!`: sort lines `start_line..=end_line` ascending, or
+    /// descending when `reverse`, and put the cursor on the first one
+    fn sort_range(&mut self, start_line: usize, end_line: usize, reverse: bool) {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer).filter(|buffer| !buffer.is_shell) else {
+            return;
+        };
+        buffer.document.sort_lines(start_line, end_line, reverse);
+        self.move_cursor_to_change((start_line, 0));
+    }
+
+    /// `:TableFormat` / Space t f: line up the Markdown pipe table the cursor is in
+    fn format_table_at_cursor(&mut self) -> Result<()> {
+        let Some(buffer) = self.buffers.get(self.active_buffer).filter(|buffer| !buffer.is_shell) else {
+            return Ok(());
+        };
+        let lines = &buffer.document.lines;
+        let row = buffer.cursor_y;
+        if !lines.get(row).is_some_and(|line| markdown::is_table_row(line)) {
+            return Err(Error::Message("The cursor is not in a table".to_string()));
+        }
+        let start = (0..row).rev().take_while(|&row| markdown::is_table_row(&lines[row])).last().unwrap_or(row);
+        let end = (row + 1..lines.len()).take_while(|&row| markdown::is_table_row(&lines[row])).last().unwrap_or(row);
+        self.format_table(start, end)
+    }
+
+    /// Pad the cells of the pipe table on lines `start..=end` of a Markdown
+    /// buffer so its columns line up, as one undo step
+    pub fn format_table(&mut self, start: usize, end: usize) -> Result<()> {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer).filter(|buffer| !buffer.is_shell) else {
+            return Ok(());
+        };
+        if buffer.filetype.as_deref() != Some("markdown") {
+            return Err(Error::Message("TableFormat only works in Markdown files".to_string()));
+        }
+        let end = end.min(buffer.document.lines.len().saturating_sub(1));
+        if start > end {
+            return Ok(());
+        }
+        let formatted = markdown::format_table(&buffer.document.lines[start..=end]);
+        if formatted[..] != buffer.document.lines[start..=end] {
+            buffer.document.replace_lines(start..end + 1, &formatted);
+        }
+        self.clamp_cursor();
+        Ok(())
+    }
+
+    /// `:diffsplit {file}`: open `file` in a vertical split and compare it with
+    /// the current buffer
+    fn diff_split(&mut self, file: &str) -> Result<()> {
+        if file.is_empty() {
+            self.notify("E471: Argument required", NOTIFICATION_TIME);
+            return Ok(());
+        }
+        if !Path::new(file).exists() {
+            self.message = Some(format!("E484: Can't open file {}", file));
+            return Ok(());
+        }
+        let Some(current) = self.buffers.get(self.active_buffer).map(|buffer| buffer.id) else {
+            return Ok(());
+        };
+        let windows = self.windows.len();
+        self.split_window(SplitType::Vertical)?;
+        if self.windows.len() == windows {
+            return Ok(());
+        }
+        self.open_file(file)?;
+        let other = self.buffers[self.active_buffer].id;
+        if other == current {
+            self.message = Some("Cannot diff a buffer with itself".to_string());
+            return Ok(());
+        }
+        self.diff_view = Some(DiffView { buffers: [current, other], hunks: Vec::new() });
+        self.refresh_diff();
+        Ok(())
+    }
+
+    /// Compare the buffers of the diff view again, ending it once either is gone
+    fn refresh_diff(&mut self) {
+        let Some(view) = &self.diff_view else {
+            return;
+        };
+        let find = |id: usize| self.buffers.iter().find(|buffer| buffer.id == id);
+        let hunks = match (find(view.buffers[0]), find(view.buffers[1])) {
+            (Some(old), Some(new)) => diff::diff_lines(&old.document.lines, &new.document.lines),
+            _ => {
+                self.diff_view = None;
+                return;
+            },
+        };
+        if let Some(view) = &mut self.diff_view {
+            view.hunks = hunks;
+        }
+    }
+
+    /// The diff view's hunks with whether the active buffer is its first buffer
+    fn active_diff(&self) -> Option<(&[DiffHunk], bool)> {
+        let view = self.diff_view.as_ref()?;
+        let id = self.buffers.get(self.active_buffer)?.id;
+        let side = view.buffers.iter().position(|&buffer| buffer == id)?;
+        Some((&view.hunks, side == 0))
+    }
+
+    /// ]c / [c: move to the start of the next or previous diff hunk
+    fn diff_hunk_step(&mut self, forward: bool) {
+        let Some((hunks, old)) = self.active_diff() else {
+            self.message = Some("E99: Current buffer is not in diff mode".to_string());
+            return;
+        };
+        let cursor_y = self.buffers[self.active_buffer].cursor_y;
+        let starts = hunks.iter().map(|hunk| hunk.side(old).start);
+        let target = if forward {
+            starts.filter(|&start| start > cursor_y).min()
+        } else {
+            starts.filter(|&start| start < cursor_y).max()
+        };
+        let Some(row) = target else {
+            self.message = Some("No more hunks".to_string());
+            return;
+        };
+        let buffer = &mut self.buffers[self.active_buffer];
+        buffer.cursor_y = row.min(buffer.document.lines.len().saturating_sub(1));
+        buffer.cursor_x = 0;
+        self.scroll_cursor_into_view();
+    }
+
+    /// dp: replace the hunk under the cursor with the other buffer's side of it
+    fn diff_put(&mut self) {
+        let Some((hunks, old)) = self.active_diff() else {
+            self.message = Some("E99: Current buffer is not in diff mode".to_string());
+            return;
+        };
+        let cursor_y = self.buffers[self.active_buffer].cursor_y;
+        // A hunk with no lines on this side sits at the row it would be inserted before
+        let Some(hunk) = hunks.iter()
+            .find(|hunk| hunk.side(old).contains(&cursor_y) || (hunk.side(old).is_empty() && hunk.side(old).start == cursor_y))
+            .cloned()
+        else {
+            self.message = Some("No diff hunk under the cursor".to_string());
+            return;
+        };
+        let Some(other_id) = self.diff_view.as_ref().map(|view| view.buffers[usize::from(old)]) else {
+            return;
+        };
+        let Some(lines) = self.buffers.iter()
+            .find(|buffer| buffer.id == other_id)
+            .map(|buffer| buffer.document.lines[hunk.side(!old).clone()].to_vec())
+        else {
+            return;
+        };
+        let buffer = &mut self.buffers[self.active_buffer];
+        buffer.document.replace_lines(hunk.side(old).clone(), &lines);
+        buffer.cursor_y = hunk.side(old).start;
+        buffer.cursor_x = 0;
+        self.clamp_cursor();
+        self.refresh_diff();
+    }
+
+    /// Look up the branch of the active buffer's repository, or of the working
+    /// directory's for buffers without a file
+    fn refresh_git_branch(&mut self) {
+        let dir = self.buffers.get(self.active_buffer)
+            .and_then(|buffer| buffer.filename.as_deref())
+            .and_then(|filename| Path::new(filename).parent())
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(&self.working_dir);
+        self.git_branch = githunk::current_branch(dir);
+        self.api_state.lock().unwrap().git_branch = self.git_branch.clone();
+    }
+
+    /// Read the unstaged git changes to the active buffer's file for the sign
+    /// column. Files outside a repository have none
+    fn refresh_git_hunks(&mut self) {
+        self.refresh_git_branch();
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return;
+        };
+        buffer.git_hunks = buffer.filename.as_deref()
+            .and_then(|filename| githunk::diff(Path::new(filename)).ok())
+            .map(|diff| diff.hunks.iter()
+                .map(|hunk| {
+                    let (start, end) = hunk.rows();
+                    (start, end, hunk.kind())
+                })
+                .collect())
+            .unwrap_or_default();
+    }
+
+    /// ]h / [h: move to the start of the next or previous git hunk
+    fn git_hunk_step(&mut self, forward: bool) {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return;
+        };
+        let starts = buffer.git_hunks.iter().map(|&(start, _, _)| start);
+        let target = if forward {
+            starts.filter(|&start| start > buffer.cursor_y).min()
+        } else {
+            starts.filter(|&start| start < buffer.cursor_y).max()
+        };
+        let Some(row) = target else {
+            self.message = Some("No more hunks".to_string());
+            return;
+        };
+        buffer.cursor_y = row.min(buffer.document.lines.len().saturating_sub(1));
+        buffer.cursor_x = 0;
+        self.scroll_cursor_into_view();
+    }
+
+    /// The active buffer's file and its unstaged changes, for Space h s / Space h r
+    fn active_git_diff(&mut self) -> Result<Option<(String, githunk::FileDiff)>> {
+        let Some(filename) = self.buffers.get(self.active_buffer).and_then(|buffer| buffer.filename.clone()) else {
+            self.message = Some("E32: No file name".to_string());
+            return Ok(None);
+        };
+        let diff = githunk::diff(Path::new(&filename))?;
+        Ok(Some((filename, diff)))
     }
 
-    fn move_cursor_left(&mut self) -> Result<()> {
-        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
-            if buffer.cursor_x > 0 {
-                buffer.cursor_x -= 1;
-            }
-        }
+    /// Space h s: stage the git hunk under the cursor
+    fn stage_git_hunk(&mut self) -> Result<()> {
+        let Some((filename, diff)) = self.active_git_diff()? else {
+            return Ok(());
+        };
+        let Some(hunk) = diff.hunk_at(self.buffers[self.active_buffer].cursor_y) else {
+            self.message = Some("No git hunk under the cursor".to_string());
+            return Ok(());
+        };
+        githunk::stage(Path::new(&filename), &diff, hunk)?;
+        self.message = Some("Hunk staged".to_string());
+        self.refresh_git_hunks();
         Ok(())
     }
 
-    fn move_cursor_right(&mut self) -> Result<()> {
-        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
-            let line_len = buffer.document.lines.get(buffer.cursor_y)
-                .map(|line| line.len())
-                .unwrap_or(0);
-            if buffer.cursor_x < line_len {
-                buffer.cursor_x += 1;
-            }
+    /// Space h r: put back the lines the index has for the git hunk under the
+    /// cursor. Hunks are read from the written file, so the buffer must be written
+    fn reset_git_hunk(&mut self) -> Result<()> {
+        if self.buffers.get(self.active_buffer).is_some_and(|buffer| buffer.document.modified) {
+            self.message = Some("E37: No write since last change".to_string());
+            return Ok(());
         }
+        let Some((_, diff)) = self.active_git_diff()? else {
+            return Ok(());
+        };
+        let buffer = &mut self.buffers[self.active_buffer];
+        let Some(hunk) = diff.hunk_at(buffer.cursor_y) else {
+            self.message = Some("No git hunk under the cursor".to_string());
+            return Ok(());
+        };
+        let rows = hunk.new_lines();
+        buffer.document.replace_lines(rows.clone(), &hunk.old_lines());
+        // The other hunks keep their marks until the file is written
+        let shift = |row: usize| (row + hunk.old_count).saturating_sub(hunk.new_count);
+        buffer.git_hunks = diff.hunks.iter()
+            .filter(|other| *other != hunk)
+            .map(|other| {
+                let (start, end) = other.rows();
+                if start >= rows.end { (shift(start), shift(end), other.kind()) } else { (start, end, other.kind()) }
+            })
+            .collect();
+        buffer.cursor_y = rows.start;
+        buffer.cursor_x = 0;
+        self.clamp_cursor();
         Ok(())
     }
 
-    fn move_cursor_up(&mut self) -> Result<()> {
-        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
-            if buffer.cursor_y > 0 {
-                buffer.cursor_y -= 1;
-            }
+    /// Space d b: set or clear a breakpoint on the cursor line
+    fn toggle_breakpoint(&mut self) -> Result<()> {
+        let Some(buffer) = self.buffers.get(self.active_buffer).filter(|buffer| !buffer.is_shell) else {
+            return Ok(());
+        };
+        let Some(filename) = &buffer.filename else {
+            self.message = Some("E32: No file name".to_string());
+            return Ok(());
+        };
+        let path = session::absolute(filename);
+        let row = buffer.cursor_y;
+        let rows = self.breakpoints.entry(path.clone()).or_default();
+        match rows.binary_search(&row) {
+            Ok(idx) => {
+                rows.remove(idx);
+                self.message = Some(format!("Breakpoint removed from line {}", row + 1));
+            },
+            Err(idx) => {
+                rows.insert(idx, row);
+                self.message = Some(format!("Breakpoint set on line {}", row + 1));
+            },
+        }
+        let rows = rows.clone();
+        if rows.is_empty() {
+            self.breakpoints.remove(&path);
+        }
+        if let Some(debug) = &mut self.debug_session {
+            debug.client.set_breakpoints(&path, &rows)?;
         }
         Ok(())
     }
 
-    fn move_cursor_down(&mut self) -> Result<()> {
-        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
-            if buffer.cursor_y < buffer.document.lines.len().saturating_sub(1) {
-                buffer.cursor_y += 1;
-            }
+    /// F5 / `:debug [program [args]]`: start the debug adapter for the active
+    /// file's language and launch `program` under it. Without a program the last
+    /// one is launched again, else the file itself for Python or the Cargo
+    /// binary named after the working directory for Rust
+    fn start_debugging(&mut self, program: Option<(String, Vec<String>)>) -> Result<()> {
+        if self.debug_session.is_some() {
+            self.message = Some("A debug session is already running".to_string());
+            return Ok(());
         }
+        let filename = self.buffers.get(self.active_buffer)
+            .and_then(|buffer| buffer.filename.clone())
+            .unwrap_or_default();
+        let language = Path::new(&filename).extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(get_language_id_from_extension);
+        let Some((language, (executable, args))) = language.and_then(|language| Some((language, dap::adapter_for(language)?))) else {
+            self.message = Some("No debug adapter for this file".to_string());
+            return Ok(());
+        };
+        let cwd = env::current_dir()?;
+        let program = program.or_else(|| self.debug_program.clone()).or_else(|| {
+            let program = match language {
+                "python" => filename.clone(),
+                "rust" => cwd.join("target").join("debug").join(cwd.file_name()?).to_string_lossy().to_string(),
+                _ => return None,
+            };
+            Some((program, Vec::new()))
+        });
+        let Some((program, program_args)) = program else {
+            self.message = Some("E471: Argument required: :debug {program} [args]".to_string());
+            return Ok(());
+        };
+
+        let mut client = DapClient::new(executable, args, &cwd)?;
+        client.initialize()?;
+        client.launch(serde_json::json!({
+            "name": "rvim",
+            "type": language,
+            "request": "launch",
+            "program": program,
+            "args": program_args,
+            "cwd": cwd,
+            "stopOnEntry": false,
+            "console": "internalConsole",
+        }))?;
+        self.message = Some(format!("Debugging {}", program));
+        self.debug_program = Some((program, program_args));
+        self.debug_session = Some(DebugSession {
+            client,
+            thread_id: None,
+            frames: Vec::new(),
+            status: "Running".to_string(),
+        });
+        self.fit_windows();
         Ok(())
     }
 
-    fn move_to_next_word_start(&mut self) -> Result<()> {
-        // Implementation coming soon
+    /// F5 / F10 / F11 / Shift+F11 while the program is stopped: continue, step
+    /// over, into or out of with the stopped thread
+    fn debug_step(&mut self, step: fn(&mut DapClient, u64) -> Result<u64>) -> Result<()> {
+        let Some(debug) = &mut self.debug_session else {
+            self.message = Some("No debug session, F5 starts one".to_string());
+            return Ok(());
+        };
+        let Some(thread_id) = debug.thread_id.take() else {
+            self.message = Some("The program is running".to_string());
+            return Ok(());
+        };
+        debug.frames.clear();
+        debug.status = "Running".to_string();
+        step(&mut debug.client, thread_id)?;
         Ok(())
     }
 
-    fn move_to_next_word_end(&mut self) -> Result<()> {
-        // Implementation coming soon
+    /// Shift+F5: end the debug session, stopping the program
+    fn stop_debugging(&mut self) -> Result<()> {
+        let Some(mut debug) = self.debug_session.take() else {
+            return Ok(());
+        };
+        if let Err(e) = debug.client.disconnect() {
+            error!("Failed to disconnect from the debug adapter: {}", e);
+        }
+        self.message = Some("Debug session ended".to_string());
+        self.fit_windows();
         Ok(())
     }
 
-    fn move_to_prev_word_start(&mut self) -> Result<()> {
-        // Implementation coming soon
-        Ok(())
+    /// Handle what the debug adapter sent since the last call; returns whether
+    /// anything arrived
+    fn poll_debug_session(&mut self) -> bool {
+        let Some(debug) = &mut self.debug_session else {
+            return false;
+        };
+        let messages = debug.client.poll();
+        let changed = !messages.is_empty();
+        for message in messages {
+            if let Err(e) = self.handle_debug_message(message) {
+                self.notify_level(NotificationLevel::Error, e.to_string(), NOTIFICATION_TIME);
+            }
+        }
+        changed
     }
 
-    fn cycle_window(&mut self) -> Result<()> {
-        if !self.windows.is_empty() {
-            self.active_window = (self.active_window + 1) % self.windows.len();
+    fn handle_debug_message(&mut self, message: DapMessage) -> Result<()> {
+        let Some(debug) = &mut self.debug_session else {
+            return Ok(());
+        };
+        match message {
+            DapMessage::Event { event, body } => match event.as_str() {
+                // The adapter is ready for the breakpoints
+                "initialized" => {
+                    for (path, rows) in &self.breakpoints {
+                        debug.client.set_breakpoints(path, rows)?;
+                    }
+                    debug.client.configuration_done()?;
+                },
+                "stopped" => {
+                    let thread_id = body["threadId"].as_u64().unwrap_or(1);
+                    debug.thread_id = Some(thread_id);
+                    debug.status = format!("Stopped ({})", body["reason"].as_str().unwrap_or("pause"));
+                    debug.client.stack_trace(thread_id)?;
+                },
+                "continued" => {
+                    debug.thread_id = None;
+                    debug.frames.clear();
+                    debug.status = "Running".to_string();
+                },
+                "exited" => {
+                    debug.status = format!("Exited with code {}", body["exitCode"]);
+                    self.message = Some(format!("Program exited with code {}", body["exitCode"]));
+                },
+                "output" => {
+                    if let Some(output) = body["output"].as_str() {
+                        info!("Debuggee: {}", output.trim_end());
+                    }
+                },
+                "terminated" => {
+                    let exited = self.debug_session.take().is_some_and(|debug| debug.status.starts_with("Exited"));
+                    if !exited {
+                        self.message = Some("Debug session ended".to_string());
+                    }
+                    self.fit_windows();
+                },
+                _ => {},
+            },
+            DapMessage::Response { command, result, .. } => match result {
+                Ok(body) if command == "stackTrace" => {
+                    debug.frames = StackFrame::from_response(&body);
+                    // Show where the program stopped
+                    if let Some(frame) = debug.frames.iter().find(|frame| frame.path.is_some()).cloned()
+                        && let Some(path) = &frame.path
+                    {
+                        self.go_to_location(path, frame.line, Some(frame.column));
+                    }
+                },
+                Ok(_) => {},
+                Err(e) => {
+                    // Without a program there is nothing to debug
+                    if matches!(command.as_str(), "launch" | "attach") {
+                        self.debug_session = None;
+                        self.fit_windows();
+                    }
+                    return Err(Error::DebugAdapter(format!("{}: {}", command, e)));
+                },
+            },
         }
         Ok(())
     }
 
-    fn close_window(&mut self) -> Result<()> {
-        if self.windows.len() > 1 {
-            self.windows.remove(self.active_window);
-            if self.active_window >= self.windows.len() {
-                self.active_window = self.windows.len() - 1;
+    /// Breakpoint markers for the rows of `buffer`, and an arrow on the row the
+    /// program stopped at
+    fn debug_signs(&self, buffer: &Buffer) -> HashMap<usize, (char, Color)> {
+        let mut signs = HashMap::new();
+        let Some(filename) = &buffer.filename else {
+            return signs;
+        };
+        if self.breakpoints.is_empty() && self.debug_session.is_none() {
+            return signs;
+        }
+        let path = session::absolute(filename);
+        for &row in self.breakpoints.get(&path).into_iter().flatten() {
+            signs.insert(row, ('●', Color::Red));
+        }
+        if let Some(frame) = self.debug_session.as_ref().and_then(|debug| debug.frames.first())
+            && frame.path.as_deref().is_some_and(|frame_path| same_path(frame_path, &path))
+        {
+            signs.insert(frame.line, ('▶', Color::Yellow));
+        }
+        signs
+    }
+
+    /// Rows the debug panel takes below the windows
+    fn debug_panel_height(&self) -> usize {
+        if self.debug_session.is_some() { DEBUG_PANEL_HEIGHT } else { 0 }
+    }
+
+    /// The debug panel between the windows and the status line: the session's
+    /// state, then the call stack with the frame the program stopped in first
+    fn draw_debug_panel(&self) -> Result<()> {
+        let Some(debug) = &self.debug_session else {
+            return Ok(());
+        };
+        let width = self.terminal_width;
+        let top = self.terminal_height.saturating_sub(DEBUG_PANEL_HEIGHT + 2);
+        execute!(io::stdout(), cursor::MoveTo(0, top as u16),
+            SetForegroundColor(Color::Black), SetBackgroundColor(Color::Grey))?;
+        print!("{:<width$}", format!(" Debug: {}", debug.status), width = width);
+        execute!(io::stdout(), ResetColor)?;
+
+        for row in 1..DEBUG_PANEL_HEIGHT {
+            let entry = debug.frames.get(row - 1).map(|frame| match &frame.path {
+                Some(path) => format!("{} {}:{}", frame.name, path.display(), frame.line + 1),
+                None => frame.name.clone(),
+            });
+            let entry: String = entry.unwrap_or_default().chars().take(width).collect();
+            execute!(io::stdout(), cursor::MoveTo(0, (top + row) as u16))?;
+            if row == 1 && !debug.frames.is_empty() {
+                execute!(io::stdout(), SetBackgroundColor(Color::DarkGrey))?;
             }
+            print!("{:<width$}", entry, width = width);
+            execute!(io::stdout(), ResetColor)?;
         }
         Ok(())
     }
@@ -1235,15 +8095,38 @@ impl Editor {
         match key.code {
             KeyCode::Esc => {
                 self.mode = Mode::Normal;
+                self.tab_choices.clear();
+                self.message = None;
+                Ok(())
+            },
+            KeyCode::Tab => {
+                self.tab_manager.switch_to_next_tab()?;
+                self.show_current_tab()
+            },
+            KeyCode::BackTab => {
+                self.tab_manager.switch_to_prev_tab()?;
+                self.show_current_tab()
+            },
+            // Pick one of the candidates listed by :tabfind
+            KeyCode::Char(c @ '1'..='9') => {
+                let choice = c.to_digit(10).unwrap_or(0) as usize - 1;
+                if let Some(&idx) = self.tab_choices.get(choice) {
+                    self.tab_manager.switch_to_tab(idx)?;
+                    self.show_current_tab()?;
+                    self.tab_choices.clear();
+                    self.message = None;
+                    self.mode = Mode::Normal;
+                }
                 Ok(())
             },
-            KeyCode::Tab => self.tab_manager.switch_to_next_tab(),
-            KeyCode::BackTab => self.tab_manager.switch_to_prev_tab(),
             _ => Ok(())
         }
     }
 
     fn process_mouse_event(&mut self, event: event::MouseEvent) -> Result<()> {
+        // Remember where the mouse is; the diagnostic tooltip follows it
+        self.mouse_x = event.column;
+        self.mouse_y = event.row;
         match event.kind {
             event::MouseEventKind::Down(button) => {
                 // Handle mouse clicks
@@ -1253,47 +8136,269 @@ impl Editor {
                     _ => {}
                 }
             },
+            // The file tree's border follows the mouse while it is dragged
+            event::MouseEventKind::Drag(event::MouseButton::Left) | event::MouseEventKind::Moved
+                if self.resizing_file_tree =>
+            {
+                if let Some(tree) = &mut self.file_tree {
+                    tree.set_width(event.column as usize, self.terminal_width);
+                }
+                self.fit_windows();
+            },
+            event::MouseEventKind::Up(event::MouseButton::Left) => self.resizing_file_tree = false,
             _ => {}
         }
         Ok(())
     }
 
     fn handle_left_click(&mut self, x: usize, y: usize) -> Result<()> {
-        // Update cursor position based on click
-        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
-            buffer.cursor_x = x;
-            buffer.cursor_y = y;
+        // A click on the file tree's border starts resizing it
+        if let Some(tree) = &self.file_tree
+            && tree.visible
+            && x.abs_diff(tree.width) <= 2
+        {
+            self.resizing_file_tree = true;
+            return Ok(());
+        }
+        if !matches!(self.mode, Mode::Normal | Mode::Insert | Mode::Visual | Mode::VisualLine | Mode::VisualBlock) {
+            return Ok(());
+        }
+        let filetree_width = match &self.file_tree {
+            Some(tree) if tree.visible => tree.width + 1,
+            _ => 0,
+        };
+        let Some(idx) = self.windows.iter().position(|window| {
+            let left = window.x + filetree_width;
+            (left..left + window.width).contains(&x) && (window.y..window.y + window.height).contains(&y)
+        }) else {
+            return Ok(());
+        };
+        if idx != self.active_window {
+            self.focus_window(idx);
+        }
+        let bordered = self.windows.len() > 1;
+        let content_x = self.windows[idx].x + filetree_width + usize::from(bordered) + self.blame_column_width();
+        let content_y = self.windows[idx].y + usize::from(bordered);
+        let (width, height) = (self.window_text_width(), self.window_text_height());
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer).filter(|buffer| !buffer.is_shell) else {
+            return Ok(());
+        };
+        if !(content_y..content_y + height).contains(&y) {
+            return Ok(());
+        }
+        // A click on the gutter goes to the start of that line
+        let text_x = x.saturating_sub(content_x + gutter_width(buffer));
+        let (row, col) = buffer.screen_to_logical_pos(y - content_y, text_x, width);
+        buffer.cursor_y = row;
+        buffer.cursor_x = col;
+
+        let double = self.last_click
+            .is_some_and(|(at, last_x, last_y)| (last_x, last_y) == (x, y) && at.elapsed() < DOUBLE_CLICK_TIME);
+        self.last_click = Some((Instant::now(), x, y));
+        if double && self.mode != Mode::Insert {
+            self.select_word_at_cursor();
+        } else if matches!(self.mode, Mode::Visual | Mode::VisualLine | Mode::VisualBlock) {
+            self.exit_visual();
         }
         Ok(())
     }
 
-    fn fuzzy_find_files(&mut self) -> Result<()> {
-        let input = &self.command_line[2..]; // Skip ":f "
-        self.fuzzy_results.clear();
-        
-        if let Some(tree) = &self.file_tree {
-            for entry in &tree.entries {
-                if let Some(score) = self.fuzzy_matcher.fuzzy_match(&entry.name, input) {
-                    self.fuzzy_results.push((entry.name.clone(), score));
+    /// Double click: select the word under the cursor in Visual mode
+    fn select_word_at_cursor(&mut self) {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return;
+        };
+        let lines = &buffer.document.lines;
+        let Some((row, start, _, end)) = TextObject::Word { inner: true }.range(lines, buffer.cursor_y, buffer.cursor_x) else {
+            return;
+        };
+        // The selection includes the character under the cursor
+        buffer.cursor_x = lines[row][..end].char_indices().last().map_or(start, |(idx, _)| idx);
+        self.visual_anchor = Some((row, start));
+        self.mode = Mode::Visual;
+    }
+
+    fn open_picker<T: 'static>(&mut self, picker: Picker<T>) {
+        self.picker = Some(Box::new(picker));
+        self.workspace_symbol_query = None;
+        self.previous_mode = self.mode;
+        self.mode = Mode::Picker;
+    }
+
+    fn process_picker_mode(&mut self, key: KeyEvent) -> Result<()> {
+        let status = match self.picker.as_mut() {
+            Some(picker) => picker.process_picker_keypress(key),
+            None => PickerStatus::Cancelled,
+        };
+        if status != PickerStatus::Open {
+            self.workspace_symbol_query = None;
+        }
+        match status {
+            // Workspace symbols are searched by the server, so ask again for the new query
+            PickerStatus::Open => match (&self.workspace_symbol_query, &self.picker) {
+                (Some(query), Some(picker)) if picker.query() != query => {
+                    let query = picker.query().to_string();
+                    self.workspace_symbol_search(&query)
+                },
+                _ => Ok(()),
+            },
+            PickerStatus::Cancelled => {
+                self.picker = None;
+                self.mode = self.previous_mode;
+                Ok(())
+            },
+            PickerStatus::Confirmed => {
+                self.mode = self.previous_mode;
+                match self.picker.take() {
+                    Some(picker) => picker.confirm(self),
+                    None => Ok(()),
                 }
-            }
+            },
         }
-        
-        self.fuzzy_results.sort_by_key(|(_, score)| -score);
+    }
+
+    /// Fuzzy find files under the working directory, skipping hidden and
+    /// git-ignored files
+    fn open_file_picker(&mut self) -> Result<()> {
+        let root = env::current_dir()?;
+        let items: Vec<PickerItem<PathBuf>> = workspace_files(&root).into_iter()
+            .map(|path| {
+                let display = path.strip_prefix(&root).unwrap_or(&path).display().to_string();
+                PickerItem::new(display, path)
+            })
+            .collect();
+
+        let picker = Picker::new("Files", items, Box::new(|editor: &mut Editor, path: PathBuf| {
+            let root = env::current_dir()?;
+            let path = path.strip_prefix(&root).unwrap_or(&path);
+            editor.open_file(&path.to_string_lossy())
+        }));
+        self.open_picker(picker.with_limit(FINDER_RESULTS));
         Ok(())
     }
-    
-    fn show_command_palette(&mut self) -> Result<()> {
-        let input = &self.command_line[1..]; // Skip ":"
-        self.fuzzy_results.clear();
-        
-        for cmd in &self.command_palette_items {
-            if let Some(score) = self.fuzzy_matcher.fuzzy_match(cmd, input) {
-                self.fuzzy_results.push((cmd.clone(), score));
+
+    fn open_buffer_picker(&mut self) -> Result<()> {
+        let items = self.buffers.iter()
+            .enumerate()
+            .map(|(idx, buffer)| {
+                let name = match &buffer.filename {
+                    Some(filename) => filename.clone(),
+                    None if buffer.is_shell => "[Shell]".to_string(),
+                    None => "[No Name]".to_string(),
+                };
+                let modified = if buffer.document.modified { " [+]" } else { "" };
+                PickerItem::new(format!("{}: {}{}", buffer.id, name, modified), idx)
+            })
+            .collect();
+
+        self.open_picker(Picker::new("Buffers", items, Box::new(|editor: &mut Editor, idx: usize| {
+            if idx < editor.buffers.len() {
+                editor.active_buffer = idx;
+                if editor.buffers[idx].is_shell {
+                    editor.mode = Mode::Shell;
+                }
+            }
+            Ok(())
+        })));
+        Ok(())
+    }
+
+    fn request_symbol_picker(&mut self) -> Result<()> {
+        self.send_lsp_request("textDocument/documentSymbol", false, LspRequest::DocumentSymbols)
+    }
+
+    /// Show document symbols and jump to the chosen one
+    fn open_symbol_picker(&mut self, symbols: Vec<DocumentSymbol>) {
+        let items = symbols.into_iter()
+            .map(|symbol| {
+                let display = match &symbol.container {
+                    Some(container) => format!("{} [{}] in {}", symbol.name, symbol.kind_name(), container),
+                    None => format!("{} [{}]", symbol.name, symbol.kind_name()),
+                };
+                PickerItem::new(display, symbol)
+            })
+            .collect();
+
+        self.open_picker(Picker::new("Symbols", items, Box::new(|editor: &mut Editor, symbol: DocumentSymbol| {
+            if let Some(buffer) = editor.buffers.get_mut(editor.active_buffer) {
+                let row = symbol.line.min(buffer.document.lines.len().saturating_sub(1));
+                // LSP columns count characters; the cursor is a byte column
+                let col = buffer.document.lines.get(row)
+                    .and_then(|line| line.char_indices().nth(symbol.character).map(|(i, _)| i))
+                    .unwrap_or(0);
+                buffer.cursor_y = row;
+                buffer.cursor_x = col;
             }
+            Ok(())
+        })));
+    }
+
+    /// Space w s: ask the language server for the workspace's symbols matching
+    /// `query`. The picker opens with the first answer and asks again as the
+    /// query is typed
+    fn workspace_symbol_search(&mut self, query: &str) -> Result<()> {
+        let Some(filename) = self.buffers.get(self.active_buffer).and_then(|buffer| buffer.filename.clone()) else {
+            self.message = Some("E32: No file name".to_string());
+            return Ok(());
+        };
+        self.workspace_symbol_query = Some(query.to_string());
+        let params = serde_json::json!({ "query": query });
+        self.send_lsp_params(Path::new(&filename), "workspace/symbol", params, LspRequest::WorkspaceSymbols(query.to_string()))
+    }
+
+    /// Show the symbols found for `query` in the workspace symbol picker, opening it
+    /// if needed, and jump to the chosen one
+    fn show_workspace_symbols(&mut self, query: &str, symbols: Vec<WorkspaceSymbol>) {
+        let cwd = env::current_dir().unwrap_or_default();
+        let items = symbols.into_iter()
+            .map(|symbol| {
+                let path = &symbol.location.path;
+                let place = format!("{}:{}", path.strip_prefix(&cwd).unwrap_or(path).display(), symbol.location.line + 1);
+                let display = match &symbol.container {
+                    Some(container) => format!("{} {} in {}  {}", symbol.kind_icon(), symbol.name, container, place),
+                    None => format!("{} {}  {}", symbol.kind_icon(), symbol.name, place),
+                };
+                PickerItem::new(display, symbol.location)
+            })
+            .collect();
+        // The server already matched the symbols against the query
+        let picker = Picker::new("Workspace symbols", items, Box::new(|editor: &mut Editor, location: Location| {
+            editor.push_jump();
+            editor.go_to_location(&location.path, location.line, Some(location.character));
+            Ok(())
+        }))
+            .with_filter(Box::new(|_, _| Some(0)))
+            .with_query(query);
+        if self.mode == Mode::Picker {
+            self.picker = Some(Box::new(picker));
+        } else {
+            self.open_picker(picker);
         }
-        
-        self.fuzzy_results.sort_by_key(|(_, score)| -score);
+        self.workspace_symbol_query = Some(query.to_string());
+    }
+
+    fn open_colorscheme_picker(&mut self) -> Result<()> {
+        let items = HighlightTheme::names().into_iter()
+            .map(|name| PickerItem::new(name.clone(), name))
+            .collect();
+
+        self.open_picker(Picker::new("Colorschemes", items, Box::new(|editor: &mut Editor, name: String| {
+            editor.set_colorscheme(&name)?;
+            editor.message = Some(format!("colorscheme {}", name));
+            Ok(())
+        })));
+        Ok(())
+    }
+
+    fn open_command_palette(&mut self) -> Result<()> {
+        let items = self.command_palette_items.iter()
+            .map(|cmd| PickerItem::new(cmd.clone(), cmd.trim_start_matches(':').to_string()))
+            .collect();
+
+        self.open_picker(Picker::new("Commands", items, Box::new(|editor: &mut Editor, cmd: String| {
+            editor.command_line = cmd;
+            editor.execute_command()
+        })));
         Ok(())
     }
     
@@ -1303,19 +8408,62 @@ impl Editor {
             "=========",
             "",
             "Normal Mode:",
-            "  h/j/k/l - Move cursor",
-            "  i - Enter insert mode",
-            "  v - Enter visual mode",
-            "  : - Enter command mode",
-            "  q - Quit",
+            "  h/j/k/l - Move cursor, gj/gk - Down/up a screen row of a wrapped line",
+            "  gg/G - First/last line, {n}G - Go to line n",
+            "  Ctrl+D/Ctrl+U - Scroll half a page, Ctrl+F/Ctrl+B - Scroll a page",
+            "  Ctrl+G - Show file information",
+            "  Ctrl+Z - Suspend to the shell (fg resumes)",
+            "  :debug [program] - Debug a program, F5 - Start/continue, Shift+F5 - Stop",
+            "  F10/F11/Shift+F11 - Step over/into/out while debugging",
+            "  / or ? - Search forward/backward, n/N - Next/previous match",
+            "  * - Add word under cursor as a search pattern",
+            "  gt/gT - Next/previous tab, {n}gt - Go to tab n",
+            "  g<Tab> - Last accessed tab",
+            "  {count}j, 3w, ... - Repeat a motion, dd - Delete line",
+            "  0/^/$ - Line start/first non-blank/line end, {n}| - Column n",
+            "  u - Undo, Ctrl+R - Redo, . - Repeat last change",
+            "  yy - Yank line, yw/ye/yb/yiw - Yank a word, p/P - Paste after/before the cursor",
+            "  >>/<< - Indent/unindent line ({n}>> for n lines)",
+            "  d/c + w/e/b/iw/aw/i\"/i( - Delete/change a word, quoted string or brackets, cc - Change line",
+            "  K - Show hover documentation, gd - Go to definition",
+            "  :lnext/:lprev - Next/previous diagnostic",
+            "  :rg/:grep {pattern} - List matches, :cnext/:cprev or ]q/[q - Next/previous match",
+            "  :bufdo {cmd} - Run a command in every buffer, :cdo {cmd} - At every match",
+            "  :TableFormat or Space t f - Line up a Markdown table",
+            "  :diffsplit {file} - Compare with a file, ]c/[c - Next/previous hunk, dp - Take the other side",
+            "  ]h/[h - Next/previous git hunk, Space h s/Space h r - Stage/reset it",
+            "  Ctrl+O/Ctrl+I - Back/forward in the jump list",
+            "  m{a-z} - Set mark, '{a-z}/`{a-z} - Jump to its line/position, '' - Jump back",
+            "  zf{motion} - Fold lines, zo/zc/za - Open/close/toggle fold, zR/zM - Open/close all",
+            "  i / a - Insert before/after the cursor",
+            "  I / A - Insert at the start/end of the line",
+            "  o/O - Open a line below/above and enter insert mode",
+            "  v - Enter visual mode, V - Visual line, Ctrl+V - Visual block",
+            "  V{motion}:sort / :sort! - Sort the selected lines ascending/descending",
+            "  : - Enter command mode, :e {file} - Edit a file (Tab completes)",
+            "  :w {file}/:saveas {file} - Save under a new name, :wa - Save all",
+            "  :q/:qa - Quit unless changes are unsaved, :q!/:qa! - Quit anyway",
+            "  :mksession/:source [file] - Save/restore the session",
+            "  :cd [dir] - Change the working directory, :cd - - Go back, :pwd - Show it",
+            "  :tabnew [file], :tabclose, :tabnext [n], :tabprev - Manage tabs",
+            "  q{a-z} - Record a macro, q - Stop, @{a-z} / @@ - Play it",
             "",
             "Leader Commands (Space):",
             "  e - Toggle file tree",
-            "  v - Open vertical shell",
-            "  h - Open horizontal shell",
+            "  s/v - Split window horizontally/vertically",
+            "  h - Open horizontal shell (:vterm for vertical)",
             "  w - Cycle windows",
             "  q - Close window",
             "  x - Close buffer",
+            "  b - Toggle git blame",
+            "  m - Toggle minimap",
+            "  a - Code actions (number and Enter to run one)",
+            "  r n - Rename symbol",
+            "  w s - Workspace symbols",
+            "  d b - Toggle breakpoint",
+            "  / - Add another search pattern",
+            "  f - Find files",
+            "  (pause after Space to list all bindings)",
             "",
             "Press any key to close help"
         ];
@@ -1350,22 +8498,106 @@ impl Editor {
     }
 
     // Delete the entire line at the cursor
-    fn delete_current_line(&mut self) -> Result<()> {
+    // Delete `count` lines starting at the cursor into the yank register, as one undo step
+    fn delete_current_line(&mut self, count: usize) -> Result<()> {
         if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
-            let row = buffer.cursor_y;
-            if row < buffer.document.lines.len() {
-                buffer.document.lines.remove(row);
-                buffer.document.modified = true;
-                // clamp cursor
-                if buffer.cursor_y >= buffer.document.lines.len() && !buffer.document.lines.is_empty() {
-                    buffer.cursor_y = buffer.document.lines.len() - 1;
-                }
+            let (row, last_row) = buffer.fold_span(buffer.cursor_y, count.max(1));
+            buffer.cursor_y = row;
+            let deleted = buffer.document.delete_lines(row, last_row);
+            if !deleted.is_empty() {
+                self.registers.insert(UNNAMED_REGISTER, deleted.join("\n") + "\n");
+                // clamp cursor; the document always keeps at least one line
+                buffer.cursor_y = buffer.cursor_y.min(buffer.document.lines.len() - 1);
                 buffer.cursor_x = 0;
             }
         }
         Ok(())
     }
 
+    /// `yy`: yank `count` lines from the cursor linewise, so `p` puts them below the line
+    fn yank_current_lines(&mut self, count: usize) -> Result<()> {
+        let Some(buffer) = self.buffers.get(self.active_buffer) else {
+            return Ok(());
+        };
+        let lines = &buffer.document.lines;
+        let (row, last_row) = buffer.fold_span(buffer.cursor_y.min(lines.len().saturating_sub(1)), count.max(1));
+        let Some(yanked) = lines.get(row..=last_row) else {
+            return Ok(());
+        };
+        let text = yanked.join("\n") + "\n";
+        let rows = last_row + 1 - row;
+        self.message = (rows > 2).then(|| format!("{} lines yanked", rows));
+        self.yank_text(text);
+        Ok(())
+    }
+
+    /// Put yanked text in the unnamed register and on the system clipboard
+    fn yank_text(&mut self, text: String) {
+        if let Err(e) = clipboard::set_clipboard(&text) {
+            self.notify_level(NotificationLevel::Warning, format!("Clipboard: {}", e), NOTIFICATION_TIME);
+        }
+        self.registers.insert(UNNAMED_REGISTER, text);
+    }
+
+    fn undo(&mut self) -> Result<()> {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return Ok(());
+        };
+        match buffer.document.undo() {
+            Some(position) => self.move_cursor_to_change(position),
+            None => self.message = Some("Already at oldest change".to_string()),
+        }
+        Ok(())
+    }
+
+    fn redo(&mut self) -> Result<()> {
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return Ok(());
+        };
+        match buffer.document.redo() {
+            Some(position) => self.move_cursor_to_change(position),
+            None => self.message = Some("Already at newest change".to_string()),
+        }
+        Ok(())
+    }
+
+    // Put the unnamed register `count` times after or before the cursor. Text
+    // ending in a newline was yanked linewise and goes below or above the line
+    fn paste(&mut self, after: bool, count: usize) -> Result<()> {
+        let Some(text) = self.registers.get(&UNNAMED_REGISTER).filter(|text| !text.is_empty()) else {
+            self.message = Some("E353: Nothing in register \"".to_string());
+            return Ok(());
+        };
+        let text = text.repeat(count.max(1));
+        let Some(buffer) = self.buffers.get_mut(self.active_buffer) else {
+            return Ok(());
+        };
+        let (row, col) = (buffer.cursor_y, buffer.cursor_x);
+        let cursor = if let Some(lines) = text.strip_suffix('\n') {
+            let at = if after { row + 1 } else { row };
+            buffer.document.insert_lines(at, &lines.split('\n').collect::<Vec<_>>());
+            (at.min(buffer.document.lines.len().saturating_sub(1)), 0)
+        } else {
+            let line = buffer.document.lines.get(row).map_or("", String::as_str);
+            let col = if after { char_end(line, col) } else { col };
+            let (end_row, end_col) = buffer.document.insert_text(row, col, &text);
+            // Leave the cursor on the last pasted character
+            let last = text.chars().last().filter(|&c| c != '\n').map_or(0, char::len_utf8);
+            (end_row, end_col - last)
+        };
+        self.move_cursor_to_change(cursor);
+        Ok(())
+    }
+
+    fn move_cursor_to_change(&mut self, (row, col): (usize, usize)) {
+        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
+            buffer.cursor_y = row;
+            buffer.cursor_x = col;
+            self.cursor_y = row;
+            self.cursor_x = col;
+        }
+    }
+
     // Delete the character under the cursor
     fn delete_char_under_cursor(&mut self) -> Result<()> {
         if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
@@ -1379,4 +8611,61 @@ impl Editor {
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf16_columns_of_multibyte_lines() {
+        let line = "café 😀x";
+        assert_eq!(byte_to_utf16(line, 3), 3);
+        assert_eq!(byte_to_utf16(line, 5), 4);
+        // Inside é and inside the emoji
+        assert_eq!(byte_to_utf16(line, 4), 3);
+        assert_eq!(byte_to_utf16(line, 8), 5);
+        assert_eq!(byte_to_utf16(line, 10), 7);
+        assert_eq!(byte_to_utf16(line, 100), 8);
+        assert_eq!(utf16_to_byte(line, 7), 10);
+        assert_eq!(utf16_to_byte(line, 4), 5);
+    }
+
+    fn classes(tokens: &[WordToken]) -> Vec<(usize, usize, WordClass)> {
+        tokens.iter().map(|token| (token.col, token.end, token.class)).collect()
+    }
+
+    #[test]
+    fn word_tokens_split_punctuation_and_keep_clusters_whole() {
+        use WordClass::*;
+        // e + combining acute accent, then a family emoji joined with ZWJs
+        let line = "e\u{301}_1.; 👨\u{200d}👩";
+        assert_eq!(classes(&line_word_tokens(line, 0)), [
+            (0, 3, Word), (3, 4, Word), (4, 5, Word),
+            (5, 6, Punctuation), (6, 7, Punctuation),
+            (7, 8, Blank),
+            (8, 19, Punctuation),
+            (19, 20, LineBreak),
+        ]);
+        assert_eq!(classes(&line_word_tokens("", 3)), [(0, 1, EmptyLine), (0, 1, LineBreak)]);
+    }
+
+    #[test]
+    fn word_extent_follows_one_class_across_line_ends() {
+        let lines: Vec<String> = ["foo.bar", "", "  baz"].iter().map(|line| line.to_string()).collect();
+        let extent = |row, col, stop_at_empty| {
+            word_extent(&mut word_tokens_after(&lines, row, col).peekable(), stop_at_empty)
+                .map(|token| (token.row, token.col))
+        };
+        assert_eq!(extent(0, 0, false), Some((0, 2)));
+        assert_eq!(extent(0, 3, false), Some((0, 3)));
+        // From the end of a line past the empty one and the indent
+        assert_eq!(extent(0, 7, false), Some((2, 4)));
+        assert_eq!(extent(0, 7, true), Some((1, 0)));
+        assert_eq!(extent(2, 5, false), None);
+
+        let mut before = word_tokens_before(&lines, 2, 2).peekable();
+        assert_eq!(word_extent(&mut before, true).map(|token| (token.row, token.col)), Some((1, 0)));
+        assert_eq!(word_extent(&mut before, true).map(|token| (token.row, token.col)), Some((0, 4)));
+    }
+}
@@ -9,6 +9,12 @@ use std::sync::mpsc::{channel, Receiver};
 use std::process::Command;
 use std::collections::HashMap;
 
+/// Width of the tree when the terminal has room for it
+pub const DEFAULT_WIDTH: usize = 30;
+
+/// Narrowest the tree can be resized to
+const MIN_WIDTH: usize = 10;
+
 pub struct FileTreeEntry {
     pub name: String,
     pub path: PathBuf,
@@ -24,6 +30,8 @@ pub struct FileTree {
     pub cursor: usize,
     pub visible: bool,
     pub width: usize,
+    /// List entries whose names start with `.`
+    pub show_hidden: bool,
     watcher: Option<RecommendedWatcher>,
     fs_events: Option<Receiver<notify::Result<notify::Event>>>,
     git_statuses: HashMap<PathBuf, GitStatus>,
@@ -38,6 +46,26 @@ enum GitStatus {
     Clean,
 }
 
+// Reject names that would leave the target directory. A trailing `/` is only
+// allowed when creating directories
+fn validate_name(name: &str, allow_dir: bool) -> Result<&str> {
+    let trimmed = if allow_dir { name.trim_end_matches('/') } else { name };
+    let valid = !trimmed.is_empty()
+        && !trimmed.starts_with('/')
+        && Path::new(trimmed).components().all(|part| matches!(part, std::path::Component::Normal(_)))
+        && (allow_dir || !trimmed.contains('/'));
+    if valid {
+        Ok(trimmed)
+    } else {
+        Err(Error::InvalidFileName(name.to_string()))
+    }
+}
+
+/// Dot files and directories, which the tree only lists with `show_hidden`
+pub fn is_hidden(name: &str) -> bool {
+    name.starts_with('.') && name != ".." && name != "."
+}
+
 impl From<notify::Error> for Error {
     fn from(err: notify::Error) -> Self {
         Error::Message(format!("File watch error: {}", err))
@@ -68,7 +96,8 @@ impl FileTree {
             entries: vec![],
             cursor: 0,
             visible: false,
-            width: 30, // Default width
+            width: DEFAULT_WIDTH,
+            show_hidden: false,
             watcher: Some(watcher),
             fs_events: Some(rx),
             git_statuses: HashMap::new(),
@@ -80,12 +109,133 @@ impl FileTree {
         Ok(tree)
     }
 
+    /// Narrow the tree so it takes at most a third of a terminal `terminal_width`
+    /// columns wide, growing back to `width` when there is room
+    pub fn fit_width(&mut self, width: usize, terminal_width: usize) {
+        self.width = width.min(terminal_width / 3).max(1);
+    }
+
+    /// Resize the tree to `width` columns, kept between MIN_WIDTH and half of a
+    /// terminal `terminal_width` columns wide
+    pub fn set_width(&mut self, width: usize, terminal_width: usize) {
+        self.width = width.max(MIN_WIDTH).min(terminal_width / 2).max(1);
+    }
+
+    /// Reload the tree from disk, keeping expanded directories open and the
+    /// cursor on the same path where it still exists
     pub fn refresh(&mut self) -> Result<()> {
+        let selected = self.get_selected_path();
+        let expanded: Vec<PathBuf> = self.entries.iter()
+            .filter(|entry| entry.is_expanded)
+            .map(|entry| entry.path.clone())
+            .collect();
+
         self.entries.clear();
         self.load_entries(&self.root.clone(), 0)?;
+
+        // Children are inserted right after their parent, so nested directories
+        // are reached later in the same pass
+        let mut idx = 0;
+        while idx < self.entries.len() {
+            if self.entries[idx].is_dir && expanded.contains(&self.entries[idx].path) {
+                self.cursor = idx;
+                self.toggle_expand()?;
+            }
+            idx += 1;
+        }
+
+        self.cursor = self.cursor.min(self.entries.len().saturating_sub(1));
+        if let Some(path) = selected {
+            self.select_path(&path);
+        }
         Ok(())
     }
 
+    /// Move the cursor to `path` if it is shown
+    pub fn select_path(&mut self, path: &Path) {
+        if let Some(idx) = self.entries.iter().position(|entry| entry.path == path) {
+            self.cursor = idx;
+        }
+    }
+
+    /// Directory new entries go into: the selected directory, or the one
+    /// containing the selected file
+    pub fn selected_directory(&self) -> PathBuf {
+        match self.entries.get(self.cursor) {
+            Some(entry) if entry.is_dir => entry.path.clone(),
+            Some(entry) => entry.path.parent().map_or_else(|| self.root.clone(), Path::to_path_buf),
+            None => self.root.clone(),
+        }
+    }
+
+    /// Create a file, or a directory when `name` ends with `/`, inside `dir`.
+    /// Missing parent directories in `name` are created too
+    pub fn create_entry(&mut self, dir: &Path, name: &str) -> Result<PathBuf> {
+        let path = dir.join(validate_name(name, true)?);
+        if path.exists() {
+            return Err(Error::FileExists(path));
+        }
+        if name.ends_with('/') {
+            fs::create_dir_all(&path).map_err(|e| Error::from((path.clone(), e)))?;
+        } else {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(|e| Error::from((parent.to_path_buf(), e)))?;
+            }
+            fs::File::create(&path).map_err(|e| Error::from((path.clone(), e)))?;
+        }
+        info!("Created {:?}", path);
+        self.after_change(&path)?;
+        Ok(path)
+    }
+
+    /// Rename `path` to `new_name` within the same directory
+    pub fn rename_entry(&mut self, path: &Path, new_name: &str) -> Result<PathBuf> {
+        let parent = path.parent().unwrap_or(&self.root);
+        let target = parent.join(validate_name(new_name, false)?);
+        if target == path {
+            return Ok(target);
+        }
+        if target.exists() {
+            return Err(Error::FileExists(target));
+        }
+        fs::rename(path, &target).map_err(|e| Error::from((path.to_path_buf(), e)))?;
+        info!("Renamed {:?} to {:?}", path, target);
+        self.after_change(&target)?;
+        Ok(target)
+    }
+
+    /// Delete a file, or a directory with everything in it
+    pub fn delete_entry(&mut self, path: &Path) -> Result<()> {
+        let result = if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+        result.map_err(|e| Error::from((path.to_path_buf(), e)))?;
+        info!("Deleted {:?}", path);
+        self.refresh()?;
+        self.update_git_status()
+    }
+
+    // Reload after a file operation and put the cursor on the changed path,
+    // expanding its parent directories so it is visible
+    fn after_change(&mut self, path: &Path) -> Result<()> {
+        self.refresh()?;
+        let mut ancestors: Vec<&Path> = path.ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.root) && *dir != self.root)
+            .collect();
+        ancestors.reverse();
+        for dir in ancestors {
+            if !self.is_directory_expanded(dir.to_path_buf()) {
+                self.select_path(dir);
+                self.toggle_expand()?;
+            }
+        }
+        self.select_path(path);
+        self.update_git_status()
+    }
+
     fn load_entries(&mut self, dir: &Path, level: usize) -> Result<()> {
         let entries = fs::read_dir(dir)?;
 
@@ -98,8 +248,7 @@ impl FileTree {
             let path = entry.path();
             let name = entry.file_name().to_string_lossy().to_string();
             
-            // Skip hidden files/directories (optional)
-            if name.starts_with('.') && name != ".." && name != "." {
+            if !self.show_hidden && is_hidden(&name) {
                 continue;
             }
 
@@ -250,8 +399,7 @@ impl FileTree {
             let path = entry.path();
             let name = entry.file_name().to_string_lossy().to_string();
             
-            // Skip hidden files/directories (optional)
-            if name.starts_with('.') && name != ".." && name != "." {
+            if !self.show_hidden && is_hidden(&name) {
                 continue;
             }
             
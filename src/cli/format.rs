@@ -0,0 +1,85 @@
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use crate::error::{Error, Result};
+
+/// Formatter program and arguments for a filetype; each reads the file from
+/// stdin and writes the formatted text to stdout. `filename` tells formatters
+/// that handle several languages which one the input is
+pub fn formatter_for(filetype: &str, filename: &str) -> Option<(&'static str, Vec<String>)> {
+    let (program, args): (&str, &[&str]) = match filetype {
+        // rustfmt reading stdin cannot see the crate's edition, so pass it on;
+        // without a Cargo.toml rustfmt.toml or rustfmt's default decides
+        "rust" => {
+            let args = cargo_edition(filename).map_or_else(Vec::new, |edition| vec!["--edition".to_string(), edition]);
+            return Some(("rustfmt", args));
+        },
+        "python" => ("black", &["-q", "-"]),
+        "go" => ("gofmt", &[]),
+        "javascript" | "typescript" | "css" | "html" | "json" | "markdown" | "yaml" => {
+            return Some(("prettier", vec!["--stdin-filepath".to_string(), filename.to_string()]));
+        },
+        "c" | "cpp" | "java" => {
+            return Some(("clang-format", vec![format!("--assume-filename={}", filename)]));
+        },
+        "lua" => ("stylua", &["-"]),
+        "toml" => ("taplo", &["fmt", "-"]),
+        "bash" => ("shfmt", &[]),
+        _ => return None,
+    };
+    Some((program, args.iter().map(|arg| arg.to_string()).collect()))
+}
+
+/// Edition of the crate `filename` belongs to, from the nearest Cargo.toml
+fn cargo_edition(filename: &str) -> Option<String> {
+    let path = std::path::absolute(filename).ok()?;
+    let (dir, manifest) = manifests_above(&path).next()?;
+    match manifest.get("package")?.get("edition")? {
+        toml::Value::String(edition) => Some(edition.clone()),
+        // `edition.workspace = true` takes it from the workspace's Cargo.toml
+        _ => manifests_above(&dir.join("Cargo.toml"))
+            .find_map(|(_, manifest)| manifest.get("workspace").cloned())?
+            .get("package")?.get("edition")?.as_str().map(String::from),
+    }
+}
+
+/// Directories above `path` that hold a readable Cargo.toml, with its contents
+fn manifests_above(path: &Path) -> impl Iterator<Item = (std::path::PathBuf, toml::Table)> + '_ {
+    path.ancestors().skip(1).filter_map(|dir| {
+        let manifest = fs::read_to_string(dir.join("Cargo.toml")).ok()?.parse().ok()?;
+        Some((dir.to_path_buf(), manifest))
+    })
+}
+
+/// Pipe `input` through `program` and return what it writes to stdout
+pub fn run_formatter(program: &str, args: &[String], input: &str) -> Result<String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Error::Message(format!("Formatter not found: {}", program)),
+            _ => Error::Message(format!("Cannot run {}: {}", program, e)),
+        })?;
+    // Write from another thread so a formatter that starts printing before it has
+    // read everything cannot block on a full pipe
+    let mut stdin = child.stdin.take()
+        .ok_or_else(|| Error::Message(format!("{}: stdin unavailable", program)))?;
+    let input = input.to_string();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    let _ = writer.join();
+
+    // Usually a syntax error in the buffer
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr.lines().find(|line| !line.trim().is_empty()).unwrap_or("failed");
+        return Err(Error::Message(format!("{}: {}", program, reason)));
+    }
+    String::from_utf8(output.stdout)
+        .map_err(|_| Error::Message(format!("{}: output is not UTF-8", program)))
+}
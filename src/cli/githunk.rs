@@ -0,0 +1,210 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use crate::error::{Error, Result};
+
+/// What a hunk of unstaged changes does to the lines of a file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HunkKind {
+    Added,
+    Modified,
+    Removed,
+}
+
+/// One `@@` hunk of `git diff -U0` output
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hunk {
+    /// 1-based first line and line count in the index, as in the header
+    pub old_start: usize,
+    pub old_count: usize,
+    /// 1-based first line and line count in the working tree
+    pub new_start: usize,
+    pub new_count: usize,
+    /// The header line and the `-` / `+` lines after it
+    pub text: String,
+}
+
+impl Hunk {
+    pub fn kind(&self) -> HunkKind {
+        match (self.old_count, self.new_count) {
+            (0, _) => HunkKind::Added,
+            (_, 0) => HunkKind::Removed,
+            _ => HunkKind::Modified,
+        }
+    }
+
+    /// 0-based rows of the file the hunk marks, end exclusive. Removed lines are
+    /// marked on the line above them, or on the first line
+    pub fn rows(&self) -> (usize, usize) {
+        if self.new_count == 0 {
+            let row = self.new_start.saturating_sub(1);
+            return (row, row + 1);
+        }
+        (self.new_start - 1, self.new_start - 1 + self.new_count)
+    }
+
+    /// 0-based rows of the file the index's lines replace; empty for removals,
+    /// at the row the removed lines would go back before
+    pub fn new_lines(&self) -> std::ops::Range<usize> {
+        if self.new_count == 0 {
+            return self.new_start..self.new_start;
+        }
+        self.new_start - 1..self.new_start - 1 + self.new_count
+    }
+
+    /// Lines the index has in place of the hunk's rows
+    pub fn old_lines(&self) -> Vec<String> {
+        self.text.lines()
+            .filter_map(|line| line.strip_prefix('-'))
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+/// Unstaged changes to one file: the diff's file header and its hunks
+#[derive(Debug, Default)]
+pub struct FileDiff {
+    pub header: String,
+    pub hunks: Vec<Hunk>,
+}
+
+impl FileDiff {
+    /// The hunk that marks `row`, if any
+    pub fn hunk_at(&self, row: usize) -> Option<&Hunk> {
+        self.hunks.iter().find(|hunk| {
+            let (start, end) = hunk.rows();
+            (start..end).contains(&row)
+        })
+    }
+}
+
+/// `(old_start, old_count, new_start, new_count)` of a `@@ -a[,b] +c[,d] @@` line
+fn parse_hunk_header(line: &str) -> Option<(usize, usize, usize, usize)> {
+    let mut fields = line.strip_prefix("@@ ")?.split(' ');
+    let range = |field: Option<&str>, sign: char| -> Option<(usize, usize)> {
+        let field = field?.strip_prefix(sign)?;
+        match field.split_once(',') {
+            Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+            None => Some((field.parse().ok()?, 1)),
+        }
+    };
+    let (old_start, old_count) = range(fields.next(), '-')?;
+    let (new_start, new_count) = range(fields.next(), '+')?;
+    Some((old_start, old_count, new_start, new_count))
+}
+
+/// Split `git diff -U0` output for one file into its header and hunks
+pub fn parse_diff(output: &str) -> FileDiff {
+    let mut diff = FileDiff::default();
+    for line in output.lines() {
+        if let Some((old_start, old_count, new_start, new_count)) = parse_hunk_header(line) {
+            diff.hunks.push(Hunk { old_start, old_count, new_start, new_count, text: format!("{}\n", line) });
+        } else if let Some(hunk) = diff.hunks.last_mut() {
+            hunk.text.push_str(line);
+            hunk.text.push('\n');
+        } else {
+            diff.header.push_str(line);
+            diff.header.push('\n');
+        }
+    }
+    diff
+}
+
+/// Top directory of the repository `path` is in, with `path` made absolute
+fn repository(path: &Path) -> Result<(PathBuf, PathBuf)> {
+    let path = fs::canonicalize(path)?;
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    let output = Command::new("git")
+        .args(["rev-parse", "--show-toplevel"])
+        .current_dir(dir)
+        .output()
+        .map_err(|e| Error::Message(format!("Cannot run git: {}", e)))?;
+    if !output.status.success() {
+        return Err(Error::Message(format!("{} is not in a git repository", path.display())));
+    }
+    let top = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    Ok((top, path))
+}
+
+/// Branch checked out in the repository `dir` is in, from `git rev-parse
+/// --abbrev-ref HEAD`; `HEAD` when it is detached
+pub fn current_branch(dir: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--abbrev-ref", "HEAD"])
+        .current_dir(dir)
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let branch = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !branch.is_empty()).then_some(branch)
+}
+
+/// Changes to `path` in the working tree that are not staged yet
+pub fn diff(path: &Path) -> Result<FileDiff> {
+    let (top, path) = repository(path)?;
+    let output = Command::new("git")
+        .args(["diff", "-U0", "--no-color", "--no-ext-diff", "--"])
+        .arg(&path)
+        .current_dir(&top)
+        .output()
+        .map_err(|e| Error::Message(format!("Cannot run git: {}", e)))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Message(stderr.lines().next().unwrap_or("git diff failed").to_string()));
+    }
+    Ok(parse_diff(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Stage one hunk of `diff`, the unstaged changes to `path`, with `git apply --cached`
+pub fn stage(path: &Path, diff: &FileDiff, hunk: &Hunk) -> Result<()> {
+    let (top, _) = repository(path)?;
+    let mut child = Command::new("git")
+        // Without context lines git apply needs --unidiff-zero
+        .args(["apply", "--cached", "--unidiff-zero", "-"])
+        .current_dir(&top)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::Message(format!("Cannot run git: {}", e)))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(format!("{}{}", diff.header, hunk.text).as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Message(stderr.lines().next().unwrap_or("git apply failed").to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hunks_from_zero_context_diff() {
+        let output = "diff --git a/f.txt b/f.txt\n\
+            index 535d2b0..b5f9c3f 100644\n\
+            --- a/f.txt\n\
+            +++ b/f.txt\n\
+            @@ -2 +2 @@\n\
+            -2\n\
+            +TWO\n\
+            @@ -5 +4,0 @@\n\
+            -5\n\
+            @@ -8,0 +8,2 @@ fn main() {\n\
+            +nine\n\
+            +ten\n";
+        let diff = parse_diff(output);
+        assert!(diff.header.starts_with("diff --git") && diff.header.ends_with("+++ b/f.txt\n"));
+        let kinds: Vec<_> = diff.hunks.iter().map(|hunk| (hunk.rows(), hunk.kind())).collect();
+        assert_eq!(kinds, [((1, 2), HunkKind::Modified), ((3, 4), HunkKind::Removed), ((7, 9), HunkKind::Added)]);
+        assert_eq!(diff.hunks[1].text, "@@ -5 +4,0 @@\n-5\n");
+        assert_eq!(diff.hunks[0].old_lines(), ["2"]);
+        assert_eq!(diff.hunks[1].new_lines(), 4..4);
+        assert_eq!(diff.hunk_at(8), Some(&diff.hunks[2]));
+        assert_eq!(diff.hunk_at(4), None);
+    }
+}
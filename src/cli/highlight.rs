@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use crossterm::style::Color;
+use log::warn;
+use tree_sitter::{Language, Parser, Query, QueryCursor};
+use crate::lsp::get_language;
+
+/// Highlight query shipped with each bundled tree-sitter grammar
+fn highlight_query_source(lang_id: &str) -> Option<&'static str> {
+    match lang_id {
+        "rust"       => Some(tree_sitter_rust::HIGHLIGHT_QUERY),
+        "javascript" => Some(tree_sitter_javascript::HIGHLIGHT_QUERY),
+        "python"     => Some(tree_sitter_python::HIGHLIGHT_QUERY),
+        "lua"        => Some(tree_sitter_lua::HIGHLIGHTS_QUERY),
+        _            => None,
+    }
+}
+
+/// Foreground color for a highlight capture such as `keyword` or `function.method`
+pub fn capture_color(capture: &str) -> Option<Color> {
+    let group = capture.split('.').next().unwrap_or(capture);
+    match group {
+        "keyword" | "conditional" | "repeat" | "include" => Some(Color::Magenta),
+        "function" | "method" | "constructor" => Some(Color::Blue),
+        "type" | "attribute" => Some(Color::Yellow),
+        "string" | "escape" => Some(Color::Green),
+        "comment" => Some(Color::DarkGrey),
+        "number" | "constant" | "boolean" | "float" => Some(Color::Cyan),
+        "label" | "property" => Some(Color::DarkCyan),
+        "operator" => Some(Color::White),
+        "variable" if capture == "variable.builtin" => Some(Color::Red),
+        _ => None,
+    }
+}
+
+/// Syntax highlighter backed by the tree-sitter highlight queries
+pub struct Highlighter {
+    parser: Parser,
+    // Compiled queries by language id; None when the query failed to compile
+    queries: HashMap<String, Option<(Language, Query)>>,
+}
+
+impl Highlighter {
+    pub fn new() -> Self {
+        Self {
+            parser: Parser::new(),
+            queries: HashMap::new(),
+        }
+    }
+
+    fn query(&mut self, lang_id: &str) -> Option<&(Language, Query)> {
+        self.queries.entry(lang_id.to_string())
+            .or_insert_with(|| {
+                let language = get_language(lang_id)?;
+                let source = highlight_query_source(lang_id)?;
+                match Query::new(language, source) {
+                    Ok(query) => Some((language, query)),
+                    Err(e) => {
+                        warn!("Failed to compile highlight query for {}: {:?}", lang_id, e);
+                        None
+                    }
+                }
+            })
+            .as_ref()
+    }
+
+    /// Whether a grammar and highlight query are available for the language
+    pub fn supports(&mut self, lang_id: &str) -> bool {
+        self.query(lang_id).is_some()
+    }
+
+    /// Colored byte ranges of `source`; later ranges take precedence over earlier ones
+    pub fn highlight(&mut self, lang_id: &str, source: &str) -> Vec<(Range<usize>, Color)> {
+        let Some((language, _)) = self.query(lang_id) else {
+            return Vec::new();
+        };
+        let language = *language;
+        if self.parser.set_language(language).is_err() {
+            return Vec::new();
+        }
+        let Some(tree) = self.parser.parse(source, None) else {
+            return Vec::new();
+        };
+        let Some((_, query)) = self.queries.get(lang_id).and_then(|q| q.as_ref()) else {
+            return Vec::new();
+        };
+
+        let names = query.capture_names();
+        let mut spans = Vec::new();
+        let mut cursor = QueryCursor::new();
+        for (m, idx) in cursor.captures(query, tree.root_node(), source.as_bytes()) {
+            let capture = m.captures[idx];
+            if let Some(color) = names.get(capture.index as usize).and_then(|name| capture_color(name)) {
+                spans.push((capture.node.byte_range(), color));
+            }
+        }
+        // Outer nodes first so nested captures paint over them
+        spans.sort_by_key(|(range, _)| (range.start, std::cmp::Reverse(range.end)));
+        spans
+    }
+
+    /// Per-line foreground colors for each byte of `source`
+    pub fn highlight_lines(&mut self, lang_id: &str, source: &str) -> Vec<Vec<Option<Color>>> {
+        let mut colors: Vec<Option<Color>> = vec![None; source.len()];
+        for (range, color) in self.highlight(lang_id, source) {
+            for slot in &mut colors[range.start.min(source.len())..range.end.min(source.len())] {
+                *slot = Some(color);
+            }
+        }
+
+        let mut lines = Vec::new();
+        let mut start = 0;
+        for line in source.split('\n') {
+            lines.push(colors[start..start + line.len()].to_vec());
+            start += line.len() + 1;
+        }
+        lines
+    }
+}
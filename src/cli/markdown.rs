@@ -0,0 +1,339 @@
+use std::io;
+use crossterm::{
+    execute,
+    style::{Attribute, Color, SetAttribute, SetForegroundColor},
+};
+use crate::cli::highlight::Highlighter;
+use crate::error::Result;
+use crate::lsp::get_language_id_from_extension;
+
+/// Color used for inline code and fences without a known language
+const CODE_COLOR: Color = Color::DarkYellow;
+
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct SpanStyle {
+    pub fg: Option<Color>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+/// A run of text drawn with a single style
+#[derive(Clone)]
+pub struct StyledSpan {
+    pub text: String,
+    pub style: SpanStyle,
+}
+
+pub type StyledLine = Vec<StyledSpan>;
+
+/// Display width of a styled line in characters
+pub fn line_width(line: &StyledLine) -> usize {
+    line.iter().map(|span| span.text.chars().count()).sum()
+}
+
+/// Print a styled line at the current cursor position, cut off after `width` characters
+pub fn print_line(line: &StyledLine, width: usize) -> Result<()> {
+    let mut remaining = width;
+    for span in line {
+        if remaining == 0 {
+            break;
+        }
+        let text: String = span.text.chars().take(remaining).collect();
+        remaining -= text.chars().count();
+
+        if let Some(fg) = span.style.fg {
+            execute!(io::stdout(), SetForegroundColor(fg))?;
+        }
+        if span.style.bold {
+            execute!(io::stdout(), SetAttribute(Attribute::Bold))?;
+        }
+        if span.style.italic {
+            execute!(io::stdout(), SetAttribute(Attribute::Italic))?;
+        }
+        if span.style.underline {
+            execute!(io::stdout(), SetAttribute(Attribute::Underlined))?;
+        }
+        print!("{}", text);
+        // Reset only colors and attributes; the caller's background stays
+        execute!(io::stdout(), SetAttribute(Attribute::NoBold), SetAttribute(Attribute::NoItalic),
+            SetAttribute(Attribute::NoUnderline), SetForegroundColor(Color::Reset))?;
+    }
+    Ok(())
+}
+
+/// Converts the subset of Markdown used in LSP documentation into styled lines:
+/// headings, `**bold**`, `*italic*`, `` `code` `` and fenced code blocks
+pub struct MarkdownRenderer {
+    highlighter: Highlighter,
+}
+
+impl MarkdownRenderer {
+    pub fn new() -> Self {
+        Self { highlighter: Highlighter::new() }
+    }
+
+    pub fn render(&mut self, markdown: &str) -> Vec<StyledLine> {
+        let mut lines = Vec::new();
+        let mut fence: Option<(String, Vec<&str>)> = None;
+
+        for raw in markdown.lines() {
+            let trimmed = raw.trim_start();
+            if let Some(info) = trimmed.strip_prefix("```") {
+                match fence.take() {
+                    Some((lang, code)) => lines.extend(self.render_code(&lang, &code.join("\n"))),
+                    None => fence = Some((info.trim().to_string(), Vec::new())),
+                }
+                continue;
+            }
+            if let Some((_, code)) = fence.as_mut() {
+                code.push(raw);
+                continue;
+            }
+
+            let heading_level = trimmed.chars().take_while(|&c| c == '#').count();
+            if (1..=6).contains(&heading_level) && trimmed[heading_level..].starts_with(' ') {
+                let style = SpanStyle { bold: true, underline: true, ..Default::default() };
+                lines.push(render_inline(trimmed[heading_level..].trim(), style));
+            } else if is_rule(trimmed) {
+                lines.push(vec![StyledSpan {
+                    text: "─".repeat(20),
+                    style: SpanStyle { fg: Some(Color::DarkGrey), ..Default::default() },
+                }]);
+            } else {
+                lines.push(render_inline(raw, SpanStyle::default()));
+            }
+        }
+
+        // An unterminated fence still shows its code
+        if let Some((lang, code)) = fence {
+            lines.extend(self.render_code(&lang, &code.join("\n")));
+        }
+
+        // Drop blank lines at the end, LSP servers often send trailing newlines
+        while lines.last().is_some_and(|line| line_width(line) == 0) {
+            lines.pop();
+        }
+        lines
+    }
+
+    fn render_code(&mut self, lang: &str, code: &str) -> Vec<StyledLine> {
+        let lang_id = get_language_id_from_extension(lang).unwrap_or(lang);
+        let colors = self.highlighter.highlight_lines(lang_id, code);
+        // Without a grammar for the fence language, color the whole block like inline code
+        let fallback = if self.highlighter.supports(lang_id) { None } else { Some(CODE_COLOR) };
+
+        code.split('\n')
+            .enumerate()
+            .map(|(row, line)| {
+                let mut spans: StyledLine = Vec::new();
+                for (i, c) in line.char_indices() {
+                    let fg = colors.get(row)
+                        .and_then(|row_colors| row_colors.get(i).copied().flatten())
+                        .or(fallback);
+                    let style = SpanStyle { fg, ..Default::default() };
+                    match spans.last_mut() {
+                        Some(span) if span.style == style => span.text.push(c),
+                        _ => spans.push(StyledSpan { text: c.to_string(), style }),
+                    }
+                }
+                spans
+            })
+            .collect()
+    }
+}
+
+fn is_rule(line: &str) -> bool {
+    let line = line.trim();
+    line.len() >= 3 && ['-', '*', '_'].iter().any(|&rule| line.chars().all(|c| c == rule))
+}
+
+/// Style emphasis and inline code within one line
+fn render_inline(text: &str, base: SpanStyle) -> StyledLine {
+    let mut spans: StyledLine = Vec::new();
+    let mut style = base;
+    let mut current = String::new();
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+
+    let flush = |spans: &mut StyledLine, current: &mut String, style: SpanStyle| {
+        if !current.is_empty() {
+            spans.push(StyledSpan { text: std::mem::take(current), style });
+        }
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            // Backslash escapes punctuation, e.g. \* or \_
+            '\\' if chars.get(i + 1).is_some_and(|next| next.is_ascii_punctuation()) => {
+                current.push(chars[i + 1]);
+                i += 2;
+                continue;
+            },
+            '`' => {
+                if let Some(len) = chars[i + 1..].iter().position(|&x| x == '`') {
+                    flush(&mut spans, &mut current, style);
+                    let code: String = chars[i + 1..i + 1 + len].iter().collect();
+                    spans.push(StyledSpan { text: code, style: SpanStyle { fg: Some(CODE_COLOR), ..style } });
+                    i += len + 2;
+                    continue;
+                }
+            },
+            // Underscores inside words, as in snake_case, are not emphasis
+            '_' if i > 0 && chars[i - 1].is_alphanumeric() => {},
+            '*' | '_' if chars.get(i + 1) == Some(&c) => {
+                flush(&mut spans, &mut current, style);
+                style.bold = !style.bold;
+                i += 2;
+                continue;
+            },
+            '*' | '_' if style.italic || chars[i + 1..].contains(&c) => {
+                flush(&mut spans, &mut current, style);
+                style.italic = !style.italic;
+                i += 1;
+                continue;
+            },
+            _ => {},
+        }
+        current.push(c);
+        i += 1;
+    }
+    flush(&mut spans, &mut current, style);
+    spans
+}
+
+/// Whether `line` is a row of a pipe table: it starts and ends with `|`
+pub fn is_table_row(line: &str) -> bool {
+    let line = line.trim();
+    line.len() >= 2 && line.starts_with('|') && line.ends_with('|')
+}
+
+/// How a table column is aligned, from the colons in its separator cell
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Align {
+    None,
+    Left,
+    Center,
+    Right,
+}
+
+/// The cells of a table row, trimmed. `\|` is a pipe inside a cell
+fn table_cells(row: &str) -> Vec<String> {
+    let row = row.trim();
+    let inner = row.strip_prefix('|').unwrap_or(row);
+    let inner = inner.strip_suffix('|').unwrap_or(inner);
+    let mut cells = Vec::new();
+    let mut cell = String::new();
+    let mut escaped = false;
+    for c in inner.chars() {
+        if c == '|' && !escaped {
+            cells.push(cell.trim().to_string());
+            cell.clear();
+        } else {
+            cell.push(c);
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    cells.push(cell.trim().to_string());
+    cells
+}
+
+/// Alignment of a separator cell such as `---` or `:-:`; `None` for other cells
+fn separator_align(cell: &str) -> Option<Align> {
+    let dashes = cell.strip_prefix(':').unwrap_or(cell);
+    let dashes = dashes.strip_suffix(':').unwrap_or(dashes);
+    if dashes.is_empty() || !dashes.chars().all(|c| c == '-') {
+        return None;
+    }
+    Some(match (cell.starts_with(':'), cell.ends_with(':')) {
+        (true, true) => Align::Center,
+        (true, false) => Align::Left,
+        (false, true) => Align::Right,
+        (false, false) => Align::None,
+    })
+}
+
+/// The rows of a pipe table with every column padded to its widest cell and
+/// the separator row's dashes stretched to match. Cells follow the alignment
+/// the separator gives their column
+pub fn format_table(rows: &[String]) -> Vec<String> {
+    let indent: String = rows.first()
+        .map(|row| row.chars().take_while(|c| c.is_whitespace()).collect())
+        .unwrap_or_default();
+    let rows: Vec<Vec<String>> = rows.iter().map(|row| table_cells(row)).collect();
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let separator = |row: &Vec<String>| -> Option<Vec<Align>> {
+        row.iter().map(|cell| separator_align(cell)).collect()
+    };
+    let mut aligns = vec![Align::None; columns];
+    // Separator cells need three dashes; other cells set the width
+    let mut widths = vec![3; columns];
+    for row in &rows {
+        match separator(row) {
+            Some(row_aligns) => aligns[..row_aligns.len()].copy_from_slice(&row_aligns),
+            None => for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            },
+        }
+    }
+
+    rows.iter()
+        .map(|row| {
+            let is_separator = separator(row).is_some();
+            let cells: Vec<String> = (0..columns)
+                .map(|column| {
+                    let (width, align) = (widths[column], aligns[column]);
+                    if is_separator {
+                        return match align {
+                            Align::None => "-".repeat(width),
+                            Align::Left => format!(":{}", "-".repeat(width - 1)),
+                            Align::Right => format!("{}:", "-".repeat(width - 1)),
+                            Align::Center => format!(":{}:", "-".repeat(width - 2)),
+                        };
+                    }
+                    let cell = row.get(column).map_or("", String::as_str);
+                    match align {
+                        Align::Right => format!("{:>width$}", cell),
+                        Align::Center => {
+                            let left = (width - cell.chars().count()) / 2;
+                            format!("{}{:<rest$}", " ".repeat(left), cell, rest = width - left)
+                        },
+                        Align::None | Align::Left => format!("{:<width$}", cell),
+                    }
+                })
+                .collect();
+            format!("{}| {} |", indent, cells.join(" | "))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn format_table_pads_columns() {
+        let table = lines("|Key|Action|\n|-|-|\n| `gg` | Go to the first line |\n|x|");
+        assert_eq!(format_table(&table), lines(
+            "| Key  | Action               |\n\
+             | ---- | -------------------- |\n\
+             | `gg` | Go to the first line |\n\
+             | x    |                      |"
+        ));
+    }
+
+    #[test]
+    fn format_table_keeps_alignment_and_escaped_pipes() {
+        let table = lines("  | a | b | c |\n  |:-|:-:|--:|\n  | a \\| b | 1 | 10 |");
+        assert_eq!(format_table(&table), lines(
+            "  | a      |  b  |   c |\n  \
+             | :----- | :-: | --: |\n  \
+             | a \\| b |  1  |  10 |"
+        ));
+    }
+}
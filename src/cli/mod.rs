@@ -7,3 +7,24 @@ pub mod shell;
 pub mod plugin;
 pub mod tabs;
 pub mod buffer;
+pub mod system;
+pub mod profile;
+pub mod api;
+pub mod picker;
+pub mod highlight;
+pub mod markdown;
+pub mod options;
+pub mod substitute;
+pub mod clipboard;
+pub mod theme;
+pub mod session;
+pub mod ansi;
+pub mod quickfix;
+pub mod format;
+pub mod blame;
+pub mod autocmd;
+pub mod textobject;
+pub mod recent;
+pub mod positions;
+pub mod githunk;
+pub mod diff;
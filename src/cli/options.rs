@@ -0,0 +1,344 @@
+use std::fmt;
+use std::path::PathBuf;
+use crate::error::{Error, Result};
+use crate::cli::buffer::{DEFAULT_SHIFTWIDTH, DEFAULT_TABSTOP};
+use crate::cli::filetree;
+
+/// Value of an editor option as set from Lua or `:set`
+#[derive(Clone, Debug, PartialEq)]
+pub enum OptionValue {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+}
+
+impl fmt::Display for OptionValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionValue::Bool(b) => write!(f, "{}", b),
+            OptionValue::Int(n) => write!(f, "{}", n),
+            OptionValue::Str(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Global editor options, named after their Vim counterparts
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Maximum number of undo states kept per buffer; `None` is unlimited
+    pub undolevels: Option<usize>,
+    /// Save undo history next to `:w` and restore it when the file is opened
+    pub undofile: bool,
+    /// Directory holding undo files
+    pub undodir: PathBuf,
+    /// Widest a hover popup line may be before it is cut off
+    pub hoverwidth: usize,
+    /// Spaces per indentation level for auto-indent and `>` / `<`
+    pub shiftwidth: usize,
+    /// Show line numbers left of the text
+    pub number: bool,
+    /// Number lines by their distance from the cursor line
+    pub relativenumber: bool,
+    /// Columns between tab stops
+    pub tabstop: usize,
+    /// `Some(true)`: Tab inserts spaces; `Some(false)`: indentation uses tabs.
+    /// Unset, Tab inserts a tab and indentation uses spaces
+    pub expandtab: Option<bool>,
+    /// Lines kept visible above and below the cursor
+    pub scrolloff: usize,
+    /// Run the filetype's formatter before `:w` writes a buffer
+    pub format_on_save: bool,
+    /// Have the language server format the buffer before `:w` writes it
+    pub format_on_save_lsp: bool,
+    /// List files and directories starting with `.` in the file tree
+    pub file_tree_show_hidden: bool,
+    /// Columns the file tree takes
+    pub file_tree_width: usize,
+    /// Continue lines longer than the window on the next screen row
+    pub wrap: bool,
+    /// Shown at the start of each continuation row of a wrapped line
+    pub showbreak: String,
+    /// What `j` and `k` move by in wrapped lines: `line`, or `screen` to swap
+    /// them with `gj` and `gk`
+    pub wrap_navigate: String,
+    /// Draw tabs with a `→` in their first column, like Vim's `list`
+    pub show_tabs: bool,
+    /// Shade other references to the symbol under a resting cursor, as reported
+    /// by the language server
+    pub cursor_word_highlight: bool,
+    /// Show where the window is in its file on the window's right edge
+    pub scrollbar: bool,
+    /// Characters whose closing partner Insert mode types along with them
+    pub auto_pairs: Vec<(char, char)>,
+    /// Show an outline of the whole file at the right edge of windows
+    pub minimap: bool,
+    /// Columns the minimap takes
+    pub minimap_width: usize,
+    /// Run a `.rvim.lua` found in the project directory after config.lua
+    pub exrc: bool,
+}
+
+/// Settings for one filetype, set with `rvim.filetype.set`. Unset fields fall
+/// back to the global options
+#[derive(Clone, Debug, Default)]
+pub struct FileTypeConfig {
+    pub tabstop: Option<usize>,
+    pub shiftwidth: Option<usize>,
+    pub expandtab: Option<bool>,
+    /// Comment template with `%s` where the text goes, e.g. `// %s`
+    pub commentstring: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            undolevels: Some(1000),
+            undofile: false,
+            undodir: dirs::data_local_dir()
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join("rvim")
+                .join("undo"),
+            hoverwidth: 80,
+            shiftwidth: DEFAULT_SHIFTWIDTH,
+            number: true,
+            relativenumber: false,
+            tabstop: DEFAULT_TABSTOP,
+            expandtab: None,
+            scrolloff: 3,
+            format_on_save: false,
+            format_on_save_lsp: false,
+            file_tree_show_hidden: false,
+            file_tree_width: filetree::DEFAULT_WIDTH,
+            wrap: false,
+            showbreak: "↪ ".to_string(),
+            wrap_navigate: "line".to_string(),
+            show_tabs: false,
+            cursor_word_highlight: true,
+            scrollbar: true,
+            auto_pairs: vec![('(', ')'), ('[', ']'), ('{', '}'), ('"', '"'), ('\'', '\''), ('`', '`')],
+            minimap: false,
+            minimap_width: 10,
+            exrc: false,
+        }
+    }
+}
+
+/// Pairs written like Vim's `matchpairs`, e.g. `(:),[:]`; an empty string has none
+fn parse_pairs(value: &str) -> Option<Vec<(char, char)>> {
+    value.split(',')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.chars().collect::<Vec<_>>()[..] {
+            [open, ':', close] => Some((open, close)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Expand a leading `~` to the home directory
+pub(crate) fn expand_home(path: &str) -> PathBuf {
+    if path == "~" {
+        return dirs::home_dir().unwrap_or_default();
+    }
+    match path.strip_prefix("~/") {
+        Some(rest) => dirs::home_dir().unwrap_or_default().join(rest),
+        None => PathBuf::from(path),
+    }
+}
+
+impl Options {
+    /// Full option name for a name or its abbreviation
+    fn canonical_name(name: &str) -> Option<&'static str> {
+        match name {
+            "undolevels" | "ul" => Some("undolevels"),
+            "undofile" | "udf" => Some("undofile"),
+            "undodir" | "udir" => Some("undodir"),
+            "hoverwidth" => Some("hoverwidth"),
+            "shiftwidth" | "sw" => Some("shiftwidth"),
+            "number" | "nu" => Some("number"),
+            "relativenumber" | "rnu" => Some("relativenumber"),
+            "tabstop" | "ts" => Some("tabstop"),
+            "expandtab" | "et" => Some("expandtab"),
+            "scrolloff" | "so" => Some("scrolloff"),
+            "format_on_save" => Some("format_on_save"),
+            "format_on_save_lsp" => Some("format_on_save_lsp"),
+            "file_tree.show_hidden" => Some("file_tree.show_hidden"),
+            "file_tree.width" => Some("file_tree.width"),
+            "wrap" => Some("wrap"),
+            "showbreak" | "sbr" => Some("showbreak"),
+            "wrap_navigate" => Some("wrap_navigate"),
+            "show_tabs" | "list" => Some("show_tabs"),
+            "cursor_word_highlight" => Some("cursor_word_highlight"),
+            "scrollbar" => Some("scrollbar"),
+            "auto_pairs" => Some("auto_pairs"),
+            "minimap" => Some("minimap"),
+            "minimap_width" => Some("minimap_width"),
+            "exrc" | "ex" => Some("exrc"),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<OptionValue> {
+        match Self::canonical_name(name) {
+            // -1 means unlimited, as in Vim
+            Some("undolevels") => Ok(OptionValue::Int(self.undolevels.map_or(-1, |n| n as i64))),
+            Some("undofile") => Ok(OptionValue::Bool(self.undofile)),
+            Some("undodir") => Ok(OptionValue::Str(self.undodir.display().to_string())),
+            Some("hoverwidth") => Ok(OptionValue::Int(self.hoverwidth as i64)),
+            Some("shiftwidth") => Ok(OptionValue::Int(self.shiftwidth as i64)),
+            Some("number") => Ok(OptionValue::Bool(self.number)),
+            Some("relativenumber") => Ok(OptionValue::Bool(self.relativenumber)),
+            Some("tabstop") => Ok(OptionValue::Int(self.tabstop as i64)),
+            Some("expandtab") => Ok(OptionValue::Bool(self.expandtab == Some(true))),
+            Some("scrolloff") => Ok(OptionValue::Int(self.scrolloff as i64)),
+            Some("format_on_save") => Ok(OptionValue::Bool(self.format_on_save)),
+            Some("format_on_save_lsp") => Ok(OptionValue::Bool(self.format_on_save_lsp)),
+            Some("file_tree.show_hidden") => Ok(OptionValue::Bool(self.file_tree_show_hidden)),
+            Some("file_tree.width") => Ok(OptionValue::Int(self.file_tree_width as i64)),
+            Some("wrap") => Ok(OptionValue::Bool(self.wrap)),
+            Some("showbreak") => Ok(OptionValue::Str(self.showbreak.clone())),
+            Some("wrap_navigate") => Ok(OptionValue::Str(self.wrap_navigate.clone())),
+            Some("show_tabs") => Ok(OptionValue::Bool(self.show_tabs)),
+            Some("cursor_word_highlight") => Ok(OptionValue::Bool(self.cursor_word_highlight)),
+            Some("scrollbar") => Ok(OptionValue::Bool(self.scrollbar)),
+            Some("minimap") => Ok(OptionValue::Bool(self.minimap)),
+            Some("minimap_width") => Ok(OptionValue::Int(self.minimap_width as i64)),
+            Some("exrc") => Ok(OptionValue::Bool(self.exrc)),
+            Some("auto_pairs") => Ok(OptionValue::Str(self.auto_pairs.iter()
+                .map(|(open, close)| format!("{}:{}", open, close))
+                .collect::<Vec<_>>()
+                .join(","))),
+            _ => Err(Error::Message(format!("E518: Unknown option: {}", name))),
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: OptionValue) -> Result<()> {
+        let invalid = || Error::Message(format!("E474: Invalid argument: {}={}", name, value));
+        match Self::canonical_name(name) {
+            Some("undolevels") => match value {
+                OptionValue::Int(n) if n < 0 => self.undolevels = None,
+                OptionValue::Int(n) => self.undolevels = Some(n as usize),
+                _ => return Err(invalid()),
+            },
+            Some("undofile") => match value {
+                OptionValue::Bool(b) => self.undofile = b,
+                _ => return Err(invalid()),
+            },
+            Some("undodir") => match value {
+                OptionValue::Str(ref path) if !path.is_empty() => self.undodir = expand_home(path),
+                _ => return Err(invalid()),
+            },
+            Some("hoverwidth") => match value {
+                OptionValue::Int(n) if n > 0 => self.hoverwidth = n as usize,
+                _ => return Err(invalid()),
+            },
+            Some("shiftwidth") => match value {
+                OptionValue::Int(n) if n > 0 => self.shiftwidth = n as usize,
+                _ => return Err(invalid()),
+            },
+            Some("number") => match value {
+                OptionValue::Bool(b) => self.number = b,
+                _ => return Err(invalid()),
+            },
+            Some("relativenumber") => match value {
+                OptionValue::Bool(b) => self.relativenumber = b,
+                _ => return Err(invalid()),
+            },
+            Some("tabstop") => match value {
+                OptionValue::Int(n) if n > 0 => self.tabstop = n as usize,
+                _ => return Err(invalid()),
+            },
+            Some("expandtab") => match value {
+                OptionValue::Bool(b) => self.expandtab = Some(b),
+                _ => return Err(invalid()),
+            },
+            Some("scrolloff") => match value {
+                OptionValue::Int(n) if n >= 0 => self.scrolloff = n as usize,
+                _ => return Err(invalid()),
+            },
+            Some("format_on_save") => match value {
+                OptionValue::Bool(b) => self.format_on_save = b,
+                _ => return Err(invalid()),
+            },
+            Some("format_on_save_lsp") => match value {
+                OptionValue::Bool(b) => self.format_on_save_lsp = b,
+                _ => return Err(invalid()),
+            },
+            Some("file_tree.show_hidden") => match value {
+                OptionValue::Bool(b) => self.file_tree_show_hidden = b,
+                _ => return Err(invalid()),
+            },
+            Some("file_tree.width") => match value {
+                OptionValue::Int(n) if n > 0 => self.file_tree_width = n as usize,
+                _ => return Err(invalid()),
+            },
+            Some("wrap") => match value {
+                OptionValue::Bool(b) => self.wrap = b,
+                _ => return Err(invalid()),
+            },
+            Some("showbreak") => match value {
+                OptionValue::Str(s) => self.showbreak = s,
+                _ => return Err(invalid()),
+            },
+            Some("wrap_navigate") => match value {
+                OptionValue::Str(s) if s == "line" || s == "screen" => self.wrap_navigate = s,
+                _ => return Err(invalid()),
+            },
+            Some("show_tabs") => match value {
+                OptionValue::Bool(b) => self.show_tabs = b,
+                _ => return Err(invalid()),
+            },
+            Some("cursor_word_highlight") => match value {
+                OptionValue::Bool(b) => self.cursor_word_highlight = b,
+                _ => return Err(invalid()),
+            },
+            Some("scrollbar") => match value {
+                OptionValue::Bool(b) => self.scrollbar = b,
+                _ => return Err(invalid()),
+            },
+            Some("minimap") => match value {
+                OptionValue::Bool(b) => self.minimap = b,
+                _ => return Err(invalid()),
+            },
+            Some("minimap_width") => match value {
+                OptionValue::Int(n) if n > 0 => self.minimap_width = n as usize,
+                _ => return Err(invalid()),
+            },
+            Some("exrc") => match value {
+                OptionValue::Bool(b) => self.exrc = b,
+                _ => return Err(invalid()),
+            },
+            Some("auto_pairs") => match value {
+                OptionValue::Str(ref s) => self.auto_pairs = parse_pairs(s).ok_or_else(invalid)?,
+                _ => return Err(invalid()),
+            },
+            _ => return Err(Error::Message(format!("E518: Unknown option: {}", name))),
+        }
+        Ok(())
+    }
+
+    /// Apply one `:set` argument: `name`, `noname`, `name=value` or `name?`.
+    /// Returns the text to show for queries
+    pub fn apply_set_arg(&mut self, arg: &str) -> Result<Option<String>> {
+        if let Some(name) = arg.strip_suffix('?') {
+            return Ok(Some(format!("{}={}", name, self.get(name)?)));
+        }
+        if let Some((name, value)) = arg.split_once('=') {
+            let value = match value.parse::<i64>() {
+                Ok(n) => OptionValue::Int(n),
+                Err(_) => OptionValue::Str(value.to_string()),
+            };
+            self.set(name, value)?;
+            return Ok(None);
+        }
+        // A bare number or string option shows its value, like Vim
+        match self.get(arg) {
+            Ok(OptionValue::Bool(_)) => self.set(arg, OptionValue::Bool(true))?,
+            Ok(value) => return Ok(Some(format!("{}={}", arg, value))),
+            Err(e) => match arg.strip_prefix("no") {
+                Some(name) => self.set(name, OptionValue::Bool(false))?,
+                None => return Err(e),
+            },
+        }
+        Ok(None)
+    }
+}
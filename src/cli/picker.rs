@@ -0,0 +1,246 @@
+use std::io;
+use crossterm::{
+    cursor,
+    event::{KeyCode, KeyEvent, KeyModifiers},
+    execute,
+    style::{Color, ResetColor, SetBackgroundColor, SetForegroundColor},
+};
+use fuzzy_matcher::FuzzyMatcher;
+use fuzzy_matcher::skim::SkimMatcherV2;
+use crate::cli::editor::Editor;
+use crate::error::Result;
+
+/// Smallest overlay, as (width, height), with room for the borders, the prompt
+/// and one result; smaller terminals get no overlay
+const MIN_SIZE: (usize, usize) = (8, 4);
+
+/// One entry in a picker: the text shown and the value handed to the callback
+pub struct PickerItem<T> {
+    pub display: String,
+    pub data: T,
+}
+
+impl<T> PickerItem<T> {
+    pub fn new(display: impl Into<String>, data: T) -> Self {
+        Self { display: display.into(), data }
+    }
+}
+
+/// Scores an item's display text against the query; `None` hides the item
+pub type PickerFilter = Box<dyn Fn(&str, &str) -> Option<i64>>;
+
+/// Runs with the chosen item's data once the picker is confirmed
+pub type PickerCallback<T> = Box<dyn FnOnce(&mut Editor, T) -> Result<()>>;
+
+/// What the editor should do after a key was handled by the picker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PickerStatus {
+    Open,
+    Cancelled,
+    Confirmed,
+}
+
+/// A filterable list overlay drawn in the middle of the screen
+pub struct Picker<T> {
+    title: String,
+    items: Vec<PickerItem<T>>,
+    filter: PickerFilter,
+    on_confirm: PickerCallback<T>,
+    query: String,
+    // Indices into `items` that pass the filter, best match first
+    matches: Vec<usize>,
+    // Show only this many of the best matches
+    limit: Option<usize>,
+    selected: usize,
+}
+
+impl<T> Picker<T> {
+    /// Create a picker that filters items with fuzzy matching
+    pub fn new(title: &str, items: Vec<PickerItem<T>>, on_confirm: PickerCallback<T>) -> Self {
+        let matcher = SkimMatcherV2::default();
+        let filter: PickerFilter = Box::new(move |query, display| {
+            if query.is_empty() {
+                Some(0)
+            } else {
+                matcher.fuzzy_match(display, query)
+            }
+        });
+        let mut picker = Self {
+            title: title.to_string(),
+            items,
+            filter,
+            on_confirm,
+            query: String::new(),
+            matches: Vec::new(),
+            limit: None,
+            selected: 0,
+        };
+        picker.update_matches();
+        picker
+    }
+
+    /// Replace the fuzzy filter, e.g. with an exact substring match
+    pub fn with_filter(mut self, filter: PickerFilter) -> Self {
+        self.filter = filter;
+        self.update_matches();
+        self
+    }
+
+    /// Start with `query` already typed
+    pub fn with_query(mut self, query: &str) -> Self {
+        self.query = query.to_string();
+        self.update_matches();
+        self
+    }
+
+    /// Keep only the best `limit` matches, for sources with many items
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self.update_matches();
+        self
+    }
+
+    fn update_matches(&mut self) {
+        let mut scored: Vec<(usize, i64)> = self.items.iter()
+            .enumerate()
+            .filter_map(|(idx, item)| (self.filter)(&self.query, &item.display).map(|score| (idx, score)))
+            .collect();
+        // Stable sort keeps the original order among equal scores
+        scored.sort_by_key(|&(_, score)| std::cmp::Reverse(score));
+        self.matches = scored.into_iter()
+            .map(|(idx, _)| idx)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect();
+        self.selected = 0;
+    }
+
+    fn move_selection(&mut self, delta: isize) {
+        if self.matches.is_empty() {
+            return;
+        }
+        let len = self.matches.len() as isize;
+        self.selected = (self.selected as isize + delta).rem_euclid(len) as usize;
+    }
+
+    /// Position and size of the overlay: (x, y, width, height), or `None` when
+    /// the terminal is too small for it
+    fn layout(terminal_width: usize, terminal_height: usize) -> Option<(usize, usize, usize, usize)> {
+        let width = (terminal_width * 3 / 5).max(20).min(terminal_width);
+        let height = (terminal_height * 3 / 5).max(5).min(terminal_height.saturating_sub(2));
+        if width < MIN_SIZE.0 || height < MIN_SIZE.1 {
+            return None;
+        }
+        let x = terminal_width.saturating_sub(width) / 2;
+        let y = terminal_height.saturating_sub(height) / 2;
+        Some((x, y, width, height))
+    }
+}
+
+/// Type-erased picker so the editor can hold pickers over any item type
+pub trait PickerWidget {
+    fn draw_picker(&self, terminal_width: usize, terminal_height: usize) -> Result<()>;
+
+    /// Text typed into the prompt
+    fn query(&self) -> &str;
+
+    /// Screen position of the end of the query prompt, if the picker is drawn
+    fn prompt_cursor(&self, terminal_width: usize, terminal_height: usize) -> Option<(u16, u16)>;
+
+    fn process_picker_keypress(&mut self, key: KeyEvent) -> PickerStatus;
+
+    /// Run the callback with the selected item, if any item matches
+    fn confirm(self: Box<Self>, editor: &mut Editor) -> Result<()>;
+}
+
+impl<T> PickerWidget for Picker<T> {
+    fn draw_picker(&self, terminal_width: usize, terminal_height: usize) -> Result<()> {
+        let Some((x, y, width, height)) = Self::layout(terminal_width, terminal_height) else {
+            return Ok(());
+        };
+        let inner = width - 2;
+
+        // Title bar with the match count
+        let title = format!(" {} ({}/{}) ", self.title, self.matches.len(), self.items.len());
+        let title: String = title.chars().take(inner).collect();
+        let fill = inner.saturating_sub(title.chars().count());
+        execute!(io::stdout(), cursor::MoveTo(x as u16, y as u16), SetForegroundColor(Color::Cyan))?;
+        print!("┌{}{}┐", title, "─".repeat(fill));
+
+        // Query prompt
+        let prompt: String = format!("> {}", self.query).chars().take(inner).collect();
+        execute!(io::stdout(), cursor::MoveTo(x as u16, (y + 1) as u16))?;
+        print!("│");
+        execute!(io::stdout(), ResetColor)?;
+        print!("{:width$}", prompt, width = inner);
+        execute!(io::stdout(), SetForegroundColor(Color::Cyan))?;
+        print!("│");
+
+        // Results, scrolled so the selection stays visible
+        let rows = height - 3;
+        let first = self.selected.saturating_sub(rows.saturating_sub(1));
+        for row in 0..rows {
+            execute!(io::stdout(), cursor::MoveTo(x as u16, (y + 2 + row) as u16), SetForegroundColor(Color::Cyan))?;
+            print!("│");
+            let idx = first + row;
+            let text: String = match self.matches.get(idx) {
+                Some(&item) => self.items[item].display.chars().take(inner.saturating_sub(1)).collect(),
+                None => String::new(),
+            };
+            if idx == self.selected && idx < self.matches.len() {
+                execute!(io::stdout(), SetBackgroundColor(Color::DarkBlue), SetForegroundColor(Color::White))?;
+            } else {
+                execute!(io::stdout(), ResetColor)?;
+            }
+            print!(" {:width$}", text, width = inner.saturating_sub(1));
+            execute!(io::stdout(), ResetColor, SetForegroundColor(Color::Cyan))?;
+            print!("│");
+        }
+
+        execute!(io::stdout(), cursor::MoveTo(x as u16, (y + height - 1) as u16))?;
+        print!("└{}┘", "─".repeat(inner));
+        execute!(io::stdout(), ResetColor)?;
+        Ok(())
+    }
+
+    fn query(&self) -> &str {
+        &self.query
+    }
+
+    fn prompt_cursor(&self, terminal_width: usize, terminal_height: usize) -> Option<(u16, u16)> {
+        let (x, y, width, _) = Self::layout(terminal_width, terminal_height)?;
+        let column = (x + 3 + self.query.chars().count()).min(x + width - 2);
+        Some((column as u16, (y + 1) as u16))
+    }
+
+    fn process_picker_keypress(&mut self, key: KeyEvent) -> PickerStatus {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Esc => return PickerStatus::Cancelled,
+            KeyCode::Char('c') if ctrl => return PickerStatus::Cancelled,
+            KeyCode::Enter => return PickerStatus::Confirmed,
+            KeyCode::Down | KeyCode::Tab => self.move_selection(1),
+            KeyCode::Char('n') if ctrl => self.move_selection(1),
+            KeyCode::Up | KeyCode::BackTab => self.move_selection(-1),
+            KeyCode::Char('p') if ctrl => self.move_selection(-1),
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.update_matches();
+            },
+            KeyCode::Char(c) if !ctrl => {
+                self.query.push(c);
+                self.update_matches();
+            },
+            _ => {}
+        }
+        PickerStatus::Open
+    }
+
+    fn confirm(self: Box<Self>, editor: &mut Editor) -> Result<()> {
+        let Some(&idx) = self.matches.get(self.selected) else {
+            return Ok(());
+        };
+        let Picker { mut items, on_confirm, .. } = *self;
+        let item = items.swap_remove(idx);
+        on_confirm(editor, item.data)
+    }
+}
@@ -113,45 +113,126 @@ impl PluginManager {
         let init_lua = plugin.path.join("init.lua");
         if init_lua.exists() {
             let init_content = fs::read_to_string(&init_lua)?;
-            lua.load(&init_content).exec()?;
+            lua.load(&init_content)
+                .set_name(format!("@{}", init_lua.display()))
+                .exec()?;
         }
         
         // Try loading plugin/init.lua
         let plugin_lua = plugin.path.join("plugin").join("init.lua");
         if plugin_lua.exists() {
             let plugin_content = fs::read_to_string(&plugin_lua)?;
-            lua.load(&plugin_content).exec()?;
+            lua.load(&plugin_content)
+                .set_name(format!("@{}", plugin_lua.display()))
+                .exec()?;
         }
         
         Ok(())
     }
     
-    /// Install a plugin from a Git repository
-    pub fn install_plugin(&mut self, url: &str) -> Result<()> {
+    /// Names of the discovered and installed plugins
+    pub fn plugin_names(&self) -> Vec<String> {
+        self.plugins.iter().map(|plugin| plugin.name.clone()).collect()
+    }
+
+    /// Load an installed plugin into `lua` by name
+    pub fn load_plugin_named(&self, lua: &mlua::Lua, name: &str) -> Result<()> {
+        let plugin = self.plugins.iter()
+            .find(|plugin| plugin.name == name)
+            .ok_or_else(|| Error::Message(format!("Plugin not installed: {}", name)))?;
+        self.load_plugin(lua, plugin)
+    }
+
+    /// Install a plugin by cloning its Git repository, returning the plugin name
+    pub fn install_plugin(&mut self, url: &str) -> Result<String> {
         // Extract plugin name from URL (last part of URL without .git)
-        let name = url.split('/').last()
+        let name = url.trim_end_matches('/').rsplit(['/', ':']).next()
+            .map(|name| name.trim_end_matches(".git"))
+            .filter(|name| !name.is_empty())
             .ok_or_else(|| Error::Message("Invalid URL format".to_string()))?
-            .trim_end_matches(".git");
-            
-        info!("Installing plugin: {} from {}", name, url);
-        
-        // Create plugin directory
-        let plugin_dir = self.plugins_dir.join(name);
-        
+            .to_string();
+
+        let plugin_dir = self.plugin_dir(&name)?;
         if plugin_dir.exists() {
             info!("Plugin already installed: {}", name);
-            return Ok(());
+            return Ok(name);
         }
-        
-        // Create the directory as a placeholder
-        fs::create_dir_all(&plugin_dir)?;
-        
-        // Create a basic init.lua file 
-        let init_lua = plugin_dir.join("init.lua");
-        fs::write(&init_lua, format!("-- Plugin: {}\n-- URL: {}\n\nreturn {{\n  setup = function()\n    print('Plugin {} loaded')\n  end\n}}\n", name, url, name))?;
-        
+
+        info!("Installing plugin: {} from {}", name, url);
+        git2::Repository::clone(url, &plugin_dir).map_err(|e| plugin_error(&name, e))?;
+        self.plugins.push(Plugin {
+            name: name.clone(),
+            path: plugin_dir,
+            enabled: true,
+            config: None,
+        });
+
         info!("Plugin {} installed successfully", name);
-        
+        Ok(name)
+    }
+
+    /// Fetch a plugin's current branch from `origin` and fast-forward to it.
+    /// Returns false when it was already up to date
+    pub fn update_plugin(&mut self, name: &str) -> Result<bool> {
+        let plugin_dir = self.plugin_dir(name)?;
+        let repo = git2::Repository::open(&plugin_dir).map_err(|e| plugin_error(name, e))?;
+        let updated = fast_forward(&repo).map_err(|e| plugin_error(name, e))?;
+        info!("Plugin {} {}", name, if updated { "updated" } else { "already up to date" });
+        Ok(updated)
+    }
+
+    /// Remove a plugin's directory; it stays loaded until the editor restarts
+    pub fn uninstall_plugin(&mut self, name: &str) -> Result<()> {
+        let plugin_dir = self.plugin_dir(name)?;
+        fs::remove_dir_all(&plugin_dir)?;
+        self.plugins.retain(|plugin| plugin.name != name);
+        info!("Plugin {} uninstalled", name);
         Ok(())
     }
+
+    // Directory of an installed plugin; the name must not leave the plugins directory
+    fn plugin_dir(&self, name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+            return Err(Error::InvalidFileName(name.to_string()));
+        }
+        Ok(self.plugins_dir.join(name))
+    }
+}
+
+fn plugin_error(name: &str, err: git2::Error) -> Error {
+    Error::PluginError { name: name.to_string(), message: err.message().to_string() }
+}
+
+// Fetch the checked out branch from origin and fast-forward it
+fn fast_forward(repo: &git2::Repository) -> std::result::Result<bool, git2::Error> {
+    let head = repo.head()?;
+    let branch = head.shorthand()
+        .ok_or_else(|| git2::Error::from_str("HEAD is not a branch"))?
+        .to_string();
+    repo.find_remote("origin")?.fetch(&[&branch], None, None)?;
+
+    let fetch_head = repo.find_reference("FETCH_HEAD")?;
+    let fetched = repo.reference_to_annotated_commit(&fetch_head)?;
+    let (analysis, _) = repo.merge_analysis(&[&fetched])?;
+    if analysis.is_up_to_date() {
+        return Ok(false);
+    }
+    if !analysis.is_fast_forward() {
+        return Err(git2::Error::from_str("local changes prevent a fast-forward"));
+    }
+    // Edits made in the plugin's directory are left for the user to commit or drop
+    let mut status_options = git2::StatusOptions::new();
+    status_options.include_untracked(false).include_ignored(false);
+    if !repo.statuses(Some(&mut status_options))?.is_empty() {
+        return Err(git2::Error::from_str("uncommitted changes in the plugin directory prevent a fast-forward"));
+    }
+    let refname = head.name()
+        .ok_or_else(|| git2::Error::from_str("branch name is not valid UTF-8"))?
+        .to_string();
+    // Check the new tree out before moving the branch, so a failure leaves both as they were
+    let target = repo.find_commit(fetched.id())?;
+    repo.checkout_tree(target.as_object(), Some(git2::build::CheckoutBuilder::default().safe()))?;
+    repo.find_reference(&refname)?.set_target(fetched.id(), "rvim: fast-forward")?;
+    repo.set_head(&refname)?;
+    Ok(true)
 }
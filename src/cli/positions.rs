@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::error::{Error, Result};
+
+/// Line and column of the cursor by absolute file path
+pub type CursorPositions = HashMap<PathBuf, (usize, usize)>;
+
+/// Where the last cursor position in each file is kept between sessions
+pub fn cursor_positions_path() -> PathBuf {
+    dirs::data_local_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("rvim")
+        .join("cursor_positions.json")
+}
+
+/// Saved cursor positions; empty when the file cannot be read
+pub fn read(path: &Path) -> CursorPositions {
+    fs::read_to_string(path).ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+pub fn write(path: &Path, positions: &CursorPositions) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let json = serde_json::to_string_pretty(positions)
+        .map_err(|e| Error::Message(format!("Failed to encode cursor positions: {}", e)))?;
+    fs::write(path, json)?;
+    Ok(())
+}
+
+/// `position` moved onto an existing line of `lines` and a character boundary within it
+pub fn clamp(lines: &[String], (row, col): (usize, usize)) -> (usize, usize) {
+    let row = row.min(lines.len().saturating_sub(1));
+    let line = lines.get(row).map_or("", |line| line.as_str());
+    let mut col = col.min(line.len());
+    while !line.is_char_boundary(col) {
+        col -= 1;
+    }
+    (row, col)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamped_to_shorter_file() {
+        let lines = vec!["fn main() {".to_string(), "    é".to_string()];
+        assert_eq!(clamp(&lines, (0, 3)), (0, 3));
+        assert_eq!(clamp(&lines, (7, 2)), (1, 2));
+        assert_eq!(clamp(&lines, (1, 40)), (1, 6));
+        // Inside the two bytes of `é`
+        assert_eq!(clamp(&lines, (1, 5)), (1, 4));
+        assert_eq!(clamp(&[], (3, 3)), (0, 0));
+    }
+}
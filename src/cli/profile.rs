@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::info;
+use mlua::{DebugEvent, HookTriggers, Lua};
+use crate::error::Result;
+
+/// Accumulated timing for one profiled function or operation
+#[derive(Default, Clone)]
+struct FunctionStats {
+    name: String,
+    count: usize,
+    total: Duration,
+    self_time: Duration,
+}
+
+/// A Lua function that is currently executing
+struct Frame {
+    key: String,
+    started: Instant,
+    children: Duration,
+}
+
+#[derive(Default)]
+struct ProfileData {
+    lua: HashMap<String, FunctionStats>,
+    rust: HashMap<String, FunctionStats>,
+    stack: Vec<Frame>,
+}
+
+impl ProfileData {
+    fn finish_frame(&mut self) {
+        if let Some(frame) = self.stack.pop() {
+            let elapsed = frame.started.elapsed();
+            if let Some(stats) = self.lua.get_mut(&frame.key) {
+                stats.total += elapsed;
+                stats.self_time += elapsed.saturating_sub(frame.children);
+            }
+            if let Some(parent) = self.stack.last_mut() {
+                parent.children += elapsed;
+            }
+        }
+    }
+}
+
+/// Records Lua function timings through a debug hook and Rust-side
+/// operation timings reported by the editor, for `:profile`
+pub struct Profiler {
+    output: PathBuf,
+    data: Arc<Mutex<ProfileData>>,
+}
+
+impl Profiler {
+    /// Start profiling, installing the hook on the given Lua state
+    pub fn start(lua: &Lua, output: PathBuf) -> Self {
+        info!("Profiling started, writing to {:?}", output);
+        let data = Arc::new(Mutex::new(ProfileData::default()));
+
+        let hook_data = data.clone();
+        lua.set_hook(HookTriggers::new().on_calls().on_returns(), move |_, debug| {
+            let mut data = hook_data.lock().unwrap();
+            match debug.event() {
+                DebugEvent::Call | DebugEvent::TailCall => {
+                    // A tail call replaces the running frame rather than nesting inside it
+                    if debug.event() == DebugEvent::TailCall {
+                        data.finish_frame();
+                    }
+                    let source = debug.source();
+                    let location = format!("{}:{}",
+                        source.short_src.as_deref().unwrap_or("?"),
+                        source.line_defined.unwrap_or(0));
+                    let name = match debug.names().name {
+                        Some(name) => format!("{} ({})", name, location),
+                        None => location.clone(),
+                    };
+                    let stats = data.lua.entry(location.clone()).or_default();
+                    stats.count += 1;
+                    if stats.name.is_empty() {
+                        stats.name = name;
+                    }
+                    data.stack.push(Frame { key: location, started: Instant::now(), children: Duration::ZERO });
+                },
+                DebugEvent::Ret => data.finish_frame(),
+                _ => {}
+            }
+            Ok(())
+        });
+
+        Self { output, data }
+    }
+
+    pub fn output(&self) -> &Path {
+        &self.output
+    }
+
+    /// Record one execution of a Rust-side operation
+    pub fn record_rust(&self, name: &str, elapsed: Duration) {
+        let mut data = self.data.lock().unwrap();
+        let stats = data.rust.entry(name.to_string()).or_insert_with(|| FunctionStats {
+            name: name.to_string(),
+            ..Default::default()
+        });
+        stats.count += 1;
+        stats.total += elapsed;
+        stats.self_time += elapsed;
+    }
+
+    /// Write the profile collected so far, keeping the profiler running
+    pub fn dump(&self) -> Result<()> {
+        let data = self.data.lock().unwrap();
+        let mut report = String::new();
+        write_section(&mut report, "LUA FUNCTIONS SORTED ON TOTAL TIME", &data.lua);
+        report.push('\n');
+        write_section(&mut report, "RUST OPERATIONS SORTED ON TOTAL TIME", &data.rust);
+        fs::write(&self.output, report)?;
+        info!("Profile written to {:?}", self.output);
+        Ok(())
+    }
+
+    /// Write the profile and remove the Lua hook
+    pub fn stop(self, lua: &Lua) -> Result<()> {
+        lua.remove_hook();
+        self.dump()
+    }
+}
+
+fn write_section(report: &mut String, title: &str, stats: &HashMap<String, FunctionStats>) {
+    let mut entries: Vec<&FunctionStats> = stats.values().collect();
+    entries.sort_by_key(|stats| std::cmp::Reverse(stats.total));
+
+    let _ = writeln!(report, "{}", title);
+    let _ = writeln!(report, "count  total (s)   self (s)  function");
+    for stats in entries {
+        let _ = writeln!(report, "{:>5} {:>10.6} {:>10.6}  {}",
+            stats.count,
+            stats.total.as_secs_f64(),
+            stats.self_time.as_secs_f64(),
+            stats.name);
+    }
+}
+
+/// Timestamps of startup milestones, reported with `--startuptime`
+pub struct StartupTimes {
+    started: Instant,
+    marks: Vec<(String, Duration)>,
+    output: PathBuf,
+}
+
+impl StartupTimes {
+    pub fn new(output: PathBuf) -> Self {
+        Self {
+            started: Instant::now(),
+            marks: Vec::new(),
+            output,
+        }
+    }
+
+    /// Record a milestone relative to process start
+    pub fn mark(&mut self, label: &str) {
+        let elapsed = self.started.elapsed();
+        info!("startup: {} at {:.3}ms", label, elapsed.as_secs_f64() * 1000.0);
+        self.marks.push((label.to_string(), elapsed));
+    }
+
+    /// One-line summary for the message line
+    pub fn summary(&self) -> String {
+        let parts: Vec<String> = self.marks.iter()
+            .map(|(label, elapsed)| format!("{} {:.1}ms", label, elapsed.as_secs_f64() * 1000.0))
+            .collect();
+        format!("Startup: {}", parts.join(", "))
+    }
+
+    /// Write the milestones in the style of Vim's --startuptime log
+    pub fn write(&self) -> Result<()> {
+        let mut report = String::new();
+        let _ = writeln!(report, "times in msec");
+        let _ = writeln!(report, " clock   elapsed: event");
+        let mut previous = Duration::ZERO;
+        for (label, elapsed) in &self.marks {
+            let _ = writeln!(report, "{:>8.3}  {:>8.3}: {}",
+                elapsed.as_secs_f64() * 1000.0,
+                elapsed.saturating_sub(previous).as_secs_f64() * 1000.0,
+                label);
+            previous = *elapsed;
+        }
+        fs::write(&self.output, report)?;
+        Ok(())
+    }
+}
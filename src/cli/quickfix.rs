@@ -0,0 +1,82 @@
+use std::path::PathBuf;
+use std::process::Command;
+use serde_json::Value;
+use crate::error::{Error, Result};
+
+/// A match in the quickfix list: file, 0-based line, byte column and the line's text
+pub type QuickfixEntry = (PathBuf, usize, usize, String);
+
+/// Search the working directory for `pattern` with `rg` or `grep` and collect the matches
+pub fn grep(program: &str, pattern: &str) -> Result<Vec<QuickfixEntry>> {
+    let mut command = Command::new(program);
+    if program == "rg" {
+        command.arg("--json");
+    } else {
+        command.args(["-rnI", "--exclude-dir=.git"]);
+    }
+    // `--` so a pattern starting with `-` is not read as an option
+    command.args(["--", pattern, "."]);
+    let output = command.output()
+        .map_err(|e| Error::Message(format!("E282: Cannot run {}: {}", program, e)))?;
+    // Both exit with 1 when nothing matched and 2 on errors
+    if output.status.code() == Some(2) {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Message(format!("{}: {}", program, stderr.lines().next().unwrap_or("failed"))));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(if program == "rg" { parse_rg_json(&stdout) } else { parse_grep(&stdout) })
+}
+
+/// Matches from `rg --json` output, one per matching line
+pub fn parse_rg_json(output: &str) -> Vec<QuickfixEntry> {
+    output.lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|message| message["type"] == "match")
+        .filter_map(|message| {
+            let data = &message["data"];
+            let path = data["path"]["text"].as_str()?;
+            let line = data["line_number"].as_u64()? as usize;
+            let col = data["submatches"][0]["start"].as_u64().unwrap_or(0) as usize;
+            let text = data["lines"]["text"].as_str().unwrap_or("").trim_end_matches(['\r', '\n']);
+            Some((clean_path(path), line.saturating_sub(1), col, text.to_string()))
+        })
+        .collect()
+}
+
+/// Matches from `grep -rn` output (`file:line:text`); grep gives no column
+pub fn parse_grep(output: &str) -> Vec<QuickfixEntry> {
+    output.lines()
+        .filter_map(|line| {
+            let (path, rest) = line.split_once(':')?;
+            let (line, text) = rest.split_once(':')?;
+            let line = line.parse::<usize>().ok()?;
+            Some((clean_path(path), line.saturating_sub(1), 0, text.to_string()))
+        })
+        .collect()
+}
+
+/// Drop the `./` both tools put in front of paths under `.`
+fn clean_path(path: &str) -> PathBuf {
+    PathBuf::from(path.strip_prefix("./").unwrap_or(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rg_matches() {
+        let output = concat!(
+            r#"{"type":"begin","data":{"path":{"text":"./src/main.rs"}}}"#, "\n",
+            r#"{"type":"match","data":{"path":{"text":"./src/main.rs"},"lines":{"text":"fn main() {\n"},"line_number":3,"absolute_offset":20,"submatches":[{"match":{"text":"main"},"start":3,"end":7}]}}"#, "\n",
+            r#"{"type":"end","data":{"path":{"text":"./src/main.rs"}}}"#, "\n",
+        );
+        assert_eq!(parse_rg_json(output), [(PathBuf::from("src/main.rs"), 2, 3, "fn main() {".to_string())]);
+    }
+
+    #[test]
+    fn parses_grep_matches() {
+        let output = "./src/lib.rs:10:let a = b: c;\nnot a match\n";
+        assert_eq!(parse_grep(output), [(PathBuf::from("src/lib.rs"), 9, 0, "let a = b: c;".to_string())]);
+    }
+}
@@ -0,0 +1,56 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::error::{Error, Result};
+
+/// Most files the recent list keeps
+pub const MAX_RECENT_FILES: usize = 20;
+
+/// Where the recently opened files are kept between sessions
+pub fn recent_files_path() -> PathBuf {
+    dirs::data_local_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("rvim")
+        .join("recent_files.json")
+}
+
+/// Recently opened files, newest first; empty when the list cannot be read
+pub fn read(path: &Path) -> Vec<PathBuf> {
+    fs::read_to_string(path).ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+pub fn write(path: &Path, files: &[PathBuf]) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let json = serde_json::to_string_pretty(files)
+        .map_err(|e| Error::Message(format!("Failed to encode recent files: {}", e)))?;
+    fs::write(path, json)?;
+    Ok(())
+}
+
+/// Put `file` at the front of the list, dropping an older entry for it and
+/// anything past MAX_RECENT_FILES
+pub fn push(files: &mut Vec<PathBuf>, file: PathBuf) {
+    files.retain(|existing| *existing != file);
+    files.insert(0, file);
+    files.truncate(MAX_RECENT_FILES);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newest_first_without_duplicates() {
+        let mut files: Vec<PathBuf> = (0..MAX_RECENT_FILES).map(|n| PathBuf::from(format!("/f{}", n))).collect();
+        push(&mut files, PathBuf::from("/f3"));
+        assert_eq!(files[0], PathBuf::from("/f3"));
+        assert_eq!(files.len(), MAX_RECENT_FILES);
+        push(&mut files, PathBuf::from("/new"));
+        assert_eq!(files[..2], [PathBuf::from("/new"), PathBuf::from("/f3")]);
+        assert_eq!(files.len(), MAX_RECENT_FILES);
+        assert!(!files.contains(&PathBuf::from(format!("/f{}", MAX_RECENT_FILES - 1))));
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+
+/// Editor layout written by `:mksession` and read back by `:source`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Session {
+    pub buffers: Vec<SessionBuffer>,
+    pub active_buffer: usize,
+    /// Tab names in order; they are the file names the tabs were opened with
+    pub tabs: Vec<PathBuf>,
+    /// Index in `tabs` of the tab that was shown
+    pub current_tab: usize,
+    pub windows: Vec<SessionWindow>,
+    pub active_window: usize,
+    pub file_tree: Option<SessionFileTree>,
+}
+
+/// An open file and where the cursor was in it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionBuffer {
+    pub path: PathBuf,
+    pub cursor_x: usize,
+    pub cursor_y: usize,
+    pub offset_y: usize,
+    /// Lowercase marks set with `m`, as (row, column)
+    #[serde(default)]
+    pub marks: BTreeMap<char, (usize, usize)>,
+    /// Folds as (first line, last line, closed)
+    #[serde(default)]
+    pub folds: Vec<(usize, usize, bool)>,
+}
+
+/// A window rectangle, scaled to the terminal when the session is loaded
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionWindow {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionFileTree {
+    pub root: PathBuf,
+    pub visible: bool,
+}
+
+impl Session {
+    /// Where the session is saved on quit and what `:mksession` and `:source`
+    /// use without a file name
+    pub fn default_path() -> PathBuf {
+        dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("rvim")
+            .join("session.json")
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .map_err(|e| Error::Message(format!("E484: Can't open file {}: {}", path.display(), e)))?;
+        serde_json::from_str(&json)
+            .map_err(|e| Error::Message(format!("Invalid session file {}: {}", path.display(), e)))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::Message(format!("Failed to encode session: {}", e)))?;
+        fs::write(path, json)?;
+        Ok(())
+    }
+}
+
+/// Absolute form of a file name, so a session loads from any directory
+pub fn absolute(name: &str) -> PathBuf {
+    let path = Path::new(name);
+    fs::canonicalize(path).unwrap_or_else(|_| env::current_dir().unwrap_or_default().join(path))
+}
+
+/// File name to open a saved path under: relative to the working directory
+/// when it is inside it, as files opened from the command line are
+pub fn display_name(path: &Path) -> String {
+    let cwd = env::current_dir().unwrap_or_default();
+    path.strip_prefix(&cwd).unwrap_or(path).to_string_lossy().to_string()
+}
@@ -3,7 +3,7 @@ use std::io::{self, Write, BufReader, BufRead};
 use std::process::{Command, Stdio, Child, ChildStdin, ChildStdout, ChildStderr};
 use std::thread;
 use std::sync::mpsc::{self, Sender, Receiver, TryRecvError};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use log::info;
 use std::env;
 use std::sync::{Arc, Mutex};
@@ -13,6 +13,16 @@ enum ShellOutput {
     Terminated,
 }
 
+/// How long to wait for the shell to list completions
+const COMPLETION_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Most completions kept from one query
+const MAX_COMPLETIONS: usize = 100;
+
+/// `word` in single quotes for the shell
+fn shell_quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
 #[derive(Clone)] // Add this line before Shell struct definition
 pub struct Shell {
     pub lines: Vec<String>,
@@ -22,6 +32,13 @@ pub struct Shell {
     pub running: bool,       // RVim's flag to indicate if this shell mode is active
     pub command_history: Vec<String>,
     pub history_position: usize,
+    /// Completions offered for the word before the cursor after `Tab`
+    pub completion_candidates: Vec<String>,
+    pub completion_selected: usize,
+    // Byte offset in input_line of the word being completed
+    completion_start: usize,
+    // Whether the shell has bash's `compgen`; other shells only complete file names
+    has_compgen: bool,
 
     child: Arc<Mutex<Option<Child>>>,
     child_stdin: Arc<Mutex<Option<ChildStdin>>>,
@@ -41,6 +58,10 @@ impl Shell {
             running: true,
             command_history: Vec::new(),
             history_position: 0,
+            completion_candidates: Vec::new(),
+            completion_selected: 0,
+            completion_start: 0,
+            has_compgen: false,
             child: Arc::new(Mutex::new(None)),
             child_stdin: Arc::new(Mutex::new(None)),
             output_receiver: Arc::new(Mutex::new(None)),
@@ -64,6 +85,7 @@ impl Shell {
         });
 
         info!("Spawning shell: {}", shell_cmd);
+        self.has_compgen = std::path::Path::new(&shell_cmd).file_name().is_some_and(|name| name == "bash");
 
         let mut child_process = Command::new(&shell_cmd)
             .stdin(Stdio::piped())
@@ -76,6 +98,7 @@ impl Shell {
             .ok_or_else(|| Error::ShellSpawnError("Failed to capture stdout".to_string()))?;
         let child_stderr = child_process.stderr.take()
             .ok_or_else(|| Error::ShellSpawnError("Failed to capture stderr".to_string()))?;
+        *self.child_stdin.lock().unwrap() = child_process.stdin.take();
         
         {
             let mut child_lock = self.child.lock().unwrap();
@@ -277,6 +300,89 @@ impl Shell {
         }
     }
 
+    /// `Tab`: complete the word before the cursor. One match is inserted at
+    /// once; with several, the first press lists them and later ones cycle
+    pub fn complete(&mut self) -> Result<()> {
+        if !self.completion_candidates.is_empty() {
+            self.completion_selected = (self.completion_selected + 1) % self.completion_candidates.len();
+            return Ok(());
+        }
+        let before = &self.input_line[..self.cursor_pos];
+        let start = before.rfind(char::is_whitespace).map_or(0, |idx| idx + 1);
+        // The first word names a command, the others are file names
+        let command = before[..start].trim().is_empty();
+        let word = before[start..].to_string();
+        let candidates = self.query_completions(&word, command)?;
+        self.completion_start = start;
+        match candidates.len() {
+            0 => {},
+            1 => {
+                self.completion_candidates = candidates;
+                self.accept_completion();
+            },
+            _ => {
+                self.completion_candidates = candidates;
+                self.completion_selected = 0;
+            },
+        }
+        Ok(())
+    }
+
+    /// Put the selected completion in place of the word being completed
+    pub fn accept_completion(&mut self) {
+        if let Some(candidate) = self.completion_candidates.get(self.completion_selected) {
+            self.input_line.replace_range(self.completion_start..self.cursor_pos, candidate);
+            self.cursor_pos = self.completion_start + candidate.len();
+        }
+        self.dismiss_completion();
+    }
+
+    pub fn dismiss_completion(&mut self) {
+        self.completion_candidates.clear();
+        self.completion_selected = 0;
+    }
+
+    /// List completions of `word` in a separate shell started in the running
+    /// shell's working directory. Nothing is written to the running shell, whose
+    /// stdin may belong to a program in the foreground
+    fn query_completions(&mut self, word: &str, command: bool) -> Result<Vec<String>> {
+        let quoted = shell_quote(word);
+        let list = match (self.has_compgen, command) {
+            (true, true) => format!("compgen -c -- {}", quoted),
+            (true, false) => format!("compgen -f -- {}", quoted),
+            (false, _) => format!("for f in {}*; do [ -e \"$f\" ] && printf '%s\\n' \"$f\"; done", quoted),
+        };
+        let mut process = Command::new(if self.has_compgen { "bash" } else { "sh" });
+        process.arg("-c")
+            .arg(format!("{{ {}; }} 2>/dev/null | LC_ALL=C sort -u | head -n {}", list, MAX_COMPLETIONS))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        if let Some(dir) = self.working_dir() {
+            process.current_dir(dir);
+        }
+        let mut child = process.spawn()
+            .map_err(|e| Error::ShellSpawnError(format!("Failed to list completions: {}", e)))?;
+        let deadline = Instant::now() + COMPLETION_TIMEOUT;
+        while child.try_wait()?.is_none() {
+            if Instant::now() >= deadline {
+                info!("Listing completions for {:?} timed out", word);
+                let _ = child.kill();
+                let _ = child.wait();
+                return Ok(Vec::new());
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        let output = child.wait_with_output()?;
+        Ok(String::from_utf8_lossy(&output.stdout).lines().map(String::from).collect())
+    }
+
+    /// Working directory of the running shell, where it can be read
+    fn working_dir(&self) -> Option<std::path::PathBuf> {
+        let pid = self.child.lock().unwrap().as_ref()?.id();
+        std::fs::read_link(format!("/proc/{}/cwd", pid)).ok()
+    }
+
     pub fn history_up(&mut self) {
         if !self.command_history.is_empty() && self.history_position > 0 {
             self.history_position -= 1;
@@ -0,0 +1,196 @@
+use regex::{Regex, RegexBuilder};
+use crate::error::{Error, Result};
+
+/// Lines a substitution applies to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SubstituteRange {
+    CurrentLine,
+    /// `%`: every line in the buffer
+    WholeFile,
+}
+
+/// A parsed `:s/pattern/replacement/flags` command
+#[derive(Debug)]
+pub struct Substitute {
+    pub range: SubstituteRange,
+    /// Empty when the last search pattern should be used
+    pub pattern: String,
+    /// Replacement in `regex` syntax, see `convert_replacement`
+    pub replacement: String,
+    /// `g`: replace every match in a line, not just the first
+    pub global: bool,
+    /// `i`: ignore case
+    pub ignore_case: bool,
+    /// `c`: ask before each replacement
+    pub confirm: bool,
+}
+
+impl Substitute {
+    /// Parse a command line such as `s/foo/bar/g` or `%s#a#b#`. Returns `None`
+    /// when the command is not a substitute command at all
+    pub fn parse(cmd: &str) -> Option<Result<Self>> {
+        let (range, rest) = match cmd.strip_prefix('%') {
+            Some(rest) => (SubstituteRange::WholeFile, rest),
+            None => (SubstituteRange::CurrentLine, cmd),
+        };
+        let rest = rest.strip_prefix("substitute")
+            .or_else(|| rest.strip_prefix('s'))?;
+        // Any punctuation can separate the parts, like in Vim
+        let delimiter = rest.chars().next()?;
+        if delimiter.is_alphanumeric() || delimiter.is_whitespace() || delimiter == '\\' || delimiter == '"' {
+            return None;
+        }
+        Some(Self::parse_parts(range, &rest[delimiter.len_utf8()..], delimiter))
+    }
+
+    fn parse_parts(range: SubstituteRange, text: &str, delimiter: char) -> Result<Self> {
+        let (pattern, rest) = split_at_delimiter(text, delimiter);
+        let (replacement, flags) = match rest {
+            Some(rest) => {
+                let (replacement, flags) = split_at_delimiter(rest, delimiter);
+                (replacement, flags.unwrap_or(""))
+            },
+            None => (String::new(), ""),
+        };
+
+        let mut substitute = Self {
+            range,
+            pattern,
+            replacement: convert_replacement(&replacement),
+            global: false,
+            ignore_case: false,
+            confirm: false,
+        };
+        for flag in flags.trim().chars() {
+            match flag {
+                'g' => substitute.global = true,
+                'i' => substitute.ignore_case = true,
+                'c' => substitute.confirm = true,
+                _ => return Err(Error::Message(format!("E488: Trailing characters: {}", flags))),
+            }
+        }
+        Ok(substitute)
+    }
+
+    /// Compile the pattern, falling back to `last_pattern` when it is empty
+    pub fn regex(&self, last_pattern: Option<&str>) -> Result<Regex> {
+        let pattern = match (self.pattern.as_str(), last_pattern) {
+            ("", Some(last)) => last,
+            ("", None) => return Err(Error::Message("E35: No previous regular expression".to_string())),
+            (pattern, _) => pattern,
+        };
+        RegexBuilder::new(pattern)
+            .case_insensitive(self.ignore_case)
+            .build()
+            .map_err(|e| Error::Message(format!("E486: Invalid pattern: {}", e)))
+    }
+}
+
+/// Split `text` at the first unescaped `delimiter`. An escaped delimiter is
+/// unescaped; other backslashes are kept for the regex
+fn split_at_delimiter(text: &str, delimiter: char) -> (String, Option<&str>) {
+    let mut part = String::new();
+    let mut chars = text.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some((_, next)) if next == delimiter => part.push(next),
+                Some((_, next)) => {
+                    part.push('\\');
+                    part.push(next);
+                },
+                None => part.push('\\'),
+            }
+        } else if c == delimiter {
+            return (part, Some(&text[idx + c.len_utf8()..]));
+        } else {
+            part.push(c);
+        }
+    }
+    (part, None)
+}
+
+/// Turn a Vim replacement string into `regex` replacement syntax: `&` and `\0`
+/// insert the whole match, `\1`..`\9` a group, `\&` and `\\` are literal, and
+/// `\t` is a tab
+fn convert_replacement(replacement: &str) -> String {
+    let mut converted = String::new();
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => converted.push_str("${0}"),
+            '$' => converted.push_str("$$"),
+            '\\' => match chars.next() {
+                Some(digit @ '0'..='9') => converted.push_str(&format!("${{{}}}", digit)),
+                Some('t') => converted.push('\t'),
+                Some(other) => converted.push(other),
+                None => converted.push('\\'),
+            },
+            _ => converted.push(c),
+        }
+    }
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::buffer::Document;
+
+    fn parse(cmd: &str) -> Substitute {
+        Substitute::parse(cmd).expect("a substitute command").expect("valid")
+    }
+
+    #[test]
+    fn parses_range_pattern_and_replacement() {
+        let substitute = parse("s/foo/bar/");
+        assert_eq!(substitute.range, SubstituteRange::CurrentLine);
+        assert_eq!((substitute.pattern.as_str(), substitute.replacement.as_str()), ("foo", "bar"));
+        assert!(!substitute.global && !substitute.ignore_case && !substitute.confirm);
+
+        let substitute = parse("%substitute#a\\#b#c");
+        assert_eq!(substitute.range, SubstituteRange::WholeFile);
+        assert_eq!((substitute.pattern.as_str(), substitute.replacement.as_str()), ("a#b", "c"));
+
+        // No replacement deletes the match
+        assert_eq!(parse("s/x").replacement, "");
+        assert!(Substitute::parse("set number").is_none());
+        assert!(Substitute::parse("sa").is_none());
+    }
+
+    #[test]
+    fn parses_flags() {
+        let substitute = parse("%s/a/b/gic");
+        assert!(substitute.global && substitute.ignore_case && substitute.confirm);
+        assert!(parse("s/a/b/ g ").global);
+        assert!(Substitute::parse("s/a/b/x").expect("a substitute command").is_err());
+    }
+
+    #[test]
+    fn converts_vim_replacements() {
+        assert_eq!(parse(r"s/(\w+)/[&] \1\t\&$/").replacement, "[${0}] ${1}\t&$$");
+    }
+
+    #[test]
+    fn empty_pattern_uses_the_last_search() {
+        let substitute = parse("s//x/i");
+        assert!(substitute.regex(None).is_err());
+        assert!(substitute.regex(Some("abc")).unwrap().is_match("xABCx"));
+    }
+
+    #[test]
+    fn counts_replacements_and_lines() {
+        let mut document = Document::new();
+        document.replace_all("a a\nb\na");
+
+        let substitute = parse("%s/a/x/");
+        let regex = substitute.regex(None).unwrap();
+        assert_eq!(document.substitute(0, 2, &regex, &substitute.replacement, substitute.global), (2, 2));
+        assert_eq!(document.lines, ["x a", "b", "x"]);
+
+        let substitute = parse("s/[ax]/y/g");
+        let regex = substitute.regex(None).unwrap();
+        assert_eq!(document.substitute(0, 0, &regex, &substitute.replacement, substitute.global), (2, 1));
+        assert_eq!(document.lines, ["y y", "b", "x"]);
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::info;
+use mlua::{Lua, RegistryKey, Table, Value};
+use crate::error::{Error, Result};
+
+/// Exit code reported when a command is killed after exceeding its timeout
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// An external command requested through `rvim.system`
+pub struct SystemCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    pub env: Vec<(String, String)>,
+    pub timeout: Option<Duration>,
+}
+
+/// Captured result of a finished command
+pub struct SystemOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub code: i32,
+}
+
+impl SystemCommand {
+    /// Run a command line through the platform shell
+    pub fn shell(cmd: &str) -> Self {
+        let (program, flag) = if cfg!(windows) { ("cmd.exe", "/C") } else { ("sh", "-c") };
+        Self {
+            program: program.to_string(),
+            args: vec![flag.to_string(), cmd.to_string()],
+            cwd: None,
+            env: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    /// Execute a program directly from an argument vector
+    pub fn exec(argv: Vec<String>) -> Result<Self> {
+        let mut argv = argv.into_iter();
+        let program = argv.next()
+            .ok_or_else(|| Error::Message("Empty command".to_string()))?;
+        Ok(Self {
+            program,
+            args: argv.collect(),
+            cwd: None,
+            env: Vec::new(),
+            timeout: None,
+        })
+    }
+
+    /// Build a command from the `cmd` and `opts` arguments of `rvim.system`
+    pub fn from_lua(cmd: Value, opts: Option<Table>) -> mlua::Result<Self> {
+        let mut command = match cmd {
+            Value::String(s) => Self::shell(s.to_str()?),
+            Value::Table(t) => {
+                let argv = t.sequence_values::<String>().collect::<mlua::Result<Vec<_>>>()?;
+                Self::exec(argv).map_err(mlua::Error::external)?
+            },
+            other => return Err(mlua::Error::RuntimeError(
+                format!("rvim.system: expected string or list of strings, got {}", other.type_name())
+            )),
+        };
+
+        if let Some(opts) = opts {
+            command.cwd = opts.get::<_, Option<String>>("cwd")?.map(PathBuf::from);
+            if let Some(env) = opts.get::<_, Option<Table>>("env")? {
+                for pair in env.pairs::<String, String>() {
+                    command.env.push(pair?);
+                }
+            }
+            command.timeout = opts.get::<_, Option<u64>>("timeout")?.map(Duration::from_millis);
+        }
+
+        Ok(command)
+    }
+
+    /// Run the command to completion, killing it if it outlives the timeout
+    pub fn run(&self) -> Result<SystemOutput> {
+        info!("Running system command: {} {:?}", self.program, self.args);
+
+        let mut command = Command::new(&self.program);
+        command.args(&self.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+        for (key, value) in &self.env {
+            command.env(key, value);
+        }
+
+        let mut child = command.spawn()?;
+
+        // Drain both pipes on their own threads so a chatty process cannot block on a full pipe
+        let mut stdout = child.stdout.take()
+            .ok_or_else(|| Error::Message("Failed to capture stdout".to_string()))?;
+        let mut stderr = child.stderr.take()
+            .ok_or_else(|| Error::Message("Failed to capture stderr".to_string()))?;
+        let stdout_handle = thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = stdout.read_to_end(&mut buf);
+            buf
+        });
+        let stderr_handle = thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = stderr.read_to_end(&mut buf);
+            buf
+        });
+
+        let started = Instant::now();
+        let code = loop {
+            if let Some(status) = child.try_wait()? {
+                break status.code().unwrap_or(-1);
+            }
+            if self.timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+                info!("System command timed out, killing: {}", self.program);
+                let _ = child.kill();
+                let _ = child.wait();
+                break TIMEOUT_EXIT_CODE;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        let stdout = stdout_handle.join().unwrap_or_default();
+        let stderr = stderr_handle.join().unwrap_or_default();
+
+        Ok(SystemOutput {
+            stdout: String::from_utf8_lossy(&stdout).to_string(),
+            stderr: String::from_utf8_lossy(&stderr).to_string(),
+            code,
+        })
+    }
+}
+
+impl SystemOutput {
+    /// Convert to the `{ stdout, stderr, code }` table returned to Lua
+    pub fn to_lua_table<'lua>(&self, lua: &'lua Lua) -> mlua::Result<Table<'lua>> {
+        let table = lua.create_table()?;
+        table.set("stdout", self.stdout.as_str())?;
+        table.set("stderr", self.stderr.as_str())?;
+        table.set("code", self.code)?;
+        Ok(table)
+    }
+}
+
+type JobResult = (usize, Result<SystemOutput>);
+
+/// Tracks commands started with `rvim.system_async` until their callbacks run
+pub struct JobRunner {
+    receiver: Receiver<JobResult>,
+    handle: JobHandle,
+}
+
+/// Cloneable handle used by Lua functions to start background jobs
+#[derive(Clone)]
+pub struct JobHandle {
+    sender: Sender<JobResult>,
+    callbacks: Arc<Mutex<HashMap<usize, RegistryKey>>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+impl JobRunner {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            receiver,
+            handle: JobHandle {
+                sender,
+                callbacks: Arc::new(Mutex::new(HashMap::new())),
+                next_id: Arc::new(AtomicUsize::new(0)),
+            },
+        }
+    }
+
+    pub fn handle(&self) -> JobHandle {
+        self.handle.clone()
+    }
+
+    /// Collect finished jobs together with the callbacks registered for them
+    pub fn drain(&self) -> Vec<(RegistryKey, Result<SystemOutput>)> {
+        let mut finished = Vec::new();
+        while let Ok((id, result)) = self.receiver.try_recv() {
+            if let Some(callback) = self.handle.callbacks.lock().unwrap().remove(&id) {
+                finished.push((callback, result));
+            }
+        }
+        finished
+    }
+}
+
+impl JobHandle {
+    /// Run the command on a background thread; the callback fires from the editor loop
+    pub fn spawn(&self, command: SystemCommand, callback: RegistryKey) -> usize {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.callbacks.lock().unwrap().insert(id, callback);
+
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            let _ = sender.send((id, command.run()));
+        });
+        id
+    }
+}
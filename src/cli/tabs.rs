@@ -1,11 +1,53 @@
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use log::info;
 use crate::error::{Error, Result};
-use crate::cli::buffer::Buffer;
+use crate::cli::buffer::{self, Buffer};
+
+/// A tab's buffer, read from disk only once the tab is shown. The editor's
+/// buffer list holds the contents of loaded ones, so there is a single copy
+pub enum LazyBuffer {
+    /// In the editor's buffer list under this id
+    Loaded(usize),
+    /// Not read yet, or dropped while hidden; the id is reserved so the Lua API
+    /// can refer to it
+    Unloaded { id: usize, path: PathBuf },
+}
+
+impl LazyBuffer {
+    pub fn id(&self) -> usize {
+        match self {
+            LazyBuffer::Loaded(id) | LazyBuffer::Unloaded { id, .. } => *id,
+        }
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        matches!(self, LazyBuffer::Loaded(_))
+    }
+
+    /// The file to read for an unloaded buffer
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            LazyBuffer::Loaded(_) => None,
+            LazyBuffer::Unloaded { path, .. } => Some(path),
+        }
+    }
+}
 
 pub struct Tab {
     id: usize,
     name: String,
-    buffer: Buffer,
+    buffer: LazyBuffer,
+}
+
+impl Tab {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn buffer(&self) -> &LazyBuffer {
+        &self.buffer
+    }
 }
 
 pub struct TabManager {
@@ -13,6 +55,8 @@ pub struct TabManager {
     current_tab: usize,
     tab_map: HashMap<String, usize>,
     next_id: usize,
+    // Tab ids in order of access, most recently left last
+    access_order: Vec<usize>,
 }
 
 impl TabManager {
@@ -22,10 +66,21 @@ impl TabManager {
             current_tab: 0,
             tab_map: HashMap::new(),
             next_id: 0,
+            access_order: Vec::new(),
         }
     }
 
-    pub fn create_tab(&mut self, name: String, buffer: Buffer) -> Result<usize> {
+    /// Add a tab for the loaded buffer with id `buffer_id`, returning its index
+    pub fn create_tab(&mut self, name: String, buffer_id: usize) -> Result<usize> {
+        self.push_tab(name, LazyBuffer::Loaded(buffer_id))
+    }
+
+    /// Add a tab for a file that is only read when the tab is first shown
+    pub fn create_lazy_tab(&mut self, name: String, path: PathBuf) -> Result<usize> {
+        self.push_tab(name, LazyBuffer::Unloaded { id: buffer::next_buffer_id(), path })
+    }
+
+    fn push_tab(&mut self, name: String, buffer: LazyBuffer) -> Result<usize> {
         if self.tab_map.contains_key(&name) {
             return Err(Error::TabExists(name));
         }
@@ -36,14 +91,26 @@ impl TabManager {
         let tab = Tab { id, name: name.clone(), buffer };
         self.tabs.push(tab);
         self.tab_map.insert(name, id);
-        Ok(id)
+        Ok(self.tabs.len() - 1)
+    }
+
+    // Change the current tab, remembering the one we leave for switch_to_last_accessed
+    fn set_current(&mut self, idx: usize) {
+        if idx != self.current_tab
+            && let Some(tab) = self.tabs.get(self.current_tab)
+        {
+            let id = tab.id;
+            self.access_order.retain(|&other| other != id);
+            self.access_order.push(id);
+        }
+        self.current_tab = idx;
     }
 
     pub fn switch_to_next_tab(&mut self) -> Result<()> {
         if self.tabs.is_empty() {
             return Err(Error::TabError("No tabs available".to_string()));
         }
-        self.current_tab = (self.current_tab + 1) % self.tabs.len();
+        self.set_current((self.current_tab + 1) % self.tabs.len());
         Ok(())
     }
 
@@ -51,33 +118,142 @@ impl TabManager {
         if self.tabs.is_empty() {
             return Err(Error::TabError("No tabs available".to_string()));
         }
-        self.current_tab = if self.current_tab == 0 {
+        let idx = if self.current_tab == 0 {
             self.tabs.len() - 1
         } else {
             self.current_tab - 1
         };
+        self.set_current(idx);
         Ok(())
     }
 
     pub fn switch_to_tab(&mut self, idx: usize) -> Result<()> {
         if idx < self.tabs.len() {
-            self.current_tab = idx;
+            self.set_current(idx);
             Ok(())
         } else {
             Err(Error::TabNotFound(idx))
         }
     }
 
-    pub fn current_buffer(&self) -> Result<&Buffer> {
-        self.tabs.get(self.current_tab)
-            .map(|tab| &tab.buffer)
-            .ok_or_else(|| Error::TabError("No active tab".to_string()))
+    /// Switch back to the most recently accessed tab other than the current one
+    pub fn switch_to_last_accessed(&mut self) -> Result<()> {
+        let current_id = self.tabs.get(self.current_tab).map(|tab| tab.id);
+        let target = self.access_order.iter().rev()
+            .filter(|&&id| Some(id) != current_id)
+            .find_map(|&id| self.tabs.iter().position(|tab| tab.id == id));
+        match target {
+            Some(idx) => {
+                self.set_current(idx);
+                Ok(())
+            },
+            None => Err(Error::TabError("No previously accessed tab".to_string())),
+        }
     }
 
-    pub fn current_buffer_mut(&mut self) -> Result<&mut Buffer> {
-        self.tabs.get_mut(self.current_tab)
-            .map(|tab| &mut tab.buffer)
-            .ok_or_else(|| Error::TabError("No active tab".to_string()))
+    /// Show the buffer with id `buffer_id` in the current tab under a new name,
+    /// creating a tab if there is none
+    pub fn replace_current(&mut self, name: String, buffer_id: usize) -> Result<()> {
+        let current_id = self.tabs.get(self.current_tab).map(|tab| tab.id);
+        if let Some(&id) = self.tab_map.get(&name)
+            && Some(id) != current_id
+        {
+            return Err(Error::TabExists(name));
+        }
+        let Some(tab) = self.tabs.get_mut(self.current_tab) else {
+            let idx = self.create_tab(name, buffer_id)?;
+            self.set_current(idx);
+            return Ok(());
+        };
+        self.tab_map.remove(&tab.name);
+        self.tab_map.insert(name.clone(), tab.id);
+        tab.name = name;
+        tab.buffer = LazyBuffer::Loaded(buffer_id);
+        Ok(())
+    }
+
+    /// Give the tab at `idx` a new name, e.g. after its file was saved under another name
+    pub fn rename_tab(&mut self, idx: usize, name: String) -> Result<()> {
+        if self.tab_map.contains_key(&name) {
+            return Err(Error::TabExists(name));
+        }
+        let tab = self.tabs.get_mut(idx).ok_or(Error::TabNotFound(idx))?;
+        self.tab_map.remove(&tab.name);
+        self.tab_map.insert(name.clone(), tab.id);
+        tab.name = name;
+        Ok(())
+    }
+
+    /// Close the current tab and show its right neighbour, or the left one when it
+    /// was the last. The last remaining tab cannot be closed
+    pub fn close_current_tab(&mut self) -> Result<Tab> {
+        if self.tabs.len() <= 1 {
+            return Err(Error::TabError("E784: Cannot close last tab page".to_string()));
+        }
+        let tab = self.tabs.remove(self.current_tab);
+        self.tab_map.remove(&tab.name);
+        self.access_order.retain(|&id| id != tab.id);
+        self.current_tab = self.current_tab.min(self.tabs.len() - 1);
+        Ok(tab)
+    }
+
+    /// Index of the tab showing the buffer with id `buffer_id`
+    pub fn tab_index_of_buffer(&self, buffer_id: usize) -> Option<usize> {
+        self.tabs.iter().position(|tab| tab.buffer.id() == buffer_id)
+    }
+
+    /// Index of the tab named exactly `name`
+    pub fn tab_index(&self, name: &str) -> Option<usize> {
+        let id = self.tab_map.get(name)?;
+        self.tabs.iter().position(|tab| tab.id == *id)
+    }
+
+    /// Indices of tabs whose name contains `partial`
+    pub fn find_tabs(&self, partial: &str) -> Vec<usize> {
+        self.tabs.iter()
+            .enumerate()
+            .filter(|(_, tab)| tab.name.contains(partial))
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    /// Read the file of tab `idx` if it is unloaded. The buffer keeps the id
+    /// reserved for it; the caller adds it to the editor's buffer list
+    pub fn load_tab_buffer(&mut self, idx: usize) -> Result<Option<Buffer>> {
+        let tab = self.tabs.get_mut(idx).ok_or(Error::TabNotFound(idx))?;
+        let LazyBuffer::Unloaded { id, path } = &tab.buffer else {
+            return Ok(None);
+        };
+        info!("Loading buffer {} from {:?}", id, path);
+        let mut buffer = Buffer::from_file(&path.to_string_lossy())?;
+        buffer.id = *id;
+        tab.buffer = LazyBuffer::Loaded(*id);
+        Ok(Some(buffer))
+    }
+
+    /// Mark the buffer of tab `idx` as dropped from memory; it is read from
+    /// `path` again when the tab is shown
+    pub fn unload_tab_buffer(&mut self, idx: usize, path: PathBuf) {
+        if let Some(tab) = self.tabs.get_mut(idx) {
+            tab.buffer = LazyBuffer::Unloaded { id: tab.buffer.id(), path };
+        }
+    }
+
+    /// Indices of the tabs whose buffer is loaded, with the buffer ids
+    pub fn loaded_tabs(&self) -> Vec<(usize, usize)> {
+        self.tabs.iter()
+            .enumerate()
+            .filter(|(_, tab)| tab.buffer.is_loaded())
+            .map(|(idx, tab)| (idx, tab.buffer.id()))
+            .collect()
+    }
+
+    /// Ids of tab buffers that have not been read from disk
+    pub fn unloaded_buffer_ids(&self) -> Vec<usize> {
+        self.tabs.iter()
+            .filter(|tab| !tab.buffer.is_loaded())
+            .map(|tab| tab.buffer.id())
+            .collect()
     }
 
     pub fn current_tab(&self) -> usize {
@@ -97,4 +273,14 @@ impl TabManager {
             .map(|tab| (tab.id, tab.name.as_str()))
             .collect()
     }
+
+    /// Resolve the relative file paths of unloaded tabs against `base` before the
+    /// working directory changes
+    pub fn make_paths_absolute(&mut self, base: &Path) {
+        for tab in &mut self.tabs {
+            if let LazyBuffer::Unloaded { path, .. } = &mut tab.buffer {
+                *path = base.join(&*path);
+            }
+        }
+    }
 }
@@ -0,0 +1,181 @@
+/// A text object typed after an operator, e.g. the `iw` in `ciw`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextObject {
+    /// `iw` / `aw`; `aw` also takes the whitespace after the word
+    Word { inner: bool },
+    /// `i"` / `a"`, also for `'` and `` ` ``; only looks at the cursor line
+    Quote { quote: char, inner: bool },
+    /// `i(` / `a(`, also for `[]`, `{}` and `<>`; may span lines
+    Pair { open: char, close: char, inner: bool },
+}
+
+impl TextObject {
+    /// The object for the key typed after `i` (inner) or `a` (around)
+    pub fn from_key(inner: bool, key: char) -> Option<Self> {
+        let pair = |open, close| Some(Self::Pair { open, close, inner });
+        match key {
+            'w' => Some(Self::Word { inner }),
+            '"' | '\'' | '`' => Some(Self::Quote { quote: key, inner }),
+            '(' | ')' | 'b' => pair('(', ')'),
+            '[' | ']' => pair('[', ']'),
+            '{' | '}' | 'B' => pair('{', '}'),
+            '<' | '>' => pair('<', '>'),
+            _ => None,
+        }
+    }
+
+    /// Start row and byte column and end row and byte column of the object
+    /// around (`row`, `col`). The end is exclusive
+    pub fn range(self, lines: &[String], row: usize, col: usize) -> Option<(usize, usize, usize, usize)> {
+        let line = lines.get(row)?;
+        match self {
+            Self::Word { inner } => word_range(line, col, inner).map(|(start, end)| (row, start, row, end)),
+            Self::Quote { quote, inner } => {
+                let (open, close) = quote_pair(line, col, quote)?;
+                let len = quote.len_utf8();
+                Some(if inner { (row, open + len, row, close) } else { (row, open, row, close + len) })
+            },
+            Self::Pair { open, close, inner } => {
+                let (open_at, close_at) = enclosing_pair(lines, row, col, open, close)?;
+                Some(if inner {
+                    (open_at.0, open_at.1 + open.len_utf8(), close_at.0, close_at.1)
+                } else {
+                    (open_at.0, open_at.1, close_at.0, close_at.1 + close.len_utf8())
+                })
+            },
+        }
+    }
+}
+
+/// 0 for whitespace, 1 for keyword characters, 2 for other punctuation
+fn char_class(c: char) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
+/// The run of same-class characters under byte column `col`; `around` adds the
+/// whitespace after it, or before it when there is none after
+fn word_range(line: &str, col: usize, inner: bool) -> Option<(usize, usize)> {
+    let chars: Vec<(usize, char)> = line.char_indices().collect();
+    let idx = chars.iter().rposition(|(byte, _)| *byte <= col)?;
+    let class = char_class(chars[idx].1);
+    let same = |i: &usize| char_class(chars[*i].1) == class;
+    let mut first = idx;
+    while first > 0 && same(&(first - 1)) {
+        first -= 1;
+    }
+    let mut last = idx;
+    while last + 1 < chars.len() && same(&(last + 1)) {
+        last += 1;
+    }
+    if !inner {
+        // Whitespace takes the word after it; a word takes the whitespace after it
+        let extend = |i: usize| if class == 0 { char_class(chars[i].1) != 0 } else { char_class(chars[i].1) == 0 };
+        let before = last;
+        while last + 1 < chars.len() && extend(last + 1) {
+            last += 1;
+        }
+        if last == before && class != 0 {
+            while first > 0 && char_class(chars[first - 1].1) == 0 {
+                first -= 1;
+            }
+        }
+    }
+    let end = chars.get(last + 1).map_or(line.len(), |(byte, _)| *byte);
+    Some((chars[first].0, end))
+}
+
+/// Byte columns of the quotes around `col`, or of the first quoted string after it.
+/// Quotes escaped with a backslash are skipped
+fn quote_pair(line: &str, col: usize, quote: char) -> Option<(usize, usize)> {
+    let mut quotes = Vec::new();
+    let mut escaped = false;
+    for (byte, c) in line.char_indices() {
+        if c == quote && !escaped {
+            quotes.push(byte);
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    quotes.chunks_exact(2)
+        .map(|pair| (pair[0], pair[1]))
+        .find(|&(_, close)| col <= close)
+}
+
+/// Positions of the unmatched `open` before (`row`, `col`) and its matching
+/// `close`. The cursor may be on either bracket
+fn enclosing_pair(lines: &[String], row: usize, col: usize, open: char, close: char) -> Option<((usize, usize), (usize, usize))> {
+    let mut depth = 0;
+    let mut open_at = None;
+    'backward: for r in (0..=row).rev() {
+        let line = &lines[r];
+        for (byte, c) in line.char_indices().rev() {
+            if r == row && byte > col {
+                continue;
+            }
+            if c == close && (r, byte) != (row, col) {
+                depth += 1;
+            } else if c == open {
+                if depth == 0 {
+                    open_at = Some((r, byte));
+                    break 'backward;
+                }
+                depth -= 1;
+            }
+        }
+    }
+    let open_at = open_at?;
+
+    let mut depth = 0;
+    for (r, line) in lines.iter().enumerate().skip(open_at.0) {
+        for (byte, c) in line.char_indices() {
+            if r == open_at.0 && byte <= open_at.1 {
+                continue;
+            }
+            if c == open {
+                depth += 1;
+            } else if c == close {
+                if depth == 0 {
+                    return Some((open_at, (r, byte)));
+                }
+                depth -= 1;
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &[&str]) -> Vec<String> {
+        text.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn finds_words_and_quotes() {
+        let text = lines(&["let name = \"a \\\" b\";"]);
+        assert_eq!(TextObject::Word { inner: true }.range(&text, 0, 5), Some((0, 4, 0, 8)));
+        assert_eq!(TextObject::Word { inner: false }.range(&text, 0, 5), Some((0, 4, 0, 9)));
+        // From before the string, the first quoted string on the line
+        let quote = TextObject::Quote { quote: '"', inner: true };
+        assert_eq!(quote.range(&text, 0, 0), Some((0, 12, 0, 18)));
+        assert_eq!(TextObject::Quote { quote: '"', inner: false }.range(&text, 0, 14), Some((0, 11, 0, 19)));
+    }
+
+    #[test]
+    fn finds_nested_pairs_across_lines() {
+        let text = lines(&["f(a, (b),", "  c)"]);
+        let inner = TextObject::Pair { open: '(', close: ')', inner: true };
+        assert_eq!(inner.range(&text, 0, 3), Some((0, 2, 1, 3)));
+        assert_eq!(inner.range(&text, 0, 6), Some((0, 6, 0, 7)));
+        // On the closing bracket
+        assert_eq!(TextObject::Pair { open: '(', close: ')', inner: false }.range(&text, 1, 3), Some((0, 1, 1, 4)));
+        assert_eq!(inner.range(&text, 0, 0), None);
+    }
+}
@@ -0,0 +1,69 @@
+use crossterm::style::Color;
+use syntect::highlighting::{Highlighter, ThemeSet};
+use syntect::parsing::Scope;
+
+/// Foreground colors for the highlight names produced by `Buffer::highlight_spans`
+#[derive(Clone, Debug)]
+pub struct HighlightTheme {
+    pub keyword: Color,
+    pub string: Color,
+    pub comment: Color,
+    pub number: Color,
+    pub type_name: Color,
+}
+
+impl Default for HighlightTheme {
+    fn default() -> Self {
+        Self {
+            keyword: Color::Magenta,
+            string: Color::Green,
+            comment: Color::DarkGrey,
+            number: Color::Cyan,
+            type_name: Color::Yellow,
+        }
+    }
+}
+
+impl HighlightTheme {
+    /// Color for a highlight name such as `keyword` or `string`
+    pub fn color(&self, name: &str) -> Option<Color> {
+        match name {
+            "keyword" => Some(self.keyword),
+            "string" => Some(self.string),
+            "comment" => Some(self.comment),
+            "number" => Some(self.number),
+            "type" => Some(self.type_name),
+            _ => None,
+        }
+    }
+
+    /// Colorschemes that can be picked: `default` and the themes bundled with syntect
+    pub fn names() -> Vec<String> {
+        let mut names: Vec<String> = ThemeSet::load_defaults().themes.into_keys().collect();
+        names.sort();
+        names.insert(0, "default".to_string());
+        names
+    }
+
+    /// Colors for the colorscheme `name`, taken from the theme's colors for the
+    /// TextMate scopes closest to each highlight name
+    pub fn from_name(name: &str) -> Option<Self> {
+        if name == "default" {
+            return Some(Self::default());
+        }
+        let themes = ThemeSet::load_defaults();
+        let highlighter = Highlighter::new(themes.themes.get(name)?);
+        let color = |scope: &str| {
+            let scope = Scope::new(scope).ok()?;
+            let fg = highlighter.style_for_stack(&[scope]).foreground;
+            Some(Color::Rgb { r: fg.r, g: fg.g, b: fg.b })
+        };
+        Some(Self {
+            keyword: color("keyword")?,
+            string: color("string")?,
+            comment: color("comment")?,
+            number: color("constant.numeric")?,
+            type_name: color("entity.name.type")?,
+        })
+    }
+}
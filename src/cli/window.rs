@@ -20,6 +20,9 @@ pub struct Window {
     pub offset_y: usize,
     pub file_path: Option<PathBuf>,
     pub is_active: bool,
+    /// Id of the buffer the window shows while another window is active; the
+    /// active window always shows the active buffer
+    pub buffer_id: Option<usize>,
 }
 
 impl Window {
@@ -35,6 +38,7 @@ impl Window {
             offset_y: 0,
             file_path: None,
             is_active: true,
+            buffer_id: None,
         }
     }
 
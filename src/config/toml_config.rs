@@ -0,0 +1,131 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use serde::Deserialize;
+use crate::cli::editor::Editor;
+use crate::cli::options::OptionValue;
+use crate::error::{Error, Result};
+
+/// config.toml written next to config.lua when the user has neither
+pub const DEFAULT_CONFIG: &str = r#"# RVim configuration. config.lua is loaded after this file and overrides it
+
+number = true            # Show line numbers
+relativenumber = false   # Show relative line numbers
+tabstop = 4              # Tab width
+shiftwidth = 4           # Indentation width
+# expandtab = true       # Tab inserts spaces; false indents with tabs
+theme = "default"        # Color theme: "default" or a colorscheme from :Picker colorschemes
+
+[file_tree]
+width = 30               # Width of file tree panel
+show_hidden = false      # Show hidden files
+
+# Normal-mode Space mappings: an Ex command ending in <CR> or keys to type
+[keymaps]
+# "<space>t" = ":pwd<CR>"
+"#;
+
+/// Settings read from config.toml, for users who would rather not write Lua.
+/// Fields left out keep their current value
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TomlConfig {
+    pub number: Option<bool>,
+    pub relativenumber: Option<bool>,
+    pub tabstop: Option<usize>,
+    pub shiftwidth: Option<usize>,
+    pub expandtab: Option<bool>,
+    pub theme: Option<String>,
+    #[serde(default)]
+    pub file_tree: FileTreeConfig,
+    /// Keys such as `<space>t` and the action they run, as with `rvim.map`
+    #[serde(default)]
+    pub keymaps: BTreeMap<String, String>,
+}
+
+/// The `[file_tree]` table
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileTreeConfig {
+    pub width: Option<usize>,
+    pub show_hidden: Option<bool>,
+}
+
+impl TomlConfig {
+    pub fn parse(content: &str) -> std::result::Result<Self, toml::de::Error> {
+        toml::from_str(content)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Self::parse(&content).map_err(|e| Error::ConfigParseError {
+            file: path.to_path_buf(),
+            message: e.message().to_string(),
+        })
+    }
+
+    /// Options set in the file, named as `:set` and `rvim.opt` name them
+    fn options(&self) -> Vec<(&'static str, OptionValue)> {
+        let bool_options = [
+            ("number", self.number),
+            ("relativenumber", self.relativenumber),
+            ("expandtab", self.expandtab),
+            ("file_tree.show_hidden", self.file_tree.show_hidden),
+        ];
+        let int_options = [
+            ("tabstop", self.tabstop),
+            ("shiftwidth", self.shiftwidth),
+            ("file_tree.width", self.file_tree.width),
+        ];
+        bool_options.into_iter()
+            .filter_map(|(name, value)| Some((name, OptionValue::Bool(value?))))
+            .chain(int_options.into_iter()
+                .filter_map(|(name, value)| Some((name, OptionValue::Int(value? as i64)))))
+            .collect()
+    }
+
+    /// Set the options, theme and mappings from the file on `editor`
+    pub fn apply_to_editor(&self, editor: &mut Editor) -> Result<()> {
+        for (name, value) in self.options() {
+            editor.set_option(name, value)?;
+        }
+        if let Some(theme) = &self.theme {
+            editor.set_colorscheme(theme)?;
+        }
+        for (keys, action) in &self.keymaps {
+            editor.map_leader(keys, action)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_parses() {
+        let config = TomlConfig::parse(DEFAULT_CONFIG).unwrap();
+        assert_eq!(config.number, Some(true));
+        assert_eq!(config.tabstop, Some(4));
+        assert_eq!(config.theme.as_deref(), Some("default"));
+        assert_eq!(config.file_tree.width, Some(30));
+        assert!(config.keymaps.is_empty());
+    }
+
+    #[test]
+    fn only_set_fields_become_options() {
+        let config = TomlConfig::parse("tabstop = 8\n[file_tree]\nshow_hidden = true\n[keymaps]\n\"<space>t\" = \":pwd<CR>\"\n").unwrap();
+        assert_eq!(config.options(), vec![
+            ("file_tree.show_hidden", OptionValue::Bool(true)),
+            ("tabstop", OptionValue::Int(8)),
+        ]);
+        assert_eq!(config.keymaps.get("<space>t").map(String::as_str), Some(":pwd<CR>"));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(TomlConfig::parse("numbers = true\n").is_err());
+        assert!(TomlConfig::parse("[file_tree]\nhidden = true\n").is_err());
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{error, info, warn};
+use crate::error::{Error, Result};
+use crate::lsp::read_message;
+
+/// How long `initialize` waits for the adapter to answer
+const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long `disconnect` gives the adapter to stop the program
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Debug adapter for a language: executable and arguments that make it speak
+/// DAP over stdio
+pub fn adapter_for(language: &str) -> Option<(&'static str, &'static [&'static str])> {
+    match language {
+        "rust" | "c" | "cpp" => Some(("lldb-dap", &[])),
+        "python" => Some(("python3", &["-m", "debugpy.adapter"])),
+        _ => None,
+    }
+}
+
+/// A message from a debug adapter, as returned by `DapClient::poll`
+#[derive(Debug, Clone)]
+pub enum DapMessage {
+    /// Answer to a request sent with `DapClient::request`; failures carry the
+    /// adapter's error message
+    Response {
+        request_seq: u64,
+        command: String,
+        result: std::result::Result<serde_json::Value, String>,
+    },
+    /// Event such as `stopped`, `output` or `terminated`
+    Event {
+        event: String,
+        body: serde_json::Value,
+    },
+}
+
+/// A frame of a `stackTrace` response
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackFrame {
+    pub name: String,
+    /// None for frames without source, e.g. in system libraries
+    pub path: Option<PathBuf>,
+    /// Zero-based line and column
+    pub line: usize,
+    pub column: usize,
+}
+
+impl StackFrame {
+    pub fn from_response(body: &serde_json::Value) -> Vec<Self> {
+        body["stackFrames"].as_array()
+            .map(|frames| frames.iter().filter_map(Self::from_json).collect())
+            .unwrap_or_default()
+    }
+
+    fn from_json(frame: &serde_json::Value) -> Option<Self> {
+        Some(Self {
+            name: frame["name"].as_str()?.to_string(),
+            path: frame["source"]["path"].as_str().map(PathBuf::from),
+            // The client asks for 1-based lines and columns in initialize
+            line: (frame["line"].as_u64().unwrap_or(1) as usize).saturating_sub(1),
+            column: (frame["column"].as_u64().unwrap_or(1) as usize).saturating_sub(1),
+        })
+    }
+}
+
+/// Running debug adapter, spoken to with the Debug Adapter Protocol
+pub struct DapClient {
+    /// Executable name, for messages
+    name: String,
+    process: Child,
+    capabilities: serde_json::Value,
+    next_seq: u64,
+    // Messages parsed by the reader thread
+    incoming: Receiver<serde_json::Value>,
+    // Messages that arrived while initialize waited for its response
+    backlog: VecDeque<DapMessage>,
+}
+
+impl DapClient {
+    pub fn new(executable: &str, args: &[&str], cwd: &Path) -> Result<Self> {
+        info!("Starting debug adapter: {} {:?}", executable, args);
+        let mut process = Command::new(executable)
+            .args(args)
+            .current_dir(cwd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::DebugAdapter(format!("Failed to start {}: {}", executable, e)))?;
+
+        let stdout = process.stdout.take()
+            .ok_or_else(|| Error::DebugAdapter(format!("No stdout for {}", executable)))?;
+        let stderr = process.stderr.take()
+            .ok_or_else(|| Error::DebugAdapter(format!("No stderr for {}", executable)))?;
+
+        // DAP uses the same Content-Length framing as LSP
+        let (tx, rx) = mpsc::channel();
+        let name = executable.to_string();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            loop {
+                match read_message(&mut reader) {
+                    Ok(Some(body)) => match serde_json::from_slice(&body) {
+                        Ok(message) => {
+                            if tx.send(message).is_err() {
+                                break; // Client was dropped
+                            }
+                        },
+                        Err(e) => warn!("Invalid message from {}: {}", name, e),
+                    },
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Failed to read from {}: {}", name, e);
+                        break;
+                    }
+                }
+            }
+            info!("Reader for {} stopped", name);
+        });
+
+        let name = executable.to_string();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(|line| line.ok()) {
+                info!("{}: {}", name, line);
+            }
+        });
+
+        Ok(Self {
+            name: executable.to_string(),
+            process,
+            capabilities: serde_json::Value::Null,
+            next_seq: 0,
+            incoming: rx,
+            backlog: VecDeque::new(),
+        })
+    }
+
+    pub fn capabilities(&self) -> &serde_json::Value {
+        &self.capabilities
+    }
+
+    /// Send `initialize` and wait for the adapter's capabilities. Breakpoints
+    /// are sent once the adapter follows up with the `initialized` event
+    pub fn initialize(&mut self) -> Result<serde_json::Value> {
+        let seq = self.request("initialize", serde_json::json!({
+            "clientID": "rvim",
+            "clientName": "RVim",
+            "adapterID": self.name,
+            "linesStartAt1": true,
+            "columnsStartAt1": true,
+            "pathFormat": "path",
+        }))?;
+        let capabilities = self.wait_for_response(seq, "initialize", INITIALIZE_TIMEOUT)?;
+        self.capabilities = capabilities.clone();
+        Ok(capabilities)
+    }
+
+    fn write_message(&mut self, message: &serde_json::Value) -> Result<()> {
+        let body = message.to_string();
+        let stdin = self.process.stdin.as_mut()
+            .ok_or_else(|| Error::DebugAdapter(format!("No stdin for {}", self.name)))?;
+        write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+            .and_then(|_| stdin.flush())
+            .map_err(|e| Error::DebugAdapter(format!("Failed to write to {}: {}", self.name, e)))
+    }
+
+    /// Send a request without waiting, returning its sequence number. The
+    /// response is delivered through `poll`
+    pub fn request(&mut self, command: &str, arguments: serde_json::Value) -> Result<u64> {
+        self.next_seq += 1;
+        let seq = self.next_seq;
+        self.write_message(&serde_json::json!({
+            "seq": seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        }))?;
+        Ok(seq)
+    }
+
+    /// Block until the response to request `seq` arrives, keeping other messages
+    /// for `poll`
+    fn wait_for_response(&mut self, seq: u64, command: &str, timeout: Duration) -> Result<serde_json::Value> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let message = match self.incoming.recv_timeout(timeout) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(Error::DebugAdapter(format!("{} request to {} timed out", command, self.name)));
+                },
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::DebugAdapter(format!("{} exited", self.name)));
+                },
+            };
+            match self.handle_message(message) {
+                Some(DapMessage::Response { request_seq, result, .. }) if request_seq == seq => {
+                    return result.map_err(|e| Error::DebugAdapter(format!("{}: {}", command, e)));
+                },
+                Some(other) => self.backlog.push_back(other),
+                None => {},
+            }
+        }
+    }
+
+    /// Start the program with adapter specific launch arguments
+    pub fn launch(&mut self, arguments: serde_json::Value) -> Result<u64> {
+        self.request("launch", arguments)
+    }
+
+    /// Attach to a running program with adapter specific arguments
+    pub fn attach(&mut self, arguments: serde_json::Value) -> Result<u64> {
+        self.request("attach", arguments)
+    }
+
+    /// Replace the breakpoints of `path` with ones on the zero-based `rows`
+    pub fn set_breakpoints(&mut self, path: &Path, rows: &[usize]) -> Result<u64> {
+        let breakpoints: Vec<_> = rows.iter().map(|row| serde_json::json!({ "line": row + 1 })).collect();
+        self.request("setBreakpoints", serde_json::json!({
+            "source": { "path": path },
+            "breakpoints": breakpoints,
+        }))
+    }
+
+    /// Tell the adapter all breakpoints are set, so the program may run
+    pub fn configuration_done(&mut self) -> Result<u64> {
+        self.request("configurationDone", serde_json::json!({}))
+    }
+
+    fn thread_request(&mut self, command: &str, thread_id: u64) -> Result<u64> {
+        self.request(command, serde_json::json!({ "threadId": thread_id }))
+    }
+
+    pub fn continue_thread(&mut self, thread_id: u64) -> Result<u64> {
+        self.thread_request("continue", thread_id)
+    }
+
+    /// Step over the current line
+    pub fn next(&mut self, thread_id: u64) -> Result<u64> {
+        self.thread_request("next", thread_id)
+    }
+
+    pub fn step_in(&mut self, thread_id: u64) -> Result<u64> {
+        self.thread_request("stepIn", thread_id)
+    }
+
+    pub fn step_out(&mut self, thread_id: u64) -> Result<u64> {
+        self.thread_request("stepOut", thread_id)
+    }
+
+    pub fn stack_trace(&mut self, thread_id: u64) -> Result<u64> {
+        self.request("stackTrace", serde_json::json!({ "threadId": thread_id, "startFrame": 0, "levels": 20 }))
+    }
+
+    /// End the session, waiting briefly for the adapter to stop a launched program
+    pub fn disconnect(&mut self) -> Result<()> {
+        let seq = self.request("disconnect", serde_json::json!({ "terminateDebuggee": true }))?;
+        self.wait_for_response(seq, "disconnect", DISCONNECT_TIMEOUT)?;
+        Ok(())
+    }
+
+    /// Messages received since the last call, without blocking
+    pub fn poll(&mut self) -> Vec<DapMessage> {
+        let mut messages: Vec<DapMessage> = self.backlog.drain(..).collect();
+        loop {
+            match self.incoming.try_recv() {
+                Ok(message) => messages.extend(self.handle_message(message)),
+                Err(TryRecvError::Empty) => break,
+                // Adapters that exit without `terminated` end the session too
+                Err(TryRecvError::Disconnected) => {
+                    messages.push(DapMessage::Event { event: "terminated".to_string(), body: serde_json::Value::Null });
+                    break;
+                },
+            }
+        }
+        messages
+    }
+
+    /// Classify a raw message. Requests from the adapter, such as runInTerminal,
+    /// are refused here; anything else is returned for the editor
+    fn handle_message(&mut self, message: serde_json::Value) -> Option<DapMessage> {
+        match message["type"].as_str() {
+            Some("response") => Some(DapMessage::Response {
+                request_seq: message["request_seq"].as_u64()?,
+                command: message["command"].as_str().unwrap_or_default().to_string(),
+                result: if message["success"].as_bool().unwrap_or(false) {
+                    Ok(message.get("body").cloned().unwrap_or(serde_json::Value::Null))
+                } else {
+                    Err(message["message"].as_str().unwrap_or("request failed").to_string())
+                },
+            }),
+            Some("event") => Some(DapMessage::Event {
+                event: message["event"].as_str()?.to_string(),
+                body: message.get("body").cloned().unwrap_or(serde_json::Value::Null),
+            }),
+            Some("request") => {
+                let command = message["command"].as_str().unwrap_or_default();
+                self.next_seq += 1;
+                let reply = serde_json::json!({
+                    "seq": self.next_seq,
+                    "type": "response",
+                    "request_seq": message["seq"],
+                    "command": command,
+                    "success": false,
+                    "message": format!("{} is not supported", command),
+                });
+                if let Err(e) = self.write_message(&reply) {
+                    error!("Failed to answer {} from {}: {}", command, self.name, e);
+                }
+                None
+            },
+            _ => {
+                warn!("Ignoring malformed message from {}", self.name);
+                None
+            },
+        }
+    }
+}
+
+impl Drop for DapClient {
+    fn drop(&mut self) {
+        if let Err(e) = self.process.kill() {
+            error!("Failed to kill debug adapter process: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_from_stack_trace() {
+        let body = serde_json::json!({
+            "stackFrames": [
+                { "id": 1, "name": "main", "source": { "path": "/src/main.rs" }, "line": 12, "column": 5 },
+                { "id": 2, "name": "start", "line": 0, "column": 0 },
+                { "id": 3 },
+            ],
+            "totalFrames": 3,
+        });
+        let frames = StackFrame::from_response(&body);
+        assert_eq!(frames, [
+            StackFrame { name: "main".to_string(), path: Some(PathBuf::from("/src/main.rs")), line: 11, column: 4 },
+            StackFrame { name: "start".to_string(), path: None, line: 0, column: 0 },
+        ]);
+    }
+}
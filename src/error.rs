@@ -45,6 +45,9 @@ pub enum Error {
     LspServerNotFound(String),
     LspConnectionError(String),
     LspInitializationError(String),
+
+    /// Debug adapter errors
+    DebugAdapter(String),
     
     /// Shell errors
     ShellSpawnError(String),
@@ -104,6 +107,8 @@ impl fmt::Display for Error {
             Error::LspServerNotFound(server) => write!(f, "LSP server not found: {}", server),
             Error::LspConnectionError(msg) => write!(f, "LSP connection error: {}", msg),
             Error::LspInitializationError(msg) => write!(f, "LSP initialization error: {}", msg),
+
+            Error::DebugAdapter(msg) => write!(f, "Debug adapter error: {}", msg),
             
             Error::ShellSpawnError(msg) => write!(f, "Failed to spawn shell: {}", msg),
             Error::ShellInputError(msg) => write!(f, "Shell input error: {}", msg),
@@ -1,8 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio, Child};
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::env;
 use std::fs;
 use log::{info, error, warn};
@@ -34,6 +38,73 @@ pub fn get_language_id_from_extension(ext: &str) -> Option<&'static str> {
     }
 }
 
+/// Convert a path to a `file://` URI, percent-encoding anything outside the
+/// unreserved characters
+pub fn path_to_uri(path: &Path) -> String {
+    let absolute = absolute_path(path);
+    let mut uri = String::from("file://");
+    let text = absolute.to_string_lossy().replace('\\', "/");
+    if !text.starts_with('/') {
+        // Windows drive paths need a leading slash: file:///C:/...
+        uri.push('/');
+    }
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+/// Convert a `file://` URI back to a path, decoding percent escapes
+pub fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?;
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    let mut path = String::from_utf8(decoded).ok()?;
+    // file:///C:/... on Windows
+    if path.len() > 2 && path.as_bytes()[2] == b':' {
+        path.remove(0);
+    }
+    Some(PathBuf::from(path))
+}
+
+fn absolute_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| {
+        if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            env::current_dir().unwrap_or_default().join(path)
+        }
+    })
+}
+
+/// `WorkspaceFolder` object for a directory: `{ uri, name }`
+fn workspace_folder_json(path: &Path) -> serde_json::Value {
+    let name = path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string_lossy().to_string());
+    serde_json::json!({ "uri": path_to_uri(path), "name": name })
+}
+
 /// Define known LSP server configurations
 struct LspServerConfig {
     language_id: &'static str,
@@ -70,6 +141,509 @@ impl From<LspError> for Error {
     }
 }
 
+impl LspError {
+    /// Parse the `error` member of a JSON-RPC response
+    fn from_json(value: &serde_json::Value) -> Self {
+        Self {
+            code: value["code"].as_i64().unwrap_or(0) as i32,
+            message: value["message"].as_str().unwrap_or("Unknown error").to_string(),
+            data: value.get("data").cloned(),
+        }
+    }
+}
+
+/// How long `send_request` waits for the server to answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long `initialize` waits; servers may index the project before answering
+const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A message from a language server, as returned by `LspManager::poll_responses`
+#[derive(Debug, Clone)]
+pub enum LspMessage {
+    /// Answer to a request sent with `LanguageServer::request`
+    Response {
+        language_id: String,
+        id: u64,
+        result: std::result::Result<serde_json::Value, LspError>,
+    },
+    /// Notification pushed by the server, e.g. `textDocument/publishDiagnostics`
+    Notification {
+        language_id: String,
+        method: String,
+        params: serde_json::Value,
+    },
+}
+
+/// Read one message body framed with `Content-Length` headers; `None` at end of stream
+pub(crate) fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("Content-Length")
+        {
+            content_length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = content_length
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+/// A diagnostic reported by a language server for one file
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub range_start_line: usize,
+    pub range_start_col: usize,
+    /// LSP `DiagnosticSeverity`: 1 error, 2 warning, 3 information, 4 hint
+    pub severity: u8,
+    pub message: String,
+    pub source: Option<String>,
+    pub code: Option<String>,
+    /// Locations and messages from `relatedInformation`, e.g. suggested fixes
+    pub related: Vec<String>,
+    /// The diagnostic as the server sent it, passed back in code action requests
+    pub json: serde_json::Value,
+}
+
+impl Diagnostic {
+    /// Parse one entry of `textDocument/publishDiagnostics`
+    pub fn from_json(value: &serde_json::Value) -> Option<Self> {
+        let start = &value["range"]["start"];
+        // The code may be a number or a string
+        let code = match &value["code"] {
+            serde_json::Value::String(code) => Some(code.clone()),
+            serde_json::Value::Number(code) => Some(code.to_string()),
+            _ => None,
+        };
+        let related = value["relatedInformation"].as_array()
+            .map(|items| items.iter()
+                .filter_map(|item| {
+                    let message = item["message"].as_str()?;
+                    let uri = item["location"]["uri"].as_str().unwrap_or("");
+                    let file = uri.rsplit('/').next().unwrap_or(uri);
+                    let line = item["location"]["range"]["start"]["line"].as_u64().unwrap_or(0) + 1;
+                    Some(format!("{}:{}: {}", file, line, message))
+                })
+                .collect())
+            .unwrap_or_default();
+
+        Some(Self {
+            range_start_line: start["line"].as_u64()? as usize,
+            range_start_col: start["character"].as_u64().unwrap_or(0) as usize,
+            severity: value["severity"].as_u64().unwrap_or(1) as u8,
+            message: value["message"].as_str()?.to_string(),
+            source: value["source"].as_str().map(str::to_string),
+            code,
+            related,
+            json: value.clone(),
+        })
+    }
+
+    pub fn severity_name(&self) -> &'static str {
+        match self.severity {
+            1 => "error",
+            2 => "warning",
+            3 => "info",
+            _ => "hint",
+        }
+    }
+}
+
+/// A symbol from a `textDocument/documentSymbol` response, flattened
+#[derive(Debug, Clone)]
+pub struct DocumentSymbol {
+    pub name: String,
+    pub kind: u32,
+    pub container: Option<String>,
+    /// Zero-based start position of the symbol
+    pub line: usize,
+    pub character: usize,
+}
+
+impl DocumentSymbol {
+    /// Parse either response shape: nested `DocumentSymbol[]` or flat `SymbolInformation[]`
+    pub fn from_response(result: &serde_json::Value) -> Vec<Self> {
+        let mut symbols = Vec::new();
+        if let Some(items) = result.as_array() {
+            for item in items {
+                Self::collect(item, None, &mut symbols);
+            }
+        }
+        symbols
+    }
+
+    fn collect(item: &serde_json::Value, container: Option<&str>, symbols: &mut Vec<Self>) {
+        let Some(name) = item["name"].as_str() else {
+            return;
+        };
+        // DocumentSymbol has selectionRange, SymbolInformation has location.range
+        let start = if item["selectionRange"].is_object() {
+            &item["selectionRange"]["start"]
+        } else {
+            &item["location"]["range"]["start"]
+        };
+        let container = container.map(str::to_string)
+            .or_else(|| item["containerName"].as_str().map(str::to_string));
+        symbols.push(Self {
+            name: name.to_string(),
+            kind: item["kind"].as_u64().unwrap_or(0) as u32,
+            container,
+            line: start["line"].as_u64().unwrap_or(0) as usize,
+            character: start["character"].as_u64().unwrap_or(0) as usize,
+        });
+        if let Some(children) = item["children"].as_array() {
+            for child in children {
+                Self::collect(child, Some(name), symbols);
+            }
+        }
+    }
+
+    /// Human readable name of the LSP `SymbolKind`
+    pub fn kind_name(&self) -> &'static str {
+        match self.kind {
+            1 => "file",
+            2 => "module",
+            3 => "namespace",
+            4 => "package",
+            5 => "class",
+            6 => "method",
+            7 => "property",
+            8 => "field",
+            9 => "constructor",
+            10 => "enum",
+            11 => "interface",
+            12 => "function",
+            13 => "variable",
+            14 => "constant",
+            22 => "enum member",
+            23 => "struct",
+            26 => "type parameter",
+            _ => "symbol",
+        }
+    }
+}
+
+/// A symbol from a `workspace/symbol` response
+#[derive(Debug, Clone)]
+pub struct WorkspaceSymbol {
+    pub name: String,
+    pub kind: u32,
+    pub container: Option<String>,
+    pub location: Location,
+}
+
+impl WorkspaceSymbol {
+    /// Parse `SymbolInformation[]` or `WorkspaceSymbol[]`; symbols without a file are skipped
+    pub fn from_response(result: &serde_json::Value) -> Vec<Self> {
+        let Some(items) = result.as_array() else {
+            return Vec::new();
+        };
+        items.iter()
+            .filter_map(|item| Some(Self {
+                name: item["name"].as_str()?.to_string(),
+                kind: item["kind"].as_u64().unwrap_or(0) as u32,
+                container: item["containerName"].as_str().filter(|name| !name.is_empty()).map(str::to_string),
+                location: Location::from_json(&item["location"])?,
+            }))
+            .collect()
+    }
+
+    /// One-character mark for the LSP `SymbolKind`, shown before the name
+    pub fn kind_icon(&self) -> &'static str {
+        match self.kind {
+            1 => "F",
+            2..=4 => "M",
+            5 => "C",
+            6 | 12 => "ƒ",
+            7 | 8 => "f",
+            9 => "n",
+            10 => "E",
+            11 => "I",
+            13 => "v",
+            14 => "c",
+            22 => "e",
+            23 => "S",
+            26 => "T",
+            _ => "·",
+        }
+    }
+}
+
+/// An entry of a `textDocument/completion` response
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompletionItem {
+    pub label: String,
+    /// Text that replaces the word before the cursor when the item is accepted
+    pub insert_text: String,
+    /// LSP `CompletionItemKind`; 0 when the server gave none
+    pub kind: u32,
+    pub detail: Option<String>,
+}
+
+impl CompletionItem {
+    /// Parse either response shape: `CompletionItem[]` or a `CompletionList`
+    pub fn from_response(result: &serde_json::Value) -> Vec<Self> {
+        let items = result.as_array().or_else(|| result["items"].as_array());
+        items.map(|items| items.iter().filter_map(Self::from_json).collect())
+            .unwrap_or_default()
+    }
+
+    fn from_json(item: &serde_json::Value) -> Option<Self> {
+        let label = item["label"].as_str()?.to_string();
+        let insert_text = item["textEdit"]["newText"].as_str()
+            .or_else(|| item["insertText"].as_str())
+            .unwrap_or(&label)
+            .to_string();
+        Some(Self {
+            label,
+            insert_text,
+            kind: item["kind"].as_u64().unwrap_or(0) as u32,
+            detail: item["detail"].as_str().map(str::to_string),
+        })
+    }
+
+    /// Human readable name of the LSP `CompletionItemKind`
+    pub fn kind_name(&self) -> &'static str {
+        match self.kind {
+            1 => "text",
+            2 => "method",
+            3 => "function",
+            4 => "constructor",
+            5 => "field",
+            6 => "variable",
+            7 => "class",
+            8 => "interface",
+            9 => "module",
+            10 => "property",
+            13 => "enum",
+            14 => "keyword",
+            15 => "snippet",
+            20 => "enum member",
+            21 => "constant",
+            22 => "struct",
+            25 => "type parameter",
+            _ => "",
+        }
+    }
+}
+
+/// A position in a file from a `Location` or `LocationLink`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub path: PathBuf,
+    /// Zero-based line and UTF-16 column
+    pub line: usize,
+    pub character: usize,
+}
+
+impl Location {
+    /// Parse a `textDocument/definition` style response: a single `Location`,
+    /// `Location[]`, `LocationLink[]` or null
+    pub fn from_response(result: &serde_json::Value) -> Vec<Self> {
+        match result.as_array() {
+            Some(items) => items.iter().filter_map(Self::from_json).collect(),
+            None => Self::from_json(result).into_iter().collect(),
+        }
+    }
+
+    fn from_json(item: &serde_json::Value) -> Option<Self> {
+        // LocationLink has targetUri and targetSelectionRange
+        let (uri, start) = match item["targetUri"].as_str() {
+            Some(uri) => (uri, &item["targetSelectionRange"]["start"]),
+            None => (item["uri"].as_str()?, &item["range"]["start"]),
+        };
+        Some(Self {
+            path: uri_to_path(uri)?,
+            line: start["line"].as_u64().unwrap_or(0) as usize,
+            character: start["character"].as_u64().unwrap_or(0) as usize,
+        })
+    }
+}
+
+/// A signature of a `textDocument/signatureHelp` response
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignatureInformation {
+    pub label: String,
+    /// Byte range in `label` of the parameter being typed
+    pub active_parameter: Option<std::ops::Range<usize>>,
+}
+
+impl SignatureInformation {
+    /// The signatures of a `SignatureHelp` and the index of the active one
+    pub fn from_response(result: &serde_json::Value) -> (Vec<Self>, usize) {
+        let active_parameter = result["activeParameter"].as_u64();
+        let signatures: Vec<Self> = result["signatures"].as_array()
+            .map(|items| items.iter().filter_map(|item| Self::from_json(item, active_parameter)).collect())
+            .unwrap_or_default();
+        let active = result["activeSignature"].as_u64().unwrap_or(0) as usize;
+        let active = active.min(signatures.len().saturating_sub(1));
+        (signatures, active)
+    }
+
+    fn from_json(item: &serde_json::Value, active_parameter: Option<u64>) -> Option<Self> {
+        let label = item["label"].as_str()?.to_string();
+        // A signature's own activeParameter wins over the response's
+        let index = item["activeParameter"].as_u64().or(active_parameter).map(|index| index as usize);
+        let active_parameter = index.and_then(|index| match &item["parameters"][index]["label"] {
+            // Searched for after the opening parenthesis and the parameters before
+            // it, so `a` in `fn swap(a: &mut T, b: &mut T)` is not the one in `swap`
+            serde_json::Value::String(_) => {
+                let mut from = label.find('(').map_or(0, |open| open + 1);
+                let mut range = None;
+                for parameter in item["parameters"].as_array()?.iter().take(index + 1) {
+                    let name = parameter["label"].as_str()?;
+                    let start = from + label[from..].find(name)?;
+                    from = start + name.len();
+                    range = Some(start..from);
+                }
+                range
+            },
+            // [start, end) in UTF-16 code units
+            serde_json::Value::Array(offsets) => {
+                let byte = |units: &serde_json::Value| {
+                    let units = units.as_u64()? as usize;
+                    let mut count = 0;
+                    for (idx, c) in label.char_indices() {
+                        if count >= units {
+                            return Some(idx);
+                        }
+                        count += c.len_utf16();
+                    }
+                    Some(label.len())
+                };
+                Some(byte(offsets.first()?)?..byte(offsets.get(1)?)?)
+            },
+            _ => None,
+        });
+        Some(Self { label, active_parameter })
+    }
+}
+
+/// A range of a `textDocument/documentHighlight` response: a read, write or
+/// other reference to the symbol under the cursor
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocumentHighlight {
+    /// Zero-based lines and UTF-16 columns
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+}
+
+impl DocumentHighlight {
+    pub fn from_response(result: &serde_json::Value) -> Vec<Self> {
+        result.as_array()
+            .map(|items| items.iter().filter_map(Self::from_json).collect())
+            .unwrap_or_default()
+    }
+
+    fn from_json(item: &serde_json::Value) -> Option<Self> {
+        let position = |position: &serde_json::Value| Some((
+            position["line"].as_u64()? as usize,
+            position["character"].as_u64()? as usize,
+        ));
+        Some(Self {
+            start: position(&item["range"]["start"])?,
+            end: position(&item["range"]["end"])?,
+        })
+    }
+}
+
+/// An entry of a `textDocument/codeAction` response, either a `CodeAction` or a
+/// bare `Command`
+#[derive(Debug, Clone)]
+pub struct CodeAction {
+    pub title: String,
+    pub kind: Option<String>,
+    /// `WorkspaceEdit` to apply when the action is chosen
+    pub edit: Option<serde_json::Value>,
+    /// `Command` to run with `workspace/executeCommand`, after the edit
+    pub command: Option<serde_json::Value>,
+}
+
+impl CodeAction {
+    pub fn from_response(result: &serde_json::Value) -> Vec<Self> {
+        result.as_array()
+            .map(|items| items.iter().filter_map(Self::from_json).collect())
+            .unwrap_or_default()
+    }
+
+    fn from_json(item: &serde_json::Value) -> Option<Self> {
+        let title = item["title"].as_str()?.to_string();
+        // A Command has the command name as a string where a CodeAction has a Command
+        if item["command"].is_string() {
+            return Some(Self { title, kind: None, edit: None, command: Some(item.clone()) });
+        }
+        Some(Self {
+            title,
+            kind: item["kind"].as_str().map(str::to_string),
+            edit: Some(item["edit"].clone()).filter(serde_json::Value::is_object),
+            command: Some(item["command"].clone()).filter(serde_json::Value::is_object),
+        })
+    }
+}
+
+/// A `TextEdit`: replace the text from `start` to `end` with `new_text`.
+/// Positions are zero-based lines and UTF-16 columns
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextEdit {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    pub new_text: String,
+}
+
+impl TextEdit {
+    fn from_json(item: &serde_json::Value) -> Option<Self> {
+        let position = |position: &serde_json::Value| Some((
+            position["line"].as_u64()? as usize,
+            position["character"].as_u64()? as usize,
+        ));
+        Some(Self {
+            start: position(&item["range"]["start"])?,
+            end: position(&item["range"]["end"])?,
+            new_text: item["newText"].as_str()?.to_string(),
+        })
+    }
+
+    /// The edits in a `TextEdit[]` response, e.g. to `textDocument/formatting`
+    pub fn from_response(result: &serde_json::Value) -> Vec<Self> {
+        result.as_array()
+            .map(|items| items.iter().filter_map(Self::from_json).collect())
+            .unwrap_or_default()
+    }
+
+    /// The edits of a `WorkspaceEdit` per file, from `changes` or `documentChanges`.
+    /// File creation, renaming and deletion in `documentChanges` are skipped
+    pub fn from_workspace_edit(edit: &serde_json::Value) -> Vec<(PathBuf, Vec<Self>)> {
+        let edits = |items: &serde_json::Value| -> Vec<Self> {
+            items.as_array()
+                .map(|items| items.iter().filter_map(Self::from_json).collect())
+                .unwrap_or_default()
+        };
+        if let Some(changes) = edit["documentChanges"].as_array() {
+            return changes.iter()
+                .filter_map(|change| {
+                    let path = uri_to_path(change["textDocument"]["uri"].as_str()?)?;
+                    Some((path, edits(&change["edits"])))
+                })
+                .collect();
+        }
+        edit["changes"].as_object()
+            .map(|changes| changes.iter()
+                .filter_map(|(uri, items)| Some((uri_to_path(uri)?, edits(items))))
+                .collect())
+            .unwrap_or_default()
+    }
+}
+
 /// Active language server process
 pub struct LanguageServer {
     language_id: String,
@@ -77,13 +651,18 @@ pub struct LanguageServer {
     root_dir: PathBuf,
     capabilities: serde_json::Value,
     initialized: bool,
+    next_request_id: u64,
+    // Messages parsed by the reader thread
+    incoming: Receiver<serde_json::Value>,
+    // Messages that arrived while send_request waited for its response
+    backlog: VecDeque<LspMessage>,
 }
 
 impl LanguageServer {
     pub fn new(language_id: &str, executable: &str, args: &[&str], root_dir: &Path) -> Result<Self> {
         info!("Starting language server for {}: {} {:?}", language_id, executable, args);
         
-        let process = Command::new(executable)
+        let mut process = Command::new(executable)
             .args(args)
             .current_dir(root_dir)
             .stdin(Stdio::piped())
@@ -91,16 +670,246 @@ impl LanguageServer {
             .stderr(Stdio::piped())
             .spawn()
             .map_err(|e| Error::LspInitializationError(format!("Failed to start {}: {}", executable, e)))?;
-        
+
+        let stdout = process.stdout.take()
+            .ok_or_else(|| Error::LspInitializationError(format!("No stdout for {}", executable)))?;
+        let stderr = process.stderr.take()
+            .ok_or_else(|| Error::LspInitializationError(format!("No stderr for {}", executable)))?;
+
+        // Read messages in the background so the editor loop never blocks on the server
+        let (tx, rx) = mpsc::channel();
+        let lang = language_id.to_string();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            loop {
+                match read_message(&mut reader) {
+                    Ok(Some(body)) => match serde_json::from_slice(&body) {
+                        Ok(message) => {
+                            if tx.send(message).is_err() {
+                                break; // Server was dropped
+                            }
+                        },
+                        Err(e) => warn!("Invalid message from {} server: {}", lang, e),
+                    },
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Failed to read from {} server: {}", lang, e);
+                        break;
+                    }
+                }
+            }
+            info!("Reader for {} server stopped", lang);
+        });
+
+        // Drain stderr into the log; a full pipe would stall the server
+        let lang = language_id.to_string();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(|line| line.ok()) {
+                info!("{} server: {}", lang, line);
+            }
+        });
+
         Ok(Self {
             language_id: language_id.to_string(),
             process,
             root_dir: root_dir.to_path_buf(),
             capabilities: serde_json::Value::Null,
             initialized: false,
+            next_request_id: 0,
+            incoming: rx,
+            backlog: VecDeque::new(),
         })
     }
-    
+
+    pub fn is_initialized(&self) -> bool {
+        self.initialized
+    }
+
+    pub fn capabilities(&self) -> &serde_json::Value {
+        &self.capabilities
+    }
+
+    /// Characters such as `.` or `::` after which the server offers completions
+    pub fn completion_triggers(&self) -> Vec<String> {
+        self.capabilities["completionProvider"]["triggerCharacters"].as_array()
+            .map(|chars| chars.iter().filter_map(|c| c.as_str().map(str::to_string)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Run the `initialize`/`initialized` handshake, waiting for the server's
+    /// `InitializeResult`, which is returned
+    pub fn initialize(&mut self, client_capabilities: serde_json::Value) -> Result<serde_json::Value> {
+        let params = serde_json::json!({
+            "processId": std::process::id(),
+            "rootUri": path_to_uri(&self.root_dir),
+            "capabilities": client_capabilities,
+            "workspaceFolders": [workspace_folder_json(&absolute_path(&self.root_dir))],
+        });
+        let id = self.request("initialize", params)?;
+        let result = self.wait_for_response(id, "initialize", INITIALIZE_TIMEOUT)
+            .map_err(|e| Error::LspInitializationError(format!("{} server: {}", self.language_id, e)))?;
+        self.send_notification("initialized", serde_json::json!({}))?;
+        self.capabilities = result["capabilities"].clone();
+        self.initialized = true;
+        info!("{} server initialized", self.language_id);
+        Ok(result)
+    }
+
+    /// Write one JSON-RPC message to the server using the LSP base protocol framing
+    fn write_message(&mut self, message: &serde_json::Value) -> Result<()> {
+        let body = message.to_string();
+        let stdin = self.process.stdin.as_mut()
+            .ok_or_else(|| Error::LspConnectionError(format!("No stdin for {} server", self.language_id)))?;
+        write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+            .and_then(|_| stdin.flush())
+            .map_err(|e| Error::LspConnectionError(format!("Failed to write to {} server: {}", self.language_id, e)))
+    }
+
+    /// Send a request without waiting, returning the id its response will carry.
+    /// The response is delivered through `poll`
+    pub fn request(&mut self, method: &str, params: serde_json::Value) -> Result<u64> {
+        self.next_request_id += 1;
+        let id = self.next_request_id;
+        self.write_message(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        }))?;
+        Ok(id)
+    }
+
+    /// Send a request and wait for its result
+    pub fn send_request(&mut self, method: &str, params: serde_json::Value) -> Result<serde_json::Value> {
+        let id = self.request(method, params)?;
+        self.wait_for_response(id, method, REQUEST_TIMEOUT)
+    }
+
+    /// Block until the response to request `id` arrives, keeping other messages
+    /// for `poll`
+    fn wait_for_response(&mut self, id: u64, method: &str, timeout: Duration) -> Result<serde_json::Value> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let message = match self.incoming.recv_timeout(timeout) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(Error::LspConnectionError(format!("{} request to {} server timed out", method, self.language_id)));
+                },
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::LspConnectionError(format!("{} server exited", self.language_id)));
+                },
+            };
+            match self.handle_message(message) {
+                Some(LspMessage::Response { id: response_id, result, .. }) if response_id == id => {
+                    return result.map_err(|e| Error::LspError {
+                        code: e.code,
+                        message: e.message,
+                        language: Some(self.language_id.clone()),
+                    });
+                },
+                Some(other) => self.backlog.push_back(other),
+                None => {},
+            }
+        }
+    }
+
+    pub fn send_notification(&mut self, method: &str, params: serde_json::Value) -> Result<()> {
+        self.write_message(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+        }))
+    }
+
+    /// `textDocument/didOpen` with the whole text of a file
+    pub fn did_open(&mut self, uri: &str, language_id: &str, version: u64, text: &str) -> Result<()> {
+        self.send_notification("textDocument/didOpen", serde_json::json!({
+            "textDocument": { "uri": uri, "languageId": language_id, "version": version, "text": text },
+        }))
+    }
+
+    /// `textDocument/didChange` replacing the whole text; edits are not sent incrementally yet
+    pub fn did_change(&mut self, uri: &str, version: u64, text: &str) -> Result<()> {
+        self.send_notification("textDocument/didChange", serde_json::json!({
+            "textDocument": { "uri": uri, "version": version },
+            "contentChanges": [{ "text": text }],
+        }))
+    }
+
+    pub fn did_close(&mut self, uri: &str) -> Result<()> {
+        self.send_notification("textDocument/didClose", serde_json::json!({
+            "textDocument": { "uri": uri },
+        }))
+    }
+
+    /// Messages received since the last call, without blocking
+    pub fn poll(&mut self) -> Vec<LspMessage> {
+        let mut messages: Vec<LspMessage> = self.backlog.drain(..).collect();
+        loop {
+            match self.incoming.try_recv() {
+                Ok(message) => messages.extend(self.handle_message(message)),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if self.initialized {
+                        warn!("{} server closed its output", self.language_id);
+                        self.initialized = false;
+                    }
+                    break;
+                },
+            }
+        }
+        messages
+    }
+
+    /// Classify a raw JSON-RPC message. The handshake and requests from the
+    /// server are dealt with here; anything else is returned for the editor
+    fn handle_message(&mut self, message: serde_json::Value) -> Option<LspMessage> {
+        let method = message["method"].as_str().map(str::to_string);
+        match (message.get("id"), method) {
+            // Request from the server, e.g. workspace/configuration
+            (Some(id), Some(method)) => {
+                let result = match method.as_str() {
+                    // One entry per requested configuration section; we have no settings
+                    "workspace/configuration" => {
+                        let items = message["params"]["items"].as_array().map_or(0, |items| items.len());
+                        serde_json::Value::Array(vec![serde_json::Value::Null; items])
+                    },
+                    // Commands run with workspace/executeCommand may edit files through
+                    // the editor, which applies the edit when it gets the notification below
+                    "workspace/applyEdit" => serde_json::json!({ "applied": true }),
+                    _ => serde_json::Value::Null,
+                };
+                let reply = serde_json::json!({ "jsonrpc": "2.0", "id": id.clone(), "result": result });
+                if let Err(e) = self.write_message(&reply) {
+                    error!("Failed to answer {} from {} server: {}", method, self.language_id, e);
+                }
+                (method == "workspace/applyEdit").then(|| LspMessage::Notification {
+                    language_id: self.language_id.clone(),
+                    method,
+                    params: message.get("params").cloned().unwrap_or(serde_json::Value::Null),
+                })
+            },
+            (Some(id), None) => {
+                let id = id.as_u64()?;
+                let result = match message.get("error") {
+                    Some(error) => Err(LspError::from_json(error)),
+                    None => Ok(message.get("result").cloned().unwrap_or(serde_json::Value::Null)),
+                };
+                Some(LspMessage::Response { language_id: self.language_id.clone(), id, result })
+            },
+            (None, Some(method)) => Some(LspMessage::Notification {
+                language_id: self.language_id.clone(),
+                method,
+                params: message.get("params").cloned().unwrap_or(serde_json::Value::Null),
+            }),
+            (None, None) => {
+                warn!("Ignoring malformed message from {} server", self.language_id);
+                None
+            },
+        }
+    }
+
     pub fn shutdown(&mut self) -> Result<()> {
         info!("Shutting down language server for {}", self.language_id);
         self.process.kill()
@@ -120,8 +929,12 @@ impl Drop for LanguageServer {
 /// LSP Manager that scans for and manages language servers
 pub struct LspManager {
     servers: HashMap<String, Arc<Mutex<LanguageServer>>>,
+    /// Languages whose server failed to start or initialize; starting one can
+    /// block for the whole initialize timeout, so they are not tried again
+    failed_servers: HashSet<String>,
     server_configs: Vec<LspServerConfig>,
     workspace_root: PathBuf,
+    workspace_folders: Vec<PathBuf>,
 }
 
 impl LspManager {
@@ -216,10 +1029,97 @@ impl LspManager {
         
         Self {
             servers: HashMap::new(),
+            failed_servers: HashSet::new(),
             server_configs: configs,
+            workspace_folders: vec![absolute_path(&workspace_root)],
             workspace_root,
         }
     }
+
+    pub fn workspace_folders(&self) -> &[PathBuf] {
+        &self.workspace_folders
+    }
+
+    /// Capabilities announced to each new server
+    fn client_capabilities(&self) -> serde_json::Value {
+        serde_json::json!({
+            "workspace": { "workspaceFolders": true, "applyEdit": true },
+            "textDocument": {
+                "codeAction": {
+                    "codeActionLiteralSupport": {
+                        "codeActionKind": { "valueSet": ["", "quickfix", "refactor", "source"] },
+                    },
+                },
+                // Items are inserted as plain text, so ask for no snippets
+                "completion": { "completionItem": { "snippetSupport": false } },
+            },
+        })
+    }
+
+    /// Add a root directory to the workspace and tell the running servers
+    pub fn add_workspace_folder(&mut self, path: PathBuf) -> Result<()> {
+        let path = absolute_path(&path);
+        if !path.is_dir() {
+            return Err(Error::Message(format!("Not a directory: {}", path.display())));
+        }
+        if self.workspace_folders.contains(&path) {
+            return Ok(());
+        }
+        info!("Adding workspace folder {:?}", path);
+        let event = serde_json::json!({ "added": [workspace_folder_json(&path)], "removed": [] });
+        self.workspace_folders.push(path);
+        self.notify_workspace_folders_changed(event)
+    }
+
+    /// Make `root` the first workspace folder in place of the current root, as
+    /// after `:cd`, and tell the running servers
+    pub fn set_workspace_root(&mut self, root: PathBuf) -> Result<()> {
+        let root = absolute_path(&root);
+        if self.workspace_folders.first() == Some(&root) {
+            return Ok(());
+        }
+        info!("Moving workspace root to {:?}", root);
+        let removed: Vec<serde_json::Value> = self.workspace_folders.first()
+            .map(|old| workspace_folder_json(old))
+            .into_iter()
+            .collect();
+        // A folder already in the workspace moves to the front rather than being added again
+        let added: Vec<serde_json::Value> = (!self.workspace_folders.contains(&root))
+            .then(|| workspace_folder_json(&root))
+            .into_iter()
+            .collect();
+        self.workspace_folders.retain(|folder| *folder != root);
+        match self.workspace_folders.first_mut() {
+            Some(first) => *first = root.clone(),
+            None => self.workspace_folders.push(root.clone()),
+        }
+        self.workspace_root = root;
+        let event = serde_json::json!({ "added": added, "removed": removed });
+        self.notify_workspace_folders_changed(event)
+    }
+
+    /// Remove a root directory from the workspace and tell the running servers
+    pub fn remove_workspace_folder(&mut self, path: &Path) -> Result<()> {
+        let path = absolute_path(path);
+        let idx = self.workspace_folders.iter().position(|folder| *folder == path)
+            .ok_or_else(|| Error::Message(format!("Not a workspace folder: {}", path.display())))?;
+        info!("Removing workspace folder {:?}", path);
+        self.workspace_folders.remove(idx);
+        let event = serde_json::json!({ "added": [], "removed": [workspace_folder_json(&path)] });
+        self.notify_workspace_folders_changed(event)
+    }
+
+    fn notify_workspace_folders_changed(&self, event: serde_json::Value) -> Result<()> {
+        let params = serde_json::json!({ "event": event });
+        for (lang_id, server) in &self.servers {
+            if let Ok(mut server) = server.lock()
+                && let Err(e) = server.send_notification("workspace/didChangeWorkspaceFolders", params.clone())
+            {
+                error!("Failed to update workspace folders for {}: {}", lang_id, e);
+            }
+        }
+        Ok(())
+    }
     
     // Scan system for installed language servers
     pub fn scan_for_language_servers(&self) -> Vec<String> {
@@ -266,6 +1166,10 @@ impl LspManager {
                 info!("Language server for {} is already running", lang_id);
                 return Ok(Some(lang_id));
             }
+            // The failure was reported when it happened
+            if self.failed_servers.contains(&lang_id) {
+                return Ok(None);
+            }
             
             // Find server config for this language
             for config in &self.server_configs {
@@ -279,13 +1183,29 @@ impl LspManager {
                             &config.args, 
                             &self.workspace_root
                         ) {
-                            Ok(server) => {
+                            Ok(mut server) => {
                                 info!("Started language server for {}", lang_id);
+                                if let Err(e) = server.initialize(self.client_capabilities()) {
+                                    error!("Language server for {} failed to initialize: {}", lang_id, e);
+                                    self.failed_servers.insert(lang_id);
+                                    return Err(e);
+                                }
+                                // `initialize` only names the root; add the folders opened since
+                                let added: Vec<serde_json::Value> = self.workspace_folders.iter()
+                                    .skip(1)
+                                    .map(|folder| workspace_folder_json(folder))
+                                    .collect();
+                                if !added.is_empty() {
+                                    let event = serde_json::json!({ "added": added, "removed": [] });
+                                    server.send_notification("workspace/didChangeWorkspaceFolders",
+                                        serde_json::json!({ "event": event }))?;
+                                }
                                 self.servers.insert(lang_id.clone(), Arc::new(Mutex::new(server)));
                                 return Ok(Some(lang_id));
                             },
                             Err(e) => {
                                 error!("Failed to start language server for {}: {}", lang_id, e);
+                                self.failed_servers.insert(lang_id);
                                 return Err(e);
                             }
                         }
@@ -306,6 +1226,17 @@ impl LspManager {
         Ok(None)
     }
     
+    /// Collect the messages every running server has sent since the last call
+    pub fn poll_responses(&mut self) -> Vec<LspMessage> {
+        let mut messages = Vec::new();
+        for server in self.servers.values() {
+            if let Ok(mut server) = server.lock() {
+                messages.extend(server.poll());
+            }
+        }
+        messages
+    }
+
     // Shutdown all running servers
     pub fn shutdown_all_servers(&mut self) -> Result<()> {
         for (lang_id, server) in self.servers.iter() {
@@ -320,6 +1251,44 @@ impl LspManager {
         Ok(())
     }
     
+    /// Markdown text of a `textDocument/hover` result. `contents` may be a
+    /// `MarkedString`, a list of them, or `MarkupContent`
+    pub fn get_hover_markup(hover: &serde_json::Value) -> Option<String> {
+        fn marked_string(value: &serde_json::Value) -> Option<String> {
+            match value {
+                serde_json::Value::String(text) => Some(text.clone()),
+                // { language, value } is a code block in that language
+                serde_json::Value::Object(map) => {
+                    let code = map.get("value")?.as_str()?;
+                    match map.get("language").and_then(|lang| lang.as_str()) {
+                        Some(lang) => Some(format!("```{}\n{}\n```", lang, code)),
+                        None => Some(code.to_string()),
+                    }
+                },
+                _ => None,
+            }
+        }
+
+        let contents = hover.get("contents")?;
+        let markup = match contents {
+            serde_json::Value::Array(items) => items.iter()
+                .filter_map(marked_string)
+                .collect::<Vec<_>>()
+                .join("\n\n---\n\n"),
+            // MarkupContent has a kind; plaintext and markdown both render fine as Markdown
+            serde_json::Value::Object(map) if map.contains_key("kind") => {
+                map.get("value")?.as_str()?.to_string()
+            },
+            other => marked_string(other)?,
+        };
+
+        if markup.trim().is_empty() {
+            None
+        } else {
+            Some(markup)
+        }
+    }
+
     // Get a reference to a running server
     pub fn get_server(&self, language_id: &str) -> Option<Arc<Mutex<LanguageServer>>> {
         self.servers.get(language_id).cloned()
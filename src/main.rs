@@ -10,21 +10,36 @@ use std::error::Error as StdError;
 
 mod cli;
 mod lsp;
+mod dap;
+mod config;
 mod error;
 
 use error::{Error, Result};
+use cli::profile::StartupTimes;
 
 fn main() -> Result<()> {
+    // Parse command line arguments
+    let args: Vec<String> = env::args().collect();
+    let mut filenames = Vec::new();
+    let mut startup_times = None;
+    let mut arg_iter = args.iter().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--startuptime" => {
+                let output = arg_iter.next()
+                    .ok_or_else(|| Error::Message("--startuptime requires a file name".to_string()))?;
+                startup_times = Some(StartupTimes::new(PathBuf::from(output)));
+            },
+            _ => filenames.push(arg.as_str()),
+        }
+    }
+
     // Initialize logging - simplify error conversion
     let log_file = File::create("rvim.log").map_err(Error::from)?;
     CombinedLogger::init(vec![
         WriteLogger::new(LevelFilter::Info, Config::default(), log_file),
     ])?;
     
-    // Parse command line arguments
-    let args: Vec<String> = env::args().collect();
-    let filename = args.get(1).map(|s| s.as_str());
-    
     // Load configuration
     let config_path = get_config_path()?;
     
@@ -34,12 +49,30 @@ fn main() -> Result<()> {
     
     // Initialize and run the editor
     let mut editor = cli::editor::Editor::new(config_path)?;
+    // Project config is looked for from the first file's directory, or the working directory
+    let project_dir = match filenames.first().map(std::path::absolute).transpose()? {
+        Some(path) if path.is_dir() => path,
+        Some(path) => path.parent().map(PathBuf::from).unwrap_or(env::current_dir()?),
+        None => env::current_dir()?,
+    };
+    editor.load_project_config(&project_dir);
+    if let Some(times) = startup_times.as_mut() {
+        times.mark("config loaded");
+    }
     
     // Set up plugin manager in the editor
     editor.set_plugin_manager(plugin_manager)?;
+    if let Some(mut times) = startup_times {
+        times.mark("plugins loaded");
+        editor.set_startup_times(times);
+    }
     
-    if let Some(file) = filename {
-        editor.open_file(file)?;
+    // Only the first file is read now, the others load when their tab is shown
+    if let Some((first, rest)) = filenames.split_first() {
+        editor.open_file(first)?;
+        for file in rest {
+            editor.open_file_lazy(file)?;
+        }
     }
     
     editor.run()
@@ -51,7 +84,8 @@ fn get_config_path() -> Result<PathBuf> {
         .ok_or_else(|| Error::ConfigError("Could not find config directory".to_string()))?
         .join("rvim");
     
-    if user_config_path.exists() && user_config_path.join("config.lua").exists() {
+    let has_config = |dir: &PathBuf| dir.join("config.lua").exists() || dir.join("config.toml").exists();
+    if has_config(&user_config_path) {
         info!("Using installed config at: {:?}", user_config_path);
         return Ok(user_config_path);
     }
@@ -61,7 +95,7 @@ fn get_config_path() -> Result<PathBuf> {
     // Fix the source config path - don't use ~ as it's not expanded automatically
     let source_config_path = current_dir.join("config");
     
-    if source_config_path.exists() && source_config_path.join("config.lua").exists() {
+    if has_config(&source_config_path) {
         info!("Using source config at: {:?}", source_config_path);
         return Ok(source_config_path);
     }
@@ -136,6 +170,11 @@ end"#;
         
         fs::write(default_config_path, default_config)?;
     }
+    let default_toml_path = user_config_path.join("config.toml");
+    if !default_toml_path.exists() {
+        info!("Creating default config at: {:?}", default_toml_path);
+        fs::write(default_toml_path, config::toml_config::DEFAULT_CONFIG)?;
+    }
     
     Ok(user_config_path)
 }